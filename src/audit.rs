@@ -0,0 +1,241 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use dav_server::fs::FsError;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{error, info};
+
+tokio::task_local! {
+    /// Who sent the request being handled, for the records of its operations
+    static CLIENT: RefCell<Client>;
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    principal: Option<String>,
+    ip: Option<IpAddr>,
+    /// Id of the file the operation in progress is about
+    file_id: Option<String>,
+}
+
+/// Run `fut` on behalf of the client at `ip`
+pub async fn with_client<F: Future>(ip: Option<IpAddr>, fut: F) -> F::Output {
+    let client = Client {
+        ip,
+        ..Default::default()
+    };
+    CLIENT.scope(RefCell::new(client), fut).await
+}
+
+/// Attribute the operations of the current request to an authenticated user
+pub fn set_principal(principal: &str) {
+    let _ = CLIENT.try_with(|client| client.borrow_mut().principal = Some(principal.to_string()));
+}
+
+/// Id of the file affected by the operation in progress, once it is known
+pub fn note_file_id(file_id: &str) {
+    let _ = CLIENT.try_with(|client| client.borrow_mut().file_id = Some(file_id.to_string()));
+}
+
+/// Mutating operation on the drive
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Operation {
+    Put,
+    Delete,
+    Move,
+    Copy,
+    Mkcol,
+}
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    principal: Option<&'a str>,
+    client_ip: Option<IpAddr>,
+    operation: Operation,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_id: Option<&'a str>,
+    result: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+}
+
+/// Operation to record
+#[derive(Debug, Clone)]
+pub struct Entry {
+    operation: Operation,
+    path: PathBuf,
+    destination: Option<PathBuf>,
+    bytes: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(operation: Operation, path: &Path) -> Self {
+        Self {
+            operation,
+            path: path.to_path_buf(),
+            destination: None,
+            bytes: None,
+        }
+    }
+
+    pub fn destination(mut self, destination: &Path) -> Self {
+        self.destination = Some(destination.to_path_buf());
+        self
+    }
+
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+}
+
+/// Append-only JSON lines file recording every mutating operation, each
+/// record is synced to disk before the operation is answered
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = open_append(&path)?;
+        Ok(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Open the file again after it was rotated, keeping the current one on errors
+    pub fn reopen(&self) {
+        match open_append(&self.path) {
+            Ok(file) => {
+                *self.file.lock().unwrap() = file;
+                info!(path = %self.path.display(), "audit log reopened");
+            }
+            Err(err) => error!(error = %err, "audit log reopen failed, keeping the current file"),
+        }
+    }
+
+    pub async fn record(&self, entry: Entry, result: Result<(), FsError>) {
+        let (principal, client_ip, file_id) = CLIENT
+            .try_with(|client| {
+                let mut client = client.borrow_mut();
+                (client.principal.clone(), client.ip, client.file_id.take())
+            })
+            .unwrap_or_default();
+        let path = entry.path.to_string_lossy();
+        let destination = entry
+            .destination
+            .as_ref()
+            .map(|dest| dest.to_string_lossy());
+        let record = Record {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            principal: principal.as_deref(),
+            client_ip,
+            operation: entry.operation,
+            path: &path,
+            destination: destination.as_deref(),
+            file_id: file_id.as_deref(),
+            result: match result {
+                Ok(()) => "ok".to_string(),
+                Err(err) => format!("{:?}", err),
+            },
+            bytes: entry.bytes,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!(error = %err, "serialize audit record failed");
+                return;
+            }
+        };
+        line.push(b'\n');
+        let file = self.file.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap();
+            file.write_all(&line)?;
+            file.sync_data()
+        })
+        .await;
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(error = %err, "write audit record failed"),
+            Err(err) => error!(error = %err, "write audit record failed"),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open audit log {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use dav_server::{memls::MemLs, DavHandler};
+    use hyper::{Request, StatusCode};
+
+    use super::*;
+    use crate::drive::mock::MockDrive;
+    use crate::vfs::AliyunDriveFileSystem;
+
+    #[tokio::test]
+    async fn delete_is_recorded() {
+        let path = std::env::temp_dir().join(format!("audit-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let drive = MockDrive::new();
+        let file_id = drive.add_file("root", "a.txt", "hello");
+        let mut fs =
+            AliyunDriveFileSystem::new(drive.clone(), "/".to_string(), 1000, 600, 0, Vec::new())
+                .unwrap();
+        fs.set_audit_log(Some(AuditLog::open(path.clone()).unwrap()));
+        let handler = DavHandler::builder()
+            .filesystem(Box::new(fs))
+            .locksystem(MemLs::new())
+            .build_handler();
+
+        let req = Request::builder()
+            .method("DELETE")
+            .uri("/a.txt")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let ip = "192.0.2.1".parse().ok();
+        let res = with_client(ip, async {
+            set_principal("alice");
+            handler.handle(req).await
+        })
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["operation"], "DELETE");
+        assert_eq!(record["path"], "/a.txt");
+        assert_eq!(record["principal"], "alice");
+        assert_eq!(record["client_ip"], "192.0.2.1");
+        assert_eq!(record["file_id"], file_id.as_str());
+        assert_eq!(record["result"], "ok");
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::{HashMap, VecDeque};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Request, Uri};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha1::Sha1;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+pub const REALM: &str = "aliyundrive-webdav";
+
+/// How long a digest nonce can be used
+const NONCE_TTL: Duration = Duration::from_secs(300);
+/// Most digest nonces valid at once, the oldest ones are dropped beyond
+const MAX_NONCES: usize = 10_000;
+
+/// HTTP authentication scheme offered to clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AuthScheme {
+    /// Base64 encoded credentials, only safe over TLS
+    #[default]
+    Basic,
+    /// Challenge-response without sending the password (RFC 7616, MD5)
+    Digest,
+}
+
+/// How long the command verifying credentials may run
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long credentials accepted by the command are accepted without running it again
+const COMMAND_CACHE_TTL: Duration = Duration::from_secs(60);
+/// How long credentials rejected by the command are rejected without running it again
+const COMMAND_REJECT_TTL: Duration = Duration::from_secs(5);
+/// Most commands run at once, other requests wait for one to finish
+const COMMAND_CONCURRENCY: usize = 4;
+
+/// Basic credentials verified by an external command, run through `sh -c`
+/// with the user name in `WEBDAV_AUTH_USER` and `user\npassword\n` on stdin,
+/// exiting with 0 to accept them
+#[derive(Debug)]
+pub struct AuthCommand {
+    command: String,
+    /// Salted hash of the credentials accepted recently and when
+    verified: Mutex<HashMap<String, Instant>>,
+    /// Salted hash of the credentials rejected recently and when
+    rejected: Mutex<HashMap<String, Instant>>,
+    running: Semaphore,
+    salt: [u8; 16],
+}
+
+impl AuthCommand {
+    pub fn new(command: String) -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self {
+            command,
+            verified: Mutex::new(HashMap::new()),
+            rejected: Mutex::new(HashMap::new()),
+            running: Semaphore::new(COMMAND_CONCURRENCY),
+            salt,
+        }
+    }
+
+    pub async fn verify(&self, user: &str, password: &str) -> bool {
+        // line breaks would let the user name smuggle a password into the input
+        // of the command, NUL can't be passed in an environment variable
+        let is_control = |c: char| matches!(c, '\n' | '\r' | '\0');
+        if user.contains(is_control) || password.contains(is_control) {
+            warn!("credentials with control characters rejected");
+            return false;
+        }
+        let mut hasher = Sha1::new();
+        hasher.update(self.salt);
+        hasher.update(user.as_bytes());
+        hasher.update([0]);
+        hasher.update(password.as_bytes());
+        let key = hex(&hasher.finalize());
+        {
+            let mut verified = self.verified.lock().unwrap();
+            verified.retain(|_, at| at.elapsed() < COMMAND_CACHE_TTL);
+            if verified.contains_key(&key) {
+                return true;
+            }
+        }
+        if self.is_rejected(&key) {
+            return false;
+        }
+        let Ok(_permit) = self.running.acquire().await else {
+            return false;
+        };
+        // the same credentials may have been checked while waiting
+        if self.verified.lock().unwrap().contains_key(&key) {
+            return true;
+        }
+        if self.is_rejected(&key) {
+            return false;
+        }
+        let accepted = match tokio::time::timeout(COMMAND_TIMEOUT, self.run(user, password)).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(err)) => {
+                warn!(user = %user, error = %err, "run auth command failed");
+                false
+            }
+            Err(_) => {
+                warn!(user = %user, timeout = ?COMMAND_TIMEOUT, "auth command timed out");
+                false
+            }
+        };
+        debug!(user = %user, accepted = accepted, "auth command verified credentials");
+        if accepted {
+            self.verified.lock().unwrap().insert(key, Instant::now());
+        } else {
+            self.rejected.lock().unwrap().insert(key, Instant::now());
+        }
+        accepted
+    }
+
+    fn is_rejected(&self, key: &str) -> bool {
+        let mut rejected = self.rejected.lock().unwrap();
+        rejected.retain(|_, at| at.elapsed() < COMMAND_REJECT_TTL);
+        rejected.contains_key(key)
+    }
+
+    async fn run(&self, user: &str, password: &str) -> std::io::Result<bool> {
+        #[cfg(unix)]
+        let mut command = Command::new("sh");
+        #[cfg(unix)]
+        command.arg("-c");
+        #[cfg(not(unix))]
+        let mut command = Command::new("cmd");
+        #[cfg(not(unix))]
+        command.arg("/C");
+        let mut child = command
+            .arg(&self.command)
+            .env("WEBDAV_AUTH_USER", user)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // the command may exit without reading its input
+            let _ = stdin
+                .write_all(format!("{}\n{}\n", user, password).as_bytes())
+                .await;
+        }
+        Ok(child.wait().await?.success())
+    }
+}
+
+/// Digest authentication with nonces issued by this server
+#[derive(Debug, Default)]
+pub struct DigestAuth {
+    nonces: Mutex<Nonces>,
+}
+
+/// Nonces issued recently, with their time of issue and the highest
+/// request counter seen for each
+#[derive(Debug, Default)]
+struct Nonces {
+    valid: HashMap<String, (Instant, u64)>,
+    /// Nonces in the order they were issued
+    order: VecDeque<String>,
+}
+
+impl Nonces {
+    fn insert(&mut self, nonce: String) {
+        while let Some(oldest) = self.order.front() {
+            let expired = match self.valid.get(oldest) {
+                Some((issued, _)) => issued.elapsed() >= NONCE_TTL,
+                None => true,
+            };
+            if !expired && self.order.len() < MAX_NONCES {
+                break;
+            }
+            self.valid.remove(oldest);
+            self.order.pop_front();
+        }
+        self.valid.insert(nonce.clone(), (Instant::now(), 0));
+        self.order.push_back(nonce);
+    }
+
+    /// Record a request counter of a nonce, it must be higher than the ones
+    /// seen before so that a captured request can't be replayed
+    fn use_nonce(&mut self, nonce: &str, nc: u64) -> bool {
+        match self.valid.get_mut(nonce) {
+            Some((issued, last_nc)) if issued.elapsed() < NONCE_TTL && nc > *last_nc => {
+                *last_nc = nc;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl DigestAuth {
+    /// `WWW-Authenticate` challenge with a fresh nonce, `stale` tells the
+    /// client that only the nonce expired and its credentials were fine
+    pub fn challenge(&self, stale: bool) -> HeaderValue {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let nonce = hex(&bytes);
+        self.nonces.lock().unwrap().insert(nonce.clone());
+        let value = format!(
+            r#"Digest realm="{}", qop="auth", algorithm=MD5, nonce="{}"{}"#,
+            REALM,
+            nonce,
+            if stale { ", stale=true" } else { "" }
+        );
+        HeaderValue::from_str(&value).unwrap()
+    }
+
+    /// Check the `Authorization: Digest` header of the request, `request_uri` is
+    /// the URI as sent by the client, before any rewriting, and `password` looks
+    /// up the password of a user.
+    ///
+    /// Returns the user name, or whether the nonce was stale on failure.
+    pub fn verify<B>(
+        &self,
+        req: &Request<B>,
+        request_uri: &Uri,
+        password: impl Fn(&str) -> Option<String>,
+    ) -> Result<String, bool> {
+        let params = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Digest "))
+            .map(parse_params)
+            .ok_or(false)?;
+        let param = |name: &str| params.get(name).map(String::as_str);
+        let (Some(user), Some(nonce), Some(uri), Some(response)) = (
+            param("username"),
+            param("nonce"),
+            param("uri"),
+            param("response"),
+        ) else {
+            return Err(false);
+        };
+        if param("realm") != Some(REALM) {
+            return Err(false);
+        }
+        let request_uri = request_uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        if uri != request_uri {
+            debug!(uri = %uri, request_uri = %request_uri, "digest uri mismatch");
+            return Err(false);
+        }
+        let password = password(user).ok_or(false)?;
+        let ha1 = md5_hex(&format!("{}:{}:{}", user, REALM, password));
+        let ha2 = md5_hex(&format!("{}:{}", req.method(), uri));
+        let (expected, nc) = match param("qop") {
+            Some("auth") => {
+                let (Some(nc), Some(cnonce)) = (param("nc"), param("cnonce")) else {
+                    return Err(false);
+                };
+                let expected =
+                    md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+                let nc = u64::from_str_radix(nc, 16).map_err(|_| false)?;
+                (expected, nc)
+            }
+            // without a counter a nonce is only good for one request
+            None => (md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2)), 1),
+            Some(_) => return Err(false),
+        };
+        if !response.eq_ignore_ascii_case(&expected) {
+            return Err(false);
+        }
+        if !self.nonces.lock().unwrap().use_nonce(nonce, nc) {
+            debug!(user = %user, "stale digest nonce");
+            return Err(true);
+        }
+        Ok(user.to_string())
+    }
+}
+
+/// Parse the comma separated `name=value` or `name="value"` parameters
+fn parse_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let Some((name, after)) = rest.split_once('=') else {
+            break;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let after = after.trim_start();
+        let (value, after) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, c)) = chars.next() {
+                            value.push(c);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            (value, &quoted[end..])
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].trim().to_string(), &after[end..])
+        };
+        params.insert(name, value);
+        rest = after.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+fn md5_hex(s: &str) -> String {
+    hex(&Md5::digest(s.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest_request(nonce: &str, nc: &str, password: &str) -> Request<()> {
+        let ha1 = md5_hex(&format!("alice:{}:{}", REALM, password));
+        let ha2 = md5_hex("GET:/a.txt");
+        let response = md5_hex(&format!("{}:{}:{}:xyz:auth:{}", ha1, nonce, nc, ha2));
+        let authorization = format!(
+            r#"Digest username="alice", realm="{}", nonce="{}", uri="/a.txt", qop=auth, nc={}, cnonce="xyz", response="{}""#,
+            REALM, nonce, nc, response
+        );
+        Request::get("/a.txt")
+            .header(AUTHORIZATION, authorization)
+            .body(())
+            .unwrap()
+    }
+
+    fn verify(
+        digest: &DigestAuth,
+        req: &Request<()>,
+        password: impl Fn(&str) -> Option<String>,
+    ) -> Result<String, bool> {
+        digest.verify(req, req.uri(), password)
+    }
+
+    fn nonce_of(challenge: &HeaderValue) -> String {
+        parse_params(challenge.to_str().unwrap().strip_prefix("Digest ").unwrap())["nonce"].clone()
+    }
+
+    #[test]
+    fn digest_rejects_replayed_counters() {
+        let digest = DigestAuth::default();
+        let nonce = nonce_of(&digest.challenge(false));
+        let password = |_: &str| Some("secret".to_string());
+
+        assert_eq!(
+            verify(
+                &digest,
+                &digest_request(&nonce, "00000001", "secret"),
+                password
+            ),
+            Ok("alice".to_string())
+        );
+        assert_eq!(
+            verify(
+                &digest,
+                &digest_request(&nonce, "00000002", "secret"),
+                password
+            ),
+            Ok("alice".to_string())
+        );
+        // replayed, the client is asked to start over with a new nonce
+        assert_eq!(
+            verify(
+                &digest,
+                &digest_request(&nonce, "00000002", "secret"),
+                password
+            ),
+            Err(true)
+        );
+        assert_eq!(
+            verify(
+                &digest,
+                &digest_request(&nonce, "00000003", "wrong"),
+                password
+            ),
+            Err(false)
+        );
+        assert_eq!(
+            verify(
+                &digest,
+                &digest_request("unknown", "00000001", "secret"),
+                password
+            ),
+            Err(true)
+        );
+    }
+
+    #[test]
+    fn digest_nonces_are_capped() {
+        let digest = DigestAuth::default();
+        let first = nonce_of(&digest.challenge(false));
+        for _ in 0..MAX_NONCES {
+            digest.challenge(false);
+        }
+        let nonces = digest.nonces.lock().unwrap();
+        assert_eq!(nonces.order.len(), MAX_NONCES);
+        assert_eq!(nonces.valid.len(), MAX_NONCES);
+        assert!(!nonces.valid.contains_key(&first));
+    }
+
+    const CHECK: &str =
+        r#"read -r user; read -r password; [ "$user" = alice ] && [ "$password" = secret ]"#;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_accepts_and_rejects() {
+        let command = AuthCommand::new(CHECK.to_string());
+        assert!(command.verify("alice", "secret").await);
+        assert!(!command.verify("alice", "wrong").await);
+        assert!(!command.verify("bob", "secret").await);
+        // the user name can't carry a password line of its own
+        assert!(!command.verify("alice\nsecret", "x").await);
+        assert!(!command.verify("alice", "secret\r").await);
+        assert!(!command.verify("alice", "sec\0ret").await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rejections_are_cached() {
+        let log = std::env::temp_dir().join(format!("auth-command-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let command = AuthCommand::new(format!("echo run >> '{}'; exit 1", log.display()));
+        assert!(!command.verify("alice", "wrong").await);
+        assert!(!command.verify("alice", "wrong").await);
+        let runs = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        assert_eq!(runs.lines().count(), 1);
+    }
+}
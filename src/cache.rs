@@ -1,38 +1,183 @@
-use std::path::Path;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use moka::future::Cache as MokaCache;
+use clap::ValueEnum;
+use futures_util::future::{BoxFuture, FutureExt};
+use moka::{future::Cache as MokaCache, Expiry};
+use rand::Rng;
 use tracing::debug;
 
 use crate::drive::AliyunFile;
+use crate::metrics;
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisConnection;
+
+/// Where directory listings are cached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CacheBackend {
+    /// In the memory of this instance
+    #[default]
+    Memory,
+    /// In Redis, shared by all instances using the same server
+    Redis,
+}
+
+/// Storage of directory listings keyed by path, expiring entries as told by its [`DirTtl`]
+pub trait CacheStore: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Vec<AliyunFile>>>;
+
+    fn insert(&self, key: String, value: Vec<AliyunFile>) -> BoxFuture<'_, ()>;
+
+    fn invalidate<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()>;
+
+    fn invalidate_all(&self) -> BoxFuture<'_, ()>;
+}
 
 #[derive(Clone)]
 pub struct Cache {
-    inner: MokaCache<String, Vec<AliyunFile>>,
+    inner: Arc<dyn CacheStore>,
+    /// Copies of the entries kept for a while after they expire, served when
+    /// refreshing them fails
+    stale: Option<Arc<dyn CacheStore>>,
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    stale_window: Option<Duration>,
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    ttl: DirTtl,
+    max_capacity: u64,
+    /// Longest time an entry lives
+    max_ttl: Duration,
+    #[cfg(feature = "redis")]
+    redis: Option<RedisConnection>,
+}
+
+/// Expiration time of the directories at and below `path`, parsed from `path=seconds`
+#[derive(Debug, Clone)]
+pub struct TtlOverride {
+    pub path: PathBuf,
+    pub ttl: u64,
+}
+
+impl FromStr for TtlOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, ttl) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid ttl override `{}`, expected `path=seconds`", s))?;
+        let ttl = ttl
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid ttl `{}` in `{}`", ttl, s))?;
+        // absolute like the cache keys
+        let path = path.trim().trim_matches('/');
+        Ok(Self {
+            path: Path::new("/").join(path),
+            ttl,
+        })
+    }
 }
 
 impl Cache {
-    pub fn new(max_capacity: u64, ttl: u64) -> Self {
-        let inner = MokaCache::builder()
-            .max_capacity(max_capacity)
-            .time_to_live(Duration::from_secs(ttl))
-            .build();
-        Self { inner }
+    /// `ttl_overrides` paths are matched against the cache keys, the longest match wins
+    pub fn new(
+        max_capacity: u64,
+        ttl: u64,
+        ttl_jitter: u64,
+        ttl_overrides: Vec<TtlOverride>,
+    ) -> Self {
+        let max_ttl = ttl_overrides
+            .iter()
+            .map(|o| o.ttl)
+            .fold(ttl, u64::max)
+            .saturating_add(ttl_jitter);
+        let mut overrides: Vec<_> = ttl_overrides
+            .into_iter()
+            .map(|o| (o.path, Duration::from_secs(o.ttl)))
+            .collect();
+        // longest paths first, so that the first match is the longest one
+        overrides.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        let ttl = DirTtl {
+            ttl: Duration::from_secs(ttl),
+            jitter: Duration::from_secs(ttl_jitter),
+            overrides,
+        };
+        Self {
+            inner: Arc::new(MemoryStore::new(max_capacity, ttl.clone())),
+            stale: None,
+            stale_window: None,
+            ttl,
+            max_capacity,
+            max_ttl: Duration::from_secs(max_ttl),
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+
+    /// Cache in Redis instead of in memory, entries expire and are evicted alike
+    #[cfg(feature = "redis")]
+    pub fn use_redis(&mut self, redis: RedisConnection) {
+        self.redis = Some(redis);
+        self.inner = self.store("dir", self.ttl.clone());
+        if let Some(window) = self.stale_window {
+            self.enable_stale(window);
+        }
+    }
+
+    fn store(&self, namespace: &str, ttl: DirTtl) -> Arc<dyn CacheStore> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = self.redis.clone() {
+            return Arc::new(redis_store::RedisStore::new(
+                redis,
+                namespace,
+                ttl,
+                self.max_capacity,
+            ));
+        }
+        let _ = namespace;
+        Arc::new(MemoryStore::new(self.max_capacity, ttl))
+    }
+
+    /// Keep entries for up to `window` after they expire, for `get_stale`
+    pub fn enable_stale(&mut self, window: Duration) {
+        self.stale_window = Some(window);
+        self.stale = Some(self.store("stale", DirTtl::fixed(self.max_ttl + window)));
     }
 
-    pub fn get(&self, key: &str) -> Option<Vec<AliyunFile>> {
+    pub async fn get(&self, key: &str) -> Option<Vec<AliyunFile>> {
         debug!(key = %key, "cache: get");
-        self.inner.get(key)
+        let value = self.inner.get(key).await;
+        metrics::record_cache_lookup(value.is_some());
+        value
+    }
+
+    pub fn keeps_stale(&self) -> bool {
+        self.stale.is_some()
+    }
+
+    /// Entry that may have expired, within the stale window
+    pub async fn get_stale(&self, key: &str) -> Option<Vec<AliyunFile>> {
+        let value = self.stale.as_ref()?.get(key).await;
+        debug!(key = %key, found = value.is_some(), "cache: get stale");
+        value
     }
 
     pub async fn insert(&self, key: String, value: Vec<AliyunFile>) {
         debug!(key = %key, "cache: insert");
+        if let Some(stale) = self.stale.as_ref() {
+            stale.insert(key.clone(), value.clone()).await;
+        }
         self.inner.insert(key, value).await;
     }
 
     pub async fn invalidate(&self, path: &Path) {
         let key = path.to_string_lossy().into_owned();
         debug!(path = %path.display(), key = %key, "cache: invalidate");
+        if let Some(stale) = self.stale.as_ref() {
+            stale.invalidate(&key).await;
+        }
         self.inner.invalidate(&key).await;
     }
 
@@ -42,8 +187,425 @@ impl Cache {
         }
     }
 
-    pub fn invalidate_all(&self) {
+    pub async fn invalidate_all(&self) {
         debug!("cache: invalidate all");
+        if let Some(stale) = self.stale.as_ref() {
+            stale.invalidate_all().await;
+        }
+        self.inner.invalidate_all().await;
+    }
+}
+
+/// Listings in the memory of this instance, evicted least recently used first
+struct MemoryStore {
+    inner: MokaCache<String, Vec<AliyunFile>>,
+}
+
+impl MemoryStore {
+    fn new(max_capacity: u64, ttl: DirTtl) -> Self {
+        let builder = MokaCache::builder().max_capacity(max_capacity);
+        let inner = if ttl.is_fixed() {
+            builder.time_to_live(ttl.ttl).build()
+        } else {
+            builder.expire_after(ttl).build()
+        };
+        Self { inner }
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Vec<AliyunFile>>> {
+        let value = self.inner.get(key);
+        async move { value }.boxed()
+    }
+
+    fn insert(&self, key: String, value: Vec<AliyunFile>) -> BoxFuture<'_, ()> {
+        self.inner.insert(key, value).boxed()
+    }
+
+    fn invalidate<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+        self.inner.invalidate(key).boxed()
+    }
+
+    fn invalidate_all(&self) -> BoxFuture<'_, ()> {
         self.inner.invalidate_all();
+        async {}.boxed()
+    }
+}
+
+/// Download urls shared by all readers of a file, keyed by file id.
+///
+/// Readers keep using their own copy of the url until it expires, replacing an
+/// entry thus never disrupts downloads in progress.
+#[derive(Clone)]
+pub struct DownloadUrlCache {
+    inner: MokaCache<String, String>,
+}
+
+impl DownloadUrlCache {
+    /// Entries are dropped after `ttl`, or after `idle` without being accessed
+    pub fn new(max_capacity: u64, ttl: Duration, idle: Option<Duration>) -> Self {
+        let builder = MokaCache::builder()
+            .max_capacity(max_capacity)
+            .time_to_live(ttl);
+        let inner = match idle {
+            Some(idle) => builder.time_to_idle(idle).build(),
+            None => builder.build(),
+        };
+        Self { inner }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.inner.get(key)
+    }
+
+    pub async fn insert(&self, key: String, url: String) {
+        debug!(key = %key, "download url cache: insert");
+        self.inner.insert(key, url).await;
+    }
+
+    pub async fn invalidate(&self, key: &str) {
+        debug!(key = %key, "download url cache: invalidate");
+        self.inner.invalidate(key).await;
+    }
+}
+
+/// Expire entries after `ttl ± random(0, jitter)` so that entries cached at the
+/// same time, e.g. when prewarming, don't all expire and get refetched at once.
+///
+/// Directories below an overridden path use the ttl of the longest matching override.
+#[derive(Debug, Clone)]
+pub struct DirTtl {
+    ttl: Duration,
+    jitter: Duration,
+    overrides: Vec<(PathBuf, Duration)>,
+}
+
+impl DirTtl {
+    fn fixed(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            jitter: Duration::ZERO,
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Whether all entries live equally long
+    fn is_fixed(&self) -> bool {
+        self.jitter.is_zero() && self.overrides.is_empty()
+    }
+
+    fn next_ttl(&self, key: &str) -> Duration {
+        let ttl = self
+            .overrides
+            .iter()
+            .find(|(path, _)| Path::new(key).starts_with(path))
+            .map(|(_, ttl)| *ttl)
+            .unwrap_or(self.ttl);
+        if self.jitter.is_zero() {
+            return ttl;
+        }
+        let span = self.jitter.as_millis() as u64 * 2;
+        let offset = Duration::from_millis(rand::thread_rng().gen_range(0..=span));
+        // never expire immediately
+        (ttl + offset)
+            .saturating_sub(self.jitter)
+            .max(Duration::from_secs(1))
+    }
+}
+
+impl Expiry<String, Vec<AliyunFile>> for DirTtl {
+    fn expire_after_create(
+        &self,
+        key: &String,
+        _value: &Vec<AliyunFile>,
+        _current_time: Instant,
+    ) -> Option<Duration> {
+        Some(self.next_ttl(key))
+    }
+
+    fn expire_after_update(
+        &self,
+        key: &String,
+        _value: &Vec<AliyunFile>,
+        _current_time: Instant,
+        _current_duration: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.next_ttl(key))
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use anyhow::{Context, Result};
+    use futures_util::future::{BoxFuture, FutureExt};
+    use redis::aio::ConnectionManager;
+    use serde::{Deserialize, Serialize};
+    use tracing::{debug, info, warn};
+
+    use super::{CacheStore, DirTtl};
+    use crate::drive::AliyunFile;
+
+    /// Prefix of all keys, followed by the drive id and the namespace of the store
+    const KEY_PREFIX: &str = "aliyundrive-webdav:";
+
+    /// Version of the serialized listings, bumped whenever [`AliyunFile`] changes
+    /// so that entries written by other versions are treated as missing
+    const FORMAT_VERSION: u32 = 1;
+
+    /// Connection to the Redis server, reconnecting when it is lost, for the
+    /// listings of one drive
+    #[derive(Clone)]
+    pub struct RedisConnection {
+        manager: ConnectionManager,
+        drive_id: String,
+    }
+
+    impl RedisConnection {
+        pub async fn connect(url: &str, drive_id: &str) -> Result<Self> {
+            let client = redis::Client::open(url).context("invalid redis url")?;
+            let manager = ConnectionManager::new(client)
+                .await
+                .context("connect to redis failed")?;
+            info!(drive_id = %drive_id, "connected to redis");
+            Ok(Self {
+                manager,
+                drive_id: drive_id.to_string(),
+            })
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Listing {
+        version: u32,
+        files: Vec<AliyunFile>,
+    }
+
+    /// Listings shared by all instances serving the same drive from the same
+    /// Redis server, invalidating them invalidates them for all these instances.
+    ///
+    /// Entries expire with Redis TTLs, a sorted set of the keys by insertion
+    /// time keeps their number within the capacity, oldest evicted first.
+    pub struct RedisStore {
+        conn: ConnectionManager,
+        prefix: String,
+        index: String,
+        ttl: DirTtl,
+        max_capacity: u64,
+    }
+
+    impl RedisStore {
+        pub fn new(conn: RedisConnection, namespace: &str, ttl: DirTtl, max_capacity: u64) -> Self {
+            let prefix = key_prefix(&conn.drive_id, namespace);
+            Self {
+                conn: conn.manager,
+                index: format!("{}-index", prefix.trim_end_matches(':')),
+                prefix,
+                ttl,
+                max_capacity,
+            }
+        }
+
+        fn key(&self, key: &str) -> String {
+            format!("{}{}", self.prefix, key)
+        }
+
+        async fn try_get(&self, key: &str) -> Result<Option<Vec<AliyunFile>>> {
+            let value: Option<Vec<u8>> = redis::cmd("GET")
+                .arg(self.key(key))
+                .query_async(&mut self.conn.clone())
+                .await?;
+            let Some(value) = value else {
+                return Ok(None);
+            };
+            match serde_json::from_slice::<Version>(&value) {
+                Ok(Version { version }) if version == FORMAT_VERSION => {}
+                _ => {
+                    debug!(key = %key, "cache: ignore entry of another format version");
+                    return Ok(None);
+                }
+            }
+            let listing: Listing = serde_json::from_slice(&value)?;
+            Ok(Some(listing.files))
+        }
+
+        async fn try_insert(&self, key: String, files: Vec<AliyunFile>) -> Result<()> {
+            let ttl = self.ttl.next_ttl(&key).as_secs().max(1);
+            let value = serde_json::to_vec(&Listing {
+                version: FORMAT_VERSION,
+                files,
+            })?;
+            let key = self.key(&key);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_millis() as u64;
+            let mut conn = self.conn.clone();
+            let (count,): (u64,) = redis::pipe()
+                .cmd("SET")
+                .arg(&key)
+                .arg(value)
+                .arg("EX")
+                .arg(ttl)
+                .ignore()
+                .cmd("ZADD")
+                .arg(&self.index)
+                .arg(now)
+                .arg(&key)
+                .ignore()
+                .cmd("ZCARD")
+                .arg(&self.index)
+                .query_async(&mut conn)
+                .await?;
+            if count > self.max_capacity {
+                let evicted: Vec<String> = redis::cmd("ZRANGE")
+                    .arg(&self.index)
+                    .arg(0)
+                    .arg(count - self.max_capacity - 1)
+                    .query_async(&mut conn)
+                    .await?;
+                self.remove(&evicted).await?;
+            }
+            Ok(())
+        }
+
+        async fn remove(&self, keys: &[String]) -> Result<()> {
+            if keys.is_empty() {
+                return Ok(());
+            }
+            redis::pipe()
+                .cmd("DEL")
+                .arg(keys)
+                .ignore()
+                .cmd("ZREM")
+                .arg(&self.index)
+                .arg(keys)
+                .ignore()
+                .query_async::<_, ()>(&mut self.conn.clone())
+                .await?;
+            Ok(())
+        }
+
+        async fn try_invalidate_all(&self) -> Result<()> {
+            let keys: Vec<String> = redis::cmd("ZRANGE")
+                .arg(&self.index)
+                .arg(0)
+                .arg(-1)
+                .query_async(&mut self.conn.clone())
+                .await?;
+            self.remove(&keys).await
+        }
+    }
+
+    /// Prefix of the keys of a store, drives never share entries as the keys
+    /// are paths which are the same on every drive
+    fn key_prefix(drive_id: &str, namespace: &str) -> String {
+        format!("{}{}:{}:", KEY_PREFIX, drive_id, namespace)
+    }
+
+    // Redis failures are logged and treated as cache misses, the drive is
+    // still there to answer
+    impl CacheStore for RedisStore {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Vec<AliyunFile>>> {
+            async move {
+                self.try_get(key).await.unwrap_or_else(|err| {
+                    warn!(key = %key, error = %err, "redis cache get failed");
+                    None
+                })
+            }
+            .boxed()
+        }
+
+        fn insert(&self, key: String, value: Vec<AliyunFile>) -> BoxFuture<'_, ()> {
+            async move {
+                if let Err(err) = self.try_insert(key.clone(), value).await {
+                    warn!(key = %key, error = %err, "redis cache insert failed");
+                }
+            }
+            .boxed()
+        }
+
+        fn invalidate<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+            async move {
+                if let Err(err) = self.remove(&[self.key(key)]).await {
+                    warn!(key = %key, error = %err, "redis cache invalidate failed");
+                }
+            }
+            .boxed()
+        }
+
+        fn invalidate_all(&self) -> BoxFuture<'_, ()> {
+            async move {
+                if let Err(err) = self.try_invalidate_all().await {
+                    warn!(error = %err, "redis cache invalidate all failed");
+                }
+            }
+            .boxed()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn drives_have_their_own_keys() {
+            assert_eq!(key_prefix("1234", "dir"), "aliyundrive-webdav:1234:dir:");
+            assert_ne!(key_prefix("1234", "dir"), key_prefix("5678", "dir"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn overridden_paths_get_their_own_ttl() {
+        let overrides = vec![
+            "archive=3600".parse().unwrap(),
+            "/archive/hot/=10".parse().unwrap(),
+        ];
+        let cache = Cache::new(100, 60, 0, overrides);
+        let ttl = |key| cache.ttl.next_ttl(key).as_secs();
+        assert_eq!(ttl("/archive"), 3600);
+        assert_eq!(ttl("/archive/2020"), 3600);
+        assert_eq!(ttl("/archive/hot/today"), 10);
+        assert_eq!(ttl("/archived"), 60);
+        assert_eq!(ttl("/"), 60);
+    }
+
+    #[test]
+    fn jitter_spreads_expirations() {
+        let cache = Cache::new(100, 60, 10, Vec::new());
+        let ttls: HashSet<_> = (0..100)
+            .map(|_| cache.ttl.next_ttl("/a").as_millis())
+            .collect();
+        assert!(ttls.len() > 1);
+        assert!(ttls.iter().all(|ttl| (50_000..=70_000).contains(ttl)));
+    }
+
+    #[tokio::test]
+    async fn idle_download_urls_are_evicted_before_they_expire() {
+        let idle = Duration::from_millis(500);
+        let urls = DownloadUrlCache::new(100, Duration::from_secs(600), Some(idle));
+        urls.insert("idle".to_string(), "https://a".to_string())
+            .await;
+        urls.insert("used".to_string(), "https://b".to_string())
+            .await;
+        for _ in 0..10 {
+            tokio::time::sleep(idle / 5).await;
+            assert!(urls.get("used").is_some());
+        }
+        assert_eq!(urls.get("idle"), None);
     }
 }
@@ -0,0 +1,132 @@
+use dav_server::body::Body;
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Method, Request, Response, StatusCode,
+};
+use tracing::debug;
+
+/// Response headers browser scripts are allowed to read
+const EXPOSE_HEADERS: &str = "DAV, ETag, Content-Range, Content-Length, Lock-Token, Location";
+
+/// Cross-Origin Resource Sharing settings
+#[derive(Debug, Clone)]
+pub struct Cors {
+    origins: Vec<String>,
+    allow_methods: HeaderValue,
+    allow_headers: HeaderValue,
+    max_age: u64,
+}
+
+impl Cors {
+    pub fn new(
+        origins: Vec<String>,
+        allow_methods: Vec<String>,
+        allow_headers: Vec<String>,
+        max_age: u64,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            origins: origins
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect(),
+            allow_methods: HeaderValue::from_str(&allow_methods.join(", "))?,
+            allow_headers: HeaderValue::from_str(&allow_headers.join(", "))?,
+            max_age,
+        })
+    }
+
+    fn allow_any(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+
+    /// `Origin` of the request if it is allowed
+    fn allowed_origin<'a>(&self, headers: &'a HeaderMap) -> Option<&'a HeaderValue> {
+        let origin = headers.get(header::ORIGIN)?;
+        let origin_str = origin.to_str().ok()?;
+        if self.allow_any() || self.origins.iter().any(|o| o == origin_str) {
+            Some(origin)
+        } else {
+            debug!(origin = %origin_str, "cors: origin not allowed");
+            None
+        }
+    }
+
+    /// Answer a CORS preflight request.
+    ///
+    /// A preflight is an `OPTIONS` request carrying `Origin` and
+    /// `Access-Control-Request-Method`, any other `OPTIONS` request is a
+    /// regular WebDAV one and `None` is returned to let the DAV handler serve it.
+    pub fn preflight(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        let headers = req.headers();
+        if req.method() != Method::OPTIONS
+            || !headers.contains_key(header::ORIGIN)
+            || !headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let mut builder = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::VARY, "Origin");
+        if let Some(origin) = self.allowed_origin(headers) {
+            debug!(origin = ?origin, "cors: preflight");
+            let allow_headers = if self.allow_headers == "*" {
+                // echo the requested headers, `*` is not honored for credentialed requests
+                headers
+                    .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                    .cloned()
+                    .unwrap_or_else(|| HeaderValue::from_static(""))
+            } else {
+                self.allow_headers.clone()
+            };
+            builder = self
+                .origin_headers(builder, origin)
+                .header(
+                    header::ACCESS_CONTROL_ALLOW_METHODS,
+                    self.allow_methods.clone(),
+                )
+                .header(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers)
+                .header(header::ACCESS_CONTROL_MAX_AGE, self.max_age);
+        }
+        Some(builder.body(Body::empty()).unwrap())
+    }
+
+    /// Add CORS headers to the response of an actual (non-preflight) request
+    pub fn apply(&self, req_headers: &HeaderMap, response: &mut Response<Body>) {
+        let Some(origin) = self.allowed_origin(req_headers) else {
+            return;
+        };
+        let headers = response.headers_mut();
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        headers.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static(EXPOSE_HEADERS),
+        );
+        if self.allow_any() {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn origin_headers(
+        &self,
+        builder: hyper::http::response::Builder,
+        origin: &HeaderValue,
+    ) -> hyper::http::response::Builder {
+        if self.allow_any() {
+            builder.header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        } else {
+            // only explicitly listed origins may send credentials
+            builder
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use sha1::{Digest, Sha1};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+use crate::drive::AliyunFile;
+use crate::spool::{private_file_options, spool_path};
+
+/// Local copies of fully downloaded files, served when the upstream is unreachable,
+/// and of downloaded chunks, served again when the same range is read.
+///
+/// Entries are keyed by file id, size and modification time, or content hash,
+/// so that a changed file never matches an old copy. The least recently used
+/// entries are evicted once the cache grows beyond `max_size` bytes.
+///
+/// The size of the cache is counted as entries are stored, the directory is
+/// only scanned when the count goes beyond `max_size`.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    /// Bytes in the cache as of the last scan plus the entries stored since,
+    /// `None` until the first scan
+    usage: Arc<Mutex<Option<u64>>>,
+    evicting: Arc<AtomicBool>,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self {
+            dir,
+            max_size,
+            usage: Arc::default(),
+            evicting: Arc::default(),
+        }
+    }
+
+    fn path(&self, file: &AliyunFile) -> PathBuf {
+        let mtime = file
+            .updated_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.dir
+            .join(format!("{}-{}-{}.cache", file.id, file.size, mtime))
+    }
+
+    fn chunk_path(&self, file: &AliyunFile, pos: u64, len: usize) -> PathBuf {
+        let version = match file.content_hash.as_deref() {
+            Some(hash) if !hash.is_empty() => hash.to_ascii_lowercase(),
+            _ => {
+                let mtime = file
+                    .updated_at
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                format!("{}-{}", file.size, mtime)
+            }
+        };
+        self.dir
+            .join(format!("{}-{}-{}-{}.chunk", file.id, version, pos, len))
+    }
+
+    /// Cached chunk of `len` bytes at `pos`, checked against the digest stored with
+    /// it, and against the content hash of the file when the chunk is the whole file
+    pub async fn read_chunk(&self, file: &AliyunFile, pos: u64, len: usize) -> Option<Bytes> {
+        let path = self.chunk_path(file, pos, len);
+        let mut content = Bytes::from(tokio::fs::read(&path).await.ok()?);
+        if content.len() != 20 + len {
+            return None;
+        }
+        let digest = content.split_to(20);
+        if Sha1::digest(&content).as_slice() != digest
+            || !matches_content_hash(file, pos, len as u64, &digest)
+        {
+            warn!(path = %path.display(), "disk cache: chunk corrupted, discarding it");
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        }
+        // mark as recently used
+        if let Ok(f) = std::fs::File::options().write(true).open(&path) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+        debug!(path = %path.display(), "disk cache: chunk hit");
+        Some(content)
+    }
+
+    /// Store a downloaded chunk along with its digest
+    pub async fn write_chunk(&self, file: &AliyunFile, pos: u64, content: &Bytes) {
+        if content.is_empty() || content.len() as u64 > self.max_size {
+            return;
+        }
+        let path = self.chunk_path(file, pos, content.len());
+        let digest = Sha1::digest(content);
+        if !matches_content_hash(file, pos, content.len() as u64, &digest) {
+            warn!(path = %path.display(), "disk cache: chunk does not match the content hash");
+            return;
+        }
+        let tmp_path = spool_path(&self.dir);
+        let res = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            let mut tmp = OpenOptions::from(private_file_options())
+                .open(&tmp_path)
+                .await?;
+            tmp.write_all(&digest).await?;
+            tmp.write_all(content).await?;
+            tmp.flush().await?;
+            drop(tmp);
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+        if let Err(err) = res {
+            warn!(path = %path.display(), error = %err, "disk cache: store chunk failed");
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return;
+        }
+        debug!(path = %path.display(), "disk cache: chunk stored");
+        self.stored(20 + content.len() as u64);
+    }
+
+    /// Count a stored entry, evicting in the background once over the budget
+    fn stored(&self, len: u64) {
+        {
+            let mut usage = self.usage.lock().unwrap();
+            if let Some(used) = usage.as_mut() {
+                *used += len;
+                if *used <= self.max_size {
+                    return;
+                }
+            }
+        }
+        if self.evicting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = cache.evict() {
+                warn!(error = %err, "disk cache eviction failed");
+            }
+            cache.evicting.store(false, Ordering::Release);
+        });
+    }
+
+    /// Complete local copy of the file, if any
+    pub fn lookup(&self, file: &AliyunFile) -> Option<PathBuf> {
+        let path = self.path(file);
+        let meta = std::fs::metadata(&path).ok()?;
+        if meta.len() != file.size {
+            return None;
+        }
+        // mark as recently used
+        if let Ok(f) = std::fs::File::options().write(true).open(&path) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+        Some(path)
+    }
+
+    /// Start caching the file if it fits into the cache
+    pub async fn writer(&self, file: &AliyunFile) -> Option<DiskCacheWriter> {
+        if file.size == 0 || file.size > self.max_size {
+            return None;
+        }
+        if let Err(err) = tokio::fs::create_dir_all(&self.dir).await {
+            warn!(dir = %self.dir.display(), error = %err, "create disk cache dir failed");
+            return None;
+        }
+        let tmp_path = spool_path(&self.dir);
+        match OpenOptions::from(private_file_options())
+            .open(&tmp_path)
+            .await
+        {
+            Ok(tmp) => Some(DiskCacheWriter {
+                cache: self.clone(),
+                path: self.path(file),
+                tmp_path,
+                tmp: Some(tmp),
+                len: 0,
+                size: file.size,
+                sha1: Sha1::new(),
+                content_hash: file.content_hash.clone().filter(|hash| !hash.is_empty()),
+            }),
+            Err(err) => {
+                warn!(path = %tmp_path.display(), error = %err, "create disk cache file failed");
+                None
+            }
+        }
+    }
+
+    /// Remove least recently used entries until the cache fits into `max_size`
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path
+                .extension()
+                .map(|ext| ext != "cache" && ext != "chunk")
+                .unwrap_or(true)
+            {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            total += meta.len();
+            entries.push((meta.modified()?, meta.len(), path));
+        }
+        if total > self.max_size {
+            entries.sort();
+            for (_, len, path) in entries {
+                if total <= self.max_size {
+                    break;
+                }
+                debug!(path = %path.display(), "disk cache: evict");
+                std::fs::remove_file(&path)?;
+                total -= len;
+            }
+        }
+        *self.usage.lock().unwrap() = Some(total);
+        Ok(())
+    }
+}
+
+/// Whether a chunk covering the whole file has the content hash of the file,
+/// partial chunks can only be checked against their own digest
+fn matches_content_hash(file: &AliyunFile, pos: u64, len: u64, digest: &[u8]) -> bool {
+    match file.content_hash.as_deref() {
+        Some(hash) if pos == 0 && len == file.size && !hash.is_empty() => {
+            let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            hash.eq_ignore_ascii_case(&hex)
+        }
+        _ => true,
+    }
+}
+
+/// Writes a file downloaded from the start into the disk cache
+#[derive(Debug)]
+pub struct DiskCacheWriter {
+    cache: DiskCache,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    tmp: Option<File>,
+    len: u64,
+    size: u64,
+    sha1: Sha1,
+    /// Hash the whole file is checked against before it is committed
+    content_hash: Option<String>,
+}
+
+impl DiskCacheWriter {
+    /// Number of bytes written so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Append downloaded bytes, the entry is committed once the whole file is written
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Some(tmp) = self.tmp.as_mut() else {
+            return Ok(());
+        };
+        tmp.write_all(buf).await?;
+        self.sha1.update(buf);
+        self.len += buf.len() as u64;
+        if self.len >= self.size {
+            let mut tmp = self.tmp.take().unwrap();
+            tmp.flush().await?;
+            drop(tmp);
+            let digest = format!("{:x}", std::mem::take(&mut self.sha1).finalize());
+            if let Some(hash) = self.content_hash.as_deref() {
+                if !hash.eq_ignore_ascii_case(&digest) {
+                    warn!(path = %self.path.display(), "disk cache: download does not match the content hash");
+                    let _ = tokio::fs::remove_file(&self.tmp_path).await;
+                    return Ok(());
+                }
+            }
+            tokio::fs::rename(&self.tmp_path, &self.path).await?;
+            info!(path = %self.path.display(), size = self.size, "disk cache: stored");
+            self.cache.stored(self.size);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskCacheWriter {
+    fn drop(&mut self) {
+        if self.tmp.is_some() {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Read up to `count` bytes at `pos` from a local copy
+pub async fn read_at(path: &Path, pos: u64, count: usize) -> io::Result<Bytes> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(pos)).await?;
+    let mut buf = BytesMut::with_capacity(count);
+    while buf.len() < count {
+        let n = (&mut file)
+            .take((count - buf.len()) as u64)
+            .read_buf(&mut buf)
+            .await?;
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::drive::{DateTime, FileType};
+
+    fn cache(name: &str, max_size: u64) -> DiskCache {
+        let dir = std::env::temp_dir().join(format!("disk-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        DiskCache::new(dir, max_size)
+    }
+
+    fn file(content: &[u8]) -> AliyunFile {
+        let now = DateTime::new(SystemTime::now());
+        AliyunFile {
+            name: "a.txt".to_string(),
+            id: "file-1".to_string(),
+            r#type: FileType::File,
+            created_at: now.clone(),
+            updated_at: now,
+            size: content.len() as u64,
+            url: None,
+            content_hash: Some(format!("{:X}", Sha1::digest(content))),
+        }
+    }
+
+    #[tokio::test]
+    async fn whole_file_chunks_match_the_content_hash() {
+        let cache = cache("hash", 1024);
+        let file = file(b"hello");
+
+        cache.write_chunk(&file, 0, &Bytes::from("jello")).await;
+        assert_eq!(cache.read_chunk(&file, 0, 5).await, None);
+        cache.write_chunk(&file, 0, &Bytes::from("hello")).await;
+        assert_eq!(cache.read_chunk(&file, 0, 5).await.unwrap(), "hello");
+        // the content hash covers the whole file only
+        cache.write_chunk(&file, 1, &Bytes::from("ell")).await;
+        assert_eq!(cache.read_chunk(&file, 1, 3).await.unwrap(), "ell");
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stored_chunks_are_counted_until_over_budget() {
+        let cache = cache("evict", 150);
+        let file = file(&[0; 200]);
+        let chunk = Bytes::from(vec![0; 40]);
+
+        for pos in [0, 40, 80] {
+            cache.write_chunk(&file, pos, &chunk).await;
+            while cache.evicting.load(Ordering::Acquire) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        let chunks = std::fs::read_dir(&cache.dir).unwrap().count();
+        assert_eq!(chunks, 2);
+        assert_eq!(*cache.usage.lock().unwrap(), Some(120));
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use futures_util::future::{BoxFuture, FutureExt};
+
+use super::model::{
+    CreateFileWithProofResponse, FileRevision, GetFileDownloadUrlResponse, ListDeltaResponse,
+    UploadPartInfo,
+};
+use super::{AliyunDrive, AliyunFile, ApiSemaphore, RapidUploadProof};
+
+/// Drive operations the WebDAV file system is built upon.
+///
+/// [`AliyunDrive`] is the production implementation, the file system only
+/// depends on this trait so that it can run on top of other storage backends.
+pub trait DriveBackend: Send + Sync {
+    fn get_file<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<Option<AliyunFile>>>;
+
+    fn get_by_path<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Option<AliyunFile>>>;
+
+    /// Name and parent folder id of a file, the id of the drive root is `root`
+    fn get_file_location<'a>(
+        &'a self,
+        file_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, String)>>>;
+
+    fn list_all<'a>(&'a self, parent_file_id: &'a str) -> BoxFuture<'a, Result<Vec<AliyunFile>>>;
+
+    /// Items in the recycle bin that were in the folder
+    fn list_trashed_children<'a>(
+        &'a self,
+        parent_file_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<AliyunFile>>>;
+
+    fn list_revisions<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<Vec<FileRevision>>>;
+
+    fn get_download_url<'a>(
+        &'a self,
+        file_id: &'a str,
+    ) -> BoxFuture<'a, Result<GetFileDownloadUrlResponse>>;
+
+    fn get_revision_download_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        revision_id: &'a str,
+    ) -> BoxFuture<'a, Result<GetFileDownloadUrlResponse>>;
+
+    fn get_thumbnail_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        width: u32,
+    ) -> BoxFuture<'a, Result<Option<String>>>;
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+        range: Option<(u64, usize)>,
+    ) -> BoxFuture<'a, Result<Bytes>>;
+
+    fn download_range_into<'a>(
+        &'a self,
+        url: reqwest::Url,
+        start_pos: u64,
+        size: usize,
+        buf: &'a mut BytesMut,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    fn remove_file<'a>(&'a self, file_id: &'a str, trash: bool) -> BoxFuture<'a, Result<()>>;
+
+    fn create_folder<'a>(
+        &'a self,
+        parent_file_id: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    fn rename_file<'a>(&'a self, file_id: &'a str, name: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Rename even when the folder has a file with the name, keeping both
+    fn rename_file_allow_duplicate<'a>(
+        &'a self,
+        file_id: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Files anywhere on the drive whose name starts with `prefix`
+    fn search_name_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<AliyunFile>>>;
+
+    fn move_file<'a>(
+        &'a self,
+        file_id: &'a str,
+        to_parent_file_id: &'a str,
+        new_name: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    fn copy_file<'a>(
+        &'a self,
+        file_id: &'a str,
+        to_parent_file_id: &'a str,
+        new_name: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    fn create_file_with_proof<'a>(
+        &'a self,
+        name: &'a str,
+        parent_file_id: &'a str,
+        size: u64,
+        chunk_count: u64,
+        proof: Option<&'a RapidUploadProof>,
+    ) -> BoxFuture<'a, Result<CreateFileWithProofResponse>>;
+
+    fn proof_code_offset(&self, size: u64) -> BoxFuture<'_, Result<u64>>;
+
+    fn get_upload_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        upload_id: &'a str,
+        chunk_count: u64,
+    ) -> BoxFuture<'a, Result<Vec<UploadPartInfo>>>;
+
+    fn upload<'a>(&'a self, url: &'a str, body: Bytes) -> BoxFuture<'a, Result<()>>;
+
+    fn complete_file_upload<'a>(
+        &'a self,
+        file_id: &'a str,
+        upload_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Used and total space in bytes
+    fn get_quota(&self) -> BoxFuture<'_, Result<(u64, u64)>>;
+
+    /// Permits of upstream requests in flight, part uploads take one each
+    fn api_semaphore(&self) -> &ApiSemaphore;
+
+    /// Changes since `cursor`, `None` when the cursor expired
+    fn list_changes<'a>(
+        &'a self,
+        cursor: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<ListDeltaResponse>>>;
+}
+
+impl DriveBackend for AliyunDrive {
+    fn get_file<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<Option<AliyunFile>>> {
+        AliyunDrive::get_file(self, file_id).boxed()
+    }
+
+    fn get_by_path<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Option<AliyunFile>>> {
+        AliyunDrive::get_by_path(self, path).boxed()
+    }
+
+    fn get_file_location<'a>(
+        &'a self,
+        file_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, String)>>> {
+        AliyunDrive::get_file_location(self, file_id).boxed()
+    }
+
+    fn list_all<'a>(&'a self, parent_file_id: &'a str) -> BoxFuture<'a, Result<Vec<AliyunFile>>> {
+        AliyunDrive::list_all(self, parent_file_id).boxed()
+    }
+
+    fn list_trashed_children<'a>(
+        &'a self,
+        parent_file_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<AliyunFile>>> {
+        AliyunDrive::list_trashed_children(self, parent_file_id).boxed()
+    }
+
+    fn list_revisions<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<Vec<FileRevision>>> {
+        AliyunDrive::list_revisions(self, file_id).boxed()
+    }
+
+    fn get_download_url<'a>(
+        &'a self,
+        file_id: &'a str,
+    ) -> BoxFuture<'a, Result<GetFileDownloadUrlResponse>> {
+        AliyunDrive::get_download_url(self, file_id).boxed()
+    }
+
+    fn get_revision_download_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        revision_id: &'a str,
+    ) -> BoxFuture<'a, Result<GetFileDownloadUrlResponse>> {
+        AliyunDrive::get_revision_download_url(self, file_id, revision_id).boxed()
+    }
+
+    fn get_thumbnail_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        width: u32,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        AliyunDrive::get_thumbnail_url(self, file_id, width).boxed()
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+        range: Option<(u64, usize)>,
+    ) -> BoxFuture<'a, Result<Bytes>> {
+        AliyunDrive::download(self, url, range).boxed()
+    }
+
+    fn download_range_into<'a>(
+        &'a self,
+        url: reqwest::Url,
+        start_pos: u64,
+        size: usize,
+        buf: &'a mut BytesMut,
+    ) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::download_range_into(self, url, start_pos, size, buf).boxed()
+    }
+
+    fn remove_file<'a>(&'a self, file_id: &'a str, trash: bool) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::remove_file(self, file_id, trash).boxed()
+    }
+
+    fn create_folder<'a>(
+        &'a self,
+        parent_file_id: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::create_folder(self, parent_file_id, name).boxed()
+    }
+
+    fn rename_file<'a>(&'a self, file_id: &'a str, name: &'a str) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::rename_file(self, file_id, name).boxed()
+    }
+
+    fn rename_file_allow_duplicate<'a>(
+        &'a self,
+        file_id: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::rename_file_allow_duplicate(self, file_id, name).boxed()
+    }
+
+    fn search_name_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<AliyunFile>>> {
+        AliyunDrive::search_name_prefix(self, prefix).boxed()
+    }
+
+    fn move_file<'a>(
+        &'a self,
+        file_id: &'a str,
+        to_parent_file_id: &'a str,
+        new_name: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::move_file(self, file_id, to_parent_file_id, new_name).boxed()
+    }
+
+    fn copy_file<'a>(
+        &'a self,
+        file_id: &'a str,
+        to_parent_file_id: &'a str,
+        new_name: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::copy_file(self, file_id, to_parent_file_id, new_name).boxed()
+    }
+
+    fn create_file_with_proof<'a>(
+        &'a self,
+        name: &'a str,
+        parent_file_id: &'a str,
+        size: u64,
+        chunk_count: u64,
+        proof: Option<&'a RapidUploadProof>,
+    ) -> BoxFuture<'a, Result<CreateFileWithProofResponse>> {
+        AliyunDrive::create_file_with_proof(self, name, parent_file_id, size, chunk_count, proof)
+            .boxed()
+    }
+
+    fn proof_code_offset(&self, size: u64) -> BoxFuture<'_, Result<u64>> {
+        AliyunDrive::proof_code_offset(self, size).boxed()
+    }
+
+    fn get_upload_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        upload_id: &'a str,
+        chunk_count: u64,
+    ) -> BoxFuture<'a, Result<Vec<UploadPartInfo>>> {
+        AliyunDrive::get_upload_url(self, file_id, upload_id, chunk_count).boxed()
+    }
+
+    fn upload<'a>(&'a self, url: &'a str, body: Bytes) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::upload(self, url, body).boxed()
+    }
+
+    fn complete_file_upload<'a>(
+        &'a self,
+        file_id: &'a str,
+        upload_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        AliyunDrive::complete_file_upload(self, file_id, upload_id).boxed()
+    }
+
+    fn get_quota(&self) -> BoxFuture<'_, Result<(u64, u64)>> {
+        AliyunDrive::get_quota(self).boxed()
+    }
+
+    fn api_semaphore(&self) -> &ApiSemaphore {
+        &self.api_semaphore
+    }
+
+    fn list_changes<'a>(
+        &'a self,
+        cursor: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<ListDeltaResponse>>> {
+        AliyunDrive::list_changes(self, cursor).boxed()
+    }
+}
@@ -0,0 +1,239 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::{Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next, Result};
+use task_local_extensions::Extensions;
+use tracing::{info, warn};
+
+/// Error of requests rejected while the circuit is open
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "circuit breaker is open, upstream requests are suspended"
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Default)]
+struct Inner {
+    failures: u32,
+    /// Time the circuit was opened, or reopened by a failed probe
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+/// Stops calling the upstream for `cooldown` after `threshold` consecutive
+/// failures, then lets a single probe through before closing again.
+///
+/// Connection errors, timeouts, throttling and 5xx responses count as failures,
+/// other responses count as successes.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Whether requests are currently failing fast, either because the circuit is
+    /// open or because a probe is already in flight
+    pub fn rejects(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => false,
+            Some(since) => since.elapsed() < self.cooldown || inner.probing,
+        }
+    }
+
+    /// Time left until a probe is let through
+    pub fn retry_after(&self) -> Duration {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .opened_at
+            .map(|since| self.cooldown.saturating_sub(since.elapsed()))
+            .unwrap_or_default()
+    }
+
+    /// Whether a request may go through, `Ok(true)` for the probe of a half-open circuit
+    fn acquire(&self) -> std::result::Result<bool, CircuitOpen> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => Ok(false),
+            Some(since) if since.elapsed() < self.cooldown || inner.probing => Err(CircuitOpen),
+            Some(_) => {
+                info!("circuit breaker half-open, probing the upstream");
+                inner.probing = true;
+                Ok(true)
+            }
+        }
+    }
+
+    /// A probe that never completed, e.g. cancelled, counts as failed
+    fn abandon_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.probing {
+            inner.probing = false;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = 0;
+        inner.probing = false;
+        if inner.opened_at.take().is_some() {
+            info!("upstream recovered, circuit breaker closed");
+        }
+    }
+
+    fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        if inner.probing {
+            inner.probing = false;
+            inner.opened_at = Some(Instant::now());
+            warn!(cooldown = ?self.cooldown, "upstream probe failed, circuit breaker open again");
+        } else if inner.failures >= self.threshold && inner.opened_at.is_none() {
+            inner.opened_at = Some(Instant::now());
+            warn!(
+                failures = inner.failures,
+                cooldown = ?self.cooldown,
+                "upstream keeps failing, circuit breaker open"
+            );
+        }
+    }
+}
+
+struct ProbeGuard<'a>(&'a CircuitBreaker);
+
+impl Drop for ProbeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.abandon_probe();
+    }
+}
+
+/// Middleware failing fast while the [`CircuitBreaker`] is open
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerMiddleware(pub Arc<CircuitBreaker>);
+
+#[async_trait::async_trait]
+impl Middleware for CircuitBreakerMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        let probe = self.0.acquire().map_err(anyhow::Error::from)?;
+        let _probe = probe.then(|| ProbeGuard(&self.0));
+        let res = next.run(req, extensions).await;
+        let failed = match &res {
+            Ok(res) => {
+                let status = res.status();
+                status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::REQUEST_TIMEOUT
+            }
+            Err(_) => true,
+        };
+        if failed {
+            self.0.record_failure();
+        } else {
+            self.0.record_success();
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use hyper::service::{make_service_fn, service_fn};
+
+    use super::*;
+
+    /// Server answering 500 until it is made healthy, returns its url, the
+    /// switch and the number of requests it got
+    fn upstream() -> (String, Arc<AtomicBool>, Arc<AtomicUsize>) {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (switch, counter) = (healthy.clone(), hits.clone());
+        let make_svc = make_service_fn(move |_| {
+            let (switch, counter) = (switch.clone(), counter.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let status = if switch.load(Ordering::SeqCst) {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    };
+                    async move {
+                        Ok::<_, Infallible>(
+                            hyper::Response::builder()
+                                .status(status)
+                                .body(hyper::Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        (url, healthy, hits)
+    }
+
+    #[tokio::test]
+    async fn breaker_opens_after_failures_and_closes_after_a_probe() {
+        let cooldown = Duration::from_millis(100);
+        let breaker = Arc::new(CircuitBreaker::new(2, cooldown));
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(CircuitBreakerMiddleware(breaker.clone()))
+            .build();
+        let (url, healthy, hits) = upstream();
+
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        assert!(breaker.rejects());
+        // failing fast without reaching the upstream
+        assert!(client.get(&url).send().await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // a failed probe opens the circuit again
+        tokio::time::sleep(cooldown).await;
+        assert!(!breaker.rejects());
+        client.get(&url).send().await.unwrap();
+        assert!(breaker.rejects());
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        healthy.store(true, Ordering::SeqCst);
+        tokio::time::sleep(cooldown).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!breaker.rejects());
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+    }
+}
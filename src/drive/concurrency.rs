@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next, Result};
+use task_local_extensions::Extensions;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Permits bounding the upstream requests in flight, shared by API calls and
+/// part uploads so that parallel uploads and deletes can't flood the upstream
+#[derive(Debug, Clone)]
+pub struct ApiSemaphore {
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+}
+
+impl ApiSemaphore {
+    pub fn new(permits: usize) -> Self {
+        let permits = permits.max(1);
+        Self {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            permits,
+        }
+    }
+
+    /// Upstream requests allowed in flight at once
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("API semaphore is never closed")
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for ApiSemaphore {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        let _permit = self.acquire().await;
+        next.run(req, extensions).await
+    }
+}
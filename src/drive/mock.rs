@@ -0,0 +1,853 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use futures_util::future::{BoxFuture, FutureExt};
+use sha1::{Digest, Sha1};
+
+use super::model::{
+    CreateFileWithProofResponse, DeltaItem, FileRevision, GetFileDownloadUrlResponse,
+    ListDeltaResponse, ListFileItem, UploadPartInfo,
+};
+use super::{AliyunFile, ApiSemaphore, DateTime, DriveBackend, FileType, RapidUploadProof};
+
+/// In-memory drive for tests, cloning it shares the same files
+#[derive(Debug, Clone)]
+pub struct MockDrive {
+    state: Arc<Mutex<State>>,
+    api_semaphore: ApiSemaphore,
+    /// Part uploads in progress and the most seen at once
+    uploading: Arc<AtomicUsize>,
+    max_uploading: Arc<AtomicUsize>,
+    /// Deletes in progress and the most seen at once
+    removing: Arc<AtomicUsize>,
+    max_removing: Arc<AtomicUsize>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    files: HashMap<String, Entry>,
+    uploads: HashMap<String, Upload>,
+    next_id: u64,
+    /// Bumped to make the upload urls handed out so far expire
+    upload_url_generation: u64,
+    /// Bumped to make the download urls handed out so far expire
+    download_url_generation: u64,
+    expire_new_upload_urls: bool,
+    calls: HashMap<&'static str, usize>,
+    /// Changes listed after any cursor
+    changes: Vec<DeltaItem>,
+    /// Operations made to fail
+    failing: HashSet<&'static str>,
+    /// Size of the drive, 1 TiB when unset
+    total_space: Option<u64>,
+    /// Time taken by every ranged download
+    download_delay: Duration,
+    /// Bytes delivered by the next download before its connection is reset
+    reset_download_after: Option<usize>,
+    /// Writes are refused like with a token lacking the write scope
+    read_only: bool,
+    /// Former versions of files by file id, oldest first
+    revisions: HashMap<String, Vec<(FileRevision, Bytes)>>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    file: AliyunFile,
+    parent_id: String,
+    content: Bytes,
+    trashed: bool,
+}
+
+#[derive(Debug)]
+struct Upload {
+    entry: Entry,
+    chunk_count: u64,
+    parts: BTreeMap<u64, Bytes>,
+}
+
+impl MockDrive {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::default(),
+            api_semaphore: ApiSemaphore::new(8),
+            uploading: Arc::default(),
+            max_uploading: Arc::default(),
+            removing: Arc::default(),
+            max_removing: Arc::default(),
+        }
+    }
+
+    pub fn add_folder(&self, parent_id: &str, name: &str) -> String {
+        self.insert(parent_id, name, FileType::Folder, Bytes::new())
+    }
+
+    pub fn add_file(&self, parent_id: &str, name: &str, content: impl Into<Bytes>) -> String {
+        self.insert(parent_id, name, FileType::File, content.into())
+    }
+
+    fn insert(&self, parent_id: &str, name: &str, r#type: FileType, content: Bytes) -> String {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_file_id();
+        let entry = new_entry(&id, parent_id, name, r#type, content);
+        state.files.insert(id.clone(), entry);
+        id
+    }
+
+    pub fn content(&self, path: &str) -> Option<Bytes> {
+        self.state
+            .lock()
+            .unwrap()
+            .find(path)
+            .map(|e| e.content.clone())
+    }
+
+    /// Replace the content of a file behind the back of the file system
+    pub fn set_content(&self, file_id: &str, content: impl Into<Bytes>) {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.files.get_mut(file_id).expect("no such file");
+        entry.set_content(content.into());
+    }
+
+    /// Keep a former version of a file
+    pub fn add_revision(&self, file_id: &str, revision_id: &str, content: impl Into<Bytes>) {
+        let content = content.into();
+        let now = DateTime::new(SystemTime::now());
+        let revision = FileRevision {
+            revision_id: revision_id.to_string(),
+            size: content.len() as u64,
+            created_at: now.clone(),
+            updated_at: now,
+        };
+        let mut state = self.state.lock().unwrap();
+        let revisions = state.revisions.entry(file_id.to_string()).or_default();
+        revisions.push((revision, content));
+    }
+
+    /// Make a file look last updated `ago`
+    pub fn set_updated_ago(&self, file_id: &str, ago: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.files.get_mut(file_id).expect("no such file");
+        entry.file.updated_at = DateTime::new(SystemTime::now() - ago);
+    }
+
+    /// Report a change of a file in the listings of changes, without the
+    /// details of the file once it was removed
+    pub fn add_change(&self, op: &str, file_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.get(file_id).map(|e| ListFileItem {
+            name: e.file.name.clone(),
+            category: None,
+            id: e.file.id.clone(),
+            r#type: e.file.r#type,
+            created_at: e.file.created_at.clone(),
+            updated_at: e.file.updated_at.clone(),
+            size: Some(e.file.size),
+            url: None,
+            content_hash: e.file.content_hash.clone(),
+            trashed_at: None,
+            parent_file_id: Some(e.parent_id.clone()),
+        });
+        state.changes.push(DeltaItem {
+            op: op.to_string(),
+            file_id: file_id.to_string(),
+            file,
+        });
+    }
+
+    /// Names of the entries of a folder, trashed ones included, sorted
+    pub fn names(&self, parent_id: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut names: Vec<_> = state
+            .files
+            .values()
+            .filter(|e| e.parent_id == parent_id)
+            .map(|e| e.file.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Limit the size of the drive reported as quota
+    pub fn set_total_space(&self, total: u64) {
+        self.state.lock().unwrap().total_space = Some(total);
+    }
+
+    /// Make later calls of `op` fail, for the operations checking it
+    pub fn fail(&self, op: &'static str) {
+        self.state.lock().unwrap().failing.insert(op);
+    }
+
+    /// Most part uploads seen in flight at once
+    pub fn max_concurrent_uploads(&self) -> usize {
+        self.max_uploading.load(Ordering::SeqCst)
+    }
+
+    /// Most deletes seen in flight at once
+    pub fn max_concurrent_removes(&self) -> usize {
+        self.max_removing.load(Ordering::SeqCst)
+    }
+
+    /// Make every ranged download take `delay`
+    pub fn set_download_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().download_delay = delay;
+    }
+
+    /// Reset the connection of the next download after `received` bytes
+    pub fn reset_next_download_after(&self, received: usize) {
+        self.state.lock().unwrap().reset_download_after = Some(received);
+    }
+
+    /// Refuse writes with 403 Forbidden, like a token lacking the write scope
+    pub fn set_read_only(&self) {
+        self.state.lock().unwrap().read_only = true;
+    }
+
+    /// Make the download urls handed out so far expire
+    pub fn expire_download_urls(&self) {
+        self.state.lock().unwrap().download_url_generation += 1;
+    }
+
+    /// Hand out upload urls that expired already, until they are refreshed
+    pub fn expire_new_upload_urls(&self) {
+        self.state.lock().unwrap().expire_new_upload_urls = true;
+    }
+
+    /// How many times an operation was called
+    pub fn calls(&self, op: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .get(op)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn call(&self, op: &'static str) -> std::sync::MutexGuard<'_, State> {
+        let mut state = self.state.lock().unwrap();
+        *state.calls.entry(op).or_default() += 1;
+        state
+    }
+}
+
+impl State {
+    fn next_file_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("file-{}", self.next_id)
+    }
+
+    fn children(&self, parent_id: &str) -> impl Iterator<Item = &Entry> + '_ {
+        let parent_id = parent_id.to_string();
+        self.files
+            .values()
+            .filter(move |e| e.parent_id == parent_id && !e.trashed)
+    }
+
+    fn revision(&self, file_id: &str, revision_id: &str) -> Option<&Bytes> {
+        self.revisions
+            .get(file_id)?
+            .iter()
+            .find(|(rev, _)| rev.revision_id == revision_id)
+            .map(|(_, content)| content)
+    }
+
+    fn child(&self, parent_id: &str, name: &str) -> Option<&Entry> {
+        self.children(parent_id).find(|e| e.file.name == name)
+    }
+
+    fn find(&self, path: &str) -> Option<&Entry> {
+        let mut parent_id = "root".to_string();
+        let mut found = None;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let entry = self.child(&parent_id, name)?;
+            parent_id = entry.file.id.clone();
+            found = Some(entry);
+        }
+        found
+    }
+
+    fn upload_urls(&self, upload_id: &str, chunk_count: u64) -> Vec<UploadPartInfo> {
+        (1..=chunk_count)
+            .map(|part_number| UploadPartInfo {
+                part_number,
+                upload_url: format!(
+                    "mock://upload/{}/{}/{}",
+                    upload_id, part_number, self.upload_url_generation
+                ),
+            })
+            .collect()
+    }
+
+    fn remove(&mut self, file_id: &str) {
+        let children: Vec<_> = self
+            .files
+            .values()
+            .filter(|e| e.parent_id == file_id)
+            .map(|e| e.file.id.clone())
+            .collect();
+        for child in children {
+            self.remove(&child);
+        }
+        self.files.remove(file_id);
+    }
+
+    fn rename(&mut self, file_id: &str, name: &str, allow_duplicate: bool) -> Result<()> {
+        let parent_id = &self.files.get(file_id).context("no such file")?.parent_id;
+        if !allow_duplicate && self.child(parent_id, name).is_some() {
+            bail!("a file named {} already exists", name);
+        }
+        self.files.get_mut(file_id).unwrap().file.name = name.to_string();
+        Ok(())
+    }
+
+    fn copy(&mut self, file_id: &str, to_parent_id: &str, name: &str) -> Result<()> {
+        let entry = self.files.get(file_id).context("no such file")?.clone();
+        let id = self.next_file_id();
+        let mut copy = entry.clone();
+        copy.file.id = id.clone();
+        copy.file.name = name.to_string();
+        copy.parent_id = to_parent_id.to_string();
+        self.files.insert(id.clone(), copy);
+        let children: Vec<_> = self
+            .children(file_id)
+            .map(|e| (e.file.id.clone(), e.file.name.clone()))
+            .collect();
+        for (child, name) in children {
+            self.copy(&child, &id, &name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Entry {
+    fn set_content(&mut self, content: Bytes) {
+        self.file.size = content.len() as u64;
+        self.file.content_hash = Some(sha1_hex(&content));
+        self.file.updated_at = DateTime::new(SystemTime::now());
+        self.content = content;
+    }
+}
+
+fn new_entry(id: &str, parent_id: &str, name: &str, r#type: FileType, content: Bytes) -> Entry {
+    let now = DateTime::new(SystemTime::now());
+    let content_hash = matches!(r#type, FileType::File).then(|| sha1_hex(&content));
+    Entry {
+        file: AliyunFile {
+            name: name.to_string(),
+            id: id.to_string(),
+            r#type,
+            created_at: now.clone(),
+            updated_at: now,
+            size: content.len() as u64,
+            url: None,
+            content_hash,
+        },
+        parent_id: parent_id.to_string(),
+        content,
+        trashed: false,
+    }
+}
+
+fn sha1_hex(content: &[u8]) -> String {
+    Sha1::digest(content)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect()
+}
+
+/// Error of a request refused with 403 Forbidden
+fn forbidden() -> anyhow::Error {
+    let res = hyper::Response::builder().status(403).body("").unwrap();
+    reqwest::Response::from(res)
+        .error_for_status()
+        .unwrap_err()
+        .into()
+}
+
+/// `mock://download/<file_id>?generation=<n>` to the file id and the generation,
+/// internal urls start with `mock://internal/` instead
+fn download_file_id(url: &str) -> Result<(&str, u64)> {
+    let (id, generation) = url
+        .strip_prefix("mock://download/")
+        .or_else(|| url.strip_prefix("mock://internal/"))
+        .and_then(|url| url.split_once("?generation="))
+        .context("not a download url")?;
+    Ok((id, generation.parse()?))
+}
+
+impl DriveBackend for MockDrive {
+    fn get_file<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<Option<AliyunFile>>> {
+        let state = self.call("get_file");
+        let file = state
+            .files
+            .get(file_id)
+            .filter(|e| !e.trashed)
+            .map(|e| e.file.clone());
+        async move { Ok(file) }.boxed()
+    }
+
+    fn get_by_path<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Option<AliyunFile>>> {
+        let state = self.call("get_by_path");
+        let file = if path == "/" || path.is_empty() {
+            Some(AliyunFile::new_root())
+        } else {
+            state.find(path).map(|e| e.file.clone())
+        };
+        async move { Ok(file) }.boxed()
+    }
+
+    fn get_file_location<'a>(
+        &'a self,
+        file_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, String)>>> {
+        let state = self.call("get_file_location");
+        let location = state
+            .files
+            .get(file_id)
+            .map(|e| (e.file.name.clone(), e.parent_id.clone()));
+        async move { Ok(location) }.boxed()
+    }
+
+    fn list_all<'a>(&'a self, parent_file_id: &'a str) -> BoxFuture<'a, Result<Vec<AliyunFile>>> {
+        let state = self.call("list_all");
+        if state.failing.contains("list_all") {
+            return async move { Err(anyhow::anyhow!("list failed")) }.boxed();
+        }
+        let mut files: Vec<_> = state
+            .children(parent_file_id)
+            .map(|e| e.file.clone())
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        async move { Ok(files) }.boxed()
+    }
+
+    fn list_trashed_children<'a>(
+        &'a self,
+        parent_file_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<AliyunFile>>> {
+        let state = self.call("list_trashed_children");
+        let files: Vec<_> = state
+            .files
+            .values()
+            .filter(|e| e.parent_id == parent_file_id && e.trashed)
+            .map(|e| e.file.clone())
+            .collect();
+        async move { Ok(files) }.boxed()
+    }
+
+    fn list_revisions<'a>(&'a self, file_id: &'a str) -> BoxFuture<'a, Result<Vec<FileRevision>>> {
+        let state = self.call("list_revisions");
+        let revisions = state
+            .revisions
+            .get(file_id)
+            .map(|revisions| revisions.iter().map(|(rev, _)| rev.clone()).collect())
+            .unwrap_or_default();
+        async move { Ok(revisions) }.boxed()
+    }
+
+    fn get_download_url<'a>(
+        &'a self,
+        file_id: &'a str,
+    ) -> BoxFuture<'a, Result<GetFileDownloadUrlResponse>> {
+        let state = self.call("get_download_url");
+        let exists = state.files.contains_key(file_id);
+        let generation = state.download_url_generation;
+        async move {
+            if !exists {
+                bail!("no such file {}", file_id);
+            }
+            Ok(GetFileDownloadUrlResponse {
+                url: format!("mock://download/{}?generation={}", file_id, generation),
+                internal_url: Some(format!(
+                    "mock://internal/{}?generation={}",
+                    file_id, generation
+                )),
+                streams_url: HashMap::new(),
+            })
+        }
+        .boxed()
+    }
+
+    fn get_revision_download_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        revision_id: &'a str,
+    ) -> BoxFuture<'a, Result<GetFileDownloadUrlResponse>> {
+        let state = self.call("get_revision_download_url");
+        let exists = state.revision(file_id, revision_id).is_some();
+        let generation = state.download_url_generation;
+        async move {
+            if !exists {
+                bail!("no such revision {} of {}", revision_id, file_id);
+            }
+            Ok(GetFileDownloadUrlResponse {
+                url: format!(
+                    "mock://download/{}/{}?generation={}",
+                    file_id, revision_id, generation
+                ),
+                internal_url: None,
+                streams_url: HashMap::new(),
+            })
+        }
+        .boxed()
+    }
+
+    fn get_thumbnail_url<'a>(
+        &'a self,
+        file_id: &'a str,
+        width: u32,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        let state = self.call("get_thumbnail_url");
+        let is_image = state
+            .files
+            .get(file_id)
+            .map(|e| e.file.name.ends_with(".jpg") || e.file.name.ends_with(".png"))
+            .unwrap_or(false);
+        let url = is_image.then(|| format!("mock://thumbnail/{}/{}", file_id, width));
+        async move { Ok(url) }.boxed()
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+        range: Option<(u64, usize)>,
+    ) -> BoxFuture<'a, Result<Bytes>> {
+        let mut state = self.call("download");
+        if url.starts_with("mock://internal/") {
+            *state.calls.entry("download_internal").or_default() += 1;
+        }
+        let expired = download_file_id(url)
+            .map(|(_, generation)| generation < state.download_url_generation)
+            .unwrap_or(false);
+        if state.failing.contains("download") || expired {
+            // the url is rejected like an expired one
+            return async move { Err(forbidden()) }.boxed();
+        }
+        if let Some(thumbnail) = url.strip_prefix("mock://thumbnail/") {
+            // thumbnails are told apart by their content
+            let content = Bytes::from(format!("thumbnail {}", thumbnail));
+            return async move { Ok(content) }.boxed();
+        }
+        let content = download_file_id(url).and_then(|(id, _)| {
+            let content = match id.split_once('/') {
+                Some((file_id, revision_id)) => state
+                    .revision(file_id, revision_id)
+                    .context("no such revision")?
+                    .clone(),
+                None => state.files.get(id).context("no such file")?.content.clone(),
+            };
+            Ok(match range {
+                Some((start, size)) => {
+                    let start = (start as usize).min(content.len());
+                    let end = (start + size).min(content.len());
+                    content.slice(start..end)
+                }
+                None => content,
+            })
+        });
+        async move { content }.boxed()
+    }
+
+    fn download_range_into<'a>(
+        &'a self,
+        url: reqwest::Url,
+        start_pos: u64,
+        size: usize,
+        buf: &'a mut BytesMut,
+    ) -> BoxFuture<'a, Result<()>> {
+        let (reset_after, delay) = {
+            let mut state = self.state.lock().unwrap();
+            (state.reset_download_after.take(), state.download_delay)
+        };
+        async move {
+            tokio::time::sleep(delay).await;
+            let content = self.download(url.as_str(), Some((start_pos, size))).await?;
+            if let Some(received) = reset_after.filter(|received| *received < content.len()) {
+                buf.extend_from_slice(&content[..received]);
+                let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+                return Err(reset.into());
+            }
+            buf.extend_from_slice(&content);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, file_id: &'a str, trash: bool) -> BoxFuture<'a, Result<()>> {
+        let mut state = self.call("remove_file");
+        let upload_id = format!("upload-{}", file_id);
+        let res = if state.uploads.remove(&upload_id).is_some() {
+            // a file is created before its content is uploaded
+            Ok(())
+        } else if !state.files.contains_key(file_id) {
+            Err(anyhow::anyhow!("no such file {}", file_id))
+        } else if !trash
+            && state.failing.contains("remove_tree")
+            && state.children(file_id).next().is_some()
+        {
+            Err(anyhow::anyhow!("folder {} is not empty", file_id))
+        } else {
+            if trash {
+                state.files.get_mut(file_id).unwrap().trashed = true;
+            } else {
+                state.remove(file_id);
+            }
+            Ok(())
+        };
+        drop(state);
+        async move {
+            // bounded like requests to Aliyun
+            let _permit = self.api_semaphore.acquire().await;
+            let removing = self.removing.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_removing.fetch_max(removing, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            self.removing.fetch_sub(1, Ordering::SeqCst);
+            res
+        }
+        .boxed()
+    }
+
+    fn create_folder<'a>(
+        &'a self,
+        parent_file_id: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        let mut state = self.call("create_folder");
+        if state.failing.contains("create_folder") {
+            return async move { Err(anyhow::anyhow!("create folder failed")) }.boxed();
+        }
+        if state.read_only {
+            return async move { Err(forbidden()) }.boxed();
+        }
+        if state.child(parent_file_id, name).is_none() {
+            let id = state.next_file_id();
+            let entry = new_entry(&id, parent_file_id, name, FileType::Folder, Bytes::new());
+            state.files.insert(id, entry);
+        }
+        async move { Ok(()) }.boxed()
+    }
+
+    fn rename_file<'a>(&'a self, file_id: &'a str, name: &'a str) -> BoxFuture<'a, Result<()>> {
+        let mut state = self.call("rename_file");
+        let res = state.rename(file_id, name, false);
+        async move { res }.boxed()
+    }
+
+    fn rename_file_allow_duplicate<'a>(
+        &'a self,
+        file_id: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        let mut state = self.call("rename_file_allow_duplicate");
+        let res = if state.failing.contains("rename_file_allow_duplicate") {
+            Err(anyhow::anyhow!("rename failed"))
+        } else {
+            state.rename(file_id, name, true)
+        };
+        async move { res }.boxed()
+    }
+
+    fn search_name_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<AliyunFile>>> {
+        let state = self.call("search_name_prefix");
+        let files = state
+            .files
+            .values()
+            .filter(|e| !e.trashed && e.file.name.starts_with(prefix))
+            .map(|e| e.file.clone())
+            .collect();
+        async move { Ok(files) }.boxed()
+    }
+
+    fn move_file<'a>(
+        &'a self,
+        file_id: &'a str,
+        to_parent_file_id: &'a str,
+        new_name: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        let mut state = self.call("move_file");
+        let res = match state.files.get_mut(file_id) {
+            Some(entry) => {
+                entry.parent_id = to_parent_file_id.to_string();
+                if let Some(name) = new_name {
+                    entry.file.name = name.to_string();
+                }
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("no such file {}", file_id)),
+        };
+        async move { res }.boxed()
+    }
+
+    fn copy_file<'a>(
+        &'a self,
+        file_id: &'a str,
+        to_parent_file_id: &'a str,
+        new_name: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        let mut state = self.call("copy_file");
+        let res = state
+            .files
+            .get(file_id)
+            .map(|e| new_name.unwrap_or(&e.file.name).to_string())
+            .context("no such file")
+            .and_then(|name| state.copy(file_id, to_parent_file_id, &name));
+        async move { res }.boxed()
+    }
+
+    fn create_file_with_proof<'a>(
+        &'a self,
+        name: &'a str,
+        parent_file_id: &'a str,
+        size: u64,
+        chunk_count: u64,
+        proof: Option<&'a RapidUploadProof>,
+    ) -> BoxFuture<'a, Result<CreateFileWithProofResponse>> {
+        let mut state = self.call("create_file_with_proof");
+        if state.read_only {
+            return async move { Err(forbidden()) }.boxed();
+        }
+        let id = state.next_file_id();
+        let mut entry = new_entry(&id, parent_file_id, name, FileType::File, Bytes::new());
+        entry.file.size = size;
+        let existing = proof.and_then(|proof| {
+            state
+                .files
+                .values()
+                .find(|e| e.file.content_hash.as_deref() == Some(proof.content_hash.as_str()))
+                .map(|e| e.content.clone())
+        });
+        let res = if let Some(content) = existing {
+            entry.set_content(content);
+            state.files.insert(id.clone(), entry);
+            CreateFileWithProofResponse {
+                part_info_list: Vec::new(),
+                file_id: id,
+                upload_id: None,
+                rapid_upload: true,
+            }
+        } else {
+            let upload_id = format!("upload-{}", id);
+            let part_info_list = state.upload_urls(&upload_id, chunk_count);
+            if state.expire_new_upload_urls {
+                state.upload_url_generation += 1;
+            }
+            state.uploads.insert(
+                upload_id.clone(),
+                Upload {
+                    entry,
+                    chunk_count,
+                    parts: BTreeMap::new(),
+                },
+            );
+            CreateFileWithProofResponse {
+                part_info_list,
+                file_id: id,
+                upload_id: Some(upload_id),
+                rapid_upload: false,
+            }
+        };
+        async move { Ok(res) }.boxed()
+    }
+
+    fn proof_code_offset(&self, _size: u64) -> BoxFuture<'_, Result<u64>> {
+        async move { Ok(0) }.boxed()
+    }
+
+    fn get_upload_url<'a>(
+        &'a self,
+        _file_id: &'a str,
+        upload_id: &'a str,
+        chunk_count: u64,
+    ) -> BoxFuture<'a, Result<Vec<UploadPartInfo>>> {
+        let state = self.call("get_upload_url");
+        let urls = state.upload_urls(upload_id, chunk_count);
+        async move { Ok(urls) }.boxed()
+    }
+
+    fn upload<'a>(&'a self, url: &'a str, body: Bytes) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let uploading = self.uploading.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_uploading.fetch_max(uploading, Ordering::SeqCst);
+            // give other parts a chance to be in flight at the same time
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.uploading.fetch_sub(1, Ordering::SeqCst);
+            let mut state = self.call("upload");
+            let parts: Vec<_> = url
+                .strip_prefix("mock://upload/")
+                .context("not an upload url")?
+                .split('/')
+                .collect();
+            let [upload_id, part_number, generation] = parts[..] else {
+                bail!("not an upload url");
+            };
+            if generation.parse::<u64>()? != state.upload_url_generation {
+                bail!("upload url expired");
+            }
+            let upload = state.uploads.get_mut(upload_id).context("no such upload")?;
+            upload.parts.insert(part_number.parse()?, body);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn complete_file_upload<'a>(
+        &'a self,
+        _file_id: &'a str,
+        upload_id: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        let mut state = self.call("complete_file_upload");
+        let res = state
+            .uploads
+            .remove(upload_id)
+            .context("no such upload")
+            .and_then(|mut upload| {
+                if upload.parts.len() as u64 != upload.chunk_count {
+                    bail!(
+                        "{} of {} parts uploaded",
+                        upload.parts.len(),
+                        upload.chunk_count
+                    );
+                }
+                let mut content = BytesMut::new();
+                for part in upload.parts.values() {
+                    content.extend_from_slice(part);
+                }
+                upload.entry.set_content(content.freeze());
+                let id = upload.entry.file.id.clone();
+                state.files.insert(id, upload.entry);
+                Ok(())
+            });
+        async move { res }.boxed()
+    }
+
+    fn get_quota(&self) -> BoxFuture<'_, Result<(u64, u64)>> {
+        let state = self.call("get_quota");
+        if state.failing.contains("get_quota") {
+            return async move { Err(anyhow::anyhow!("quota unavailable")) }.boxed();
+        }
+        let used = state.files.values().map(|e| e.file.size).sum();
+        let total = state.total_space.unwrap_or(1 << 40);
+        async move { Ok((used, total)) }.boxed()
+    }
+
+    fn api_semaphore(&self) -> &ApiSemaphore {
+        &self.api_semaphore
+    }
+
+    fn list_changes<'a>(
+        &'a self,
+        cursor: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<ListDeltaResponse>>> {
+        let state = self.call("list_changes");
+        let res = ListDeltaResponse {
+            items: cursor.map(|_| state.changes.clone()).unwrap_or_default(),
+            cursor: state.changes.len().to_string(),
+            has_more: false,
+        };
+        async move { Ok(Some(res)) }.boxed()
+    }
+}
@@ -1,19 +1,18 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use clap::ValueEnum;
 use dav_server::fs::{DavDirEntry, DavMetaData, FsFuture, FsResult};
 use futures_util::future::FutureExt;
 use reqwest::{
-    header::{HeaderMap, HeaderValue},
-    IntoUrl, StatusCode,
+    header::{HeaderMap, HeaderName, HeaderValue},
+    IntoUrl, StatusCode, Url,
 };
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tokio::{
@@ -22,13 +21,36 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
+use crate::health::TokenHealth;
+use crate::metrics;
+
+mod backend;
+mod breaker;
+mod concurrency;
+#[cfg(test)]
+pub mod mock;
 pub mod model;
+mod rate_limit;
+mod retry;
+mod trace;
 
+pub use backend::DriveBackend;
+pub use breaker::CircuitBreaker;
+use breaker::CircuitBreakerMiddleware;
+pub use concurrency::ApiSemaphore;
 use model::*;
 pub use model::{AliyunFile, DateTime, FileType};
+pub use rate_limit::RateLimiter;
+use retry::{NonIdempotent, RetryMiddleware};
+use trace::TraceApiCalls;
 
 const ORIGIN: &str = "https://www.aliyundrive.com";
 const REFERER: &str = "https://www.aliyundrive.com/";
+/// Shortest time an access token is used before it is refreshed again,
+/// when the refresh margin is as long as its lifetime
+const MIN_TOKEN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// How often `--refresh-token-file` is checked for changes
+const REFRESH_TOKEN_FILE_POLL_INTERVAL: Duration = Duration::from_secs(10);
 const UA: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/99.0.4844.83 Safari/537.36";
 
 /// Aliyundrive drive type
@@ -38,10 +60,33 @@ pub enum DriveType {
     Resource,
     /// Backup drive
     Backup,
-    /// Default drive
+    /// Default drive, the personal drive of the account
+    #[value(alias = "personal")]
     Default,
 }
 
+/// What to do when a listing is throttled after some of its pages were listed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PartialListingPolicy {
+    /// Return the entries listed so far
+    Allow,
+    /// Fail the listing once the retries of the throttled page are used up
+    #[default]
+    Deny,
+}
+
+/// Listing cut short by throttling, with the entries listed before it
+#[derive(Debug)]
+pub struct PartialListing(pub Vec<AliyunFile>);
+
+impl std::fmt::Display for PartialListing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "listing throttled after {} entries", self.0.len())
+    }
+}
+
+impl std::error::Error for PartialListing {}
+
 #[derive(Debug, Clone)]
 pub struct DriveConfig {
     pub api_base_url: String,
@@ -50,19 +95,58 @@ pub struct DriveConfig {
     pub client_id: Option<String>,
     pub client_secret: Option<String>,
     pub drive_type: Option<DriveType>,
+    pub drive_id: Option<String>,
+    pub trace_api_calls: bool,
+    pub partial_listing: PartialListingPolicy,
+    /// How long to wait for operations completing in the background
+    pub async_op_timeout: Duration,
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Aliyun API calls per second, unlimited when `None`
+    pub upstream_rate_limit: Option<f64>,
+    /// Aliyun API calls and part uploads in flight at once
+    pub upstream_concurrency: usize,
+    /// Retries of throttled requests and transient upstream failures
+    pub max_retries: u32,
+    /// Upstream statuses worth retrying
+    pub retryable_statuses: Vec<StatusCode>,
+    /// File holding the refresh token, reloaded when it changes
+    pub refresh_token_file: Option<PathBuf>,
+    /// How long before its expiry the access token is refreshed
+    pub token_refresh_margin: Duration,
+    /// User-Agent of upstream requests instead of the built-in one
+    pub user_agent: Option<HeaderValue>,
+    /// Headers added to every upstream request
+    pub extra_headers: Vec<(HeaderName, HeaderValue)>,
+    /// Proxy of all upstream connections, http, https or socks5
+    pub proxy: Option<Url>,
+    /// Connect directly, ignoring the proxy environment variables
+    pub no_proxy: bool,
+}
+
+/// Content hash and proof code used to try rapid upload
+#[derive(Debug, Clone)]
+pub struct RapidUploadProof {
+    pub content_hash: String,
+    pub proof_code: String,
 }
 
 #[derive(Debug, Clone)]
 struct Credentials {
     refresh_token: String,
     access_token: Option<String>,
+    /// When to refresh the access token ahead of its expiry, on the monotonic
+    /// clock so that adjustments of the system clock don't matter
+    refresh_at: Option<time::Instant>,
 }
 
 #[derive(Debug, Clone)]
 pub struct AliyunDrive {
     config: DriveConfig,
     client: ClientWithMiddleware,
+    download_client: ClientWithMiddleware,
     credentials: Arc<RwLock<Credentials>>,
+    token_health: TokenHealth,
+    api_semaphore: ApiSemaphore,
     drive_id: Option<String>,
 }
 
@@ -72,6 +156,7 @@ impl AliyunDrive {
         let credentials = Credentials {
             refresh_token,
             access_token: None,
+            refresh_at: None,
         };
         let mut headers = HeaderMap::new();
         headers.insert("Origin", HeaderValue::from_static(ORIGIN));
@@ -80,28 +165,75 @@ impl AliyunDrive {
             // 灰度环境：gray
             headers.insert("X-Canary", HeaderValue::from_str(&canary_env)?);
         }
-        let retry_policy = ExponentialBackoff::builder()
-            .backoff_exponent(2)
-            .retry_bounds(Duration::from_millis(100), Duration::from_secs(5))
-            .build_with_max_retries(3);
-        let client = reqwest::Client::builder()
-            .user_agent(UA)
-            .default_headers(headers)
-            // OSS closes idle connections after 60 seconds,
-            // so we can close idle connections ahead of time to prevent re-using them.
-            // See also https://github.com/hyperium/hyper/issues/2136
-            .pool_idle_timeout(Duration::from_secs(50))
-            .connect_timeout(Duration::from_secs(10))
-            .timeout(Duration::from_secs(30))
-            .build()?;
-        let client = ClientBuilder::new(client)
-            .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-            .build();
-        let drive_type = config.drive_type.clone();
+        for (name, value) in &config.extra_headers {
+            headers.insert(name.clone(), value.clone());
+        }
+        let user_agent = config
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| HeaderValue::from_static(UA));
+        let retry = RetryMiddleware::new(config.max_retries, config.retryable_statuses.clone());
+        let proxy = config
+            .proxy
+            .as_ref()
+            .map(|url| reqwest::Proxy::all(url.clone()))
+            .transpose()?;
+        let client_builder = || {
+            let builder = match (proxy.clone(), config.no_proxy) {
+                (Some(proxy), _) => reqwest::Client::builder().proxy(proxy),
+                (None, true) => reqwest::Client::builder().no_proxy(),
+                (None, false) => reqwest::Client::builder(),
+            };
+            builder
+                .user_agent(user_agent.clone())
+                .default_headers(headers.clone())
+                // OSS closes idle connections after 60 seconds,
+                // so we can close idle connections ahead of time to prevent re-using them.
+                // See also https://github.com/hyperium/hyper/issues/2136
+                .pool_idle_timeout(Duration::from_secs(50))
+                .connect_timeout(Duration::from_secs(10))
+                .timeout(Duration::from_secs(30))
+        };
+        let rate_limiter = config
+            .upstream_rate_limit
+            .map(|rate| Arc::new(RateLimiter::new(rate)));
+        let api_semaphore = ApiSemaphore::new(config.upstream_concurrency);
+        let with_middleware = |client: reqwest::Client, api: bool| {
+            let mut builder = ClientBuilder::new(client);
+            // outermost, so that retries of a request count as one failure
+            if let Some(breaker) = config.circuit_breaker.clone() {
+                builder = builder.with(CircuitBreakerMiddleware(breaker));
+            }
+            let mut builder = builder.with(retry.clone());
+            // inside the retries, so that every call to the API waits for its turn
+            if let (true, Some(rate_limiter)) = (api, rate_limiter.clone()) {
+                builder = builder.with_arc(rate_limiter);
+            }
+            // a permit is only held while a request is in flight, not while backing off
+            if api {
+                builder = builder.with(api_semaphore.clone());
+            }
+            if config.trace_api_calls {
+                builder.with(TraceApiCalls::default()).build()
+            } else {
+                builder.build()
+            }
+        };
+        let client = with_middleware(client_builder().build()?, true);
+        // File content is served to WebDAV clients byte for byte with ranges and
+        // `Content-Length` taken from the file metadata, so downloads must never be
+        // transparently decoded: always ask OSS for the identity encoding and don't
+        // let reqwest gunzip responses. Already compressed files (`.gz` and friends)
+        // are thus passed through untouched and never compressed twice.
+        let download_client = with_middleware(client_builder().no_gzip().build()?, false);
+        let drive_type = config.drive_type;
         let mut drive = Self {
             config,
             client,
+            download_client,
             credentials: Arc::new(RwLock::new(credentials)),
+            token_health: TokenHealth::default(),
+            api_semaphore,
             drive_id: None,
         };
 
@@ -118,14 +250,11 @@ impl AliyunDrive {
 
         let client = drive.clone();
         tokio::spawn(async move {
-            let mut delay_seconds = 7000;
             match client
                 .do_refresh_token_with_retry(refresh_token_from_file)
                 .await
             {
                 Ok(res) => {
-                    // token usually expires in 7200s, refresh earlier
-                    delay_seconds = res.expires_in - 200;
                     if tx.send(res.access_token).is_err() {
                         error!("send access_token failed");
                     }
@@ -136,9 +265,16 @@ impl AliyunDrive {
                 }
             }
             loop {
-                time::sleep(time::Duration::from_secs(delay_seconds)).await;
+                // the token may have been refreshed meanwhile after a 401,
+                // so the refresh time is looked up again after waiting
+                let refresh_at = client.token_refresh_at().await;
+                if time::Instant::now() < refresh_at {
+                    time::sleep_until(refresh_at).await;
+                    continue;
+                }
                 if let Err(err) = client.do_refresh_token_with_retry(None).await {
                     error!("refresh token failed: {}", err);
+                    time::sleep(Duration::from_secs(60)).await;
                 }
             }
         });
@@ -147,21 +283,70 @@ impl AliyunDrive {
         if access_token.is_empty() {
             bail!("get access_token failed");
         }
-        let drive_type_str = match drive_type {
-            Some(DriveType::Resource) => "resource",
-            Some(DriveType::Backup) => "backup",
-            Some(DriveType::Default) | None => "default",
+        if let Some(path) = drive.config.refresh_token_file.clone() {
+            drive.clone().spawn_refresh_token_file_watcher(path);
+        }
+        let drive_id = if let Some(drive_id) = drive.config.drive_id.clone() {
+            let drives = drive.list_drives().await.context("list drives failed")?;
+            let Some((drive_type_str, _)) = drives.iter().find(|(_, id)| *id == drive_id) else {
+                bail!("drive id {} does not belong to this account", drive_id);
+            };
+            info!(drive_id = %drive_id, "use {} drive", drive_type_str);
+            drive_id
+        } else {
+            let drive_type_str = match drive_type {
+                Some(DriveType::Resource) => "resource",
+                Some(DriveType::Backup) => "backup",
+                Some(DriveType::Default) | None => "default",
+            };
+            let drive_id = drive
+                .get_drive_id(drive_type)
+                .await
+                .context("get drive id failed")?;
+            info!(drive_id = %drive_id, "found {} drive", drive_type_str);
+            drive_id
         };
-        let drive_id = drive
-            .get_drive_id(drive_type)
-            .await
-            .context("get drive id failed")?;
-        info!(drive_id = %drive_id, "found {} drive", drive_type_str);
         drive.drive_id = Some(drive_id);
 
         Ok(drive)
     }
 
+    /// Switch to the refresh token in `path` whenever its modification time changes
+    fn spawn_refresh_token_file_watcher(self, path: PathBuf) {
+        let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        tokio::spawn(async move {
+            let mut last_mtime = mtime(&path);
+            loop {
+                time::sleep(REFRESH_TOKEN_FILE_POLL_INTERVAL).await;
+                let current = mtime(&path);
+                if current.is_none() || current == last_mtime {
+                    continue;
+                }
+                last_mtime = current;
+                let token = match tokio::fs::read_to_string(&path).await {
+                    Ok(token) => token.trim().to_string(),
+                    Err(err) => {
+                        warn!(path = %path.display(), error = %err, "read refresh token file failed");
+                        continue;
+                    }
+                };
+                if token.is_empty() || token == self.refresh_token().await {
+                    continue;
+                }
+                info!(path = %path.display(), "refresh token file changed, reloading");
+                let previous = {
+                    let mut cred = self.credentials.write().await;
+                    std::mem::replace(&mut cred.refresh_token, token)
+                };
+                // the current access token stays in use until the new one is issued
+                if let Err(err) = self.do_refresh_token_with_retry(None).await {
+                    error!(path = %path.display(), error = %err, "refresh token from file failed, keep using the previous one");
+                    self.credentials.write().await.refresh_token = previous;
+                }
+            }
+        });
+    }
+
     async fn save_refresh_token(&self, refresh_token: &str) -> Result<()> {
         if let Some(dir) = self.config.workdir.as_ref() {
             tokio::fs::create_dir_all(dir).await?;
@@ -220,6 +405,12 @@ impl AliyunDrive {
                     let mut cred = self.credentials.write().await;
                     cred.refresh_token = res.refresh_token.clone();
                     cred.access_token = Some(res.access_token.clone());
+                    let lifetime = Duration::from_secs(res.expires_in);
+                    let refresh_in = lifetime
+                        .saturating_sub(self.config.token_refresh_margin)
+                        .max(MIN_TOKEN_REFRESH_INTERVAL.min(lifetime));
+                    cred.refresh_at = Some(time::Instant::now() + refresh_in);
+                    self.token_health.set_valid(true);
                     if let Err(err) = self.save_refresh_token(&res.refresh_token).await {
                         error!(error = %err, "save refresh token failed");
                     }
@@ -259,37 +450,74 @@ impl AliyunDrive {
                 }
             }
         }
+        self.token_health.set_valid(false);
         Err(last_err.unwrap())
     }
 
+    /// Outcome of the last token refresh, shared with the health check
+    pub fn token_health(&self) -> TokenHealth {
+        self.token_health.clone()
+    }
+
     async fn refresh_token(&self) -> String {
         let cred = self.credentials.read().await;
         cred.refresh_token.clone()
     }
 
+    /// When the access token should be refreshed, ahead of its expiry by the refresh margin
+    async fn token_refresh_at(&self) -> time::Instant {
+        let cred = self.credentials.read().await;
+        cred.refresh_at.unwrap_or_else(time::Instant::now)
+    }
+
     async fn access_token(&self) -> Result<String> {
         let cred = self.credentials.read().await;
         cred.access_token.clone().context("missing access_token")
     }
 
-    fn drive_id(&self) -> Result<&str> {
+    pub fn drive_id(&self) -> Result<&str> {
         self.drive_id.as_deref().context("missing drive_id")
     }
 
     async fn request<T, U>(&self, url: String, req: &T) -> Result<Option<U>>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        self.send_request(url, req, true).await
+    }
+
+    /// Like [`Self::request`] for calls that must not be repeated once they reached
+    /// the upstream, e.g. creating files, so they aren't retried on 5xx or throttling
+    async fn request_non_idempotent<T, U>(&self, url: String, req: &T) -> Result<Option<U>>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        self.send_request(url, req, false).await
+    }
+
+    async fn send_request<T, U>(&self, url: String, req: &T, idempotent: bool) -> Result<Option<U>>
     where
         T: Serialize + ?Sized,
         U: DeserializeOwned,
     {
         let mut access_token = self.access_token().await?;
         let url = reqwest::Url::parse(&url)?;
-        let res = self
-            .client
-            .post(url.clone())
-            .bearer_auth(&access_token)
-            .json(&req)
-            .send()
-            .await?;
+        let post = |access_token: &str| {
+            let builder = self
+                .client
+                .post(url.clone())
+                .bearer_auth(access_token)
+                .json(&req);
+            if idempotent {
+                builder
+            } else {
+                builder.with_extension(NonIdempotent)
+            }
+        };
+        metrics::record_api_call();
+        let res = post(&access_token).send().await?;
         match res.error_for_status_ref() {
             Ok(_) => {
                 if res.status() == StatusCode::NO_CONTENT {
@@ -304,57 +532,50 @@ impl AliyunDrive {
             Err(err) => {
                 let err_msg = res.text().await?;
                 debug!(error = %err_msg, url = %url, "request failed");
+                // throttling and 5xx were already retried by the middleware
                 match err.status() {
-                    Some(
-                        status_code
-                        @
-                        // 4xx
-                        (StatusCode::UNAUTHORIZED
-                        | StatusCode::REQUEST_TIMEOUT
-                        | StatusCode::TOO_MANY_REQUESTS
-                        // 5xx
-                        | StatusCode::INTERNAL_SERVER_ERROR
-                        | StatusCode::BAD_GATEWAY
-                        | StatusCode::SERVICE_UNAVAILABLE
-                        | StatusCode::GATEWAY_TIMEOUT),
-                    ) => {
-                        if status_code == StatusCode::UNAUTHORIZED {
-                            // refresh token and retry
-                            let token_res = self.do_refresh_token_with_retry(None).await?;
-                            access_token = token_res.access_token;
-                        } else {
-                            // wait for a while and retry
-                            time::sleep(Duration::from_secs(1)).await;
-                        }
-                        let res = self
-                            .client
-                            .post(url)
-                            .bearer_auth(&access_token)
-                            .json(&req)
-                            .send()
-                            .await?
-                            .error_for_status()?;
+                    Some(StatusCode::UNAUTHORIZED) => {
+                        // refresh token and retry
+                        let token_res = self.do_refresh_token_with_retry(None).await?;
+                        access_token = token_res.access_token;
+                        let res = post(&access_token).send().await?.error_for_status()?;
                         if res.status() == StatusCode::NO_CONTENT {
                             return Ok(None);
                         }
                         let res = res.json::<U>().await?;
                         Ok(Some(res))
                     }
-                    _ => Err(err.into()),
+                    _ => Err(with_api_error(err, &err_msg)),
                 }
             }
         }
     }
 
-    pub async fn get_drive_id(&self, drive_type: Option<DriveType>) -> Result<String> {
+    async fn get_drive_info(&self) -> Result<GetDriveInfoResponse> {
         let req = HashMap::<String, String>::new();
-        let res: GetDriveInfoResponse = self
-            .request(
-                format!("{}/adrive/v1.0/user/getDriveInfo", self.config.api_base_url),
-                &req,
-            )
-            .await
-            .and_then(|res| res.context("expect response"))?;
+        self.request(
+            format!("{}/adrive/v1.0/user/getDriveInfo", self.config.api_base_url),
+            &req,
+        )
+        .await
+        .and_then(|res| res.context("expect response"))
+    }
+
+    /// List drive type and drive id of all drives in the account
+    pub async fn list_drives(&self) -> Result<Vec<(&'static str, String)>> {
+        let res = self.get_drive_info().await?;
+        let mut drives = vec![("default", res.default_drive_id)];
+        if let Some(drive_id) = res.resource_drive_id {
+            drives.push(("resource", drive_id));
+        }
+        if let Some(drive_id) = res.backup_drive_id {
+            drives.push(("backup", drive_id));
+        }
+        Ok(drives)
+    }
+
+    pub async fn get_drive_id(&self, drive_type: Option<DriveType>) -> Result<String> {
+        let res = self.get_drive_info().await?;
         let drive_id = match drive_type {
             Some(DriveType::Resource) => res.resource_drive_id.unwrap_or_else(|| {
                 warn!("resource drive not found, use default drive instead");
@@ -370,6 +591,18 @@ impl AliyunDrive {
     }
 
     pub async fn get_file(&self, file_id: &str) -> Result<Option<AliyunFile>> {
+        Ok(self.get_file_response(file_id).await?.map(AliyunFile::from))
+    }
+
+    /// Name and parent folder id of a file
+    pub async fn get_file_location(&self, file_id: &str) -> Result<Option<(String, String)>> {
+        Ok(self.get_file_response(file_id).await?.map(|file| {
+            let parent_file_id = file.parent_file_id.unwrap_or_else(|| "root".to_string());
+            (file.name, parent_file_id)
+        }))
+    }
+
+    async fn get_file_response(&self, file_id: &str) -> Result<Option<GetFileResponse>> {
         let drive_id = self.drive_id()?;
         debug!(drive_id = %drive_id, file_id = %file_id, "get file");
         let req = GetFileRequest { drive_id, file_id };
@@ -381,7 +614,7 @@ impl AliyunDrive {
             .await
             .and_then(|res| res.context("expect response"));
         match res {
-            Ok(file) => Ok(Some(file.into())),
+            Ok(file) => Ok(Some(file)),
             Err(err) => {
                 if let Some(req_err) = err.downcast_ref::<reqwest::Error>() {
                     if matches!(req_err.status(), Some(StatusCode::NOT_FOUND)) {
@@ -422,11 +655,27 @@ impl AliyunDrive {
         }
     }
 
+    /// List all entries of a folder.
+    ///
+    /// Throttled pages are retried with backoff by the [`RetryMiddleware`], a page
+    /// still throttled after that fails the listing, unless partial listings are
+    /// allowed, then a [`PartialListing`] error carries the entries listed so far.
     pub async fn list_all(&self, parent_file_id: &str) -> Result<Vec<AliyunFile>> {
         let mut files = Vec::new();
         let mut marker = None;
         loop {
-            let res = self.list(parent_file_id, marker.as_deref()).await?;
+            let res = match self.list(parent_file_id, marker.as_deref()).await {
+                Ok(res) => res,
+                Err(err)
+                    if is_throttled(&err)
+                        && self.config.partial_listing == PartialListingPolicy::Allow
+                        && !files.is_empty() =>
+                {
+                    warn!(parent_file_id = %parent_file_id, listed = files.len(), "listing throttled, returning a partial listing");
+                    return Err(PartialListing(files).into());
+                }
+                Err(err) => return Err(err),
+            };
             files.extend(res.items.into_iter().map(|f| f.into()));
             if res.next_marker.is_empty() {
                 break;
@@ -436,6 +685,19 @@ impl AliyunDrive {
         Ok(files)
     }
 
+    /// Items in the recycle bin that were in the folder
+    pub async fn list_trashed_children(&self, parent_file_id: &str) -> Result<Vec<AliyunFile>> {
+        let files: Vec<AliyunFile> = self
+            .list_trash()
+            .await?
+            .into_iter()
+            .filter(|item| item.parent_file_id.as_deref() == Some(parent_file_id))
+            .map(AliyunFile::from)
+            .collect();
+        debug!(parent_file_id = %parent_file_id, count = files.len(), "list trashed items");
+        Ok(files)
+    }
+
     pub async fn list(
         &self,
         parent_file_id: &str,
@@ -460,27 +722,106 @@ impl AliyunDrive {
         .and_then(|res| res.context("expect response"))
     }
 
+    /// Files anywhere on the drive whose name starts with `prefix`
+    pub async fn search_name_prefix(&self, prefix: &str) -> Result<Vec<AliyunFile>> {
+        let drive_id = self.drive_id()?;
+        let query = format!("name match \"{}\"", prefix.replace('"', "\\\""));
+        let mut files = Vec::new();
+        let mut marker = None;
+        loop {
+            debug!(drive_id = %drive_id, query = %query, marker = ?marker, "search file");
+            let req = SearchFileRequest {
+                drive_id,
+                query: &query,
+                limit: 100,
+                order_by: "updated_at ASC",
+                marker: marker.as_deref(),
+            };
+            let res: ListFileResponse = self
+                .request(
+                    format!("{}/adrive/v1.0/openFile/search", self.config.api_base_url),
+                    &req,
+                )
+                .await?
+                .context("expect response")?;
+            // `match` is a fuzzy search
+            files.extend(
+                res.items
+                    .into_iter()
+                    .map(AliyunFile::from)
+                    .filter(|file| file.name.starts_with(prefix)),
+            );
+            if res.next_marker.is_empty() {
+                break;
+            }
+            marker = Some(res.next_marker);
+        }
+        Ok(files)
+    }
+
     pub async fn download<U: IntoUrl>(&self, url: U, range: Option<(u64, usize)>) -> Result<Bytes> {
-        use reqwest::header::RANGE;
+        use reqwest::header::{ACCEPT_ENCODING, RANGE};
 
         let url = url.into_url()?;
         let res = if let Some((start_pos, size)) = range {
             let end_pos = start_pos + size as u64 - 1;
             debug!(url = %url, start = start_pos, end = end_pos, "download file");
             let range = format!("bytes={}-{}", start_pos, end_pos);
-            self.client
+            self.download_client
                 .get(url)
+                .header(ACCEPT_ENCODING, "identity")
                 .header(RANGE, range)
                 .send()
                 .await?
                 .error_for_status()?
         } else {
             debug!(url = %url, "download file");
-            self.client.get(url).send().await?.error_for_status()?
+            self.download_client
+                .get(url)
+                .header(ACCEPT_ENCODING, "identity")
+                .send()
+                .await?
+                .error_for_status()?
         };
         Ok(res.bytes().await?)
     }
 
+    /// Download `size` bytes starting at `start_pos` and append them to `buf`.
+    ///
+    /// Bytes received before an error occurred are kept in `buf`,
+    /// so the caller can resume the download from where it stopped.
+    pub async fn download_range_into(
+        &self,
+        url: reqwest::Url,
+        start_pos: u64,
+        size: usize,
+        buf: &mut BytesMut,
+    ) -> Result<()> {
+        use reqwest::header::{ACCEPT_ENCODING, RANGE};
+
+        let end_pos = start_pos + size as u64 - 1;
+        debug!(url = %url, start = start_pos, end = end_pos, "download file");
+        let range = format!("bytes={}-{}", start_pos, end_pos);
+        let mut res = self
+            .download_client
+            .get(url)
+            .header(ACCEPT_ENCODING, "identity")
+            .header(RANGE, range)
+            .send()
+            .await?
+            .error_for_status()?;
+        let limit = buf.len() + size;
+        while let Some(chunk) = res.chunk().await? {
+            let wanted = limit - buf.len();
+            if chunk.len() >= wanted {
+                buf.extend_from_slice(&chunk[..wanted]);
+                break;
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(())
+    }
+
     pub async fn get_download_url(&self, file_id: &str) -> Result<GetFileDownloadUrlResponse> {
         debug!(file_id = %file_id, "get download url");
         let req = GetFileDownloadUrlRequest {
@@ -501,6 +842,102 @@ impl AliyunDrive {
         Ok(res)
     }
 
+    /// Url of a JPEG thumbnail `width` pixels wide, `None` for files other than images
+    pub async fn get_thumbnail_url(&self, file_id: &str, width: u32) -> Result<Option<String>> {
+        debug!(file_id = %file_id, width = width, "get thumbnail url");
+        let req = GetThumbnailRequest {
+            drive_id: self.drive_id()?,
+            file_id,
+            image_thumbnail_process: format!("image/resize,w_{}/format,jpeg", width),
+        };
+        let res: GetThumbnailResponse = self
+            .request(
+                format!("{}/adrive/v1.0/openFile/get", self.config.api_base_url),
+                &req,
+            )
+            .await?
+            .context("expect response")?;
+        if res.category.as_deref() != Some("image") {
+            return Ok(None);
+        }
+        Ok(res.thumbnail.filter(|url| !url.is_empty()))
+    }
+
+    /// List the version history of a file, newest first.
+    ///
+    /// Files or drives without version history yield an empty list.
+    pub async fn list_revisions(&self, file_id: &str) -> Result<Vec<FileRevision>> {
+        let drive_id = self.drive_id()?;
+        let mut revisions = Vec::new();
+        let mut marker = None;
+        loop {
+            debug!(drive_id = %drive_id, file_id = %file_id, marker = ?marker, "list file revisions");
+            let req = ListFileRevisionsRequest {
+                drive_id,
+                file_id,
+                limit: 100,
+                marker: marker.as_deref(),
+            };
+            let res: Result<Option<ListFileRevisionsResponse>> = self
+                .request(
+                    format!(
+                        "{}/adrive/v1.0/openFile/revision/list",
+                        self.config.api_base_url
+                    ),
+                    &req,
+                )
+                .await;
+            let res = match res {
+                Ok(res) => res.context("expect response")?,
+                Err(err) => {
+                    if let Some(req_err) = err.downcast_ref::<reqwest::Error>() {
+                        if matches!(
+                            req_err.status(),
+                            Some(
+                                StatusCode::NOT_FOUND
+                                    | StatusCode::BAD_REQUEST
+                                    | StatusCode::FORBIDDEN
+                            )
+                        ) {
+                            debug!(file_id = %file_id, error = %err, "file has no version history");
+                            break;
+                        }
+                    }
+                    return Err(err);
+                }
+            };
+            revisions.extend(res.items);
+            if res.next_marker.is_empty() {
+                break;
+            }
+            marker = Some(res.next_marker);
+        }
+        Ok(revisions)
+    }
+
+    pub async fn get_revision_download_url(
+        &self,
+        file_id: &str,
+        revision_id: &str,
+    ) -> Result<GetFileDownloadUrlResponse> {
+        debug!(file_id = %file_id, revision_id = %revision_id, "get revision download url");
+        let req = GetRevisionDownloadUrlRequest {
+            drive_id: self.drive_id()?,
+            file_id,
+            revision_id,
+            expire_sec: 14400, // 4 hours
+        };
+        self.request(
+            format!(
+                "{}/adrive/v1.0/openFile/revision/getDownloadUrl",
+                self.config.api_base_url
+            ),
+            &req,
+        )
+        .await?
+        .context("expect response")
+    }
+
     async fn trash(&self, file_id: &str) -> Result<()> {
         debug!(file_id = %file_id, "trash file");
         let req = TrashRequest {
@@ -530,9 +967,45 @@ impl AliyunDrive {
         Ok(())
     }
 
+    /// List all items in the recycle bin
+    pub async fn list_trash(&self) -> Result<Vec<ListFileItem>> {
+        let drive_id = self.drive_id()?;
+        let mut items = Vec::new();
+        let mut marker = None;
+        loop {
+            debug!(drive_id = %drive_id, marker = ?marker, "list trash");
+            let req = ListTrashRequest {
+                drive_id,
+                limit: 200,
+                marker: marker.as_deref(),
+            };
+            let res: ListFileResponse = self
+                .request(
+                    format!(
+                        "{}/adrive/v1.0/openFile/recyclebin/list",
+                        self.config.api_base_url
+                    ),
+                    &req,
+                )
+                .await?
+                .context("expect response")?;
+            items.extend(res.items);
+            if res.next_marker.is_empty() {
+                break;
+            }
+            marker = Some(res.next_marker);
+        }
+        Ok(items)
+    }
+
+    /// Permanently delete an item in the recycle bin
+    pub async fn purge_trash(&self, file_id: &str) -> Result<()> {
+        self.delete_file(file_id).await
+    }
+
     async fn delete_file(&self, file_id: &str) -> Result<()> {
         debug!(file_id = %file_id, "delete file");
-        let req = TrashRequest {
+        let req = DeleteFileRequest {
             drive_id: self.drive_id()?,
             file_id,
         };
@@ -575,7 +1048,7 @@ impl AliyunDrive {
             r#type: "folder",
         };
         let _res: Option<serde::de::IgnoredAny> = self
-            .request(
+            .request_non_idempotent(
                 format!("{}/adrive/v1.0/openFile/create", self.config.api_base_url),
                 &req,
             )
@@ -584,11 +1057,22 @@ impl AliyunDrive {
     }
 
     pub async fn rename_file(&self, file_id: &str, name: &str) -> Result<()> {
-        debug!(file_id = %file_id, name = %name, "rename file");
+        self.update_name(file_id, name, "refuse").await
+    }
+
+    /// Rename a file even when another one in its folder has the name,
+    /// both are listed until one of them is removed
+    pub async fn rename_file_allow_duplicate(&self, file_id: &str, name: &str) -> Result<()> {
+        self.update_name(file_id, name, "ignore").await
+    }
+
+    async fn update_name(&self, file_id: &str, name: &str, check_name_mode: &str) -> Result<()> {
+        debug!(file_id = %file_id, name = %name, check_name_mode = %check_name_mode, "rename file");
         let req = RenameFileRequest {
             drive_id: self.drive_id()?,
             file_id,
             name,
+            check_name_mode,
         };
         let _res: Option<serde::de::IgnoredAny> = self
             .request(
@@ -622,32 +1106,93 @@ impl AliyunDrive {
         Ok(())
     }
 
-    pub async fn copy_file(&self, file_id: &str, to_parent_file_id: &str) -> Result<()> {
-        debug!(file_id = %file_id, to_parent_file_id = %to_parent_file_id, "copy file");
+    /// Copy a file on the server side, renaming the copy when `new_name` is given
+    /// since the copy API always keeps the name of the source
+    pub async fn copy_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: Option<&str>,
+    ) -> Result<()> {
+        debug!(file_id = %file_id, to_parent_file_id = %to_parent_file_id, new_name = ?new_name, "copy file");
         let drive_id = self.drive_id()?;
         let req = CopyFileRequest {
             drive_id,
             file_id,
             to_parent_file_id,
-            auto_rename: false,
+            // the source name may be taken in the destination folder when the
+            // copy gets renamed afterwards, e.g. a copy within the same folder
+            auto_rename: new_name.is_some(),
         };
-        let _res: Option<serde::de::IgnoredAny> = self
-            .request(
+        let res: Option<CopyFileResponse> = self
+            .request_non_idempotent(
                 format!("{}/adrive/v1.0/openFile/copy", self.config.api_base_url),
                 &req,
             )
             .await?;
+        let copied_file_id = res.as_ref().and_then(|res| res.file_id.clone());
+        self.wait_async_task(res.map(|res| AsyncTaskResponse {
+            async_task_id: res.async_task_id,
+        }))
+        .await?;
+        if let (Some(new_name), Some(copied_file_id)) = (new_name, copied_file_id) {
+            self.rename_file(&copied_file_id, new_name).await?;
+        }
         Ok(())
     }
 
+    /// Wait until an operation running in the background completed, so that
+    /// its result is visible to the next request
+    async fn wait_async_task(&self, res: Option<AsyncTaskResponse>) -> Result<()> {
+        let Some(async_task_id) = res.and_then(|res| res.async_task_id) else {
+            return Ok(());
+        };
+        if async_task_id.is_empty() {
+            return Ok(());
+        }
+        let deadline = time::Instant::now() + self.config.async_op_timeout;
+        let mut delay = Duration::from_millis(200);
+        loop {
+            let req = GetAsyncTaskRequest {
+                async_task_id: &async_task_id,
+            };
+            let res: GetAsyncTaskResponse = self
+                .request(
+                    format!(
+                        "{}/adrive/v1.0/openFile/async_task/get",
+                        self.config.api_base_url
+                    ),
+                    &req,
+                )
+                .await?
+                .context("expect response")?;
+            debug!(async_task_id = %async_task_id, state = %res.state, "async task state");
+            match res.state.as_str() {
+                "Succeed" => return Ok(()),
+                "Failed" => bail!("async task {} failed", async_task_id),
+                _ => {}
+            }
+            if time::Instant::now() + delay > deadline {
+                bail!(
+                    "async task {} not completed within {:?}",
+                    async_task_id,
+                    self.config.async_op_timeout
+                );
+            }
+            time::sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(2));
+        }
+    }
+
     pub async fn create_file_with_proof(
         &self,
         name: &str,
         parent_file_id: &str,
         size: u64,
         chunk_count: u64,
+        proof: Option<&RapidUploadProof>,
     ) -> Result<CreateFileWithProofResponse> {
-        debug!(name = %name, parent_file_id = %parent_file_id, size = size, "create file with proof");
+        debug!(name = %name, parent_file_id = %parent_file_id, size = size, rapid_upload = proof.is_some(), "create file with proof");
         let drive_id = self.drive_id()?;
         let part_info_list = (1..=chunk_count)
             .map(|part_number| UploadPartInfo {
@@ -655,21 +1200,29 @@ impl AliyunDrive {
                 upload_url: String::new(),
             })
             .collect();
+        let (content_hash, content_hash_name, proof_code) = match proof {
+            Some(proof) => (
+                proof.content_hash.as_str(),
+                "sha1",
+                proof.proof_code.as_str(),
+            ),
+            None => ("", "none", ""),
+        };
         let req = CreateFileWithProofRequest {
             check_name_mode: "refuse",
-            content_hash: "",
-            content_hash_name: "none",
+            content_hash,
+            content_hash_name,
             drive_id,
             name,
             parent_file_id,
-            proof_code: "",
+            proof_code,
             proof_version: "v1",
             size,
             part_info_list,
             r#type: "file",
         };
         let res: CreateFileWithProofResponse = self
-            .request(
+            .request_non_idempotent(
                 format!("{}/adrive/v1.0/openFile/create", self.config.api_base_url),
                 &req,
             )
@@ -678,6 +1231,20 @@ impl AliyunDrive {
         Ok(res)
     }
 
+    /// Offset of the 8 bytes of file content used as rapid upload proof code,
+    /// derived from the md5 of the access token.
+    pub async fn proof_code_offset(&self, size: u64) -> Result<u64> {
+        use md5::{Digest, Md5};
+
+        if size == 0 {
+            return Ok(0);
+        }
+        let access_token = self.access_token().await?;
+        let digest = format!("{:x}", Md5::digest(access_token.as_bytes()));
+        let seed = u64::from_str_radix(&digest[..16], 16)?;
+        Ok(seed % size)
+    }
+
     pub async fn complete_file_upload(&self, file_id: &str, upload_id: &str) -> Result<()> {
         debug!(file_id = %file_id, upload_id = %upload_id, "complete file upload");
         let drive_id = self.drive_id()?;
@@ -686,13 +1253,13 @@ impl AliyunDrive {
             file_id,
             upload_id,
         };
-        let _res: Option<serde::de::IgnoredAny> = self
+        let res: Option<AsyncTaskResponse> = self
             .request(
                 format!("{}/adrive/v1.0/openFile/complete", self.config.api_base_url),
                 &req,
             )
             .await?;
-        Ok(())
+        self.wait_async_task(res).await
     }
 
     pub async fn upload(&self, url: &str, body: Bytes) -> Result<()> {
@@ -740,6 +1307,55 @@ impl AliyunDrive {
         Ok(res.part_info_list)
     }
 
+    /// Changes since `cursor`, or no changes and the current cursor without one.
+    ///
+    /// Returns `None` when the cursor is no longer accepted and the caller
+    /// has to list everything again.
+    pub async fn list_changes(&self, cursor: Option<&str>) -> Result<Option<ListDeltaResponse>> {
+        let drive_id = self.drive_id()?;
+        let Some(cursor) = cursor else {
+            let req = GetLastCursorRequest { drive_id };
+            let res: GetLastCursorResponse = self
+                .request(
+                    format!(
+                        "{}/adrive/v1.0/openFile/get_last_cursor",
+                        self.config.api_base_url
+                    ),
+                    &req,
+                )
+                .await?
+                .context("expect response")?;
+            return Ok(Some(ListDeltaResponse {
+                items: Vec::new(),
+                cursor: res.cursor,
+                has_more: false,
+            }));
+        };
+        debug!(cursor = %cursor, "list changes");
+        let req = ListDeltaRequest {
+            drive_id,
+            cursor,
+            limit: 100,
+        };
+        let res = self
+            .request(
+                format!(
+                    "{}/adrive/v1.0/openFile/list_delta",
+                    self.config.api_base_url
+                ),
+                &req,
+            )
+            .await;
+        match res {
+            Ok(res) => Ok(Some(res.context("expect response")?)),
+            Err(err) if is_cursor_expired(&err) => {
+                warn!(cursor = %cursor, error = %err, "change cursor expired");
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
     pub async fn get_quota(&self) -> Result<(u64, u64)> {
         let drive_id = self.drive_id()?;
         let mut data = HashMap::new();
@@ -774,6 +1390,26 @@ impl DavMetaData for AliyunFile {
     fn created(&self) -> FsResult<SystemTime> {
         Ok(*self.created_at)
     }
+
+    /// Size and modification time, along with the content hash when known so
+    /// that the ETag changes with the content even within the same second
+    fn etag(&self) -> Option<String> {
+        let t = self.updated_at.duration_since(UNIX_EPOCH).ok()?;
+        let t = t.as_secs() * 1_000_000 + u64::from(t.subsec_micros());
+        if !matches!(self.r#type, FileType::File) {
+            return Some(format!("{:x}", t));
+        }
+        match self.content_hash.as_deref().and_then(|hash| hash.get(..16)) {
+            Some(hash) => Some(format!(
+                "{:x}-{:x}-{}",
+                self.size,
+                t,
+                hash.to_ascii_lowercase()
+            )),
+            _ if self.size > 0 => Some(format!("{:x}-{:x}", self.size, t)),
+            _ => Some(format!("{:x}", t)),
+        }
+    }
 }
 
 impl DavDirEntry for AliyunFile {
@@ -781,11 +1417,91 @@ impl DavDirEntry for AliyunFile {
         self.name.as_bytes().to_vec()
     }
 
-    fn metadata(&self) -> FsFuture<Box<dyn DavMetaData>> {
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
         async move { Ok(Box::new(self.clone()) as Box<dyn DavMetaData>) }.boxed()
     }
 }
 
+/// Log a clear error when the proxy can't be connected to, requests would
+/// otherwise fail with less obvious errors
+pub async fn check_proxy(proxy: &Url) {
+    let Some(host) = proxy.host_str() else {
+        return;
+    };
+    let port = proxy.port_or_known_default().unwrap_or(1080);
+    let connect = tokio::net::TcpStream::connect((host, port));
+    match time::timeout(Duration::from_secs(5), connect).await {
+        Ok(Ok(_)) => debug!(proxy = %proxy, "proxy reachable"),
+        Ok(Err(err)) => error!(proxy = %proxy, error = %err, "proxy unreachable"),
+        Err(_) => error!(proxy = %proxy, "proxy unreachable, connecting timed out"),
+    }
+}
+
+/// Whether the upstream rejected the request for sending too many requests
+pub fn is_throttled(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            .map(|status| status == StatusCode::TOO_MANY_REQUESTS)
+            .unwrap_or(false)
+    })
+}
+
+/// Whether the error is caused by the connection being reset or closed prematurely
+pub fn is_connection_reset(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+
+    err.chain().any(|e| {
+        if let Some(req_err) = e.downcast_ref::<reqwest::Error>() {
+            req_err.is_body() || req_err.is_request() || req_err.is_connect()
+        } else if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+            matches!(
+                io_err.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+            )
+        } else {
+            false
+        }
+    })
+}
+
+/// Attach the code of the error in the response body, for callers telling
+/// errors apart, the status is still found in the error chain
+fn with_api_error(err: reqwest::Error, body: &str) -> anyhow::Error {
+    match serde_json::from_str::<ApiError>(body) {
+        Ok(api_err) => anyhow::Error::new(err).context(api_err),
+        Err(_) => err.into(),
+    }
+}
+
+/// Whether the change cursor was refused, e.g. because it is too old, with
+/// an error code about the cursor rather than any other invalid parameter
+fn is_cursor_expired(err: &anyhow::Error) -> bool {
+    let refused = err.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<reqwest::Error>()
+                .and_then(|req_err| req_err.status()),
+            Some(StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::GONE)
+        )
+    });
+    refused
+        && err
+            .downcast_ref::<ApiError>()
+            .is_some_and(|api_err| api_err.code.to_ascii_lowercase().contains("cursor"))
+}
+
+/// Whether OSS refused a download url, e.g. because its signature expired
+pub fn is_url_rejected(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .and_then(|req_err| req_err.status())
+            == Some(StatusCode::FORBIDDEN)
+    })
+}
+
 pub async fn read_refresh_token(workdir: &Path) -> Result<String> {
     let file = workdir.join("refresh_token");
     let token = tokio::fs::read_to_string(&file).await?;
@@ -797,3 +1513,311 @@ pub async fn read_refresh_token(workdir: &Path) -> Result<String> {
     }
     Ok(token)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// Path, authorization and body of the requests a [`fake_api`] got
+    type Requests = Arc<Mutex<Vec<(String, String, Value)>>>;
+
+    /// Aliyun API answering with `respond(path, authorization)`, returns a config
+    /// using it and the requests it got
+    fn fake_api<F>(respond: F) -> (DriveConfig, Requests)
+    where
+        F: Fn(&str, &str) -> (StatusCode, Value) + Send + Sync + 'static,
+    {
+        let respond = Arc::new(respond);
+        let requests = Requests::default();
+        let log = requests.clone();
+        let make_svc = make_service_fn(move |_| {
+            let (respond, log) = (respond.clone(), log.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<hyper::Body>| {
+                    let (respond, log) = (respond.clone(), log.clone());
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let authorization = req
+                            .headers()
+                            .get(hyper::header::AUTHORIZATION)
+                            .and_then(|v| v.to_str().ok())
+                            .unwrap_or_default()
+                            .to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                        let (status, res) = respond(&path, &authorization);
+                        log.lock().unwrap().push((path, authorization, body));
+                        Ok::<_, Infallible>(
+                            hyper::Response::builder()
+                                .status(status)
+                                .header("content-type", "application/json")
+                                .body(hyper::Body::from(res.to_string()))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        let config = DriveConfig {
+            api_base_url: url.clone(),
+            refresh_token_host: url,
+            workdir: None,
+            client_id: None,
+            client_secret: None,
+            drive_type: None,
+            drive_id: None,
+            trace_api_calls: false,
+            partial_listing: PartialListingPolicy::Deny,
+            async_op_timeout: Duration::from_secs(1),
+            circuit_breaker: None,
+            upstream_rate_limit: None,
+            upstream_concurrency: 4,
+            max_retries: 0,
+            retryable_statuses: Vec::new(),
+            refresh_token_file: None,
+            token_refresh_margin: Duration::from_secs(60),
+            user_agent: None,
+            extra_headers: Vec::new(),
+            proxy: None,
+            no_proxy: true,
+        };
+        (config, requests)
+    }
+
+    fn drive_info() -> Value {
+        json!({"default_drive_id": "default-id", "backup_drive_id": "backup-id"})
+    }
+
+    #[tokio::test]
+    async fn the_given_drive_id_is_used() {
+        let (mut config, requests) = fake_api(|path, _| match path {
+            "/oauth/access_token" => (
+                StatusCode::OK,
+                json!({"access_token": "token", "refresh_token": "a.b.c", "expires_in": 7200}),
+            ),
+            "/adrive/v1.0/user/getDriveInfo" => (StatusCode::OK, drive_info()),
+            _ => (StatusCode::OK, json!({"url": "https://download"})),
+        });
+        config.drive_id = Some("backup-id".to_string());
+        let drive = AliyunDrive::new(config.clone(), "a.b.c".to_string())
+            .await
+            .unwrap();
+        drive.get_download_url("file-1").await.unwrap();
+        let (path, _, body) = requests.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(path, "/adrive/v1.0/openFile/getDownloadUrl");
+        assert_eq!(body["drive_id"], "backup-id");
+
+        config.drive_id = Some("someone-else".to_string());
+        let err = AliyunDrive::new(config, "a.b.c".to_string())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not belong"), "{}", err);
+    }
+
+    fn api_error(status: u16, body: &str) -> anyhow::Error {
+        let res = hyper::Response::builder().status(status).body("").unwrap();
+        let err = reqwest::Response::from(res).error_for_status().unwrap_err();
+        with_api_error(err, body)
+    }
+
+    #[tokio::test]
+    async fn refreshed_tokens_are_saved_in_the_workdir() {
+        let (mut config, _) = fake_api(|path, _| match path {
+            "/oauth/access_token" => (
+                StatusCode::OK,
+                json!({"access_token": "token", "refresh_token": "d.e.f", "expires_in": 7200}),
+            ),
+            _ => (StatusCode::OK, drive_info()),
+        });
+        let workdir = std::env::temp_dir().join(format!("drive-workdir-{}", std::process::id()));
+        config.workdir = Some(workdir.clone());
+        AliyunDrive::new(config, "a.b.c".to_string()).await.unwrap();
+        assert_eq!(read_refresh_token(&workdir).await.unwrap(), "d.e.f");
+        std::fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unexpected_401_refreshes_the_token() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let counter = refreshes.clone();
+        let (config, requests) = fake_api(move |path, authorization| match path {
+            "/oauth/access_token" => {
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                // valid for two hours, as far as the server can tell
+                let token = json!({
+                    "access_token": format!("token-{}", n),
+                    "refresh_token": "a.b.c",
+                    "expires_in": 7200,
+                });
+                (StatusCode::OK, token)
+            }
+            _ if authorization == "Bearer token-1" => (StatusCode::UNAUTHORIZED, json!({})),
+            _ => (StatusCode::OK, drive_info()),
+        });
+        let drive = AliyunDrive::new(config, "a.b.c".to_string()).await.unwrap();
+        assert_eq!(drive.drive_id().unwrap(), "default-id");
+        assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+        let (_, authorization, _) = requests.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(authorization, "Bearer token-2");
+    }
+
+    #[test]
+    fn only_cursor_errors_expire_the_cursor() {
+        let expired = api_error(
+            400,
+            r#"{"code":"InvalidParameter.Cursor","message":"cursor expired"}"#,
+        );
+        assert!(is_cursor_expired(&expired));
+        let other = api_error(
+            400,
+            r#"{"code":"InvalidParameter.Limit","message":"bad limit"}"#,
+        );
+        assert!(!is_cursor_expired(&other));
+        assert!(!is_cursor_expired(&api_error(400, "")));
+        // the code doesn't hide the status from the other checks
+        assert!(is_throttled(&api_error(
+            429,
+            r#"{"code":"TooManyRequests"}"#
+        )));
+    }
+
+    #[tokio::test]
+    async fn compressed_files_are_downloaded_as_is() {
+        let (config, _) = fake_api(|path, _| match path {
+            "/oauth/access_token" => (
+                StatusCode::OK,
+                json!({"access_token": "token", "refresh_token": "a.b.c", "expires_in": 7200}),
+            ),
+            _ => (StatusCode::OK, drive_info()),
+        });
+        let drive = AliyunDrive::new(config, "a.b.c".to_string()).await.unwrap();
+
+        // gzip magic followed by garbage, decoding it would fail
+        let gz = b"\x1f\x8b\x08\x00not really gzip".to_vec();
+        let accept_encodings = Arc::new(Mutex::new(Vec::new()));
+        let log = accept_encodings.clone();
+        let content = gz.clone();
+        let make_svc = make_service_fn(move |_| {
+            let (log, content) = (log.clone(), content.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<hyper::Body>| {
+                    let accept_encoding = req
+                        .headers()
+                        .get(hyper::header::ACCEPT_ENCODING)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+                    log.lock().unwrap().push(accept_encoding);
+                    let res = hyper::Response::builder()
+                        .header(hyper::header::CONTENT_ENCODING, "gzip")
+                        .body(hyper::Body::from(content.clone()))
+                        .unwrap();
+                    async move { Ok::<_, Infallible>(res) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/a.gz", server.local_addr());
+        tokio::spawn(server);
+
+        let content = drive.download(url.as_str(), None).await.unwrap();
+        assert_eq!(content, gz);
+        let mut buf = BytesMut::new();
+        let url = reqwest::Url::parse(&url).unwrap();
+        drive
+            .download_range_into(url, 0, gz.len(), &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, gz);
+        assert_eq!(*accept_encodings.lock().unwrap(), ["identity", "identity"]);
+    }
+
+    #[tokio::test]
+    async fn throttled_listings_are_partial_only_when_allowed() {
+        let list_for = |policy| async move {
+            let pages = Arc::new(AtomicUsize::new(0));
+            let (mut config, _) = fake_api(move |path, _| match path {
+                "/oauth/access_token" => (
+                    StatusCode::OK,
+                    json!({"access_token": "token", "refresh_token": "a.b.c", "expires_in": 7200}),
+                ),
+                "/adrive/v1.0/openFile/list" if pages.fetch_add(1, Ordering::SeqCst) == 0 => (
+                    StatusCode::OK,
+                    json!({
+                        "items": [{
+                            "name": "a.txt",
+                            "file_id": "file-1",
+                            "type": "file",
+                            "created_at": "2023-01-01T00:00:00.000Z",
+                            "updated_at": "2023-01-01T00:00:00.000Z",
+                            "size": 5
+                        }],
+                        "next_marker": "page-2"
+                    }),
+                ),
+                "/adrive/v1.0/openFile/list" => (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    json!({"code": "TooManyRequests"}),
+                ),
+                _ => (StatusCode::OK, drive_info()),
+            });
+            config.partial_listing = policy;
+            let drive = AliyunDrive::new(config, "a.b.c".to_string()).await.unwrap();
+            drive.list_all("root").await.unwrap_err()
+        };
+
+        let err = list_for(PartialListingPolicy::Allow).await;
+        let partial = err.downcast_ref::<PartialListing>().unwrap();
+        let names: Vec<_> = partial.0.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["a.txt"]);
+
+        let err = list_for(PartialListingPolicy::Deny).await;
+        assert!(err.downcast_ref::<PartialListing>().is_none());
+        assert!(is_throttled(&err), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn async_tasks_are_polled_until_completed() {
+        let complete_with = |states: &'static [&'static str]| async move {
+            let polls = Arc::new(AtomicUsize::new(0));
+            let counted = polls.clone();
+            let (config, _) = fake_api(move |path, _| match path {
+                "/oauth/access_token" => (
+                    StatusCode::OK,
+                    json!({"access_token": "token", "refresh_token": "a.b.c", "expires_in": 7200}),
+                ),
+                "/adrive/v1.0/openFile/complete" => {
+                    (StatusCode::OK, json!({"async_task_id": "task-1"}))
+                }
+                "/adrive/v1.0/openFile/async_task/get" => {
+                    let poll = counted.fetch_add(1, Ordering::SeqCst);
+                    let state = states[poll.min(states.len() - 1)];
+                    (StatusCode::OK, json!({ "state": state }))
+                }
+                _ => (StatusCode::OK, drive_info()),
+            });
+            let drive = AliyunDrive::new(config, "a.b.c".to_string()).await.unwrap();
+            let res = drive.complete_file_upload("file-1", "upload-1").await;
+            (res, polls.load(Ordering::SeqCst))
+        };
+
+        let (res, polls) = complete_with(&["Running", "Succeed"]).await;
+        res.unwrap();
+        assert_eq!(polls, 2);
+        let (res, _) = complete_with(&["Running", "Failed"]).await;
+        assert!(res.unwrap_err().to_string().contains("failed"));
+        // the config of the fake api gives up waiting after a second
+        let (res, _) = complete_with(&["Running"]).await;
+        assert!(res.unwrap_err().to_string().contains("not completed"));
+    }
+}
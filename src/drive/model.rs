@@ -1,16 +1,16 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::ops;
 use std::time::SystemTime;
 
 use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RefreshTokenResponse {
     pub access_token: String,
     pub refresh_token: String,
     pub expires_in: u64,
-    pub token_type: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -31,6 +31,15 @@ pub struct ListFileRequest<'a> {
     pub marker: Option<&'a str>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchFileRequest<'a> {
+    pub drive_id: &'a str,
+    pub query: &'a str,
+    pub limit: u64,
+    pub order_by: &'a str,
+    pub marker: Option<&'a str>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListFileResponse {
     pub items: Vec<ListFileItem>,
@@ -49,6 +58,17 @@ pub struct ListFileItem {
     pub size: Option<u64>,
     pub url: Option<String>,
     pub content_hash: Option<String>,
+    /// Only present for items in the recycle bin
+    pub trashed_at: Option<DateTime>,
+    #[serde(default)]
+    pub parent_file_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListTrashRequest<'a> {
+    pub drive_id: &'a str,
+    pub limit: u64,
+    pub marker: Option<&'a str>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -63,6 +83,45 @@ pub struct GetFileRequest<'a> {
     pub file_id: &'a str,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct GetThumbnailRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub image_thumbnail_process: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetThumbnailResponse {
+    pub category: Option<String>,
+    pub thumbnail: Option<String>,
+}
+
+/// Response of operations that may complete in the background
+#[derive(Debug, Clone, Deserialize)]
+pub struct AsyncTaskResponse {
+    #[serde(default)]
+    pub async_task_id: Option<String>,
+}
+
+/// Response of a copy, the copied file may still be filled in the background
+#[derive(Debug, Clone, Deserialize)]
+pub struct CopyFileResponse {
+    #[serde(default)]
+    pub file_id: Option<String>,
+    #[serde(default)]
+    pub async_task_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetAsyncTaskRequest<'a> {
+    pub async_task_id: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetAsyncTaskResponse {
+    pub state: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct StreamInfo {
     pub size: u64,
@@ -80,6 +139,8 @@ pub struct GetFileResponse {
     #[serde(default)]
     pub size: u64,
     pub streams_info: HashMap<String, StreamInfo>,
+    #[serde(default)]
+    pub parent_file_id: Option<String>,
 }
 
 impl From<GetFileResponse> for AliyunFile {
@@ -127,10 +188,44 @@ pub struct GetFileDownloadUrlRequest<'a> {
 #[derive(Debug, Clone, Deserialize)]
 pub struct GetFileDownloadUrlResponse {
     pub url: String,
+    /// Intranet endpoint, only reachable from within Alibaba Cloud
+    #[serde(default)]
+    pub internal_url: Option<String>,
     #[serde(default)]
     pub streams_url: HashMap<String, String>,
-    pub expiration: String,
-    pub method: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListFileRevisionsRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub limit: u64,
+    pub marker: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListFileRevisionsResponse {
+    #[serde(default)]
+    pub items: Vec<FileRevision>,
+    #[serde(default)]
+    pub next_marker: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileRevision {
+    pub revision_id: String,
+    #[serde(default)]
+    pub size: u64,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetRevisionDownloadUrlRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub revision_id: &'a str,
+    pub expire_sec: usize,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -159,6 +254,7 @@ pub struct RenameFileRequest<'a> {
     pub drive_id: &'a str,
     pub file_id: &'a str,
     pub name: &'a str,
+    pub check_name_mode: &'a str,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -205,7 +301,8 @@ pub struct CreateFileWithProofResponse {
     pub part_info_list: Vec<UploadPartInfo>,
     pub file_id: String,
     pub upload_id: Option<String>,
-    pub file_name: String,
+    #[serde(default)]
+    pub rapid_upload: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -223,6 +320,55 @@ pub struct GetUploadUrlRequest<'a> {
     pub part_info_list: Vec<UploadPartInfo>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct GetLastCursorRequest<'a> {
+    pub drive_id: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetLastCursorResponse {
+    pub cursor: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListDeltaRequest<'a> {
+    pub drive_id: &'a str,
+    pub cursor: &'a str,
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListDeltaResponse {
+    #[serde(default)]
+    pub items: Vec<DeltaItem>,
+    pub cursor: String,
+    #[serde(default)]
+    pub has_more: bool,
+}
+
+/// Error code and message of a failed API call
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+    pub code: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+/// A change to a file, `op` is `create`, `update`, `delete`, `move` or `trash`
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeltaItem {
+    pub op: String,
+    pub file_id: String,
+    #[serde(default)]
+    pub file: Option<ListFileItem>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SpaceInfo {
     pub total_size: u64,
@@ -251,6 +397,15 @@ impl<'a> Deserialize<'a> for DateTime {
     }
 }
 
+impl Serialize for DateTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let dt = OffsetDateTime::from(self.0)
+            .format(&Rfc3339)
+            .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&dt)
+    }
+}
+
 impl ops::Deref for DateTime {
     type Target = SystemTime;
 
@@ -259,14 +414,14 @@ impl ops::Deref for DateTime {
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum FileType {
     Folder,
     File,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AliyunFile {
     pub name: String,
     #[serde(rename = "file_id")]
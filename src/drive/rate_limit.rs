@@ -0,0 +1,81 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next, Result};
+use task_local_extensions::Extensions;
+use tokio::time;
+use tracing::debug;
+
+/// Token bucket refilled with `rate` tokens per second, holding up to `burst` tokens,
+/// one token per request or e.g. per byte.
+///
+/// Callers wait for their turn instead of failing, a token is reserved even
+/// when the bucket is empty so that waiting callers are served in order.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    /// Tokens available, negative when reserved ahead, as of the instant
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        Self::with_burst(rate, rate)
+    }
+
+    pub fn with_burst(rate: f64, burst: f64) -> Self {
+        let burst = burst.max(1.0);
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Time to wait before `count` tokens may be spent
+    fn reserve(&self, count: f64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = *bucket;
+        let now = Instant::now();
+        let tokens =
+            (tokens + now.duration_since(last).as_secs_f64() * self.rate).min(self.burst) - count;
+        *bucket = (tokens, now);
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+
+    pub async fn acquire(&self) {
+        let wait = self.reserve(1.0);
+        if !wait.is_zero() {
+            debug!(wait = ?wait, "upstream rate limit reached, waiting");
+            time::sleep(wait).await;
+        }
+    }
+
+    /// Wait until `count` tokens are available, without holding on to the
+    /// bucket meanwhile
+    pub async fn acquire_many(&self, count: u64) {
+        let wait = self.reserve(count as f64);
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for RateLimiter {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        self.acquire().await;
+        next.run(req, extensions).await
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{header, Request, Response, StatusCode};
+use reqwest_middleware::{Error, Middleware, Next, Result};
+use task_local_extensions::Extensions;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use tokio::time::sleep;
+use tracing::warn;
+
+use super::trace::redact_url;
+
+/// Longest `Retry-After` honored, longer waits give up instead
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Request extension marking a request that must not be sent twice, e.g.
+/// creating a file. It is only retried when it could not be sent at all.
+#[derive(Debug, Clone, Copy)]
+pub struct NonIdempotent;
+
+/// Retries responses with one of the retryable statuses and network errors
+/// with exponential backoff and jitter, honoring `Retry-After`.
+#[derive(Debug, Clone)]
+pub struct RetryMiddleware {
+    max_retries: u32,
+    statuses: Vec<StatusCode>,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryMiddleware {
+    pub fn new(max_retries: u32, statuses: Vec<StatusCode>) -> Self {
+        Self {
+            max_retries,
+            statuses,
+            min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+
+    fn is_retryable_status(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    /// Backoff before the retry number `attempt`, counting from 0, with up to
+    /// half of it taken off at random
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .min_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// `Retry-After` in seconds or as an HTTP date
+fn retry_after(res: &Response) -> Option<Duration> {
+    let value = res
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+    let wait = date - OffsetDateTime::now_utc();
+    Some(wait.try_into().unwrap_or_default())
+}
+
+#[async_trait::async_trait]
+impl Middleware for RetryMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        let idempotent = extensions.get::<NonIdempotent>().is_none();
+        let mut attempt = 0;
+        loop {
+            let Some(duplicate) = req.try_clone() else {
+                // streaming bodies can't be sent again
+                return next.run(req, extensions).await;
+            };
+            let res = next.clone().run(duplicate, extensions).await;
+            if attempt >= self.max_retries {
+                return res;
+            }
+            let delay = match &res {
+                Ok(res) if idempotent && self.is_retryable_status(res.status()) => {
+                    match retry_after(res) {
+                        Some(wait) if wait > MAX_RETRY_AFTER => None,
+                        Some(wait) => Some(wait),
+                        None => Some(self.backoff(attempt)),
+                    }
+                }
+                Err(Error::Reqwest(err))
+                    if err.is_connect() || (idempotent && err.is_timeout()) =>
+                {
+                    Some(self.backoff(attempt))
+                }
+                _ => None,
+            };
+            let Some(delay) = delay else {
+                return res;
+            };
+            warn!(
+                url = %redact_url(req.url()),
+                attempt = attempt + 1,
+                max_retries = self.max_retries,
+                delay = ?delay,
+                status = ?res.as_ref().ok().map(|res| res.status()),
+                "transient upstream failure, retrying"
+            );
+            drop(res);
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+
+    use super::*;
+
+    /// Server answering 429 to the first `throttled` requests, returns its url
+    /// and the number of requests it got
+    fn throttling_server(throttled: usize) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let make_svc = make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let hit = counter.fetch_add(1, Ordering::SeqCst);
+                    let status = if hit < throttled {
+                        StatusCode::TOO_MANY_REQUESTS
+                    } else {
+                        StatusCode::OK
+                    };
+                    async move {
+                        Ok::<_, Infallible>(
+                            hyper::Response::builder()
+                                .status(status)
+                                .body(hyper::Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_are_retried_up_to_max_retries() {
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(RetryMiddleware::new(2, vec![StatusCode::TOO_MANY_REQUESTS]))
+            .build();
+
+        let (url, hits) = throttling_server(2);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        let (url, hits) = throttling_server(usize::MAX);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+}
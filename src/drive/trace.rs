@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next, Result};
+use task_local_extensions::Extensions;
+use tracing::debug;
+
+/// Middleware logging a summary of every upstream call.
+///
+/// Headers are never logged and query strings are reduced to their parameter
+/// names, so neither tokens nor url signatures end up in the logs.
+#[derive(Debug, Default)]
+pub struct TraceApiCalls {
+    next_id: AtomicU64,
+}
+
+#[async_trait::async_trait]
+impl Middleware for TraceApiCalls {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let method = req.method().clone();
+        let url = redact_url(req.url());
+        let request_bytes = req
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|body| body.len());
+        let started = Instant::now();
+        let res = next.run(req, extensions).await;
+        let elapsed = started.elapsed();
+        match &res {
+            Ok(response) => debug!(
+                id = id,
+                method = %method,
+                url = %url,
+                status = response.status().as_u16(),
+                elapsed = ?elapsed,
+                request_bytes = ?request_bytes,
+                response_bytes = ?response.content_length(),
+                "api call"
+            ),
+            Err(err) => debug!(
+                id = id,
+                method = %method,
+                url = %url,
+                elapsed = ?elapsed,
+                request_bytes = ?request_bytes,
+                error = %redact_error(err),
+                "api call failed"
+            ),
+        }
+        res
+    }
+}
+
+/// Error message with the url reqwest errors carry redacted
+fn redact_error(err: &reqwest_middleware::Error) -> String {
+    let message = err.to_string();
+    match err {
+        reqwest_middleware::Error::Reqwest(err) => match err.url() {
+            Some(url) => message.replace(url.as_str(), &redact_url(url)),
+            None => message,
+        },
+        reqwest_middleware::Error::Middleware(_) => message,
+    }
+}
+
+/// Url without query parameter values
+pub(super) fn redact_url(url: &reqwest::Url) -> String {
+    let mut redacted = format!(
+        "{}://{}{}",
+        url.scheme(),
+        url.host_str().unwrap_or_default(),
+        url.path()
+    );
+    let names: Vec<_> = url
+        .query_pairs()
+        .map(|(name, _)| name.into_owned())
+        .collect();
+    if !names.is_empty() {
+        redacted.push('?');
+        redacted.push_str(&names.join("=<redacted>&"));
+        redacted.push_str("=<redacted>");
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values_are_redacted() {
+        let url =
+            "https://cn-beijing-data.aliyundrive.net/file?x-oss-expires=1&x-oss-signature=secret"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            redact_url(&url),
+            "https://cn-beijing-data.aliyundrive.net/file?x-oss-expires=<redacted>&x-oss-signature=<redacted>"
+        );
+    }
+
+    #[tokio::test]
+    async fn error_urls_are_redacted() {
+        // nothing listens on port 1, the connection is refused
+        let err = reqwest::get("http://127.0.0.1:1/file?x-oss-signature=secret")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("secret"));
+        let message = redact_error(&err.into());
+        assert!(!message.contains("secret"), "{}", message);
+        assert!(
+            message.contains("x-oss-signature=<redacted>"),
+            "{}",
+            message
+        );
+    }
+}
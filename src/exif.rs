@@ -0,0 +1,128 @@
+use std::io::{self, Read, Write};
+
+/// Image formats we know how to strip metadata from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    Jpeg,
+    Png,
+}
+
+impl ImageKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let ext = name.rsplit_once('.')?.1.to_ascii_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" | "jpe" | "jfif" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            _ => None,
+        }
+    }
+}
+
+/// Copy an image from `src` to `dst` while dropping its metadata,
+/// only the segments/chunks needed to render it are kept.
+pub fn strip_metadata<R: Read, W: Write>(kind: ImageKind, src: R, dst: W) -> io::Result<()> {
+    match kind {
+        ImageKind::Jpeg => strip_jpeg(src, dst),
+        ImageKind::Png => strip_png(src, dst),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn copy_exact<R: Read, W: Write>(src: &mut R, dst: &mut W, len: u64) -> io::Result<()> {
+    let copied = io::copy(&mut src.take(len), dst)?;
+    if copied != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn skip_exact<R: Read>(src: &mut R, len: u64) -> io::Result<()> {
+    copy_exact(src, &mut io::sink(), len)
+}
+
+fn strip_jpeg<R: Read, W: Write>(mut src: R, mut dst: W) -> io::Result<()> {
+    const SOI: u8 = 0xD8;
+    const SOS: u8 = 0xDA;
+    const APP1: u8 = 0xE1; // EXIF, XMP
+    const APP13: u8 = 0xED; // IPTC, Photoshop
+    const COM: u8 = 0xFE;
+
+    let mut header = [0u8; 2];
+    src.read_exact(&mut header)?;
+    if header != [0xFF, SOI] {
+        return Err(invalid_data("not a JPEG file"));
+    }
+    dst.write_all(&header)?;
+    loop {
+        let mut byte = [0u8; 1];
+        src.read_exact(&mut byte)?;
+        if byte[0] != 0xFF {
+            return Err(invalid_data("invalid JPEG marker"));
+        }
+        // skip fill bytes
+        let mut marker = 0xFF;
+        while marker == 0xFF {
+            src.read_exact(&mut byte)?;
+            marker = byte[0];
+        }
+        if marker == SOS {
+            // entropy coded data follows, copy the rest verbatim
+            dst.write_all(&[0xFF, marker])?;
+            io::copy(&mut src, &mut dst)?;
+            break;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            // standalone markers without payload
+            dst.write_all(&[0xFF, marker])?;
+            continue;
+        }
+        let mut len_buf = [0u8; 2];
+        src.read_exact(&mut len_buf)?;
+        let len = u16::from_be_bytes(len_buf);
+        if len < 2 {
+            return Err(invalid_data("invalid JPEG segment length"));
+        }
+        let payload_len = u64::from(len - 2);
+        if matches!(marker, APP1 | APP13 | COM) {
+            skip_exact(&mut src, payload_len)?;
+        } else {
+            dst.write_all(&[0xFF, marker])?;
+            dst.write_all(&len_buf)?;
+            copy_exact(&mut src, &mut dst, payload_len)?;
+        }
+    }
+    dst.flush()
+}
+
+fn strip_png<R: Read, W: Write>(mut src: R, mut dst: W) -> io::Result<()> {
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    const METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+    let mut signature = [0u8; 8];
+    src.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(invalid_data("not a PNG file"));
+    }
+    dst.write_all(&signature)?;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        src.read_exact(&mut chunk_header)?;
+        let len = u32::from_be_bytes(chunk_header[..4].try_into().unwrap());
+        let chunk_type: [u8; 4] = chunk_header[4..].try_into().unwrap();
+        // chunk data followed by 4 bytes CRC
+        let total = u64::from(len) + 4;
+        if METADATA_CHUNKS.contains(&&chunk_type) {
+            skip_exact(&mut src, total)?;
+        } else {
+            dst.write_all(&chunk_header)?;
+            copy_exact(&mut src, &mut dst, total)?;
+        }
+        if &chunk_type == b"IEND" {
+            break;
+        }
+    }
+    dst.flush()
+}
@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::drive::DriveBackend;
+
+/// Tracks upstream write failures to degrade to read-only mode when writes
+/// keep failing, while still letting reads through.
+///
+/// Once degraded, a single write is let through every `probe_interval`
+/// to find out whether writes work again.
+#[derive(Debug)]
+pub struct WriteHealth {
+    threshold: u32,
+    probe_interval: Duration,
+    failures: AtomicU32,
+    /// Time of degradation or of the last probe while degraded
+    degraded: Mutex<Option<Instant>>,
+}
+
+impl WriteHealth {
+    pub fn new(threshold: u32, probe_interval: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            probe_interval,
+            failures: AtomicU32::new(0),
+            degraded: Mutex::new(None),
+        }
+    }
+
+    /// Whether writes are degraded to read-only mode, probes aside
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.lock().unwrap().is_some()
+    }
+
+    /// Whether a write should be rejected right now
+    pub fn should_reject(&self) -> bool {
+        let mut degraded = self.degraded.lock().unwrap();
+        match *degraded {
+            None => false,
+            Some(since) if since.elapsed() >= self.probe_interval => {
+                info!("read-only mode: probing whether writes work again");
+                *degraded = Some(Instant::now());
+                false
+            }
+            Some(_) => true,
+        }
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        if self.degraded.lock().unwrap().take().is_some() {
+            info!("write succeeded, leaving read-only mode");
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.threshold {
+            return;
+        }
+        let mut degraded = self.degraded.lock().unwrap();
+        if degraded.is_none() {
+            warn!(
+                failures = failures,
+                probe_interval = ?self.probe_interval,
+                "writes keep failing, switching to read-only mode"
+            );
+        }
+        *degraded = Some(Instant::now());
+    }
+}
+
+/// Upstream availability as seen by a periodic probe
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamHealth {
+    down: Arc<AtomicBool>,
+}
+
+impl UpstreamHealth {
+    pub fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    fn set_down(&self, down: bool) {
+        if self.down.swap(down, Ordering::Relaxed) != down {
+            if down {
+                warn!("upstream is unavailable");
+            } else {
+                info!("upstream is available again");
+            }
+        }
+    }
+
+    /// Probe the upstream every `interval` in the background
+    pub fn spawn_probe(&self, drive: impl DriveBackend + 'static, interval: Duration) {
+        let health = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match drive.get_quota().await {
+                    Ok(_) => health.set_down(false),
+                    Err(err) => {
+                        debug!(error = %err, "upstream health probe failed");
+                        health.set_down(true);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Whether the last access token refresh succeeded
+#[derive(Debug, Clone, Default)]
+pub struct TokenHealth {
+    valid: Arc<AtomicBool>,
+}
+
+impl TokenHealth {
+    pub fn is_valid(&self) -> bool {
+        self.valid.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_valid(&self, valid: bool) {
+        self.valid.store(valid, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Names of files and folders hidden from clients, e.g. `.DS_Store` or
+/// `@eaDir`, matched against each path component
+#[derive(Debug, Clone, Default)]
+pub struct HidePatterns {
+    globs: Option<GlobSet>,
+}
+
+impl HidePatterns {
+    pub fn new(patterns: &[String], case_insensitive: bool) -> Result<Self> {
+        if patterns.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .case_insensitive(case_insensitive)
+                .build()
+                .with_context(|| format!("invalid hide pattern `{}`", pattern))?;
+            builder.add(glob);
+        }
+        Ok(Self {
+            globs: Some(builder.build()?),
+        })
+    }
+
+    /// Whether an entry with this name is hidden
+    pub fn matches(&self, name: &str) -> bool {
+        self.globs
+            .as_ref()
+            .map(|globs| globs.is_match(name))
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use dav_server::{
+    davpath::DavPath,
+    ls::{DavLock, DavLockSystem},
+};
+use tracing::debug;
+use xmltree::Element;
+
+/// Lock system wrapper that never hands out perpetual locks.
+///
+/// `Timeout: Infinite` (RFC 4918 section 10.7) as well as a missing `Timeout`
+/// header are clamped to `max_timeout`, so is any finite timeout larger than it.
+/// The effective timeout is stored in the returned lock and thus reported back
+/// to the client in the LOCK response.
+#[derive(Debug, Clone)]
+pub struct TimeoutLs {
+    inner: Box<dyn DavLockSystem>,
+    max_timeout: Duration,
+}
+
+impl TimeoutLs {
+    pub fn new(inner: Box<dyn DavLockSystem>, max_timeout: Duration) -> Box<Self> {
+        Box::new(Self { inner, max_timeout })
+    }
+
+    fn clamp(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let effective = match timeout {
+            Some(timeout) => timeout.min(self.max_timeout),
+            None => self.max_timeout,
+        };
+        if timeout != Some(effective) {
+            debug!(requested = ?timeout, effective = ?effective, "lock: clamp timeout");
+        }
+        Some(effective)
+    }
+}
+
+impl DavLockSystem for TimeoutLs {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> Result<DavLock, DavLock> {
+        let timeout = self.clamp(timeout);
+        self.inner
+            .lock(path, principal, owner, timeout, shared, deep)
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> Result<(), ()> {
+        self.inner.unlock(path, token)
+    }
+
+    fn refresh(
+        &self,
+        path: &DavPath,
+        token: &str,
+        timeout: Option<Duration>,
+    ) -> Result<DavLock, ()> {
+        let timeout = self.clamp(timeout);
+        self.inner.refresh(path, token, timeout)
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> Result<(), DavLock> {
+        self.inner
+            .check(path, principal, ignore_principal, deep, submitted_tokens)
+    }
+
+    fn discover(&self, path: &DavPath) -> Vec<DavLock> {
+        self.inner.discover(path)
+    }
+
+    fn delete(&self, path: &DavPath) -> Result<(), ()> {
+        self.inner.delete(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dav_server::{memls::MemLs, DavHandler};
+    use hyper::{Body, Request, StatusCode};
+
+    use super::*;
+    use crate::drive::mock::MockDrive;
+    use crate::vfs::AliyunDriveFileSystem;
+
+    #[tokio::test]
+    async fn infinite_locks_get_the_max_timeout() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let fs =
+            AliyunDriveFileSystem::new(drive, "/".to_string(), 1000, 600, 0, Vec::new()).unwrap();
+        let handler = DavHandler::builder()
+            .filesystem(Box::new(fs))
+            .locksystem(TimeoutLs::new(MemLs::new(), Duration::from_secs(300)))
+            .build_handler();
+        let lock = r#"<?xml version="1.0" encoding="utf-8"?><D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"#;
+
+        for timeout in ["Infinite", "Second-86400"] {
+            let req = Request::builder()
+                .method("LOCK")
+                .uri("/a.txt")
+                .header("Timeout", timeout)
+                .body(Body::from(lock))
+                .unwrap();
+            let res = handler.handle(req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let body = String::from_utf8_lossy(&body);
+            assert!(body.contains("Second-300"), "{}", body);
+            assert!(!body.contains("Infinite"), "{}", body);
+            // unlock so the next lock is granted
+            let token = body
+                .split("<D:href>")
+                .nth(1)
+                .and_then(|rest| rest.split('<').next())
+                .unwrap();
+            let req = Request::builder()
+                .method("UNLOCK")
+                .uri("/a.txt")
+                .header("Lock-Token", format!("<{}>", token))
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(handler.handle(req).await.status(), StatusCode::NO_CONTENT);
+        }
+    }
+}
@@ -1,25 +1,72 @@
 use std::env;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::bail;
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
 use dav_server::{memls::MemLs, DavHandler};
 #[cfg(unix)]
 use futures_util::stream::StreamExt;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
 use self_update::cargo_crate_version;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
+use url::Url;
 #[cfg(unix)]
 use {signal_hook::consts::signal::*, signal_hook_tokio::Signals};
 
-use cache::Cache;
-use drive::{read_refresh_token, AliyunDrive, DriveConfig, DriveType};
-use vfs::AliyunDriveFileSystem;
-use webdav::WebDavServer;
+use audit::AuditLog;
+use auth::AuthScheme;
+#[cfg(feature = "redis")]
+use cache::RedisConnection;
+use cache::{Cache, CacheBackend, TtlOverride};
+use cors::Cors;
+use disk_cache::DiskCache;
+use drive::{
+    check_proxy, read_refresh_token, AliyunDrive, CircuitBreaker, DriveConfig, DriveType,
+    PartialListingPolicy,
+};
+use health::UpstreamHealth;
+use hidden::HidePatterns;
+use lock::TimeoutLs;
+use net::IpNet;
+use overlay::{Overlay, OverlayFile};
+use protected::ProtectedPaths;
+use quirks::QuirkRule;
+use reload::Reloader;
+use size::{parse_size, parse_size_in};
+use static_files::StaticFiles;
+use tls::{TlsCert, TlsCerts};
+use vfs::{AliyunDriveFileSystem, QuotaReserve};
+use webdav::{
+    AliyunDriveWebDav, AuthUser, ExtraHeader, OutOfRootStatus, PropfindMode, SymlinkPolicy,
+    WebDavServer,
+};
 
+mod audit;
+mod auth;
 mod cache;
+mod cors;
+mod disk_cache;
 mod drive;
+mod exif;
+mod health;
+mod hidden;
+mod lock;
 mod login;
+mod metrics;
+mod net;
+mod overlay;
+mod protected;
+mod quirks;
+mod reload;
+mod size;
+mod spool;
+mod static_files;
+mod tls;
 mod vfs;
 mod webdav;
 
@@ -33,6 +80,9 @@ struct Opt {
     /// Listen port
     #[arg(short, env = "PORT", long, default_value = "8080")]
     port: u16,
+    /// Listen socket accept backlog, defaults to the OS default
+    #[arg(long)]
+    listen_backlog: Option<u32>,
     /// Aliyun drive client_id
     #[arg(long, env = "CLIENT_ID")]
     client_id: Option<String>,
@@ -42,30 +92,99 @@ struct Opt {
     /// Aliyun drive type
     #[arg(long, env = "DRIVE_TYPE")]
     drive_type: Option<DriveType>,
+    /// Aliyun drive id, overrides `--drive-type`
+    #[arg(long, env = "DRIVE_ID", conflicts_with = "drive_type")]
+    drive_id: Option<String>,
     /// Aliyun drive refresh token
     #[arg(short, long, env = "REFRESH_TOKEN")]
     refresh_token: Option<String>,
+    /// Read the refresh token from the first line of stdin, takes precedence over `--refresh-token`
+    #[arg(long)]
+    refresh_token_stdin: bool,
+    /// File holding the refresh token, re-read whenever it changes so tokens can be rotated
+    /// without a restart, takes precedence over `--refresh-token`
+    #[arg(
+        long,
+        env = "REFRESH_TOKEN_FILE",
+        conflicts_with = "refresh_token_stdin"
+    )]
+    refresh_token_file: Option<PathBuf>,
+    /// Seconds before its expiry the access token is refreshed, an access token refused
+    /// with 401 is refreshed right away regardless
+    #[arg(long, default_value = "300")]
+    token_refresh_margin: u64,
     /// WebDAV authentication username
     #[arg(short = 'U', long, env = "WEBDAV_AUTH_USER")]
     auth_user: Option<String>,
     /// WebDAV authentication password
     #[arg(short = 'W', long, env = "WEBDAV_AUTH_PASSWORD")]
     auth_password: Option<String>,
+    /// Additional WebDAV users confined to a directory, as `user:password:/root`, comma separated
+    #[arg(long, env = "WEBDAV_AUTH_USERS", value_delimiter = ',')]
+    auth_users: Vec<AuthUser>,
+    /// Command verifying Basic credentials of other users, run with the user name in
+    /// `WEBDAV_AUTH_USER` and `user` and `password` lines on stdin, exit code 0 accepts them
+    #[arg(long, env = "WEBDAV_AUTH_COMMAND")]
+    auth_command: Option<String>,
+    /// HTTP authentication scheme, digest avoids sending passwords without TLS
+    #[arg(long, value_enum, default_value = "basic")]
+    auth_scheme: AuthScheme,
     /// Automatically generate index.html
     #[arg(short = 'I', long)]
     auto_index: bool,
-    /// Read/download buffer size in bytes, defaults to 10MB
-    #[arg(short = 'S', long, default_value = "10485760")]
+    /// Read/download buffer size in bytes or with a K, M or G suffix, between 64K and 1G
+    #[arg(short = 'S', long, default_value = "10M", value_parser = parse_buffer_size)]
     read_buffer_size: usize,
-    /// Upload buffer size in bytes, defaults to 16MB
-    #[arg(long, default_value = "16777216")]
+    /// Upload buffer size in bytes or with a K, M or G suffix, between 64K and 1G
+    #[arg(long, default_value = "16M", value_parser = parse_buffer_size)]
     upload_buffer_size: usize,
+    /// Upload files larger than this size in parts of the upload buffer
+    /// size, smaller files in a single part
+    #[arg(long, default_value = "16M", value_parser = parse_size)]
+    multipart_threshold: u64,
+    /// Parts of a file uploaded in parallel, each one takes an upload buffer of memory,
+    /// parts of all uploads in flight are bounded by `--upstream-concurrency`
+    #[arg(long, default_value = "1")]
+    upload_concurrency: usize,
+    /// Reject uploads larger than this size, e.g. `4G`, with 413 Payload Too Large
+    #[arg(long, value_parser = parse_size)]
+    max_upload_size: Option<u64>,
     /// Directory entries cache size
     #[arg(long, default_value = "1000")]
     cache_size: u64,
     /// Directory entries cache expiration time in seconds
     #[arg(long, default_value = "600")]
     cache_ttl: u64,
+    /// Randomly vary the directory cache expiration time by up to this many seconds
+    #[arg(long, default_value = "0")]
+    cache_ttl_jitter: u64,
+    /// Serve directory listings up to this many seconds past their expiry, with
+    /// `Warning: 110`, when refreshing them fails because of upstream errors
+    #[arg(long, value_name = "SECONDS")]
+    serve_stale_on_error: Option<u64>,
+    /// Directory cache expiration time in seconds for a path and everything below it,
+    /// as `path=seconds`, can be repeated, the longest matching path wins
+    #[arg(long = "cache-ttl-override", value_name = "PATH=SECONDS")]
+    cache_ttl_overrides: Vec<TtlOverride>,
+    /// Where directory entries are cached, redis shares them between instances
+    #[arg(long, value_enum, default_value = "memory")]
+    cache_backend: CacheBackend,
+    /// Redis server of `--cache-backend redis`, e.g. `redis://127.0.0.1:6379/0`
+    #[arg(long, env = "REDIS_URL", required_if_eq("cache_backend", "redis"))]
+    redis_url: Option<String>,
+    /// Drop cached download urls of files not read for this many seconds, even before they expire
+    #[arg(long)]
+    download_url_idle_ttl: Option<u64>,
+    /// Directory to list and cache at startup, can be repeated
+    #[arg(long)]
+    prewarm_path: Vec<String>,
+    /// List and cache the root directory in the background at startup,
+    /// like `--prewarm-path /`
+    #[arg(long)]
+    warm_cache: bool,
+    /// Also prewarm subdirectories up to this many levels below each prewarm path
+    #[arg(long, default_value = "0")]
+    prewarm_depth: usize,
     /// Root directory path
     #[arg(long, env = "WEBDAV_ROOT", default_value = "/")]
     root: String,
@@ -75,21 +194,63 @@ struct Opt {
     /// Delete file permanently instead of trashing it
     #[arg(long)]
     no_trash: bool,
+    /// Entries listed at most per collection in PROPFIND responses, truncated
+    /// listings carry a `Warning` header
+    #[arg(long)]
+    max_propfind_results: Option<usize>,
+    /// Longest file name accepted for uploads and new folders in UTF-8 bytes, as limited
+    /// by Aliyun, 0 disables the check
+    #[arg(long, default_value = "1024")]
+    max_filename_bytes: usize,
+    /// Free space to keep on the drive as a size, e.g. `5G`, or percent of the total, e.g. `10%`,
+    /// uploads that would eat into it are rejected
+    #[arg(long)]
+    quota_reserve: Option<QuotaReserve>,
     /// Enable read only mode
     #[arg(long)]
     read_only: bool,
-    /// TLS certificate file path
+    /// The refresh token only grants read access, never attempt writes
+    #[arg(long)]
+    assume_read_only_token: bool,
+    /// Switch to read only mode after this many consecutive upstream write failures
+    #[arg(long, value_name = "FAILURES")]
+    auto_readonly_on_write_failure: Option<u32>,
+    /// Seconds between probing writes while automatically switched to read only mode
+    #[arg(long, default_value = "60")]
+    write_probe_interval: u64,
+    /// TLS certificate file path, `host=path` for the certificate served to
+    /// clients asking for that SNI host name, can be repeated
     #[arg(long, env = "TLS_CERT")]
-    tls_cert: Option<PathBuf>,
-    /// TLS private key file path
+    tls_cert: Vec<String>,
+    /// TLS private key file path, `host=path` for the key of the certificate
+    /// of that host name, can be repeated
     #[arg(long, env = "TLS_KEY")]
-    tls_key: Option<PathBuf>,
+    tls_key: Vec<String>,
     /// Prefix to be stripped off when handling request.
     #[arg(long, env = "WEBDAV_STRIP_PREFIX")]
     strip_prefix: Option<String>,
+    /// Serve WebDAV under this path prefix, e.g. `/dav`, while control endpoints stay at the root
+    #[arg(long, env = "WEBDAV_DAV_PREFIX", conflicts_with = "strip_prefix", value_parser = parse_dav_prefix)]
+    dav_prefix: Option<String>,
+    /// Trailing path component to be stripped off when handling request, e.g. `@dav`
+    #[arg(long, env = "WEBDAV_STRIP_SUFFIX")]
+    strip_suffix: Option<String>,
     /// Enable debug log
     #[arg(long)]
     debug: bool,
+    /// Log output format, `json` also logs every request
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+    /// Append a JSON line for every write, delete, move, copy and folder creation
+    /// to this file, reopened on SIGHUP for rotation
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+    /// Log a summary of every Aliyun API call at debug level
+    #[arg(long)]
+    trace_api_calls: bool,
+    /// Include recycle bin items in listings, suffixed with ` (trashed)`, to debug deleted files still showing up
+    #[arg(long)]
+    listing_include_trashed: bool,
     /// Disable self auto upgrade
     #[arg(long)]
     no_self_upgrade: bool,
@@ -99,19 +260,281 @@ struct Opt {
     /// Prefer downloading using HTTP protocol
     #[arg(long)]
     prefer_http_download: bool,
+    /// Prefer Alibaba Cloud intranet download endpoints, falls back to public ones
+    #[arg(long)]
+    download_url_internal: bool,
     /// Enable 302 redirect when possible
     #[arg(long)]
     redirect: bool,
+    /// Strip EXIF and other metadata from uploaded JPEG and PNG images
+    #[arg(long)]
+    strip_exif: bool,
+    /// Try rapid upload, uploads are spooled to disk to compute their content hash first
+    #[arg(long)]
+    rapid_upload: bool,
+    /// Upload under a hidden `.partial-` name and rename it once complete, so that
+    /// listings never show half written files and overwritten files stay until then
+    #[arg(long)]
+    staged_upload: bool,
+    /// Directory used to spool uploads on disk, defaults to the system temporary directory
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+    /// Number of times to resume a download after the connection was reset mid-stream
+    #[arg(long, default_value = "0")]
+    retry_download_on_reset: u32,
+    /// Maximum number of upstream retries while serving a single file read, unlimited by default
+    #[arg(long)]
+    read_retry_budget: Option<u32>,
+    /// Client quirks as `User-Agent substring=quirk,...`, can be repeated.
+    ///
+    /// Quirks are brief, no-range, no-redirect and ms-author-via,
+    /// a rule replaces the built-in rule for the same substring.
+    #[arg(long = "quirk", value_name = "USER_AGENT=QUIRKS")]
+    quirks: Vec<QuirkRule>,
+    /// Disable the built-in quirks for known clients
+    #[arg(long)]
+    no_builtin_quirks: bool,
+    /// Serve image thumbnails generated by Aliyun for `GET /image.jpg?thumbnail=<width>`
+    #[arg(long)]
+    enable_thumbnails: bool,
+    /// What to do with symbolic links uploaded by sync tools
+    #[arg(long, value_enum, default_value = "store")]
+    symlink_policy: SymlinkPolicy,
+    /// Default verbosity of PROPFIND responses, minimal only returns core properties
+    /// unless specific ones are asked for, `Brief` and `Prefer` headers override it
+    #[arg(long, value_enum, default_value = "full")]
+    propfind_mode: PropfindMode,
+    /// Status of requests for paths outside of the served root
+    #[arg(long, value_enum, default_value = "404")]
+    out_of_root_status: OutOfRootStatus,
+    /// Reject uploads buffered in the spool directory with 507 when it would be
+    /// left with less than this much space free, e.g. `1G`
+    #[arg(long, value_parser = parse_size)]
+    min_spool_free: Option<u64>,
+    /// Whether a listing still throttled by Aliyun midway after `--max-retries` is served
+    /// partially or fails
+    #[arg(long, value_enum, default_value = "deny")]
+    partial_listing_on_throttle: PartialListingPolicy,
+    /// Seconds to wait for copies and uploads that Aliyun completes in the background
+    #[arg(long, default_value = "60")]
+    async_op_timeout: u64,
+    /// Consecutive upstream failures after which requests fail fast with 503, disabled by default
+    #[arg(long)]
+    circuit_breaker_threshold: Option<u32>,
+    /// Seconds the circuit breaker stays open before probing the upstream again
+    #[arg(long, default_value = "30")]
+    circuit_breaker_cooldown: u64,
+    /// Proxy of the connections to Aliyun, `http://`, `https://` or `socks5://` URL
+    #[arg(long, value_parser = parse_proxy)]
+    proxy: Option<Url>,
+    /// Connect to Aliyun directly, ignoring the proxy environment variables
+    #[arg(long, conflicts_with = "proxy")]
+    no_proxy: bool,
+    /// Aliyun API calls per second, calls beyond it wait for their turn, unlimited by default
+    #[arg(long)]
+    upstream_rate_limit: Option<f64>,
+    /// Aliyun API calls and file part uploads in flight at once, across all clients
+    #[arg(long, default_value = "10")]
+    upstream_concurrency: usize,
+    /// Retries of Aliyun requests failing with 429 or 5xx, with exponential backoff
+    #[arg(long, default_value = "3")]
+    max_retries: u32,
+    /// Upstream HTTP statuses that are retried, comma separated, network errors are always retried
+    #[arg(long, value_delimiter = ',', default_value = "429,500,502,503,504", value_parser = parse_status)]
+    retryable_status: Vec<StatusCode>,
+    /// User-Agent sent to Aliyun instead of the built-in browser one
+    #[arg(long, value_parser = parse_header_value)]
+    upstream_user_agent: Option<HeaderValue>,
+    /// Header added to every request to Aliyun, as `Name=Value`, can be repeated
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_upstream_header)]
+    upstream_header: Vec<(HeaderName, HeaderValue)>,
+    /// Read buffers downloaded concurrently ahead of sequential reads, bounding memory
+    /// to `--read-buffer-size` times this per reader, 1 disables prefetching
+    #[arg(long, default_value = "1")]
+    prefetch_chunks: usize,
+    /// Limit the download speed of each request to this many bytes per second,
+    /// e.g. `2M`
+    #[arg(long, value_parser = parse_size)]
+    download_rate_limit: Option<u64>,
+    /// Share one upstream download between concurrent reads of the same file range
+    #[arg(long)]
+    coalesce_reads: bool,
+    /// Answer MOVEs of files being downloaded with 423 Locked.
+    ///
+    /// Moves are allowed by default as downloads use their own urls and are
+    /// not affected. MOVEs of resources locked with LOCK always need the lock token.
+    #[arg(long)]
+    block_move_during_read: bool,
+    /// Seconds to wait on SIGTERM or SIGINT for open connections and uploads in progress
+    /// to complete after new connections and uploads are refused
+    #[arg(long, default_value = "30")]
+    shutdown_timeout: u64,
+    /// Keep waiting for uploads in progress up to `--shutdown-drain-timeout` on shutdown
+    #[arg(long)]
+    shutdown_drain_uploads: bool,
+    /// Seconds to wait for uploads in progress with `--shutdown-drain-uploads`
+    #[arg(long, default_value = "600")]
+    shutdown_drain_timeout: u64,
+    /// Expose read-only file version history in virtual `.versions` folders
+    #[arg(long)]
+    enable_versions: bool,
+    /// Use the content hash as ETag of files, ignoring size and modification
+    /// time, so that re-uploading the same content keeps the ETag
+    #[arg(long)]
+    file_id_stable_etag: bool,
+    /// Serve a read-only local file at a virtual path, e.g. `README.md=/etc/share/README.md`,
+    /// real files at the same path take precedence
+    #[arg(long = "overlay-file", value_name = "PATH=LOCALFILE")]
+    overlay_files: Vec<OverlayFile>,
+    /// Glob of files refused to be overwritten, deleted or moved, e.g. `/backup/*.key`
+    /// or `.env` at any depth, can be repeated
+    #[arg(long = "protected-path", value_name = "GLOB")]
+    protected_paths: Vec<String>,
+    /// Hide files and directories starting with a dot
+    #[arg(long)]
+    deny_hidden_files: bool,
+    /// Glob of names hidden from listings and answered with 404, e.g. `.DS_Store`
+    /// or `@eaDir`, can be repeated
+    #[arg(long = "hide-pattern", value_name = "GLOB")]
+    hide_patterns: Vec<String>,
+    /// Match `--hide-pattern` globs case-insensitively
+    #[arg(long)]
+    hide_pattern_ignore_case: bool,
+    /// Hide empty placeholder files left by other tools from listings
+    #[arg(long)]
+    hide_placeholders: bool,
+    /// Names of the placeholder files hidden by `--hide-placeholders`, comma separated
+    #[arg(
+        long = "placeholder-name",
+        value_delimiter = ',',
+        default_value = ".keep,.gitkeep,.placeholder,.emptyfolder"
+    )]
+    placeholder_names: Vec<String>,
+    /// Trusted proxy IP addresses or CIDR networks, comma separated
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Vec<IpNet>,
+    /// Host names served, comma separated, requests for other hosts get 421 Misdirected Request
+    #[arg(long = "allowed-host", env = "ALLOWED_HOSTS", value_delimiter = ',')]
+    allowed_hosts: Vec<String>,
+    /// Path readable without authentication, along with everything below it,
+    /// writes still require authentication
+    #[arg(long = "public-path", value_name = "PATH")]
+    public_paths: Vec<String>,
+    /// Allow GET, HEAD, OPTIONS and PROPFIND without authentication anywhere,
+    /// writes still require authentication
+    #[arg(long)]
+    allow_anonymous_read: bool,
+    /// Serve Prometheus metrics at this path without authentication, e.g. `/metrics`,
+    /// requires a build with the `metrics` feature
+    #[arg(long)]
+    metrics_path: Option<String>,
+    /// Path of the health check endpoint, answering 503 while the token refresh
+    /// fails or the upstream is unreachable, empty to disable
+    #[arg(long, default_value = "/healthz")]
+    health_path: String,
+    /// Icon served at `/favicon.ico` instead of the built-in one
+    #[arg(long)]
+    favicon: Option<PathBuf>,
+    /// File served at `/robots.txt` instead of the built-in one disallowing all crawlers
+    #[arg(long)]
+    robots: Option<PathBuf>,
+    /// Don't serve the built-in `/favicon.ico` and `/robots.txt`
+    #[arg(long)]
+    no_builtin_static: bool,
+    /// JSON file overriding auth_user, auth_password, allowed_hosts, quirks and
+    /// no_builtin_quirks, re-read on SIGUSR1 without dropping connections, other
+    /// keys are rejected
+    #[arg(long, value_name = "FILE")]
+    reload_config: Option<PathBuf>,
+    /// Header set by a trusted proxy with the authenticated user, e.g. `X-Remote-User`,
+    /// requests carrying it skip WebDAV authentication
+    #[arg(long, requires = "trusted_proxies")]
+    principal_header: Option<HeaderName>,
+    /// Maximum WebDAV lock timeout in seconds, `Timeout: Infinite` is clamped to it
+    #[arg(long, default_value = "3600")]
+    max_lock_timeout: u64,
+    /// Several instances serve the same drive, locks held in memory by one instance
+    /// are unknown to the others, so DAV class 2 (locking) isn't advertised
+    #[arg(long)]
+    multi_instance: bool,
+    /// Language reported as `getcontentlanguage` and `Content-Language`, e.g. `en`
+    #[arg(long)]
+    default_content_language: Option<String>,
+    /// Charset appended to text content types of downloads, empty to omit it
+    #[arg(long, default_value = "utf-8")]
+    text_charset: String,
+    /// HTML page served to browsers with status 503 while the upstream is unavailable
+    #[arg(long)]
+    maintenance_page: Option<PathBuf>,
+    /// Directory keeping copies of downloaded files and chunks, served on repeated reads and while the upstream is unreachable
+    #[arg(long)]
+    disk_cache_dir: Option<PathBuf>,
+    /// Maximum size of `--disk-cache-dir`
+    #[arg(long, default_value = "1G", value_parser = parse_size)]
+    disk_cache_size: u64,
+    /// Seconds between upstream health probes when `--maintenance-page` or `--disk-cache-dir` is set
+    #[arg(long, default_value = "30")]
+    upstream_probe_interval: u64,
+    /// Add a header to every response, e.g. `X-Frame-Options: DENY`, can be repeated
+    #[arg(long = "add-header", value_name = "NAME: VALUE")]
+    extra_headers: Vec<ExtraHeader>,
+    /// Allowed CORS origins, comma separated, `*` allows any origin
+    #[arg(long, env = "CORS_ORIGIN", value_delimiter = ',')]
+    cors_origin: Vec<String>,
+    /// Methods allowed in CORS requests, comma separated
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "GET,HEAD,PUT,DELETE,OPTIONS,PROPFIND,PROPPATCH,MKCOL,COPY,MOVE,LOCK,UNLOCK"
+    )]
+    cors_allow_methods: Vec<String>,
+    /// Headers allowed in CORS requests, comma separated, `*` allows any header
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "Authorization,Content-Type,Depth,Destination,Overwrite,If,Lock-Token,Timeout,Range"
+    )]
+    cors_allow_headers: Vec<String>,
+    /// How long in seconds browsers may cache CORS preflight results
+    #[arg(long, default_value = "600")]
+    cors_max_age: u64,
 
     #[command(subcommand)]
     subcommands: Option<Commands>,
 }
 
+/// Format of the log output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with the fields of the request span
+    Json,
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Scan QRCode
     #[command(subcommand)]
     Qr(QrCommand),
+    /// List drives available in the account
+    Drives {
+        /// Aliyun drive refresh token
+        #[arg(short, long, env = "REFRESH_TOKEN")]
+        refresh_token: Option<String>,
+    },
+    /// Permanently delete items in the recycle bin
+    EmptyTrash {
+        /// Aliyun drive refresh token
+        #[arg(short, long, env = "REFRESH_TOKEN")]
+        refresh_token: Option<String>,
+        /// Only purge items trashed longer ago than this, e.g. `30d`, `12h`
+        #[arg(long, value_parser = parse_age)]
+        older_than: Option<Duration>,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -142,30 +565,132 @@ async fn main() -> anyhow::Result<()> {
             env::set_var("RUST_LOG", "aliyundrive_webdav=info,reqwest=warn");
         }
     }
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
-        .with_timer(tracing_subscriber::fmt::time::time())
-        .init();
+        .with_timer(tracing_subscriber::fmt::time::time());
+    match opt.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).init(),
+    }
 
     let workdir = opt
         .workdir
         .or_else(|| dirs::cache_dir().map(|c| c.join("aliyundrive-webdav")));
+    if let Some(dir) = workdir.as_ref() {
+        prepare_workdir(dir)?;
+    }
     let refresh_token_host = if opt.client_id.is_none() || opt.client_secret.is_none() {
         env::var("ALIYUNDRIVE_OAUTH_SERVER")
             .unwrap_or_else(|_| "https://aliyundrive-oauth.messense.me".to_string())
     } else {
         "https://openapi.aliyundrive.com".to_string()
     };
+    let circuit_breaker = opt.circuit_breaker_threshold.map(|threshold| {
+        Arc::new(CircuitBreaker::new(
+            threshold,
+            Duration::from_secs(opt.circuit_breaker_cooldown),
+        ))
+    });
     let drive_config = DriveConfig {
         api_base_url: "https://openapi.aliyundrive.com".to_string(),
         refresh_token_host,
         workdir,
         client_id: opt.client_id.clone(),
         client_secret: opt.client_secret.clone(),
-        drive_type: opt.drive_type.clone(),
+        drive_type: opt.drive_type,
+        drive_id: opt.drive_id.clone(),
+        trace_api_calls: opt.trace_api_calls,
+        partial_listing: opt.partial_listing_on_throttle,
+        async_op_timeout: Duration::from_secs(opt.async_op_timeout),
+        circuit_breaker: circuit_breaker.clone(),
+        upstream_rate_limit: opt.upstream_rate_limit.filter(|rate| *rate > 0.0),
+        upstream_concurrency: opt.upstream_concurrency,
+        max_retries: opt.max_retries,
+        retryable_statuses: opt.retryable_status,
+        refresh_token_file: opt.refresh_token_file.clone(),
+        token_refresh_margin: Duration::from_secs(opt.token_refresh_margin),
+        user_agent: opt.upstream_user_agent.clone(),
+        extra_headers: opt.upstream_header.clone(),
+        proxy: opt.proxy.clone(),
+        no_proxy: opt.no_proxy,
+    };
+    if opt.listing_include_trashed {
+        warn!("listing recycle bin items alongside regular files, this is meant for troubleshooting only");
+    }
+    if let Some(proxy) = opt.proxy.as_ref() {
+        check_proxy(proxy).await;
+    }
+
+    // used by the subcommands as well, a token stored in the workdir is read by the drive
+    let cli_refresh_token = if let Some(path) = opt.refresh_token_file.as_ref() {
+        let token = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read refresh token file {}", path.display()))?;
+        Some(token.trim().to_string())
+    } else if opt.refresh_token_stdin {
+        Some(read_refresh_token_stdin()?)
+    } else {
+        opt.refresh_token.clone()
     };
 
     // subcommands
+    if let Some(Commands::Drives { refresh_token }) = opt.subcommands.as_ref() {
+        let refresh_token = refresh_token.clone().or_else(|| cli_refresh_token.clone());
+        let drive = AliyunDrive::new(drive_config, refresh_token.unwrap_or_default()).await?;
+        for (drive_type, drive_id) in drive.list_drives().await? {
+            println!("{}\t{}", drive_type, drive_id);
+        }
+        return Ok(());
+    }
+    if let Some(Commands::EmptyTrash {
+        refresh_token,
+        older_than,
+        yes,
+    }) = opt.subcommands.as_ref()
+    {
+        let refresh_token = refresh_token.clone().or_else(|| cli_refresh_token.clone());
+        let drive = AliyunDrive::new(drive_config, refresh_token.unwrap_or_default()).await?;
+        let now = SystemTime::now();
+        let items: Vec<_> = drive
+            .list_trash()
+            .await?
+            .into_iter()
+            .filter(|item| match older_than {
+                Some(age) => {
+                    let trashed_at = item.trashed_at.as_ref().unwrap_or(&item.updated_at);
+                    now.duration_since(**trashed_at).unwrap_or_default() >= *age
+                }
+                None => true,
+            })
+            .collect();
+        if items.is_empty() {
+            println!("Nothing to purge");
+            return Ok(());
+        }
+        if !yes {
+            print!(
+                "Permanently delete {} item(s) from the recycle bin? [y/N] ",
+                items.len()
+            );
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                println!("Aborted");
+                return Ok(());
+            }
+        }
+        let mut purged = 0;
+        for item in &items {
+            match drive.purge_trash(&item.id).await {
+                Ok(_) => purged += 1,
+                Err(err) => {
+                    warn!(file_id = %item.id, name = %item.name, error = %err, "purge trash failed")
+                }
+            }
+        }
+        println!("Purged {} item(s)", purged);
+        return Ok(());
+    }
     if let Some(Commands::Qr(qr)) = opt.subcommands.as_ref() {
         match qr {
             QrCommand::Login => {
@@ -206,11 +731,22 @@ async fn main() -> anyhow::Result<()> {
     {
         bail!("auth-user and auth-password must be specified together.");
     }
+    if opt.metrics_path.is_some() && cfg!(not(feature = "metrics")) {
+        bail!("Metrics are not supported in this build.");
+    }
+    if opt.auth_command.is_some() && opt.auth_scheme == AuthScheme::Digest {
+        bail!("auth-command needs the password, it requires the basic auth-scheme.");
+    }
+
+    if opt.multipart_threshold > opt.upload_buffer_size as u64 {
+        bail!("multipart-threshold must not be larger than upload-buffer-size.");
+    }
 
-    let tls_config = match (opt.tls_cert, opt.tls_key) {
-        (Some(cert), Some(key)) => Some((cert, key)),
-        (None, None) => None,
-        _ => bail!("tls-cert and tls-key must be specified together."),
+    let tls_certs = TlsCert::pair(opt.tls_cert, opt.tls_key)?;
+    let tls_config = if tls_certs.is_empty() {
+        None
+    } else {
+        Some(Arc::new(TlsCerts::load(tls_certs)?))
     };
 
     let refresh_token_from_file = if let Some(dir) = drive_config.workdir.as_ref() {
@@ -218,13 +754,13 @@ async fn main() -> anyhow::Result<()> {
     } else {
         None
     };
-    let refresh_token = if opt.refresh_token.is_none()
+    let refresh_token = if cli_refresh_token.is_none()
         && refresh_token_from_file.is_none()
         && atty::is(atty::Stream::Stdout)
     {
         login(drive_config.clone(), 30).await?
     } else {
-        let token = opt.refresh_token.unwrap_or_default();
+        let token = cli_refresh_token.unwrap_or_default();
         if !token.is_empty() && token.split('.').count() < 3 {
             bail!("Invalid refresh token value found in `--refresh-token` argument");
         }
@@ -232,24 +768,135 @@ async fn main() -> anyhow::Result<()> {
     };
 
     let drive = AliyunDrive::new(drive_config, refresh_token).await?;
-    let mut fs = AliyunDriveFileSystem::new(drive, opt.root, opt.cache_size, opt.cache_ttl)?;
+    let token_health = drive.token_health();
+    let upstream_health = if opt.maintenance_page.is_some() || opt.disk_cache_dir.is_some() {
+        let health = UpstreamHealth::default();
+        health.spawn_probe(
+            drive.clone(),
+            Duration::from_secs(opt.upstream_probe_interval.max(1)),
+        );
+        Some(health)
+    } else {
+        None
+    };
+    let maintenance_page = if let Some(path) = opt.maintenance_page.as_ref() {
+        let page = std::fs::read(path)
+            .with_context(|| format!("failed to read maintenance page {}", path.display()))?;
+        Some(page)
+    } else {
+        None
+    };
+    let disk_cache = opt
+        .disk_cache_dir
+        .clone()
+        .map(|dir| DiskCache::new(dir, opt.disk_cache_size));
+    let audit_log = opt.audit_log.clone().map(AuditLog::open).transpose()?;
+    #[cfg(feature = "redis")]
+    let drive_id = drive.drive_id()?.to_string();
+    let mut fs = AliyunDriveFileSystem::new(
+        drive,
+        opt.root,
+        opt.cache_size,
+        opt.cache_ttl,
+        opt.cache_ttl_jitter,
+        opt.cache_ttl_overrides,
+    )?;
+    match opt.cache_backend {
+        CacheBackend::Memory => {}
+        #[cfg(feature = "redis")]
+        CacheBackend::Redis => {
+            let url = opt.redis_url.as_deref().unwrap_or_default();
+            fs.set_redis_cache(RedisConnection::connect(url, &drive_id).await?);
+        }
+        #[cfg(not(feature = "redis"))]
+        CacheBackend::Redis => bail!("Redis cache is not supported in this build."),
+    }
     fs.set_no_trash(opt.no_trash)
-        .set_read_only(opt.read_only)
+        .set_read_only(opt.read_only || opt.assume_read_only_token)
+        .set_quota_reserve(opt.quota_reserve)
+        .set_auto_read_only(
+            opt.auto_readonly_on_write_failure,
+            Duration::from_secs(opt.write_probe_interval),
+        )
         .set_upload_buffer_size(opt.upload_buffer_size)
+        .set_multipart_threshold(opt.multipart_threshold)
+        .set_upload_concurrency(opt.upload_concurrency)
+        .set_max_upload_size(opt.max_upload_size)
+        .set_download_url_idle_ttl(opt.download_url_idle_ttl.map(Duration::from_secs))
         .set_skip_upload_same_size(opt.skip_upload_same_size)
-        .set_prefer_http_download(opt.prefer_http_download);
+        .set_prefer_http_download(opt.prefer_http_download)
+        .set_download_url_internal(opt.download_url_internal)
+        .set_strip_exif(opt.strip_exif)
+        .set_retry_download_on_reset(opt.retry_download_on_reset)
+        .set_read_retry_budget(opt.read_retry_budget)
+        .set_coalesce_reads(opt.coalesce_reads)
+        .set_prefetch_chunks(opt.prefetch_chunks)
+        .set_download_rate_limit(opt.download_rate_limit, opt.read_buffer_size)
+        .set_block_move_during_read(opt.block_move_during_read)
+        .set_rapid_upload(opt.rapid_upload)
+        .set_staged_upload(opt.staged_upload)
+        .set_min_spool_free(opt.min_spool_free)
+        .set_deny_hidden_files(opt.deny_hidden_files)
+        .set_hide_patterns(HidePatterns::new(
+            &opt.hide_patterns,
+            opt.hide_pattern_ignore_case,
+        )?)
+        .set_placeholder_names(if opt.hide_placeholders {
+            opt.placeholder_names
+        } else {
+            Vec::new()
+        })
+        .set_enable_versions(opt.enable_versions)
+        .set_content_hash_etag(opt.file_id_stable_etag)
+        .set_listing_include_trashed(opt.listing_include_trashed)
+        .set_max_filename_bytes(Some(opt.max_filename_bytes).filter(|max| *max > 0))
+        .set_overlay(Overlay::load(opt.overlay_files)?)
+        .set_protected_paths(ProtectedPaths::new(&opt.protected_paths)?)
+        .set_audit_log(audit_log.clone())
+        .set_serve_stale_on_error(opt.serve_stale_on_error.map(Duration::from_secs))
+        .set_content_language(opt.default_content_language.clone())
+        .set_disk_cache(disk_cache)
+        .set_upstream_health(upstream_health.clone());
+    if let Some(spool_dir) = opt.spool_dir {
+        fs.set_spool_dir(spool_dir);
+    }
     debug!("aliyundrive file system initialized");
+    let mut prewarm_paths = opt.prewarm_path.clone();
+    if opt.warm_cache {
+        prewarm_paths.push("/".to_string());
+    }
+    if opt.staged_upload {
+        let fs = fs.clone();
+        tokio::spawn(async move { fs.sweep_staged().await });
+    }
+    if !prewarm_paths.is_empty() {
+        let fs = fs.clone();
+        let paths = prewarm_paths;
+        let depth = opt.prewarm_depth;
+        tokio::spawn(async move { fs.prewarm(paths, depth).await });
+    }
 
     #[cfg(unix)]
     let dir_cache = fs.dir_cache.clone();
+    let shutdown_timeout = Duration::from_secs(opt.shutdown_timeout);
+    let shutdown = Shutdown {
+        fs: fs.clone(),
+        timeout: shutdown_timeout,
+        upload_timeout: if opt.shutdown_drain_uploads {
+            Duration::from_secs(opt.shutdown_drain_timeout).max(shutdown_timeout)
+        } else {
+            shutdown_timeout
+        },
+    };
 
+    let lock_system = TimeoutLs::new(MemLs::new(), Duration::from_secs(opt.max_lock_timeout));
     let mut dav_server_builder = DavHandler::builder()
-        .filesystem(Box::new(fs))
-        .locksystem(MemLs::new())
+        .filesystem(Box::new(fs.clone()))
+        .locksystem(lock_system.clone())
         .read_buf_size(opt.read_buffer_size)
         .autoindex(opt.auto_index)
         .redirect(opt.redirect);
-    if let Some(prefix) = opt.strip_prefix {
+    if let Some(prefix) = opt.dav_prefix.clone().or_else(|| opt.strip_prefix.clone()) {
         dav_server_builder = dav_server_builder.strip_prefix(prefix);
     }
 
@@ -260,25 +907,111 @@ async fn main() -> anyhow::Result<()> {
         "webdav handler initialized"
     );
 
+    let cors = if opt.cors_origin.is_empty() {
+        None
+    } else {
+        Some(Cors::new(
+            opt.cors_origin,
+            opt.cors_allow_methods,
+            opt.cors_allow_headers,
+            opt.cors_max_age,
+        )?)
+    };
+    let text_charset = if opt.text_charset.is_empty() {
+        None
+    } else {
+        Some(HeaderValue::from_str(&opt.text_charset).context("invalid `--text-charset`")?)
+    };
+    let content_language = opt
+        .default_content_language
+        .as_deref()
+        .map(HeaderValue::from_str)
+        .transpose()
+        .context("invalid `--default-content-language`")?;
+    let mut service = AliyunDriveWebDav::new(dav_server, fs);
+    service
+        .set_auth(auth_user, auth_password)
+        .set_auth_users(opt.auth_users)
+        .set_auth_command(opt.auth_command)
+        .set_auth_scheme(opt.auth_scheme)
+        .set_auto_index(opt.auto_index)
+        .set_strip_prefix(opt.strip_prefix)
+        .set_strip_suffix(opt.strip_suffix)
+        .set_dav_prefix(opt.dav_prefix)
+        .set_trusted_proxies(opt.trusted_proxies)
+        .set_allowed_hosts(opt.allowed_hosts)
+        .set_public_paths(opt.public_paths)
+        .set_allow_anonymous_read(opt.allow_anonymous_read)
+        .set_max_propfind_results(opt.max_propfind_results)
+        .set_principal_header(opt.principal_header)
+        .set_extra_headers(opt.extra_headers)
+        .set_cors(cors)
+        .set_text_charset(text_charset)
+        .set_content_language(content_language)
+        .set_lock_system(lock_system)
+        .set_max_lock_timeout(Duration::from_secs(opt.max_lock_timeout))
+        .set_circuit_breaker(circuit_breaker)
+        .set_metrics_path(opt.metrics_path)
+        .set_health_check(Some(opt.health_path), token_health, upstream_health.clone())
+        .set_static_files(StaticFiles::load(
+            opt.favicon.as_deref(),
+            opt.robots.as_deref(),
+            !opt.no_builtin_static,
+        )?)
+        .set_enable_thumbnails(opt.enable_thumbnails)
+        .set_symlink_policy(opt.symlink_policy)
+        .set_access_log(opt.log_format == LogFormat::Json)
+        .set_advertise_locking(!opt.multi_instance)
+        .set_out_of_root_status(opt.out_of_root_status)
+        .set_propfind_mode(opt.propfind_mode)
+        .set_quirks(opt.quirks, !opt.no_builtin_quirks);
+    let reloader = opt
+        .reload_config
+        .map(|path| Reloader::new(path, service.live_settings()));
+    if let Some(reloader) = reloader.as_ref() {
+        reloader.reload()?;
+    }
+    if let (Some(health), Some(page)) = (upstream_health, maintenance_page) {
+        service.set_maintenance_page(health, page.into());
+    }
     let server = WebDavServer {
         host: opt.host,
         port: opt.port,
-        auth_user,
-        auth_password,
-        tls_config,
-        handler: dav_server,
+        tls_config: tls_config.clone(),
+        listen_backlog: opt.listen_backlog,
+        service,
     };
 
     #[cfg(not(unix))]
-    server.serve().await?;
+    {
+        let _ = (shutdown, audit_log);
+        server.serve(std::future::pending()).await?;
+    }
 
     #[cfg(unix)]
     {
-        let signals = Signals::new([SIGHUP])?;
+        let mut signal_list = vec![SIGHUP, SIGTERM, SIGINT];
+        if reloader.is_some() {
+            signal_list.push(SIGUSR1);
+        }
+        let signals = Signals::new(signal_list)?;
         let handle = signals.handle();
-        let signals_task = tokio::spawn(handle_signals(signals, dir_cache));
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let signals_task = tokio::spawn(handle_signals(
+            signals,
+            dir_cache,
+            (shutdown, shutdown_tx),
+            reloader,
+            tls_config,
+            audit_log,
+        ));
 
-        server.serve().await?;
+        server
+            .serve(async {
+                let _ = shutdown_rx.await;
+            })
+            .await?;
+        info!("all connections closed, shutting down");
 
         // Terminate the signal stream.
         handle.close();
@@ -287,19 +1020,164 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Graceful shutdown settings
+#[cfg_attr(not(unix), allow(dead_code))]
+struct Shutdown {
+    fs: AliyunDriveFileSystem,
+    /// How long open connections are waited for
+    timeout: Duration,
+    /// How long uploads in progress are waited for, at least `timeout`
+    upload_timeout: Duration,
+}
+
 #[cfg(unix)]
-async fn handle_signals(mut signals: Signals, dir_cache: Cache) {
+async fn handle_signals(
+    mut signals: Signals,
+    dir_cache: Cache,
+    (shutdown, shutdown_tx): (Shutdown, tokio::sync::oneshot::Sender<()>),
+    reloader: Option<Reloader>,
+    tls_certs: Option<Arc<TlsCerts>>,
+    audit_log: Option<AuditLog>,
+) {
+    let mut shutdown_tx = Some(shutdown_tx);
     while let Some(signal) = signals.next().await {
         match signal {
             SIGHUP => {
-                dir_cache.invalidate_all();
+                dir_cache.invalidate_all().await;
                 info!("directory cache invalidated by SIGHUP");
+                if let Some(tls_certs) = tls_certs.as_ref() {
+                    tls_certs.reload();
+                }
+                if let Some(audit_log) = audit_log.as_ref() {
+                    audit_log.reopen();
+                }
+            }
+            SIGUSR1 => {
+                if let Some(reloader) = reloader.as_ref() {
+                    if let Err(err) = reloader.reload() {
+                        error!(error = %err, "config reload failed, keeping the current settings");
+                    }
+                }
+            }
+            SIGTERM | SIGINT => {
+                let Some(shutdown_tx) = shutdown_tx.take() else {
+                    warn!("shutting down without waiting for connections and uploads in progress");
+                    std::process::exit(1);
+                };
+                info!(timeout = ?shutdown.timeout, "shutting down, no longer accepting connections");
+                // new uploads on connections still open are refused as well
+                shutdown.fs.drain_uploads();
+                let _ = shutdown_tx.send(());
+                let fs = shutdown.fs.clone();
+                let (timeout, upload_timeout) = (shutdown.timeout, shutdown.upload_timeout);
+                tokio::spawn(async move { shutdown_watchdog(&fs, timeout, upload_timeout).await });
             }
             _ => unreachable!(),
         }
     }
 }
 
+/// Exit once connections still open after `timeout` are only downloads, or once
+/// the uploads in progress are still not finished after `upload_timeout`.
+///
+/// The server normally returns before, when the last connection is closed.
+#[cfg(unix)]
+async fn shutdown_watchdog(
+    fs: &AliyunDriveFileSystem,
+    timeout: Duration,
+    upload_timeout: Duration,
+) -> ! {
+    let start = tokio::time::Instant::now();
+    loop {
+        let uploads = fs.active_uploads();
+        if uploads.is_empty() && start.elapsed() >= timeout {
+            warn!("shutdown timed out, closing the remaining connections");
+            std::process::exit(0);
+        }
+        if start.elapsed() >= upload_timeout {
+            warn!(
+                remaining = uploads.len(),
+                "upload drain timed out, shutting down"
+            );
+            std::process::exit(1);
+        }
+        let deadline = if uploads.is_empty() {
+            debug!("shutting down, waiting for open connections");
+            timeout
+        } else {
+            info!(
+                remaining = uploads.len(),
+                "shutting down, waiting for uploads in progress"
+            );
+            upload_timeout
+        };
+        for upload in uploads {
+            let received = upload.received.load(std::sync::atomic::Ordering::Relaxed);
+            info!(
+                path = %upload.path.display(),
+                received = received,
+                size = ?upload.size,
+                "upload in progress"
+            );
+        }
+        let remaining = deadline.saturating_sub(start.elapsed());
+        tokio::time::sleep(Duration::from_secs(5).min(remaining)).await;
+    }
+}
+
+/// Read the refresh token from the first line of stdin
+fn read_refresh_token_stdin() -> anyhow::Result<String> {
+    read_refresh_token_line(io::stdin().lock())
+}
+
+fn read_refresh_token_line(mut input: impl io::BufRead) -> anyhow::Result<String> {
+    let mut line = String::new();
+    let n = input
+        .read_line(&mut line)
+        .context("failed to read the refresh token from stdin")?;
+    if n == 0 {
+        bail!("No refresh token on stdin, got end of input");
+    }
+    let token = line.trim();
+    if token.is_empty() {
+        bail!("Empty refresh token on stdin");
+    }
+    if token.split('.').count() < 3 {
+        bail!("Invalid refresh token value found on stdin");
+    }
+    Ok(token.to_string())
+}
+
+/// Create the working directory holding the refresh token, only accessible by
+/// the current user, and make sure it is writable
+fn prepare_workdir(dir: &Path) -> anyhow::Result<()> {
+    if !dir.exists() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create workdir {}", dir.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+                .with_context(|| format!("failed to set permissions of {}", dir.display()))?;
+        }
+        info!(workdir = %dir.display(), "workdir created");
+    } else {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                warn!(workdir = %dir.display(), mode = format!("{:o}", mode & 0o777), "workdir is accessible by other users");
+            }
+        }
+    }
+    let probe = dir.join(".write-test");
+    std::fs::write(&probe, b"")
+        .with_context(|| format!("workdir {} is not writable", dir.display()))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
 async fn login(drive_config: DriveConfig, timeout: u64) -> anyhow::Result<String> {
     const SLEEP: u64 = 3;
 
@@ -382,3 +1260,136 @@ fn check_for_update(show_output: bool) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+/// Parse an age like `90s`, `30m`, `12h`, `7d` or `2w`, plain numbers are seconds
+fn parse_age(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let num: u64 = num.parse().map_err(|_| format!("invalid age `{}`", s))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid age unit `{}`, expected s, m, h, d or w",
+                unit
+            ))
+        }
+    };
+    Ok(Duration::from_secs(num * secs))
+}
+
+/// Buffers are held in memory, too small ones make for many tiny requests
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    parse_size_in(s, 64 << 10, 1 << 30).map(|size| size as usize)
+}
+
+/// Normalize a path prefix to a leading slash and no trailing slash
+fn parse_dav_prefix(s: &str) -> Result<String, String> {
+    let prefix = s.trim().trim_matches('/');
+    if prefix.is_empty() {
+        return Err("dav prefix must not be empty or `/`".to_string());
+    }
+    Ok(format!("/{}", prefix))
+}
+
+fn parse_proxy(s: &str) -> Result<Url, String> {
+    let url = Url::parse(s).map_err(|err| format!("invalid proxy URL `{}`: {}", s, err))?;
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => Ok(url),
+        scheme => Err(format!("unsupported proxy scheme `{}`", scheme)),
+    }
+}
+
+fn parse_header_value(s: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(s).map_err(|_| format!("invalid header value `{}`", s))
+}
+
+fn parse_upstream_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid header `{}`, expected `Name=Value`", s))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name `{}`", name.trim()))?;
+    Ok((name, parse_header_value(value.trim())?))
+}
+
+fn parse_status(s: &str) -> Result<StatusCode, String> {
+    s.trim()
+        .parse::<u16>()
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| format!("invalid HTTP status `{}`", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dav_prefixes_are_normalized() {
+        assert_eq!(parse_dav_prefix("dav").unwrap(), "/dav");
+        assert_eq!(parse_dav_prefix("/dav/files/").unwrap(), "/dav/files");
+        assert!(parse_dav_prefix("/").is_err());
+        assert!(parse_dav_prefix("").is_err());
+    }
+
+    #[test]
+    fn missing_workdirs_are_created_private() {
+        let base = env::temp_dir().join(format!("workdir-test-{}", std::process::id()));
+        let dir = base.join("nested").join("workdir");
+        prepare_workdir(&dir).unwrap();
+        assert!(dir.is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        // below a file, so it can't be created
+        let file = base.join("file");
+        std::fs::write(&file, b"").unwrap();
+        let err = prepare_workdir(&file.join("workdir")).unwrap_err();
+        assert!(
+            err.to_string().contains("failed to create workdir"),
+            "{}",
+            err
+        );
+        #[cfg(unix)]
+        if unsafe { libc::geteuid() } != 0 {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o500)).unwrap();
+            let err = prepare_workdir(&dir).unwrap_err();
+            assert!(err.to_string().contains("not writable"), "{}", err);
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn refresh_token_is_read_from_the_first_line() {
+        let token = read_refresh_token_line(&b" a.b.c \nd.e.f\n"[..]).unwrap();
+        assert_eq!(token, "a.b.c");
+        let err = read_refresh_token_line(&b""[..]).unwrap_err();
+        assert!(err.to_string().contains("end of input"), "{}", err);
+        assert!(read_refresh_token_line(&b"\n"[..]).is_err());
+        assert!(read_refresh_token_line(&b"not-a-token\n"[..]).is_err());
+
+        let opt = Opt::try_parse_from(["aliyundrive-webdav", "--refresh-token-stdin"]).unwrap();
+        assert!(opt.refresh_token_stdin);
+        let args = [
+            "aliyundrive-webdav",
+            "--refresh-token-stdin",
+            "--refresh-token-file",
+            "token",
+        ];
+        assert!(Opt::try_parse_from(args).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::sync::OnceLock;
+
+    use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+    pub struct Metrics {
+        registry: Registry,
+        pub requests: IntCounterVec,
+        pub bytes_downloaded: IntCounter,
+        pub cache_hits: IntCounter,
+        pub cache_misses: IntCounter,
+        pub api_calls: IntCounter,
+        pub circuit_breaker_open: IntGauge,
+        pub write_degraded: IntGauge,
+    }
+
+    impl Metrics {
+        fn new() -> Self {
+            let registry = Registry::new();
+            let requests = IntCounterVec::new(
+                Opts::new("webdav_requests_total", "WebDAV requests by method"),
+                &["method"],
+            )
+            .unwrap();
+            let bytes_downloaded = IntCounter::new(
+                "webdav_downloaded_bytes_total",
+                "Bytes of file content served",
+            )
+            .unwrap();
+            let cache_hits = IntCounter::new(
+                "webdav_dir_cache_hits_total",
+                "Directory listings served from the cache",
+            )
+            .unwrap();
+            let cache_misses = IntCounter::new(
+                "webdav_dir_cache_misses_total",
+                "Directory listings not found in the cache",
+            )
+            .unwrap();
+            let api_calls =
+                IntCounter::new("aliyundrive_api_calls_total", "Aliyun API calls").unwrap();
+            let circuit_breaker_open = IntGauge::new(
+                "aliyundrive_circuit_breaker_open",
+                "Whether upstream requests currently fail fast",
+            )
+            .unwrap();
+            let write_degraded = IntGauge::new(
+                "webdav_write_degraded",
+                "Whether writes are rejected after repeated upstream write failures",
+            )
+            .unwrap();
+            registry.register(Box::new(requests.clone())).unwrap();
+            registry
+                .register(Box::new(bytes_downloaded.clone()))
+                .unwrap();
+            registry.register(Box::new(cache_hits.clone())).unwrap();
+            registry.register(Box::new(cache_misses.clone())).unwrap();
+            registry.register(Box::new(api_calls.clone())).unwrap();
+            registry
+                .register(Box::new(circuit_breaker_open.clone()))
+                .unwrap();
+            registry.register(Box::new(write_degraded.clone())).unwrap();
+            Self {
+                registry,
+                requests,
+                bytes_downloaded,
+                cache_hits,
+                cache_misses,
+                api_calls,
+                circuit_breaker_open,
+                write_degraded,
+            }
+        }
+
+        pub fn render(&self) -> String {
+            let mut buf = Vec::new();
+            TextEncoder::new()
+                .encode(&self.registry.gather(), &mut buf)
+                .unwrap();
+            String::from_utf8(buf).unwrap()
+        }
+    }
+
+    pub fn metrics() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(Metrics::new)
+    }
+}
+
+/// Methods counted under their own label, others are counted as `OTHER`
+#[cfg(feature = "metrics")]
+const METHODS: &[&str] = &[
+    "GET",
+    "HEAD",
+    "PUT",
+    "DELETE",
+    "OPTIONS",
+    "PROPFIND",
+    "PROPPATCH",
+    "MKCOL",
+    "COPY",
+    "MOVE",
+    "LOCK",
+    "UNLOCK",
+    "POST",
+];
+
+pub fn record_request(method: &str) {
+    #[cfg(feature = "metrics")]
+    {
+        let method = if METHODS.contains(&method) {
+            method
+        } else {
+            "OTHER"
+        };
+        imp::metrics().requests.with_label_values(&[method]).inc();
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = method;
+}
+
+pub fn record_bytes_downloaded(bytes: usize) {
+    #[cfg(feature = "metrics")]
+    imp::metrics().bytes_downloaded.inc_by(bytes as u64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = bytes;
+}
+
+pub fn record_cache_lookup(hit: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = imp::metrics();
+        if hit {
+            metrics.cache_hits.inc();
+        } else {
+            metrics.cache_misses.inc();
+        }
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = hit;
+}
+
+pub fn record_api_call() {
+    #[cfg(feature = "metrics")]
+    imp::metrics().api_calls.inc();
+}
+
+/// Metrics in the Prometheus text format, `None` without the `metrics` feature
+pub fn render(circuit_breaker_open: bool, write_degraded: bool) -> Option<String> {
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = imp::metrics();
+        metrics
+            .circuit_breaker_open
+            .set(i64::from(circuit_breaker_open));
+        metrics.write_degraded.set(i64::from(write_degraded));
+        Some(metrics.render())
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = (circuit_breaker_open, write_degraded);
+        None
+    }
+}
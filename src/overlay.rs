@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
+use dav_server::fs::{DavFile, DavMetaData, FsError, FsFuture};
+use futures_util::future::{ready, FutureExt};
+use tracing::debug;
+
+use crate::drive::{AliyunFile, DateTime, FileType};
+
+/// Local file served at a virtual path, parsed from `path=localfile`
+#[derive(Debug, Clone)]
+pub struct OverlayFile {
+    pub path: PathBuf,
+    pub local: PathBuf,
+}
+
+impl FromStr for OverlayFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, local) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid overlay file `{}`, expected `path=localfile`", s))?;
+        let path = path.trim().trim_matches('/');
+        if path.is_empty() || local.is_empty() {
+            return Err(format!(
+                "invalid overlay file `{}`, expected `path=localfile`",
+                s
+            ));
+        }
+        Ok(Self {
+            path: PathBuf::from(path),
+            local: PathBuf::from(local),
+        })
+    }
+}
+
+/// Read-only files kept in memory and injected into listings, keyed by
+/// their path relative to the served root. Real files at the same path
+/// take precedence.
+#[derive(Debug, Clone, Default)]
+pub struct Overlay {
+    files: HashMap<PathBuf, (AliyunFile, Bytes)>,
+}
+
+impl Overlay {
+    pub fn load(files: Vec<OverlayFile>) -> Result<Self> {
+        let mut overlay = HashMap::new();
+        for file in files {
+            let content = std::fs::read(&file.local)
+                .with_context(|| format!("failed to read overlay file {}", file.local.display()))?;
+            let mtime = std::fs::metadata(&file.local)
+                .and_then(|meta| meta.modified())
+                .unwrap_or_else(|_| SystemTime::now());
+            let path = file.path;
+            let name = path
+                .file_name()
+                .context("invalid overlay path")?
+                .to_string_lossy()
+                .into_owned();
+            debug!(path = %path.display(), local = %file.local.display(), size = content.len(), "overlay file loaded");
+            let entry = AliyunFile {
+                name,
+                id: format!("overlay:{}", path.display()),
+                r#type: FileType::File,
+                created_at: DateTime::new(mtime),
+                updated_at: DateTime::new(mtime),
+                size: content.len() as u64,
+                url: None,
+                content_hash: None,
+            };
+            overlay.insert(path, (entry, Bytes::from(content)));
+        }
+        Ok(Self { files: overlay })
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    pub fn metadata(&self, path: &Path) -> Option<AliyunFile> {
+        self.files.get(path).map(|(file, _)| file.clone())
+    }
+
+    pub fn open(&self, path: &Path) -> Option<OverlayDavFile> {
+        self.files.get(path).map(|(file, content)| OverlayDavFile {
+            file: file.clone(),
+            content: content.clone(),
+            pos: 0,
+        })
+    }
+
+    /// Overlay files directly in `dir`
+    pub fn children<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = &'a AliyunFile> + 'a {
+        self.files
+            .iter()
+            .filter(move |(path, _)| path.parent() == Some(dir))
+            .map(|(_, (file, _))| file)
+    }
+}
+
+/// Open overlay file, writes are rejected
+#[derive(Debug)]
+pub struct OverlayDavFile {
+    file: AliyunFile,
+    content: Bytes,
+    pos: u64,
+}
+
+impl DavFile for OverlayDavFile {
+    fn metadata(&'_ mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let file = self.file.clone();
+        ready(Ok(Box::new(file) as Box<dyn DavMetaData>)).boxed()
+    }
+
+    fn write_buf(&'_ mut self, _buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        ready(Err(FsError::Forbidden)).boxed()
+    }
+
+    fn write_bytes(&mut self, _buf: Bytes) -> FsFuture<'_, ()> {
+        ready(Err(FsError::Forbidden)).boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        let start = (self.pos as usize).min(self.content.len());
+        let end = start.saturating_add(count).min(self.content.len());
+        self.pos = end as u64;
+        ready(Ok(self.content.slice(start..end))).boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        let size = self.content.len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(pos) => size + pos,
+            SeekFrom::Current(pos) => self.pos as i64 + pos,
+        };
+        if new_pos < 0 {
+            return ready(Err(FsError::GeneralFailure)).boxed();
+        }
+        self.pos = new_pos as u64;
+        ready(Ok(self.pos)).boxed()
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        ready(Ok(())).boxed()
+    }
+}
@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Files and folders refused to be overwritten, deleted or moved, even when
+/// the rest of the tree is writable.
+///
+/// Patterns are matched against the path relative to the served root, a
+/// pattern without a leading `/` matches at any depth.
+#[derive(Debug, Clone, Default)]
+pub struct ProtectedPaths {
+    globs: Option<GlobSet>,
+}
+
+impl ProtectedPaths {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        if patterns.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let anchored = match pattern.strip_prefix('/') {
+                Some(pattern) => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            let glob = GlobBuilder::new(anchored.trim_end_matches('/'))
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("invalid protected path `{}`", pattern))?;
+            builder.add(glob);
+        }
+        Ok(Self {
+            globs: Some(builder.build()?),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.globs.is_none()
+    }
+
+    /// Whether the path relative to the root is protected
+    pub fn matches(&self, rel_path: &Path) -> bool {
+        self.globs
+            .as_ref()
+            .map(|globs| globs.is_match(rel_path))
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use hyper::header::{self, HeaderMap};
+use xmltree::{Element, XMLNode};
+
+/// Compatibility tweak applied to the requests of some clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quirk {
+    /// Leave out properties that were not found from PROPFIND responses
+    Brief,
+    /// Ignore `Range` headers and always send the whole file
+    NoRange,
+    /// Never redirect downloads to the upstream, even with `--redirect`
+    NoRedirect,
+    /// Announce WebDAV authoring with `MS-Author-Via: DAV` in OPTIONS responses
+    MsAuthorVia,
+}
+
+impl FromStr for Quirk {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "brief" => Ok(Quirk::Brief),
+            "no-range" => Ok(Quirk::NoRange),
+            "no-redirect" => Ok(Quirk::NoRedirect),
+            "ms-author-via" => Ok(Quirk::MsAuthorVia),
+            _ => Err(format!(
+                "unknown quirk `{}`, expected one of brief, no-range, no-redirect, ms-author-via",
+                s
+            )),
+        }
+    }
+}
+
+/// Quirks for clients whose `User-Agent` contains `pattern`, parsed from
+/// `pattern=quirk,quirk`. No quirks after `=` disables a built-in rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuirkRule {
+    pattern: String,
+    quirks: Vec<Quirk>,
+}
+
+impl FromStr for QuirkRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, quirks) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid quirk `{}`, expected `User-Agent=quirk,...`", s))?;
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(format!("empty User-Agent pattern in `{}`", s));
+        }
+        let quirks = quirks
+            .split(',')
+            .filter(|q| !q.trim().is_empty())
+            .map(Quirk::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            quirks,
+        })
+    }
+}
+
+/// Built-in rules for known clients
+const BUILTIN_RULES: &[(&str, &[Quirk])] = &[
+    // Windows Explorer, chokes on large PROPFIND responses and does not follow redirects
+    (
+        "Microsoft-WebDAV-MiniRedir",
+        &[Quirk::Brief, Quirk::NoRedirect, Quirk::MsAuthorVia],
+    ),
+    // Office only opens documents for editing when authoring is announced
+    ("Microsoft Office", &[Quirk::MsAuthorVia]),
+    // macOS Finder does not follow redirects of downloads
+    ("WebDAVFS", &[Quirk::NoRedirect]),
+];
+
+/// Per client compatibility tweaks keyed on `User-Agent` substrings
+#[derive(Debug, Clone, Default)]
+pub struct Quirks {
+    rules: Vec<QuirkRule>,
+}
+
+impl Quirks {
+    /// Configured rules replace built-in rules with the same pattern
+    pub fn new(rules: Vec<QuirkRule>, builtin: bool) -> Self {
+        let mut all_rules = Vec::new();
+        if builtin {
+            all_rules.extend(
+                BUILTIN_RULES
+                    .iter()
+                    .filter(|(pattern, _)| !rules.iter().any(|rule| rule.pattern == *pattern))
+                    .map(|(pattern, quirks)| QuirkRule {
+                        pattern: pattern.to_string(),
+                        quirks: quirks.to_vec(),
+                    }),
+            );
+        }
+        all_rules.extend(rules);
+        Self { rules: all_rules }
+    }
+
+    /// Quirks of all rules matching the `User-Agent` of the request
+    pub fn for_request(&self, headers: &HeaderMap) -> Vec<Quirk> {
+        let Some(user_agent) = headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return Vec::new();
+        };
+        let mut quirks = Vec::new();
+        for rule in &self.rules {
+            if !user_agent.contains(&rule.pattern) {
+                continue;
+            }
+            for quirk in &rule.quirks {
+                if !quirks.contains(quirk) {
+                    quirks.push(*quirk);
+                }
+            }
+        }
+        quirks
+    }
+}
+
+/// Remove the `propstat` elements of properties that were not found from a
+/// multistatus response, `None` if it can not be parsed.
+pub fn brief_multistatus(body: &[u8]) -> Option<Vec<u8>> {
+    let mut root = Element::parse(body).ok()?;
+    for response in root.children.iter_mut() {
+        let XMLNode::Element(response) = response else {
+            continue;
+        };
+        response.children.retain(|node| {
+            let XMLNode::Element(elem) = node else {
+                return true;
+            };
+            if elem.name != "propstat" {
+                return true;
+            }
+            let status = elem
+                .get_child("status")
+                .and_then(|status| status.get_text())
+                .unwrap_or_default();
+            !status.contains(" 404 ")
+        });
+    }
+    let mut buf = Vec::new();
+    root.write(&mut buf).ok()?;
+    Some(buf)
+}
+
+/// Properties returned by PROPFIND in minimal mode when none are asked for
+pub const MINIMAL_PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?><D:propfind xmlns:D="DAV:"><D:prop><D:resourcetype/><D:getcontentlength/><D:getlastmodified/><D:getetag/><D:getcontenttype/></D:prop></D:propfind>"#;
+
+/// Whether a PROPFIND body asks for all properties, an empty body does too,
+/// `allprop` with `include` elements asks for more and is left alone
+pub fn is_allprop(body: &[u8]) -> bool {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return true;
+    }
+    let Ok(root) = Element::parse(body) else {
+        return false;
+    };
+    root.get_child("allprop").is_some() && root.get_child("include").is_none()
+}
+
+/// Verbosity asked for by the `Brief` or `Prefer` headers of a request, `true`
+/// for minimal responses
+pub fn prefers_minimal(headers: &HeaderMap) -> Option<bool> {
+    if let Some(brief) = headers.get("brief").and_then(|v| v.to_str().ok()) {
+        match brief.trim() {
+            "t" | "T" => return Some(true),
+            "f" | "F" => return Some(false),
+            _ => {}
+        }
+    }
+    headers.get_all("prefer").iter().find_map(|v| {
+        v.to_str()
+            .ok()?
+            .split(',')
+            .find_map(|pref| match pref.trim() {
+                "return=minimal" => Some(true),
+                "return=representation" => Some(false),
+                _ => None,
+            })
+    })
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::quirks::{QuirkRule, Quirks};
+
+/// Settings of the WebDAV service that can be changed while running,
+/// shared by all connections.
+#[derive(Debug, Clone, Default)]
+pub struct LiveSettings {
+    pub auth_user: Option<String>,
+    pub auth_password: Option<String>,
+    /// Password and root directory by user name
+    pub auth_users: Arc<HashMap<String, (String, String)>>,
+    pub allowed_hosts: Vec<String>,
+    pub quirk_rules: Vec<QuirkRule>,
+    pub builtin_quirks: bool,
+    pub quirks: Quirks,
+}
+
+impl LiveSettings {
+    pub fn set_allowed_hosts(&mut self, allowed_hosts: Vec<String>) {
+        self.allowed_hosts = allowed_hosts
+            .into_iter()
+            .map(|host| host.trim().to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+    }
+
+    pub fn set_quirks(&mut self, rules: Vec<QuirkRule>, builtin: bool) {
+        self.quirks = Quirks::new(rules.clone(), builtin);
+        self.quirk_rules = rules;
+        self.builtin_quirks = builtin;
+    }
+}
+
+/// Options that can be reloaded, a missing key falls back to the command line value
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ReloadConfig {
+    auth_user: Option<String>,
+    auth_password: Option<String>,
+    allowed_hosts: Option<Vec<String>>,
+    quirks: Option<Vec<String>>,
+    no_builtin_quirks: Option<bool>,
+}
+
+const RELOADABLE_KEYS: &[&str] = &[
+    "auth_user",
+    "auth_password",
+    "allowed_hosts",
+    "quirks",
+    "no_builtin_quirks",
+];
+
+/// Applies `--reload-config` on top of the command line options, at startup and
+/// whenever asked to, e.g. on SIGUSR1.
+#[derive(Debug, Clone)]
+pub struct Reloader {
+    path: PathBuf,
+    base: LiveSettings,
+    live: Arc<RwLock<LiveSettings>>,
+}
+
+impl Reloader {
+    pub fn new(path: PathBuf, live: Arc<RwLock<LiveSettings>>) -> Self {
+        let base = live.read().unwrap().clone();
+        Self { path, base, live }
+    }
+
+    /// Read the config file and apply it, an invalid file leaves the settings untouched.
+    ///
+    /// Other options, e.g. exclusions, rate limits or cache settings, are only
+    /// read at startup, a file setting them is rejected rather than half applied.
+    pub fn reload(&self) -> Result<()> {
+        let content = std::fs::read(&self.path)
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        let value: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&content)
+            .with_context(|| format!("invalid config file {}", self.path.display()))?;
+        let restart_only: Vec<&str> = value
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !RELOADABLE_KEYS.contains(key))
+            .collect();
+        if !restart_only.is_empty() {
+            bail!(
+                "{} sets {} which can not be reloaded, change them on the command line and restart",
+                self.path.display(),
+                restart_only.join(", ")
+            );
+        }
+        let config: ReloadConfig = serde_json::from_value(value.into())
+            .with_context(|| format!("invalid config file {}", self.path.display()))?;
+        let settings = self.apply(config)?;
+
+        let mut live = self.live.write().unwrap();
+        let mut changed = Vec::new();
+        if live.auth_user != settings.auth_user || live.auth_password != settings.auth_password {
+            changed.push("auth");
+        }
+        if live.allowed_hosts != settings.allowed_hosts {
+            changed.push("allowed_hosts");
+        }
+        if live.quirk_rules != settings.quirk_rules
+            || live.builtin_quirks != settings.builtin_quirks
+        {
+            changed.push("quirks");
+        }
+        *live = settings;
+        if changed.is_empty() {
+            info!(path = %self.path.display(), "config reloaded, nothing changed");
+        } else {
+            info!(path = %self.path.display(), changed = ?changed, "config reloaded");
+        }
+        Ok(())
+    }
+
+    fn apply(&self, config: ReloadConfig) -> Result<LiveSettings> {
+        let mut settings = self.base.clone();
+        if config.auth_user.is_some() || config.auth_password.is_some() {
+            if config.auth_user.is_none() || config.auth_password.is_none() {
+                bail!("auth_user and auth_password must be set together");
+            }
+            settings.auth_user = config.auth_user;
+            settings.auth_password = config.auth_password;
+        }
+        if let Some(allowed_hosts) = config.allowed_hosts {
+            settings.set_allowed_hosts(allowed_hosts);
+        }
+        if config.quirks.is_some() || config.no_builtin_quirks.is_some() {
+            let rules = match config.quirks {
+                Some(quirks) => quirks
+                    .iter()
+                    .map(|rule| rule.parse())
+                    .collect::<Result<_, String>>()
+                    .map_err(anyhow::Error::msg)?,
+                None => settings.quirk_rules.clone(),
+            };
+            let builtin = config
+                .no_builtin_quirks
+                .map(|no_builtin| !no_builtin)
+                .unwrap_or(settings.builtin_quirks);
+            settings.set_quirks(rules, builtin);
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reloader(name: &str, content: &str) -> (Reloader, Arc<RwLock<LiveSettings>>) {
+        let path =
+            std::env::temp_dir().join(format!("reload-{}-{}.json", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let live = Arc::new(RwLock::new(LiveSettings::default()));
+        (Reloader::new(path, live.clone()), live)
+    }
+
+    #[test]
+    fn auth_is_reloaded() {
+        let (reloader, live) = reloader(
+            "auth",
+            r#"{"auth_user": "alice", "auth_password": "secret"}"#,
+        );
+        reloader.reload().unwrap();
+        let settings = live.read().unwrap().clone();
+        assert_eq!(settings.auth_user.as_deref(), Some("alice"));
+        assert_eq!(settings.auth_password.as_deref(), Some("secret"));
+
+        std::fs::write(&reloader.path, r#"{"auth_user": "bob"}"#).unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(live.read().unwrap().auth_user.as_deref(), Some("alice"));
+        std::fs::remove_file(&reloader.path).unwrap();
+    }
+
+    #[test]
+    fn reloaded_hosts_are_lowercased() {
+        let (reloader, live) = reloader("hosts", r#"{"allowed_hosts": ["Example.com "]}"#);
+        reloader.reload().unwrap();
+        assert_eq!(live.read().unwrap().allowed_hosts, ["example.com"]);
+        std::fs::remove_file(&reloader.path).unwrap();
+    }
+
+    #[test]
+    fn restart_only_options_are_rejected() {
+        let (reloader, live) = reloader(
+            "restart",
+            r#"{"auth_user": "alice", "auth_password": "secret", "cache_ttl": 60}"#,
+        );
+        let err = reloader.reload().unwrap_err();
+        assert!(err.to_string().contains("cache_ttl"), "{}", err);
+        assert_eq!(live.read().unwrap().auth_user, None);
+        std::fs::remove_file(&reloader.path).unwrap();
+    }
+}
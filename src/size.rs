@@ -0,0 +1,87 @@
+/// Parse a number of bytes with an optional binary `K`, `M`, `G` or `T`
+/// suffix, e.g. `512K` or `10M`, optionally followed by `B` or `iB`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let value = s.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (num, unit) = value.split_at(split);
+    let unit = unit.trim();
+    let unit = unit
+        .strip_suffix("iB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(unit);
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => {
+            return Err(format!(
+                "invalid size `{}`, expected bytes with an optional K, M, G or T suffix",
+                s
+            ))
+        }
+    };
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid size `{}`, expected a whole number of bytes", s))?;
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{}` is too large", s))
+}
+
+/// Parse a size that must be within `min..=max`
+pub fn parse_size_in(s: &str, min: u64, max: u64) -> Result<u64, String> {
+    let size = parse_size(s)?;
+    if !(min..=max).contains(&size) {
+        return Err(format!(
+            "size `{}` is out of range, expected between {} and {}",
+            s,
+            format_size(min),
+            format_size(max)
+        ));
+    }
+    Ok(size)
+}
+
+/// Bytes with the largest suffix that represents them exactly
+pub fn format_size(size: u64) -> String {
+    for (shift, suffix) in [(40, "T"), (30, "G"), (20, "M"), (10, "K")] {
+        if size != 0 && size.trailing_zeros() >= shift {
+            return format!("{}{}", size >> shift, suffix);
+        }
+    }
+    size.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_suffixes() {
+        assert_eq!(parse_size("10M"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("1 KB"), Ok(1024));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        let err = parse_size_in("4K", 64 * 1024, 1 << 30).unwrap_err();
+        assert!(err.contains("between 64K and 1G"), "{}", err);
+        assert_eq!(parse_size_in("64K", 64 * 1024, 1 << 30), Ok(64 * 1024));
+    }
+
+    #[test]
+    fn formats_exact_suffix() {
+        assert_eq!(format_size(0), "0");
+        assert_eq!(format_size(1536), "1536");
+        assert_eq!(format_size(3 << 20), "3M");
+    }
+}
@@ -0,0 +1,180 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+/// Temporary file in the spool directory used to buffer uploads on disk,
+/// removed when dropped.
+#[derive(Debug)]
+pub struct SpoolFile {
+    path: PathBuf,
+    file: Option<File>,
+    hasher: Sha1,
+}
+
+impl SpoolFile {
+    pub async fn create(dir: &Path) -> io::Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = spool_path(dir);
+        let file = OpenOptions::from(private_file_options())
+            .open(&path)
+            .await?;
+        debug!(path = %path.display(), "spool: create");
+        Ok(Self {
+            path,
+            file: Some(file),
+            hasher: Sha1::new(),
+        })
+    }
+
+    /// Reserve a path in the spool directory to be written by other means,
+    /// it is removed on drop as well.
+    pub fn reserve(dir: &Path) -> Self {
+        Self {
+            path: spool_path(dir),
+            file: None,
+            hasher: Sha1::new(),
+        }
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| io::Error::other("spool file already finished"))?;
+        file.write_all(buf).await?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    /// Hex encoded SHA1 of the content written so far
+    pub fn sha1(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+
+    /// Flush and close the writing handle, the content can then be read from `path`.
+    pub async fn finish(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        debug!(path = %self.path.display(), "spool: remove");
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!(path = %self.path.display(), error = %err, "remove spool file failed");
+            }
+        }
+    }
+}
+
+/// Writer computing the SHA1 of the content written through it
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha1,
+}
+
+impl<W> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha1::new(),
+        }
+    }
+
+    /// Hex encoded SHA1 of the content written so far
+    pub fn sha1(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<W: io::Write> io::Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Space available to unprivileged users on the file system of `dir`
+#[cfg(unix)]
+pub fn available_space(dir: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_dir: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space check is not supported on this platform",
+    ))
+}
+
+/// Unguessable path for a new file in the spool directory
+pub fn spool_path(dir: &Path) -> PathBuf {
+    dir.join(format!(
+        "aliyundrive-webdav-{}-{:016x}.spool",
+        std::process::id(),
+        rand::random::<u64>()
+    ))
+}
+
+/// Options creating a file only its owner can read, failing if the path
+/// exists so that nothing planted in a shared spool directory is written to
+pub fn private_file_options() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spool_files_are_private_and_never_reused() {
+        let dir = std::env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        let mut a = SpoolFile::create(&dir).await.unwrap();
+        let b = SpoolFile::create(&dir).await.unwrap();
+        assert_ne!(a.path(), b.path());
+        a.write_all(b"hello").await.unwrap();
+        a.finish().await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(a.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // an existing file is never opened for writing
+        let err = private_file_options().open(a.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        drop((a, b));
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use anyhow::Context;
+use bytes::Bytes;
+use dav_server::body::Body;
+use hyper::{header, Method, Request, Response};
+
+const DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+const DEFAULT_ROBOTS: &str = "User-agent: *\nDisallow: /\n";
+
+/// `/favicon.ico` and `/robots.txt` answered before WebDAV dispatch, so that
+/// browsers don't cause drive lookups
+#[derive(Debug, Clone, Default)]
+pub struct StaticFiles {
+    favicon: Option<Bytes>,
+    robots: Option<Bytes>,
+}
+
+impl StaticFiles {
+    /// Serve the given files, or the built-in ones unless `builtin` is false
+    pub fn load(
+        favicon: Option<&Path>,
+        robots: Option<&Path>,
+        builtin: bool,
+    ) -> anyhow::Result<Self> {
+        let read = |path: &Path| {
+            std::fs::read(path)
+                .map(Bytes::from)
+                .with_context(|| format!("failed to read {}", path.display()))
+        };
+        let favicon = match favicon {
+            Some(path) => Some(read(path)?),
+            None => builtin.then(|| Bytes::from_static(DEFAULT_FAVICON)),
+        };
+        let robots = match robots {
+            Some(path) => Some(read(path)?),
+            None => builtin.then(|| Bytes::from_static(DEFAULT_ROBOTS.as_bytes())),
+        };
+        Ok(Self { favicon, robots })
+    }
+
+    pub fn response(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+        let (content, content_type) = match req.uri().path() {
+            "/favicon.ico" => (self.favicon.as_ref()?, "image/x-icon"),
+            "/robots.txt" => (self.robots.as_ref()?, "text/plain; charset=utf-8"),
+            _ => return None,
+        };
+        let body = if req.method() == Method::HEAD {
+            Body::from(String::new())
+        } else {
+            Body::from(content.clone())
+        };
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, content.len())
+            .header(header::CACHE_CONTROL, "public, max-age=86400")
+            .body(body)
+            .unwrap();
+        Some(response)
+    }
+}
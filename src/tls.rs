@@ -0,0 +1,258 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+#[cfg(feature = "rustls-tls")]
+use {
+    anyhow::Context,
+    std::collections::HashMap,
+    std::fs::File,
+    std::io,
+    std::path::Path,
+    std::sync::{Arc, RwLock},
+    tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert},
+    tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey},
+    tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig},
+    tokio_rustls::TlsAcceptor,
+    tracing::{error, info},
+};
+
+/// Certificate and key files, for the given SNI host name or for any host
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "rustls-tls"), allow(dead_code))]
+pub struct TlsCert {
+    host: Option<String>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TlsCert {
+    /// Pair `--tls-cert` and `--tls-key` values, given as `path` or
+    /// `host=path`, by host name
+    pub fn pair(certs: Vec<String>, keys: Vec<String>) -> Result<Vec<Self>> {
+        let mut keys: Vec<_> = keys.iter().map(|key| split_host(key)).collect();
+        let mut pairs: Vec<TlsCert> = Vec::new();
+        for cert in &certs {
+            let (host, cert) = split_host(cert);
+            if pairs.iter().any(|pair| pair.host == host) {
+                bail!(
+                    "duplicate tls-cert for {}",
+                    host.as_deref().unwrap_or("default")
+                );
+            }
+            let Some(index) = keys.iter().position(|(key_host, _)| *key_host == host) else {
+                bail!(
+                    "missing tls-key for {}",
+                    host.as_deref().unwrap_or("default")
+                );
+            };
+            let (_, key) = keys.remove(index);
+            pairs.push(TlsCert { host, cert, key });
+        }
+        if let Some((host, _)) = keys.first() {
+            bail!(
+                "tls-key without tls-cert for {}",
+                host.as_deref().unwrap_or("default")
+            );
+        }
+        Ok(pairs)
+    }
+}
+
+/// `host=path` or `path`, a path with a `=` in a directory name is not a host
+fn split_host(value: &str) -> (Option<String>, PathBuf) {
+    match value.split_once('=') {
+        Some((host, path)) if !host.is_empty() && !host.contains(['/', '\\']) => {
+            (Some(host.to_ascii_lowercase()), PathBuf::from(path))
+        }
+        _ => (None, PathBuf::from(value)),
+    }
+}
+
+/// Server certificates chosen by the SNI host name of the client, falling back
+/// to the certificate without a host name, or else the first one
+#[cfg_attr(not(feature = "rustls-tls"), allow(dead_code))]
+pub struct TlsCerts {
+    certs: Vec<TlsCert>,
+    #[cfg(feature = "rustls-tls")]
+    loaded: RwLock<LoadedCerts>,
+}
+
+#[cfg(feature = "rustls-tls")]
+struct LoadedCerts {
+    default: Arc<CertifiedKey>,
+    by_host: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl TlsCerts {
+    #[cfg(feature = "rustls-tls")]
+    pub fn load(certs: Vec<TlsCert>) -> Result<Self> {
+        let loaded = RwLock::new(load_certs(&certs)?);
+        Ok(Self { certs, loaded })
+    }
+
+    #[cfg(not(feature = "rustls-tls"))]
+    pub fn load(certs: Vec<TlsCert>) -> Result<Self> {
+        let _ = certs;
+        bail!("TLS is not supported in this build.");
+    }
+
+    /// Read the certificate files again, keeping the current certificates if
+    /// any of them fails to load
+    pub fn reload(&self) {
+        #[cfg(feature = "rustls-tls")]
+        match load_certs(&self.certs) {
+            Ok(loaded) => {
+                *self.loaded.write().unwrap() = loaded;
+                info!(certs = self.certs.len(), "TLS certificates reloaded");
+            }
+            Err(err) => {
+                error!(error = %err, "TLS certificate reload failed, keeping the current ones")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rustls-tls")]
+impl TlsCerts {
+    /// Certificate for the SNI host name, an exact match or else a wildcard one
+    fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let loaded = self.loaded.read().unwrap();
+        let Some(name) = server_name else {
+            return loaded.default.clone();
+        };
+        let name = name.to_ascii_lowercase();
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, domain)| format!("*.{}", domain));
+        loaded
+            .by_host
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| loaded.by_host.get(&wildcard)))
+            .unwrap_or(&loaded.default)
+            .clone()
+    }
+}
+
+#[cfg(feature = "rustls-tls")]
+impl ResolvesServerCert for TlsCerts {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.select(client_hello.server_name()))
+    }
+}
+
+#[cfg(feature = "rustls-tls")]
+fn load_certs(certs: &[TlsCert]) -> Result<LoadedCerts> {
+    let mut default = None;
+    let mut by_host = HashMap::new();
+    for cert in certs {
+        let key = certified_key(&cert.cert, &cert.key)
+            .with_context(|| format!("failed to load {}", cert.cert.display()))?;
+        match cert.host.as_ref() {
+            Some(host) => {
+                default.get_or_insert_with(|| key.clone());
+                by_host.insert(host.clone(), key);
+            }
+            None => default = Some(key),
+        }
+    }
+    let Some(default) = default else {
+        bail!("no TLS certificate configured");
+    };
+    Ok(LoadedCerts { default, by_host })
+}
+
+#[cfg(feature = "rustls-tls")]
+fn certified_key(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>> {
+    let mut key_reader = io::BufReader::new(File::open(key)?);
+    let mut cert_reader = io::BufReader::new(File::open(cert)?);
+
+    let Some(key) = private_keys(&mut key_reader)?.into_iter().next() else {
+        bail!("no private key found in {}", key.display());
+    };
+    let key = any_supported_type(&PrivateKey(key))?;
+    let certs = rustls_pemfile::certs(&mut cert_reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+#[cfg(feature = "rustls-tls")]
+pub fn tls_acceptor(certs: Arc<TlsCerts>) -> TlsAcceptor {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Arc::new(config).into()
+}
+
+#[cfg(feature = "rustls-tls")]
+fn private_keys(rd: &mut dyn io::BufRead) -> Result<Vec<Vec<u8>>, io::Error> {
+    use rustls_pemfile::{read_one, Item};
+
+    let mut keys = Vec::<Vec<u8>>::new();
+    loop {
+        match read_one(rd)? {
+            None => return Ok(keys),
+            Some(Item::RSAKey(key)) => keys.push(key),
+            Some(Item::PKCS8Key(key)) => keys.push(key),
+            Some(Item::ECKey(key)) => keys.push(key),
+            _ => {}
+        };
+    }
+}
+
+#[cfg(all(test, feature = "rustls-tls"))]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/tests/data/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn cert_der(name: &str) -> Vec<u8> {
+        let mut reader = io::BufReader::new(File::open(testdata(name)).unwrap());
+        rustls_pemfile::certs(&mut reader).unwrap().remove(0)
+    }
+
+    fn certs() -> TlsCerts {
+        let certs = TlsCert::pair(
+            vec![
+                testdata("default.pem"),
+                format!("a.example.com={}", testdata("a.example.com.pem")),
+                format!("*.b.example.com={}", testdata("b.example.com.pem")),
+            ],
+            vec![
+                format!("*.b.example.com={}", testdata("b.example.com.key")),
+                testdata("default.key"),
+                format!("a.example.com={}", testdata("a.example.com.key")),
+            ],
+        )
+        .unwrap();
+        TlsCerts::load(certs).unwrap()
+    }
+
+    #[test]
+    fn sni_selects_matching_cert() {
+        let certs = certs();
+        let selected = |name| certs.select(name).cert[0].0.clone();
+        assert_eq!(
+            selected(Some("a.example.com")),
+            cert_der("a.example.com.pem")
+        );
+        assert_eq!(
+            selected(Some("A.Example.COM")),
+            cert_der("a.example.com.pem")
+        );
+        assert_eq!(
+            selected(Some("x.b.example.com")),
+            cert_der("b.example.com.pem")
+        );
+        assert_eq!(selected(Some("other.example.com")), cert_der("default.pem"));
+        assert_eq!(selected(None), cert_der("default.pem"));
+    }
+}
@@ -1,63 +1,297 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Debug, Formatter};
+use std::future::Future;
 use std::io::{Cursor, SeekFrom, Write};
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
+use base64::Engine;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use dashmap::DashMap;
 use dav_server::{
     davpath::DavPath,
     fs::{
-        DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsStream, OpenOptions,
-        ReadDirMeta,
+        DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream,
+        OpenOptions, ReadDirMeta,
     },
 };
-use futures_util::future::{ready, FutureExt};
+use futures_util::{
+    future::{ready, BoxFuture, FutureExt},
+    stream::StreamExt,
+};
 use path_slash::PathBufExt;
-use tracing::{debug, error, trace, warn};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace, warn};
 use zip::write::{FileOptions, ZipWriter};
 
+#[cfg(feature = "redis")]
+use crate::cache::RedisConnection;
 use crate::{
-    cache::Cache,
-    drive::{model::GetFileDownloadUrlResponse, AliyunDrive, AliyunFile, DateTime, FileType},
+    audit::{self, AuditLog, Operation},
+    cache::{Cache, DownloadUrlCache, TtlOverride},
+    disk_cache::{self, DiskCache, DiskCacheWriter},
+    drive::{
+        is_connection_reset, is_url_rejected,
+        model::{FileRevision, GetFileDownloadUrlResponse, ListDeltaResponse},
+        AliyunFile, DateTime, DriveBackend, FileType, PartialListing, RapidUploadProof,
+        RateLimiter,
+    },
+    exif::{strip_metadata, ImageKind},
+    health::{UpstreamHealth, WriteHealth},
+    hidden::HidePatterns,
+    metrics,
+    overlay::Overlay,
+    protected::ProtectedPaths,
+    size::parse_size,
+    spool::{self, HashWriter, SpoolFile},
 };
 
 #[derive(Clone)]
 pub struct AliyunDriveFileSystem {
-    drive: AliyunDrive,
+    drive: Arc<dyn DriveBackend>,
     pub(crate) dir_cache: Cache,
+    download_urls: DownloadUrlCache,
     uploading: Arc<DashMap<String, Vec<AliyunFile>>>,
     root: PathBuf,
     no_trash: bool,
     read_only: bool,
     upload_buffer_size: usize,
+    multipart_threshold: u64,
+    upload_concurrency: usize,
     skip_upload_same_size: bool,
     prefer_http_download: bool,
+    strip_exif: bool,
+    spool_dir: PathBuf,
+    retry_download_on_reset: u32,
+    /// Chunks downloaded concurrently when reading sequentially, including the one being read
+    prefetch_chunks: usize,
+    /// Bytes per second served to each download, with bursts of up to a read buffer
+    download_rate_limit: Option<(u64, usize)>,
+    read_retry_budget: Option<u32>,
+    rapid_upload: bool,
+    /// Upload under a hidden staging name, renamed once complete
+    staged_upload: bool,
+    deny_hidden_files: bool,
+    hide_patterns: Arc<HidePatterns>,
+    /// Names of empty marker files hidden from listings
+    placeholder_names: Vec<String>,
+    enable_versions: bool,
+    write_health: Option<Arc<WriteHealth>>,
+    content_language: Option<String>,
+    quota_reserve: Option<QuotaReserve>,
+    download_url_internal: bool,
+    quota: Arc<Mutex<Option<CachedQuota>>>,
+    /// How long the quota is reused, for PROPFIND and `--quota-reserve` checks
+    quota_ttl: Duration,
+    disk_cache: Option<DiskCache>,
+    upstream_health: Option<UpstreamHealth>,
+    max_upload_size: Option<u64>,
+    /// Downloads in flight, shared by concurrent identical reads
+    inflight_reads: Option<Arc<DashMap<ReadKey, InflightRead>>>,
+    /// Number of reads in progress by path, tracked when moves are blocked during reads
+    active_reads: Option<Arc<DashMap<PathBuf, usize>>>,
+    uploads: Arc<Uploads>,
+    min_spool_free: Option<u64>,
+    overlay: Arc<Overlay>,
+    protected: Arc<ProtectedPaths>,
+    audit_log: Option<AuditLog>,
+    dir_entry_limit: Option<usize>,
+    content_hash_etag: bool,
+    listing_include_trashed: bool,
+    max_filename_bytes: Option<usize>,
+    cache_size: u64,
+}
+
+/// Spooled bytes between two checks of the free space in the spool directory
+const SPOOL_SPACE_CHECK_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// Uploads in progress, so that they can be drained on shutdown
+#[derive(Debug, Default)]
+struct Uploads {
+    next_id: AtomicU64,
+    active: DashMap<u64, UploadProgress>,
+    draining: AtomicBool,
+}
+
+/// Path, expected size if known and bytes received so far of an upload
+#[derive(Debug, Clone)]
+pub struct UploadProgress {
+    pub path: PathBuf,
+    pub size: Option<u64>,
+    pub received: Arc<AtomicU64>,
+}
+
+/// Download url cache key of the file, position and length of a read
+type ReadKey = (String, u64, usize);
+/// Result of a download shared by concurrent readers, along with the url used
+type InflightRead = Arc<tokio::sync::OnceCell<Result<(Bytes, String), FsError>>>;
+
+/// Entry below a collection, relative to it, that could not be deleted
+pub type DeleteFailure = (PathBuf, FsError);
+
+/// Quota as `(used, total)` along with when it was fetched
+type CachedQuota = (Instant, (u64, u64));
+
+/// Free space to keep on the drive, uploads eating into it are rejected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaReserve {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl QuotaReserve {
+    fn bytes(&self, total: u64) -> u64 {
+        match *self {
+            QuotaReserve::Bytes(bytes) => bytes,
+            QuotaReserve::Percent(percent) => (total as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+impl FromStr for QuotaReserve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            percent
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=100.0).contains(p))
+                .map(QuotaReserve::Percent)
+                .ok_or_else(|| format!("invalid percentage `{}`", s))
+        } else {
+            parse_size(s).map(QuotaReserve::Bytes)
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Set when a listing was served past its expiry because refreshing it failed,
+    /// or a file from the disk cache while the upstream is down
+    static SERVED_STALE: std::cell::Cell<bool>;
+}
+
+/// Tell `track_stale` that the response is stale
+pub fn mark_stale() {
+    let _ = SERVED_STALE.try_with(|stale| stale.set(true));
+}
+
+/// Run `fut`, telling whether it was served an expired listing
+pub async fn track_stale<F: Future>(fut: F) -> (F::Output, bool) {
+    SERVED_STALE
+        .scope(std::cell::Cell::new(false), async {
+            let output = fut.await;
+            (output, SERVED_STALE.with(|stale| stale.get()))
+        })
+        .await
+}
+
+/// Attempts at uploading a single part before the whole upload fails
+const PART_UPLOAD_ATTEMPTS: u32 = 4;
+
+/// Download urls are requested with 4 hours validity
+const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(4 * 3600);
+
+/// Name of the virtual folder exposing the version history of the files next to it
+const VERSIONS_DIR: &str = ".versions";
+
+/// Prefix of the hidden names staged uploads are uploaded under
+const STAGING_PREFIX: &str = ".partial-";
+/// Age of the staged files swept at startup, younger ones may belong to
+/// uploads still running on another instance
+const STAGING_SWEEP_AGE: Duration = Duration::from_secs(24 * 3600);
+
+/// Location inside a virtual `.versions` folder
+#[derive(Debug)]
+enum VersionPath {
+    /// `dir/.versions`, lists the files of `dir` as folders
+    Root(PathBuf),
+    /// `dir/.versions/file`, lists the versions of `dir/file`
+    File(PathBuf),
+    /// `dir/.versions/file/<revision>`, a single version of `dir/file`
+    Revision(PathBuf, String),
 }
 
 impl AliyunDriveFileSystem {
     #[allow(clippy::too_many_arguments)]
-    pub fn new(drive: AliyunDrive, root: String, cache_size: u64, cache_ttl: u64) -> Result<Self> {
-        let dir_cache = Cache::new(cache_size, cache_ttl);
-        debug!("dir cache initialized");
+    pub fn new(
+        drive: impl DriveBackend + 'static,
+        root: String,
+        cache_size: u64,
+        cache_ttl: u64,
+        cache_ttl_jitter: u64,
+        cache_ttl_overrides: Vec<TtlOverride>,
+    ) -> Result<Self> {
         let root = if root.starts_with('/') {
             PathBuf::from(root)
         } else {
             Path::new("/").join(root)
         };
+        // overridden paths are relative to the root, like the paths of requests
+        let cache_ttl_overrides = cache_ttl_overrides
+            .into_iter()
+            .map(|o| TtlOverride {
+                path: root.join(o.path.strip_prefix("/").unwrap_or(&o.path)),
+                ttl: o.ttl,
+            })
+            .collect();
+        let dir_cache = Cache::new(cache_size, cache_ttl, cache_ttl_jitter, cache_ttl_overrides);
+        let download_urls = DownloadUrlCache::new(cache_size, DOWNLOAD_URL_TTL, None);
+        debug!("dir cache initialized");
         Ok(Self {
-            drive,
+            drive: Arc::new(drive),
             dir_cache,
+            download_urls,
             uploading: Arc::new(DashMap::new()),
             root,
             no_trash: false,
             read_only: false,
             upload_buffer_size: 16 * 1024 * 1024,
+            multipart_threshold: 16 * 1024 * 1024,
+            upload_concurrency: 1,
             skip_upload_same_size: false,
             prefer_http_download: false,
+            strip_exif: false,
+            spool_dir: std::env::temp_dir(),
+            retry_download_on_reset: 0,
+            prefetch_chunks: 1,
+            download_rate_limit: None,
+            read_retry_budget: None,
+            rapid_upload: false,
+            staged_upload: false,
+            deny_hidden_files: false,
+            hide_patterns: Arc::new(HidePatterns::default()),
+            placeholder_names: Vec::new(),
+            enable_versions: false,
+            write_health: None,
+            content_language: None,
+            quota_reserve: None,
+            download_url_internal: false,
+            quota: Arc::new(Mutex::new(None)),
+            quota_ttl: Duration::from_secs(cache_ttl),
+            disk_cache: None,
+            upstream_health: None,
+            max_upload_size: None,
+            inflight_reads: None,
+            active_reads: None,
+            uploads: Arc::new(Uploads::default()),
+            min_spool_free: None,
+            overlay: Arc::new(Overlay::default()),
+            protected: Arc::new(ProtectedPaths::default()),
+            audit_log: None,
+            dir_entry_limit: None,
+            content_hash_etag: false,
+            listing_include_trashed: false,
+            max_filename_bytes: None,
+            cache_size,
         })
     }
 
@@ -76,6 +310,46 @@ impl AliyunDriveFileSystem {
         self
     }
 
+    /// Files up to this size are uploaded in a single part, larger ones in
+    /// parts of the upload buffer size
+    pub fn set_multipart_threshold(&mut self, multipart_threshold: u64) -> &mut Self {
+        self.multipart_threshold = multipart_threshold;
+        self
+    }
+
+    /// Size of the parts a file of `size` bytes is uploaded in, a single part
+    /// never exceeds the upload buffer size even if the threshold is larger
+    fn part_size(&self, size: u64) -> u64 {
+        let buffer_size = self.upload_buffer_size as u64;
+        if size <= self.multipart_threshold.min(buffer_size) {
+            size.max(1)
+        } else {
+            buffer_size
+        }
+    }
+
+    /// Parts of a file uploaded at the same time, each one buffered in memory
+    pub fn set_upload_concurrency(&mut self, upload_concurrency: usize) -> &mut Self {
+        self.upload_concurrency = upload_concurrency.max(1);
+        self
+    }
+
+    pub fn set_max_upload_size(&mut self, max_upload_size: Option<u64>) -> &mut Self {
+        self.max_upload_size = max_upload_size;
+        self
+    }
+
+    /// Reject uploads larger than `--max-upload-size`
+    fn check_upload_size(&self, size: u64) -> Result<(), FsError> {
+        match self.max_upload_size {
+            Some(max) if size > max => {
+                warn!(size = size, max = max, "upload rejected, too large");
+                Err(FsError::TooLarge)
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn set_skip_upload_same_size(&mut self, skip_upload_same_size: bool) -> &mut Self {
         self.skip_upload_same_size = skip_upload_same_size;
         self
@@ -86,7 +360,829 @@ impl AliyunDriveFileSystem {
         self
     }
 
-    fn find_in_cache(&self, path: &Path) -> Result<Option<AliyunFile>, FsError> {
+    pub fn set_strip_exif(&mut self, strip_exif: bool) -> &mut Self {
+        self.strip_exif = strip_exif;
+        self
+    }
+
+    pub fn set_spool_dir(&mut self, spool_dir: PathBuf) -> &mut Self {
+        self.spool_dir = spool_dir;
+        self
+    }
+
+    pub fn set_min_spool_free(&mut self, min_spool_free: Option<u64>) -> &mut Self {
+        self.min_spool_free = min_spool_free;
+        self
+    }
+
+    /// Whether uploads of files with this name are buffered in the spool directory
+    fn spools(&self, name: &str) -> bool {
+        self.rapid_upload || (self.strip_exif && ImageKind::from_name(name).is_some())
+    }
+
+    /// Reject spooling `size` more bytes when the spool directory would be left
+    /// with less than `--min-spool-free` bytes
+    fn check_spool_space(&self, size: u64) -> Result<(), FsError> {
+        let Some(min_free) = self.min_spool_free else {
+            return Ok(());
+        };
+        let available = match spool::available_space(&self.spool_dir) {
+            Ok(available) => available,
+            Err(err) => {
+                warn!(dir = %self.spool_dir.display(), error = %err, "check spool free space failed");
+                return Ok(());
+            }
+        };
+        if available < min_free.saturating_add(size) {
+            warn!(
+                dir = %self.spool_dir.display(),
+                available = available,
+                size = size,
+                min_free = min_free,
+                "upload rejected, not enough free space to spool it"
+            );
+            return Err(FsError::InsufficientStorage);
+        }
+        Ok(())
+    }
+
+    pub fn set_retry_download_on_reset(&mut self, retries: u32) -> &mut Self {
+        self.retry_download_on_reset = retries;
+        self
+    }
+
+    /// Download up to `chunks` read buffers ahead concurrently, 1 reads one buffer at a time
+    pub fn set_prefetch_chunks(&mut self, chunks: usize) -> &mut Self {
+        self.prefetch_chunks = chunks.max(1);
+        self
+    }
+
+    /// Pace every download to `rate` bytes per second, a first read buffer of
+    /// `read_buffer_size` is sent right away
+    pub fn set_download_rate_limit(
+        &mut self,
+        rate: Option<u64>,
+        read_buffer_size: usize,
+    ) -> &mut Self {
+        self.download_rate_limit = rate
+            .filter(|rate| *rate > 0)
+            .map(|rate| (rate, read_buffer_size));
+        self
+    }
+
+    /// Let concurrent reads of the same range of a file share one upstream download
+    pub fn set_coalesce_reads(&mut self, coalesce_reads: bool) -> &mut Self {
+        self.inflight_reads = coalesce_reads.then(|| Arc::new(DashMap::new()));
+        self
+    }
+
+    /// Track reads in progress so that MOVEs of files being read can be refused
+    pub fn set_block_move_during_read(&mut self, block_move_during_read: bool) -> &mut Self {
+        self.active_reads = block_move_during_read.then(|| Arc::new(DashMap::new()));
+        self
+    }
+
+    /// Whether the file, or any file below the directory, is being read
+    pub fn is_being_read(&self, dav_path: &DavPath) -> bool {
+        let Some(active_reads) = self.active_reads.as_ref() else {
+            return false;
+        };
+        let path = self.normalize_dav_path(dav_path);
+        active_reads
+            .iter()
+            .any(|entry| entry.key().starts_with(&path))
+    }
+
+    /// Stop accepting uploads, those in progress are let through
+    pub fn drain_uploads(&self) {
+        self.uploads.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining_uploads(&self) -> bool {
+        self.uploads.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn active_uploads(&self) -> Vec<UploadProgress> {
+        self.uploads
+            .active
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    pub fn set_read_retry_budget(&mut self, budget: Option<u32>) -> &mut Self {
+        self.read_retry_budget = budget;
+        self
+    }
+
+    /// Derive ETags of files from their content hash alone, so that a changed
+    /// modification time with the same content keeps the ETag
+    pub fn set_content_hash_etag(&mut self, content_hash_etag: bool) -> &mut Self {
+        self.content_hash_etag = content_hash_etag;
+        self
+    }
+
+    /// Also list items in the recycle bin, with ` (trashed)` appended to their
+    /// names, for troubleshooting. They are left out of the cache and path
+    /// lookups, only directory listings show them.
+    pub fn set_listing_include_trashed(&mut self, listing_include_trashed: bool) -> &mut Self {
+        self.listing_include_trashed = listing_include_trashed;
+        self
+    }
+
+    /// Metadata of the file as served, with the configured ETag
+    fn file_metadata(&self, file: AliyunFile) -> Box<dyn DavMetaData> {
+        if self.content_hash_etag {
+            Box::new(ContentHashEtag(file))
+        } else {
+            Box::new(file)
+        }
+    }
+
+    /// Copy of the file system serving `root`, relative to the current root
+    pub fn with_root(&self, root: &str) -> Self {
+        let mut fs = self.clone();
+        fs.root = self.root.join(root.trim_start_matches('/'));
+        fs
+    }
+
+    /// Copy of the file system listing at most `limit` entries per directory
+    pub fn with_dir_entry_limit(&self, limit: usize) -> Self {
+        let mut fs = self.clone();
+        fs.dir_entry_limit = Some(limit);
+        fs
+    }
+
+    /// Drop download urls of files not read for `idle`, before they expire
+    pub fn set_download_url_idle_ttl(&mut self, idle: Option<Duration>) -> &mut Self {
+        if let Some(idle) = idle {
+            self.download_urls =
+                DownloadUrlCache::new(self.cache_size, DOWNLOAD_URL_TTL, Some(idle));
+        }
+        self
+    }
+
+    pub fn set_rapid_upload(&mut self, rapid_upload: bool) -> &mut Self {
+        self.rapid_upload = rapid_upload;
+        self
+    }
+
+    /// Upload new content under a hidden name and rename it over the file
+    /// once complete, so that listings never show a half written file
+    pub fn set_staged_upload(&mut self, staged_upload: bool) -> &mut Self {
+        self.staged_upload = staged_upload;
+        self
+    }
+
+    pub fn set_deny_hidden_files(&mut self, deny_hidden_files: bool) -> &mut Self {
+        self.deny_hidden_files = deny_hidden_files;
+        self
+    }
+
+    /// Hide entries with a name matching one of these patterns
+    pub fn set_hide_patterns(&mut self, hide_patterns: HidePatterns) -> &mut Self {
+        self.hide_patterns = Arc::new(hide_patterns);
+        self
+    }
+
+    /// Refuse to create files and folders with names longer than this in UTF-8 bytes
+    pub fn set_max_filename_bytes(&mut self, max_filename_bytes: Option<usize>) -> &mut Self {
+        self.max_filename_bytes = max_filename_bytes;
+        self
+    }
+
+    /// Byte length of the name of `dav_path` and the limit, if the name is too long
+    pub fn filename_too_long(&self, dav_path: &DavPath) -> Option<(usize, usize)> {
+        let limit = self.max_filename_bytes?;
+        let len = dav_path.file_name_bytes().len();
+        (len > limit).then_some((len, limit))
+    }
+
+    fn check_filename(&self, path: &Path) -> Result<(), FsError> {
+        let (Some(limit), Some(name)) = (self.max_filename_bytes, path.file_name()) else {
+            return Ok(());
+        };
+        let len = name.to_string_lossy().len();
+        if len > limit {
+            debug!(path = %path.display(), len = len, limit = limit, "file name too long");
+            return Err(FsError::PathTooLong);
+        }
+        Ok(())
+    }
+
+    /// Hide empty files with these names, they are left out of listings and
+    /// answered with 404, a real file uploaded under the same name replaces the
+    /// placeholder
+    pub fn set_placeholder_names(&mut self, placeholder_names: Vec<String>) -> &mut Self {
+        self.placeholder_names = placeholder_names;
+        self
+    }
+
+    /// Switch to read-only mode after `threshold` consecutive upstream write failures
+    pub fn set_auto_read_only(
+        &mut self,
+        threshold: Option<u32>,
+        probe_interval: Duration,
+    ) -> &mut Self {
+        self.write_health =
+            threshold.map(|threshold| Arc::new(WriteHealth::new(threshold, probe_interval)));
+        self
+    }
+
+    /// Whether writes keep failing and are rejected until a probe succeeds
+    pub fn is_write_degraded(&self) -> bool {
+        self.write_health
+            .as_ref()
+            .map(|health| health.is_degraded())
+            .unwrap_or(false)
+    }
+
+    /// Whether writes are rejected, either configured so or because writes keep failing
+    fn is_read_only(&self) -> bool {
+        self.read_only
+            || self
+                .write_health
+                .as_ref()
+                .map(|health| health.should_reject())
+                .unwrap_or(false)
+    }
+
+    /// Track the outcome of a write operation for the automatic read-only mode
+    async fn record_write<T>(
+        &self,
+        fut: impl Future<Output = Result<T, FsError>>,
+    ) -> Result<T, FsError> {
+        let res = fut.await;
+        if let Some(health) = self.write_health.as_ref() {
+            match res {
+                Ok(_) => health.record_success(),
+                Err(FsError::GeneralFailure) => health.record_failure(),
+                Err(_) => {}
+            }
+        }
+        res
+    }
+
+    /// Prefer intranet download urls, for deployments inside Alibaba Cloud
+    pub fn set_download_url_internal(&mut self, download_url_internal: bool) -> &mut Self {
+        self.download_url_internal = download_url_internal;
+        self
+    }
+
+    /// Keep local copies of fully downloaded files to serve while the upstream is down
+    pub fn set_disk_cache(&mut self, disk_cache: Option<DiskCache>) -> &mut Self {
+        self.disk_cache = disk_cache;
+        self
+    }
+
+    pub fn set_upstream_health(&mut self, upstream_health: Option<UpstreamHealth>) -> &mut Self {
+        self.upstream_health = upstream_health;
+        self
+    }
+
+    /// Cache directory listings in Redis, shared with other instances
+    #[cfg(feature = "redis")]
+    pub fn set_redis_cache(&mut self, redis: RedisConnection) -> &mut Self {
+        self.dir_cache.use_redis(redis);
+        self
+    }
+
+    /// Serve listings up to `window` past their expiry when refreshing them fails
+    pub fn set_serve_stale_on_error(&mut self, window: Option<Duration>) -> &mut Self {
+        if let Some(window) = window {
+            self.dir_cache.enable_stale(window);
+        }
+        self
+    }
+
+    /// List the directory ahead of the DAV handler, so that whether the listing
+    /// is stale is known before the response is sent
+    pub async fn preload_dir(&self, dav_path: &DavPath) {
+        if !self.dir_cache.keeps_stale() {
+            return;
+        }
+        let path = self.normalize_dav_path(dav_path);
+        if let Ok(Some(file)) = self.get_file(path.clone()).await {
+            if matches!(file.r#type, FileType::Folder) {
+                let _ = self.read_dir_and_cache(path).await;
+            }
+        }
+    }
+
+    /// Expired listing of the directory, after refreshing it failed
+    async fn stale_listing(&self, path_str: &str, err: &anyhow::Error) -> Option<Vec<AliyunFile>> {
+        let files = self.dir_cache.get_stale(path_str).await?;
+        warn!(path = %path_str, error = %err, "upstream error, serving a stale listing");
+        mark_stale();
+        Some(files)
+    }
+
+    /// Whether the upstream probe considers the drive unreachable
+    pub fn is_upstream_down(&self) -> bool {
+        self.upstream_health
+            .as_ref()
+            .map(|health| health.is_down())
+            .unwrap_or(false)
+    }
+
+    /// Whether a complete local copy of the file is available, without asking the upstream
+    pub async fn has_disk_cached(&self, dav_path: &DavPath) -> bool {
+        let Some(disk_cache) = self.disk_cache.as_ref() else {
+            return false;
+        };
+        let path = self.normalize_dav_path(dav_path);
+        match self.find_in_cache(&path).await {
+            Ok(Some(file)) => disk_cache.lookup(&file).is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn set_quota_reserve(&mut self, quota_reserve: Option<QuotaReserve>) -> &mut Self {
+        self.quota_reserve = quota_reserve;
+        self
+    }
+
+    /// Quota as `(used, total)`, cached for a short while
+    async fn cached_quota(&self) -> Result<(u64, u64), FsError> {
+        if let Some((fetched_at, quota)) = *self.quota.lock().unwrap() {
+            if fetched_at.elapsed() < self.quota_ttl {
+                return Ok(quota);
+            }
+        }
+        let quota = self.drive.get_quota().await.map_err(|err| {
+            error!(error = %err, "get quota failed");
+            FsError::GeneralFailure
+        })?;
+        *self.quota.lock().unwrap() = Some((Instant::now(), quota));
+        Ok(quota)
+    }
+
+    /// Whether collections are deleted for good, with [`Self::remove_tree`]
+    pub fn deletes_trees(&self) -> bool {
+        self.no_trash
+    }
+
+    /// Permanently delete a collection along with everything in it.
+    ///
+    /// Uses the recursive delete of the drive and falls back to deleting the
+    /// entries one by one when that fails, as many at a time as the API
+    /// semaphore lets through.
+    /// Returns the entries that could not be deleted.
+    pub async fn remove_tree(&self, dav_path: &DavPath) -> Result<Vec<DeleteFailure>, FsError> {
+        let path = self.normalize_dav_path(dav_path);
+        debug!(path = %path.display(), "fs: remove_tree");
+        let entry = audit::Entry::new(Operation::Delete, &path);
+        self.audited(entry, self.remove_tree_at(path)).await
+    }
+
+    async fn remove_tree_at(&self, path: PathBuf) -> Result<Vec<DeleteFailure>, FsError> {
+        if self.is_hidden_path(&path) {
+            return Err(FsError::NotFound);
+        }
+        if self.is_read_only()
+            || self.version_path(&path).is_some()
+            || self.overlay_path(&path).is_some()
+        {
+            return Err(FsError::Forbidden);
+        }
+        self.check_protected(&path).await?;
+        let file = self
+            .get_file(path.clone())
+            .await?
+            .ok_or(FsError::NotFound)?;
+        if !matches!(file.r#type, FileType::Folder) {
+            return Err(FsError::Forbidden);
+        }
+        audit::note_file_id(&file.id);
+        let fut = async {
+            let err = match self.drive.remove_file(&file.id, false).await {
+                Ok(()) => return Ok(Vec::new()),
+                Err(err) => err,
+            };
+            if write_error(&err) == FsError::Forbidden {
+                error!(path = %path.display(), error = %err, "remove directory failed");
+                return Err(FsError::Forbidden);
+            }
+            warn!(path = %path.display(), error = %err, "recursive delete failed, deleting entries one by one");
+            let mut failures = self.remove_entries(&file).await;
+            if failures.is_empty() {
+                if let Err(err) = self.drive.remove_file(&file.id, false).await {
+                    error!(path = %path.display(), error = %err, "remove directory failed");
+                    failures.push((PathBuf::new(), write_error(&err)));
+                }
+            }
+            Ok(failures)
+        };
+        let res = self.record_write(fut).await;
+        self.dir_cache.invalidate(&path).await;
+        self.dir_cache.invalidate_parent(&path).await;
+        res
+    }
+
+    /// Delete everything below `folder`, files first and then the folders deepest first
+    async fn remove_entries(&self, folder: &AliyunFile) -> Vec<DeleteFailure> {
+        let mut failures = Vec::new();
+        let mut files = Vec::new();
+        let mut folders = Vec::new();
+        let mut level = vec![(PathBuf::new(), folder.id.clone())];
+        while !level.is_empty() {
+            let results: Vec<_> = futures_util::stream::iter(level)
+                .map(|(rel_path, id)| async move {
+                    let res = self.drive.list_all(&id).await;
+                    (rel_path, res)
+                })
+                .buffer_unordered(self.drive.api_semaphore().permits())
+                .collect()
+                .await;
+            let mut next_level = Vec::new();
+            for (rel_path, res) in results {
+                match res {
+                    Ok(children) => {
+                        for child in children {
+                            let child_path = rel_path.join(&child.name);
+                            match child.r#type {
+                                FileType::Folder => {
+                                    next_level.push((child_path.clone(), child.id.clone()));
+                                    folders.push((child_path, child.id));
+                                }
+                                FileType::File => files.push((child_path, child.id)),
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!(path = %rel_path.display(), error = %err, "list directory failed");
+                        failures.push((rel_path, FsError::GeneralFailure));
+                    }
+                }
+            }
+            level = next_level;
+        }
+        let remove = |(rel_path, id): (PathBuf, String)| async move {
+            self.drive
+                .remove_file(&id, false)
+                .await
+                .map_err(|err| {
+                    error!(path = %rel_path.display(), error = %err, "remove failed");
+                    (rel_path, write_error(&err))
+                })
+                .err()
+        };
+        let file_failures: Vec<_> = futures_util::stream::iter(files)
+            .map(remove)
+            .buffer_unordered(self.drive.api_semaphore().permits())
+            .filter_map(ready)
+            .collect()
+            .await;
+        failures.extend(file_failures);
+        // a folder is only removed when everything below it was
+        folders.sort_by_key(|(rel_path, _)| std::cmp::Reverse(rel_path.components().count()));
+        for folder in folders {
+            if failures
+                .iter()
+                .any(|(failed, _)| failed.starts_with(&folder.0))
+            {
+                continue;
+            }
+            if let Some(failure) = remove(folder).await {
+                failures.push(failure);
+            }
+        }
+        failures
+    }
+
+    /// Changes since `cursor` of the entries clients can see, below the root
+    /// and not hidden, `None` when a full resync is needed.
+    ///
+    /// Removed files come without their details and can't be placed, they
+    /// are only reported with their ids.
+    pub async fn list_changes(
+        &self,
+        cursor: Option<&str>,
+    ) -> Result<Option<ListDeltaResponse>, FsError> {
+        let delta = self.drive.list_changes(cursor).await.map_err(|err| {
+            error!(error = %err, "list changes failed");
+            FsError::GeneralFailure
+        })?;
+        let Some(mut delta) = delta else {
+            return Ok(None);
+        };
+        let root_id = self
+            .get_file(self.root.clone())
+            .await?
+            .ok_or(FsError::NotFound)?
+            .id;
+        let mut visible_folders = HashMap::new();
+        let mut items = Vec::with_capacity(delta.items.len());
+        for item in delta.items {
+            if let Some(file) = item.file.as_ref() {
+                if self.is_hidden_name(&file.name) {
+                    continue;
+                }
+                let parent_id = file.parent_file_id.as_deref().unwrap_or("root");
+                if !self
+                    .is_visible_folder(parent_id, &root_id, &mut visible_folders)
+                    .await?
+                {
+                    continue;
+                }
+            }
+            items.push(item);
+        }
+        delta.items = items;
+        Ok(Some(delta))
+    }
+
+    /// Whether the entries of a folder can be seen by clients, it is the root
+    /// or below it without a hidden folder in between, `known` remembers the
+    /// folders looked up
+    async fn is_visible_folder(
+        &self,
+        folder_id: &str,
+        root_id: &str,
+        known: &mut HashMap<String, bool>,
+    ) -> Result<bool, FsError> {
+        let mut walked = Vec::new();
+        let mut current = folder_id.to_string();
+        let visible = loop {
+            if current == root_id {
+                break true;
+            }
+            if let Some(visible) = known.get(&current) {
+                break *visible;
+            }
+            if current == "root" {
+                break false;
+            }
+            walked.push(current.clone());
+            let location = self
+                .drive
+                .get_file_location(&current)
+                .await
+                .map_err(|err| {
+                    error!(file_id = %current, error = %err, "get file location failed");
+                    FsError::GeneralFailure
+                })?;
+            match location {
+                Some((name, parent_id)) if !self.is_hidden_name(&name) => current = parent_id,
+                _ => break false,
+            }
+        };
+        for folder_id in walked {
+            known.insert(folder_id, visible);
+        }
+        Ok(visible)
+    }
+
+    /// Reject uploads of `size` bytes that would eat into the quota reserve
+    async fn check_quota_reserve(&self, size: u64) -> Result<(), FsError> {
+        let Some(reserve) = self.quota_reserve else {
+            return Ok(());
+        };
+        let (used, total) = self.cached_quota().await?;
+        let limit = total.saturating_sub(reserve.bytes(total));
+        if used.saturating_add(size) > limit {
+            warn!(
+                size = size,
+                used = used,
+                total = total,
+                limit = limit,
+                "upload rejected by quota reserve"
+            );
+            return Err(FsError::InsufficientStorage);
+        }
+        Ok(())
+    }
+
+    pub fn set_content_language(&mut self, content_language: Option<String>) -> &mut Self {
+        self.content_language = content_language;
+        self
+    }
+
+    pub fn set_enable_versions(&mut self, enable_versions: bool) -> &mut Self {
+        self.enable_versions = enable_versions;
+        self
+    }
+
+    pub fn set_overlay(&mut self, overlay: Overlay) -> &mut Self {
+        self.overlay = Arc::new(overlay);
+        self
+    }
+
+    pub fn set_protected_paths(&mut self, protected: ProtectedPaths) -> &mut Self {
+        self.protected = Arc::new(protected);
+        self
+    }
+
+    /// Record every mutating operation in this audit log
+    pub fn set_audit_log(&mut self, audit_log: Option<AuditLog>) -> &mut Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    async fn audit(&self, entry: audit::Entry, result: Result<(), FsError>) {
+        if let Some(audit_log) = self.audit_log.as_ref() {
+            audit_log.record(entry, result).await;
+        }
+    }
+
+    /// Run a mutating operation and record its outcome in the audit log
+    async fn audited<T>(
+        &self,
+        entry: audit::Entry,
+        fut: impl Future<Output = Result<T, FsError>>,
+    ) -> Result<T, FsError> {
+        let res = fut.await;
+        self.audit(entry, res.as_ref().map(|_| ()).map_err(|err| *err))
+            .await;
+        res
+    }
+
+    /// Refuse to change a protected path, or a folder with a protected entry
+    /// somewhere below it
+    async fn check_protected(&self, path: &Path) -> Result<(), FsError> {
+        if self.protected.is_empty() {
+            return Ok(());
+        }
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return Ok(());
+        };
+        if self.protected.matches(rel) {
+            debug!(path = %path.display(), "protected path");
+            return Err(FsError::Forbidden);
+        }
+        match self.get_file(path.to_path_buf()).await? {
+            Some(file) if matches!(file.r#type, FileType::Folder) => {}
+            _ => return Ok(()),
+        }
+        let mut dirs = vec![path.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for file in self.read_dir_and_cache(dir.clone()).await? {
+                let child = dir.join(&file.name);
+                let rel = child.strip_prefix(&self.root).unwrap_or(&child);
+                if self.protected.matches(rel) {
+                    debug!(path = %path.display(), protected = %child.display(), "folder contains a protected path");
+                    return Err(FsError::Forbidden);
+                }
+                if matches!(file.r#type, FileType::Folder) {
+                    dirs.push(child);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Path relative to the root when it has an overlay file
+    fn overlay_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.strip_prefix(&self.root)
+            .ok()
+            .filter(|rel| self.overlay.contains(rel))
+    }
+
+    fn version_path(&self, path: &Path) -> Option<VersionPath> {
+        if !self.enable_versions {
+            return None;
+        }
+        let is_versions_dir = |p: &Path| p.file_name().map(|n| n == VERSIONS_DIR).unwrap_or(false);
+        let parent = path.parent()?;
+        if is_versions_dir(path) {
+            return Some(VersionPath::Root(parent.to_path_buf()));
+        }
+        let file_name = path.file_name()?;
+        if is_versions_dir(parent) {
+            return Some(VersionPath::File(parent.parent()?.join(file_name)));
+        }
+        let grandparent = parent.parent()?;
+        if is_versions_dir(grandparent) {
+            let file_path = grandparent.parent()?.join(parent.file_name()?);
+            return Some(VersionPath::Revision(
+                file_path,
+                file_name.to_string_lossy().into_owned(),
+            ));
+        }
+        None
+    }
+
+    /// Regular file whose versions are exposed at `path`
+    async fn versioned_file(&self, path: &Path) -> Result<AliyunFile, FsError> {
+        let file = self
+            .get_file(path.to_path_buf())
+            .await?
+            .ok_or(FsError::NotFound)?;
+        if !matches!(file.r#type, FileType::File) {
+            return Err(FsError::NotFound);
+        }
+        Ok(file)
+    }
+
+    async fn list_versions(&self, file: &AliyunFile) -> Result<Vec<AliyunFile>, FsError> {
+        let revisions = self.drive.list_revisions(&file.id).await.map_err(|err| {
+            error!(file_id = %file.id, file_name = %file.name, error = %err, "list file versions failed");
+            FsError::GeneralFailure
+        })?;
+        Ok(revisions
+            .into_iter()
+            .map(|rev| version_entry(file, rev))
+            .collect())
+    }
+
+    /// Metadata of an entry in a virtual `.versions` folder
+    async fn version_metadata(&self, vpath: &VersionPath) -> Result<AliyunFile, FsError> {
+        match vpath {
+            VersionPath::Root(dir) => {
+                let dir_file = self.get_file(dir.clone()).await?.ok_or(FsError::NotFound)?;
+                if !matches!(dir_file.r#type, FileType::Folder) {
+                    return Err(FsError::NotFound);
+                }
+                Ok(AliyunFile {
+                    name: VERSIONS_DIR.to_string(),
+                    id: String::new(),
+                    ..dir_file
+                })
+            }
+            VersionPath::File(path) => {
+                let file = self.versioned_file(path).await?;
+                Ok(AliyunFile {
+                    r#type: FileType::Folder,
+                    size: 0,
+                    url: None,
+                    content_hash: None,
+                    ..file
+                })
+            }
+            VersionPath::Revision(path, name) => {
+                let file = self.versioned_file(path).await?;
+                self.list_versions(&file)
+                    .await?
+                    .into_iter()
+                    .find(|version| &version.name == name)
+                    .ok_or(FsError::NotFound)
+            }
+        }
+    }
+
+    /// Drop the cached metadata of `dav_path` so that it is fetched fresh next time
+    pub async fn invalidate_metadata(&self, dav_path: &DavPath) {
+        let path = self.normalize_dav_path(dav_path);
+        self.dir_cache.invalidate_parent(&path).await;
+    }
+
+    /// Check the cached entry of a file against Aliyun and drop the cached listing
+    /// when the file changed meanwhile, so that the size reported now is the one
+    /// downloaded next
+    pub async fn refresh_metadata(&self, dav_path: &DavPath) {
+        let path = self.normalize_dav_path(dav_path);
+        if self.is_hidden_path(&path) || self.version_path(&path).is_some() {
+            return;
+        }
+        // not cached, looked up on Aliyun anyway
+        let Ok(Some(cached)) = self.find_in_cache(&path).await else {
+            return;
+        };
+        if !matches!(cached.r#type, FileType::File) {
+            return;
+        }
+        match self.drive.get_file(&cached.id).await {
+            Ok(Some(file))
+                if file.size == cached.size && *file.updated_at == *cached.updated_at => {}
+            Ok(file) => {
+                debug!(
+                    path = %path.display(),
+                    cached_size = cached.size,
+                    size = ?file.map(|file| file.size),
+                    "cached metadata is outdated"
+                );
+                self.dir_cache.invalidate_parent(&path).await;
+                self.download_urls.invalidate(&cached.id).await;
+            }
+            Err(err) => {
+                warn!(path = %path.display(), error = %err, "refresh metadata failed, using cached one")
+            }
+        }
+    }
+
+    /// Whether an entry with this name is hidden from clients
+    fn is_hidden_name(&self, name: &str) -> bool {
+        (self.deny_hidden_files && name.starts_with('.'))
+            || self.hide_patterns.matches(name)
+            || (self.staged_upload && name.starts_with(STAGING_PREFIX))
+    }
+
+    fn is_placeholder(&self, file: &AliyunFile) -> bool {
+        matches!(file.r#type, FileType::File)
+            && file.size == 0
+            && self.placeholder_names.contains(&file.name)
+    }
+
+    /// Whether any component of the path below root is hidden from clients
+    fn is_hidden_path(&self, path: &Path) -> bool {
+        let rel_path = path.strip_prefix(&self.root).unwrap_or(path);
+        rel_path.components().any(|c| match c {
+            Component::Normal(name) => self.is_hidden_name(&name.to_string_lossy()),
+            _ => false,
+        })
+    }
+
+    async fn find_in_cache(&self, path: &Path) -> Result<Option<AliyunFile>, FsError> {
         if let Some(parent) = path.parent() {
             let parent_str = parent.to_string_lossy();
             let file_name = path
@@ -94,7 +1190,7 @@ impl AliyunDriveFileSystem {
                 .ok_or(FsError::NotFound)?
                 .to_string_lossy()
                 .into_owned();
-            let file = self.dir_cache.get(&parent_str).and_then(|files| {
+            let file = self.dir_cache.get(&parent_str).await.and_then(|files| {
                 for file in &files {
                     if file.name == file_name {
                         return Some(file.clone());
@@ -111,7 +1207,7 @@ impl AliyunDriveFileSystem {
 
     async fn get_file(&self, path: PathBuf) -> Result<Option<AliyunFile>, FsError> {
         let path_str = path.to_slash_lossy();
-        let file = self.find_in_cache(&path)?;
+        let file = self.find_in_cache(&path).await?;
         if let Some(file) = file {
             trace!(path = %path.display(), file_id = %file.id, "file found in cache");
             Ok(Some(file))
@@ -136,8 +1232,87 @@ impl AliyunDriveFileSystem {
                     return Ok(Some(file.clone()));
                 }
             }
-            Ok(None)
+            Ok(None)
+        }
+    }
+
+    /// Copy a file on the server side without transferring its content, folders
+    /// are copied by creating the destination folder and copying their children
+    fn copy_tree(
+        &self,
+        file: AliyunFile,
+        from: PathBuf,
+        to: PathBuf,
+    ) -> BoxFuture<'_, Result<(), FsError>> {
+        async move {
+            let to_parent = to.parent().ok_or(FsError::Forbidden)?;
+            let to_name = to
+                .file_name()
+                .ok_or(FsError::Forbidden)?
+                .to_string_lossy()
+                .into_owned();
+            let to_parent_file = self
+                .get_file(to_parent.to_path_buf())
+                .await?
+                .ok_or(FsError::NotFound)?;
+            if !matches!(file.r#type, FileType::Folder) {
+                let new_name = (file.name != to_name).then_some(to_name.as_str());
+                self.drive
+                    .copy_file(&file.id, &to_parent_file.id, new_name)
+                    .await
+                    .map_err(|err| {
+                        error!(from = %from.display(), to = %to.display(), error = %err, "copy file failed");
+                        write_error(&err)
+                    })?;
+                self.dir_cache.invalidate(&to).await;
+                self.dir_cache.invalidate_parent(&to).await;
+                return Ok(());
+            }
+
+            self.drive
+                .create_folder(&to_parent_file.id, &to_name)
+                .await
+                .map_err(|err| {
+                    error!(path = %to.display(), error = %err, "create folder failed");
+                    write_error(&err)
+                })?;
+            self.dir_cache.invalidate(to_parent).await;
+            for child in self.read_dir_and_cache(from.clone()).await? {
+                let name = child.name.clone();
+                self.copy_tree(child, from.join(&name), to.join(&name))
+                    .await?;
+            }
+            Ok(())
         }
+        .boxed()
+    }
+
+    /// Recycle bin items that were in the folder, named apart from the live ones
+    async fn list_trashed_children(&self, path: &Path) -> Result<Vec<AliyunFile>, FsError> {
+        let folder_id = if path == Path::new("/") {
+            "root".to_string()
+        } else {
+            self.get_file(path.to_path_buf())
+                .await?
+                .ok_or(FsError::NotFound)?
+                .id
+        };
+        let files = self
+            .drive
+            .list_trashed_children(&folder_id)
+            .await
+            .map_err(|err| {
+                error!(path = %path.display(), error = %err, "list trashed items failed");
+                FsError::GeneralFailure
+            })?;
+        debug!(path = %path.display(), count = files.len(), "list trashed items");
+        Ok(files
+            .into_iter()
+            .map(|file| AliyunFile {
+                name: format!("{} (trashed)", file.name),
+                ..file
+            })
+            .collect())
     }
 
     async fn read_dir_and_cache(&self, path: PathBuf) -> Result<Vec<AliyunFile>, FsError> {
@@ -145,19 +1320,22 @@ impl AliyunDriveFileSystem {
         let parent_file_id = if path_str == "/" {
             "root".to_string()
         } else {
-            match self.find_in_cache(&path) {
+            match self.find_in_cache(&path).await {
                 Ok(Some(file)) => file.id,
                 _ => match self.drive.get_by_path(&path_str).await {
                     Ok(Some(file)) => file.id,
                     Ok(None) => return Err(FsError::NotFound),
                     Err(err) => {
+                        if let Some(files) = self.stale_listing(&path_str, &err).await {
+                            return Ok(files);
+                        }
                         error!(path = %path_str, error = %err, "get_by_path failed");
                         return Err(FsError::GeneralFailure);
                     }
                 },
             }
         };
-        let mut files = if let Some(files) = self.dir_cache.get(&path_str) {
+        let mut files = if let Some(files) = self.dir_cache.get(&path_str).await {
             debug!(path = %path_str, "read_dir cache hit");
             files
         } else {
@@ -169,18 +1347,27 @@ impl AliyunDriveFileSystem {
                     debug!(path = %path_str, "read_dir cache miss");
                     files
                 }
+                Err(err) if err.is::<PartialListing>() => {
+                    // served but not cached, the next listing gets a chance to be complete
+                    warn!(path = %path_str, error = %err, "serving a partial listing");
+                    err.downcast::<PartialListing>().unwrap().0
+                }
                 Err(err) => {
-                    if let Some(req_err) = err.downcast_ref::<reqwest::Error>() {
-                        if matches!(req_err.status(), Some(reqwest::StatusCode::NOT_FOUND)) {
-                            debug!(path = %path_str, "read_dir not found");
-                            return Err(FsError::NotFound);
-                        } else {
+                    let not_found = err
+                        .downcast_ref::<reqwest::Error>()
+                        .and_then(|err| err.status())
+                        .map(|status| status == reqwest::StatusCode::NOT_FOUND)
+                        .unwrap_or(false);
+                    if not_found {
+                        debug!(path = %path_str, "read_dir not found");
+                        return Err(FsError::NotFound);
+                    }
+                    match self.stale_listing(&path_str, &err).await {
+                        Some(files) => files,
+                        None => {
                             error!(path = %path_str, error = %err, "list_files_and_cache failed");
                             return Err(FsError::GeneralFailure);
                         }
-                    } else {
-                        error!(path = %path_str, error = %err, "list_files_and_cache failed");
-                        return Err(FsError::GeneralFailure);
                     }
                 }
             }
@@ -193,6 +1380,90 @@ impl AliyunDriveFileSystem {
         Ok(files)
     }
 
+    /// Remove the files left behind by staged uploads interrupted by a restart
+    pub async fn sweep_staged(&self) {
+        let files = match self.drive.search_name_prefix(STAGING_PREFIX).await {
+            Ok(files) => files,
+            Err(err) => {
+                warn!(error = %err, "search staged uploads failed");
+                return;
+            }
+        };
+        for file in files {
+            let is_staged = matches!(file.r#type, FileType::File)
+                && file
+                    .name
+                    .strip_prefix(STAGING_PREFIX)
+                    .is_some_and(|id| id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit()));
+            let is_old = file
+                .updated_at
+                .elapsed()
+                .is_ok_and(|age| age >= STAGING_SWEEP_AGE);
+            if !is_staged || !is_old {
+                continue;
+            }
+            match self.drive.remove_file(&file.id, false).await {
+                Ok(()) => {
+                    info!(file_id = %file.id, file_name = %file.name, "removed stale staged upload")
+                }
+                Err(err) => {
+                    warn!(file_id = %file.id, file_name = %file.name, error = %err, "remove stale staged upload failed")
+                }
+            }
+        }
+    }
+
+    /// List and cache the given directories and their subdirectories up to
+    /// `depth` levels below them.
+    pub async fn prewarm(&self, paths: Vec<String>, depth: usize) {
+        const CONCURRENCY: usize = 8;
+
+        let started = Instant::now();
+        let mut entries = 0;
+        let mut level: Vec<PathBuf> = paths
+            .iter()
+            .map(|path| self.root.join(path.trim_start_matches('/')))
+            .collect();
+        for current_depth in 0..=depth {
+            if level.is_empty() {
+                break;
+            }
+            let results: Vec<_> = futures_util::stream::iter(level)
+                .map(|dir| async move {
+                    let res = self.read_dir_and_cache(dir.clone()).await;
+                    (dir, res)
+                })
+                .buffer_unordered(CONCURRENCY)
+                .collect()
+                .await;
+            let mut next_level = Vec::new();
+            for (dir, res) in results {
+                match res {
+                    Ok(files) => {
+                        entries += files.len();
+                        if current_depth < depth {
+                            next_level.extend(
+                                files
+                                    .iter()
+                                    .filter(|f| matches!(f.r#type, FileType::Folder))
+                                    .map(|f| dir.join(&f.name)),
+                            );
+                        }
+                    }
+                    Err(err) => {
+                        warn!(path = %dir.display(), error = ?err, "prewarm directory failed")
+                    }
+                }
+            }
+            level = next_level;
+        }
+        info!(
+            entries = entries,
+            elapsed = ?started.elapsed(),
+            "directory cache prewarmed"
+        );
+    }
+
     fn list_uploading_files(&self, parent_file_id: &str) -> Vec<AliyunFile> {
         self.uploading
             .get(parent_file_id)
@@ -223,6 +1494,59 @@ impl AliyunDriveFileSystem {
         self.dir_cache.insert(dir_path, files).await;
     }
 
+    /// JPEG thumbnail of an image `width` pixels wide, `None` for other files
+    pub async fn thumbnail(
+        &self,
+        dav_path: &DavPath,
+        width: u32,
+    ) -> Result<Option<Bytes>, FsError> {
+        let path = self.normalize_dav_path(dav_path);
+        if self.is_hidden_path(&path) {
+            return Err(FsError::NotFound);
+        }
+        let file = self
+            .get_file(path.clone())
+            .await?
+            .ok_or(FsError::NotFound)?;
+        if !matches!(file.r#type, FileType::File) {
+            return Ok(None);
+        }
+        let url = self
+            .drive
+            .get_thumbnail_url(&file.id, width)
+            .await
+            .map_err(|err| {
+                error!(path = %path.display(), error = %err, "get thumbnail url failed");
+                FsError::GeneralFailure
+            })?;
+        let Some(url) = url else {
+            return Ok(None);
+        };
+        let content = self.drive.download(&url, None).await.map_err(|err| {
+            error!(path = %path.display(), error = %err, "download thumbnail failed");
+            FsError::GeneralFailure
+        })?;
+        Ok(Some(content))
+    }
+
+    /// Whether two paths name the same file, either literally, trailing slashes
+    /// aside, or by resolving to the same file id
+    pub async fn is_same_resource(&self, a: &DavPath, b: &DavPath) -> bool {
+        let a = self.normalize_dav_path(a);
+        let b = self.normalize_dav_path(b);
+        if a == b {
+            return true;
+        }
+        // versions share the id of their file
+        if self.version_path(&a).is_some() || self.version_path(&b).is_some() {
+            return false;
+        }
+        match (self.get_file(a).await, self.get_file(b).await) {
+            (Ok(Some(a)), Ok(Some(b))) => !a.id.is_empty() && a.id == b.id,
+            _ => false,
+        }
+    }
+
     fn normalize_dav_path(&self, dav_path: &DavPath) -> PathBuf {
         let path = dav_path.as_pathbuf();
         if self.root.parent().is_none() || path.starts_with(&self.root) {
@@ -241,11 +1565,49 @@ impl DavFileSystem for AliyunDriveFileSystem {
         &'a self,
         dav_path: &'a DavPath,
         options: OpenOptions,
-    ) -> FsFuture<Box<dyn DavFile>> {
+    ) -> FsFuture<'a, Box<dyn DavFile>> {
         let path = self.normalize_dav_path(dav_path);
         let mode = if options.write { "write" } else { "read" };
         debug!(path = %path.display(), mode = %mode, "fs: open");
         async move {
+            if self.is_hidden_path(&path) {
+                return Err(FsError::NotFound);
+            }
+            if options.write && self.is_draining_uploads() {
+                debug!(path = %path.display(), "shutting down, upload rejected");
+                return Err(FsError::GeneralFailure);
+            }
+            if let Some(vpath) = self.version_path(&path) {
+                if options.write {
+                    return Err(FsError::Forbidden);
+                }
+                let VersionPath::Revision(file_path, _) = &vpath else {
+                    return Err(FsError::NotFound);
+                };
+                let version = self.version_metadata(&vpath).await?;
+                let parent_path = file_path.parent().ok_or(FsError::NotFound)?;
+                let revision_id = version_revision_id(&version.name).to_string();
+                let mut dav_file = AliyunDavFile::new(
+                    self.clone(),
+                    version,
+                    String::new(),
+                    parent_path.to_path_buf(),
+                    0,
+                    None,
+                );
+                dav_file.http_download = self.prefer_http_download;
+                dav_file.revision_id = Some(revision_id);
+                return Ok(Box::new(dav_file) as Box<dyn DavFile>);
+            }
+            if let Some(rel) = self.overlay_path(&path) {
+                if options.write {
+                    return Err(FsError::Forbidden);
+                }
+                if self.get_file(path.clone()).await?.is_none() {
+                    let file = self.overlay.open(rel).ok_or(FsError::NotFound)?;
+                    return Ok(Box::new(file) as Box<dyn DavFile>);
+                }
+            }
             if options.append {
                 // Can't support open in write-append mode
                 error!(path = %path.display(), "unsupported write-append mode");
@@ -267,13 +1629,34 @@ impl DavFileSystem for AliyunDriveFileSystem {
                     None
                 }
             });
+            if options.write {
+                self.check_filename(&path)?;
+                self.check_protected(&path).await?;
+            }
             let mut dav_file = if let Some(file) = self.get_file(path.clone()).await? {
-                if options.write && options.create_new {
+                if !options.write && self.is_placeholder(&file) {
+                    return Err(FsError::NotFound);
+                }
+                // a hidden placeholder does not count as an existing file
+                if options.write && options.create_new && !self.is_placeholder(&file) {
+                    return Err(FsError::Exists);
+                }
+                if options.write && matches!(file.r#type, FileType::Folder) {
+                    // never replace a collection with a file, PUT answers 409 Conflict
+                    debug!(path = %path.display(), "can not write to a directory");
                     return Err(FsError::Exists);
                 }
-                if options.write && self.read_only {
+                if options.write && self.is_read_only() {
                     return Err(FsError::Forbidden);
                 }
+                if let (true, Some(size)) = (options.write, options.size) {
+                    self.check_upload_size(size)?;
+                    self.check_quota_reserve(size.saturating_sub(file.size))
+                        .await?;
+                }
+                if options.write && self.spools(&file.name) {
+                    self.check_spool_space(options.size.unwrap_or_default())?;
+                }
                 AliyunDavFile::new(
                     self.clone(),
                     file,
@@ -283,15 +1666,22 @@ impl DavFileSystem for AliyunDriveFileSystem {
                     sha1,
                 )
             } else if options.write && (options.create || options.create_new) {
-                if self.read_only {
+                if self.is_read_only() {
                     return Err(FsError::Forbidden);
                 }
 
                 let size = options.size;
+                if let Some(size) = size {
+                    self.check_upload_size(size)?;
+                    self.check_quota_reserve(size).await?;
+                }
                 let name = dav_path
                     .file_name()
                     .ok_or(FsError::GeneralFailure)?
                     .to_string();
+                if self.spools(&name) {
+                    self.check_spool_space(size.unwrap_or_default())?;
+                }
 
                 // 忽略 macOS 上的一些特殊文件
                 if name == ".DS_Store" || name.starts_with("._") {
@@ -323,6 +1713,30 @@ impl DavFileSystem for AliyunDriveFileSystem {
                 return Err(FsError::NotFound);
             };
             dav_file.http_download = self.prefer_http_download;
+            if !options.write && self.is_upstream_down() {
+                dav_file.stale_copy = dav_file.disk_cached_copy();
+            }
+            if options.write {
+                dav_file.active_upload = Some(ActiveUpload::new(
+                    self.uploads.clone(),
+                    path.clone(),
+                    options.size,
+                ));
+            }
+            if let (false, Some(active_reads)) = (options.write, self.active_reads.as_ref()) {
+                dav_file.active_read = Some(ActiveRead::new(active_reads.clone(), path.clone()));
+            }
+            if let (false, Some((rate, burst))) = (options.write, self.download_rate_limit) {
+                dav_file.download_limiter =
+                    Some(RateLimiter::with_burst(rate as f64, burst.max(1) as f64));
+            }
+            if options.write {
+                dav_file.write_mode = true;
+                if self.strip_exif {
+                    dav_file.strip_metadata = ImageKind::from_name(&dav_file.file.name);
+                }
+                dav_file.spool_upload = self.rapid_upload || dav_file.strip_metadata.is_some();
+            }
             Ok(Box::new(dav_file) as Box<dyn DavFile>)
         }
         .boxed()
@@ -332,14 +1746,63 @@ impl DavFileSystem for AliyunDriveFileSystem {
         &'a self,
         path: &'a DavPath,
         _meta: ReadDirMeta,
-    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
         let path = self.normalize_dav_path(path);
         debug!(path = %path.display(), "fs: read_dir");
         async move {
-            let files = self.read_dir_and_cache(path.clone()).await?;
+            if self.is_hidden_path(&path) {
+                return Err(FsError::NotFound);
+            }
+            let files = match self.version_path(&path) {
+                Some(VersionPath::Root(dir)) => self
+                    .read_dir_and_cache(dir)
+                    .await?
+                    .into_iter()
+                    .filter(|file| matches!(file.r#type, FileType::File))
+                    .map(|file| AliyunFile {
+                        r#type: FileType::Folder,
+                        size: 0,
+                        url: None,
+                        content_hash: None,
+                        ..file
+                    })
+                    .collect(),
+                Some(VersionPath::File(file_path)) => {
+                    let file = self.versioned_file(&file_path).await?;
+                    self.list_versions(&file).await?
+                }
+                Some(VersionPath::Revision(..)) => return Err(FsError::NotFound),
+                None => {
+                    let mut files = self.read_dir_and_cache(path.clone()).await?;
+                    if let Ok(rel) = path.strip_prefix(&self.root) {
+                        let overlay: Vec<AliyunFile> = self
+                            .overlay
+                            .children(rel)
+                            .filter(|o| !files.iter().any(|f| f.name == o.name))
+                            .cloned()
+                            .collect();
+                        files.extend(overlay);
+                    }
+                    if self.listing_include_trashed {
+                        files.extend(self.list_trashed_children(&path).await?);
+                    }
+                    files
+                }
+            };
             let mut v: Vec<Box<dyn DavDirEntry>> = Vec::with_capacity(files.len());
             for file in files {
-                v.push(Box::new(file));
+                if self.is_hidden_name(&file.name) || self.is_placeholder(&file) {
+                    continue;
+                }
+                if self.content_hash_etag {
+                    v.push(Box::new(ContentHashEtag(file)));
+                } else {
+                    v.push(Box::new(file));
+                }
+            }
+            if let Some(limit) = self.dir_entry_limit.filter(|limit| v.len() > *limit) {
+                debug!(path = %path.display(), entries = v.len(), limit = limit, "listing truncated");
+                v.truncate(limit);
             }
             let stream = futures_util::stream::iter(v);
             Ok(Box::pin(stream) as FsStream<Box<dyn DavDirEntry>>)
@@ -347,23 +1810,45 @@ impl DavFileSystem for AliyunDriveFileSystem {
         .boxed()
     }
 
-    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
         let path = self.normalize_dav_path(path);
         debug!(path = %path.display(), "fs: metadata");
         async move {
-            let file = self.get_file(path).await?.ok_or(FsError::NotFound)?;
-            Ok(Box::new(file) as Box<dyn DavMetaData>)
+            if self.is_hidden_path(&path) {
+                return Err(FsError::NotFound);
+            }
+            if let Some(vpath) = self.version_path(&path) {
+                let file = self.version_metadata(&vpath).await?;
+                return Ok(self.file_metadata(file));
+            }
+            let file = match self.get_file(path.clone()).await? {
+                Some(file) if self.is_placeholder(&file) => return Err(FsError::NotFound),
+                Some(file) => file,
+                None => self
+                    .overlay_path(&path)
+                    .and_then(|rel| self.overlay.metadata(rel))
+                    .ok_or(FsError::NotFound)?,
+            };
+            Ok(self.file_metadata(file))
         }
         .boxed()
     }
 
-    fn create_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<()> {
+    fn create_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<'a, ()> {
         let path = self.normalize_dav_path(dav_path);
         debug!(path = %path.display(), "fs: create_dir");
-        async move {
-            if self.read_only {
+        let entry = audit::Entry::new(Operation::Mkcol, &path);
+        let fut = async move {
+            if self.is_hidden_path(&path) {
+                return Err(FsError::NotFound);
+            }
+            if self.is_read_only()
+                || self.version_path(&path).is_some()
+                || self.overlay_path(&path).is_some()
+            {
                 return Err(FsError::Forbidden);
             }
+            self.check_filename(&path)?;
 
             let parent_path = path.parent().ok_or(FsError::NotFound)?;
             let parent_file = self
@@ -380,24 +1865,32 @@ impl DavFileSystem for AliyunDriveFileSystem {
                     .await
                     .map_err(|err| {
                         error!(path = %path.display(), error = %err, "create folder failed");
-                        FsError::GeneralFailure
+                        write_error(&err)
                     })?;
                 self.dir_cache.invalidate(parent_path).await;
                 Ok(())
             } else {
                 Err(FsError::Forbidden)
             }
-        }
-        .boxed()
+        };
+        self.audited(entry, self.record_write(fut)).boxed()
     }
 
-    fn remove_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<()> {
+    fn remove_dir<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<'a, ()> {
         let path = self.normalize_dav_path(dav_path);
         debug!(path = %path.display(), "fs: remove_dir");
-        async move {
-            if self.read_only {
+        let entry = audit::Entry::new(Operation::Delete, &path);
+        let fut = async move {
+            if self.is_hidden_path(&path) {
+                return Err(FsError::NotFound);
+            }
+            if self.is_read_only()
+                || self.version_path(&path).is_some()
+                || self.overlay_path(&path).is_some()
+            {
                 return Err(FsError::Forbidden);
             }
+            self.check_protected(&path).await?;
 
             let file = self
                 .get_file(path.clone())
@@ -406,27 +1899,36 @@ impl DavFileSystem for AliyunDriveFileSystem {
             if !matches!(file.r#type, FileType::Folder) {
                 return Err(FsError::Forbidden);
             }
+            audit::note_file_id(&file.id);
             self.drive
                 .remove_file(&file.id, !self.no_trash)
                 .await
                 .map_err(|err| {
                     error!(path = %path.display(), error = %err, "remove directory failed");
-                    FsError::GeneralFailure
+                    write_error(&err)
                 })?;
             self.dir_cache.invalidate(&path).await;
             self.dir_cache.invalidate_parent(&path).await;
             Ok(())
-        }
-        .boxed()
+        };
+        self.audited(entry, self.record_write(fut)).boxed()
     }
 
-    fn remove_file<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<()> {
+    fn remove_file<'a>(&'a self, dav_path: &'a DavPath) -> FsFuture<'a, ()> {
         let path = self.normalize_dav_path(dav_path);
         debug!(path = %path.display(), "fs: remove_file");
-        async move {
-            if self.read_only {
+        let entry = audit::Entry::new(Operation::Delete, &path);
+        let fut = async move {
+            if self.is_hidden_path(&path) {
+                return Err(FsError::NotFound);
+            }
+            if self.is_read_only()
+                || self.version_path(&path).is_some()
+                || self.overlay_path(&path).is_some()
+            {
                 return Err(FsError::Forbidden);
             }
+            self.check_protected(&path).await?;
 
             let file = self
                 .get_file(path.clone())
@@ -435,59 +1937,70 @@ impl DavFileSystem for AliyunDriveFileSystem {
             if !matches!(file.r#type, FileType::File) {
                 return Err(FsError::Forbidden);
             }
+            audit::note_file_id(&file.id);
             self.drive
                 .remove_file(&file.id, !self.no_trash)
                 .await
                 .map_err(|err| {
                     error!(path = %path.display(), error = %err, "remove file failed");
-                    FsError::GeneralFailure
+                    write_error(&err)
                 })?;
             self.dir_cache.invalidate_parent(&path).await;
             Ok(())
-        }
-        .boxed()
+        };
+        self.audited(entry, self.record_write(fut)).boxed()
     }
 
-    fn copy<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<()> {
+    fn copy<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<'a, ()> {
         let from = self.normalize_dav_path(from_dav);
         let to = self.normalize_dav_path(to_dav);
         debug!(from = %from.display(), to = %to.display(), "fs: copy");
-        async move {
-            if self.read_only {
+        let entry = audit::Entry::new(Operation::Copy, &from).destination(&to);
+        let fut = async move {
+            if self.is_hidden_path(&from) || self.is_hidden_path(&to) {
+                return Err(FsError::NotFound);
+            }
+            if self.is_read_only()
+                || self.version_path(&from).is_some()
+                || self.version_path(&to).is_some()
+                || self.overlay_path(&from).is_some()
+                || self.overlay_path(&to).is_some()
+            {
                 return Err(FsError::Forbidden);
             }
+            self.check_filename(&to)?;
+            self.check_protected(&to).await?;
 
             let file = self
                 .get_file(from.clone())
                 .await?
                 .ok_or(FsError::NotFound)?;
-            let to_parent_file = self
-                .get_file(to.parent().unwrap().to_path_buf())
-                .await?
-                .ok_or(FsError::NotFound)?;
-            self.drive
-                .copy_file(&file.id, &to_parent_file.id)
-                .await
-                .map_err(|err| {
-                    error!(from = %from.display(), to = %to.display(), error = %err, "copy file failed");
-                    FsError::GeneralFailure
-                })?;
-
-            self.dir_cache.invalidate(&to).await;
-            self.dir_cache.invalidate_parent(&to).await;
-            Ok(())
-        }
-        .boxed()
+            audit::note_file_id(&file.id);
+            self.copy_tree(file, from, to).await
+        };
+        self.audited(entry, self.record_write(fut)).boxed()
     }
 
-    fn rename<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<()> {
+    fn rename<'a>(&'a self, from_dav: &'a DavPath, to_dav: &'a DavPath) -> FsFuture<'a, ()> {
         let from = self.normalize_dav_path(from_dav);
         let to = self.normalize_dav_path(to_dav);
         debug!(from = %from.display(), to = %to.display(), "fs: rename");
-        async move {
-            if self.read_only {
+        let entry = audit::Entry::new(Operation::Move, &from).destination(&to);
+        let fut = async move {
+            if self.is_hidden_path(&from) || self.is_hidden_path(&to) {
+                return Err(FsError::NotFound);
+            }
+            if self.is_read_only()
+                || self.version_path(&from).is_some()
+                || self.version_path(&to).is_some()
+                || self.overlay_path(&from).is_some()
+                || self.overlay_path(&to).is_some()
+            {
                 return Err(FsError::Forbidden);
             }
+            self.check_filename(&to)?;
+            self.check_protected(&from).await?;
+            self.check_protected(&to).await?;
 
             let is_dir;
             if from.parent() == to.parent() {
@@ -498,13 +2011,14 @@ impl DavFileSystem for AliyunDriveFileSystem {
                         .await?
                         .ok_or(FsError::NotFound)?;
                     is_dir = matches!(file.r#type, FileType::Folder);
+                    audit::note_file_id(&file.id);
                     let name = name.to_string_lossy().into_owned();
                     self.drive
                         .rename_file(&file.id, &name)
                         .await
                         .map_err(|err| {
                             error!(from = %from.display(), to = %to.display(), error = %err, "rename file failed");
-                            FsError::GeneralFailure
+                            write_error(&err)
                         })?;
                 } else {
                     return Err(FsError::Forbidden);
@@ -516,6 +2030,7 @@ impl DavFileSystem for AliyunDriveFileSystem {
                     .await?
                     .ok_or(FsError::NotFound)?;
                 is_dir = matches!(file.r#type, FileType::Folder);
+                audit::note_file_id(&file.id);
                 let to_parent_file = self
                     .get_file(to.parent().unwrap().to_path_buf())
                     .await?
@@ -526,7 +2041,7 @@ impl DavFileSystem for AliyunDriveFileSystem {
                     .await
                     .map_err(|err| {
                         error!(from = %from.display(), to = %to.display(), error = %err, "move file failed");
-                        FsError::GeneralFailure
+                        write_error(&err)
                     })?;
             }
 
@@ -536,17 +2051,14 @@ impl DavFileSystem for AliyunDriveFileSystem {
             self.dir_cache.invalidate_parent(&from).await;
             self.dir_cache.invalidate_parent(&to).await;
             Ok(())
-        }
-        .boxed()
+        };
+        self.audited(entry, self.record_write(fut)).boxed()
     }
 
-    fn get_quota(&self) -> FsFuture<(u64, Option<u64>)> {
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
         debug!("fs: get_quota");
         async move {
-            let (used, total) = self.drive.get_quota().await.map_err(|err| {
-                error!(error = %err, "get quota failed");
-                FsError::GeneralFailure
-            })?;
+            let (used, total) = self.cached_quota().await?;
             Ok((used, Some(total)))
         }
         .boxed()
@@ -559,69 +2071,320 @@ impl DavFileSystem for AliyunDriveFileSystem {
         Box::pin(ready(true))
     }
 
-    fn get_prop(&self, dav_path: &DavPath, prop: dav_server::fs::DavProp) -> FsFuture<Vec<u8>> {
-        let path = self.normalize_dav_path(dav_path);
-        let prop_name = match prop.prefix.as_ref() {
-            Some(prefix) => format!("{}:{}", prefix, prop.name),
-            None => prop.name.to_string(),
+    fn get_prop(&self, dav_path: &DavPath, prop: dav_server::fs::DavProp) -> FsFuture<'_, Vec<u8>> {
+        let path = self.normalize_dav_path(dav_path);
+        let prop_name = match prop.prefix.as_ref() {
+            Some(prefix) => format!("{}:{}", prefix, prop.name),
+            None => prop.name.to_string(),
+        };
+        debug!(path = %path.display(), prop = %prop_name, "fs: get_prop");
+        async move {
+            if self.is_hidden_path(&path) {
+                return Err(FsError::NotFound);
+            }
+            if prop.namespace.as_deref() == Some("DAV:") && prop.name == "getcontentlanguage" {
+                if let Some(language) = self.content_language.as_deref() {
+                    let xml = format!(
+                        r#"<?xml version="1.0"?><D:getcontentlanguage xmlns:D="DAV:">{}</D:getcontentlanguage>"#,
+                        language
+                    );
+                    return Ok(xml.into_bytes());
+                }
+                return Err(FsError::NotFound);
+            }
+            if prop.namespace.as_deref() == Some("http://owncloud.org/ns")
+                && prop.name == "checksums"
+            {
+                let file = self.get_file(path).await?.ok_or(FsError::NotFound)?;
+                if let Some(sha1) = file.content_hash {
+                    let xml = format!(
+                        r#"<?xml version="1.0"?>
+                        <oc:checksums xmlns:d="DAV:" xmlns:nc="http://nextcloud.org/ns" xmlns:oc="http://owncloud.org/ns">
+                            <oc:checksum>sha1:{}</oc:checksum>
+                        </oc:checksums>
+                    "#,
+                        sha1
+                    );
+                    return Ok(xml.into_bytes());
+                }
+            }
+            Err(FsError::NotImplemented)
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct UploadState {
+    size: u64,
+    buffer: BytesMut,
+    chunk_count: u64,
+    chunk: u64,
+    part_size: usize,
+    parts: PartUploads,
+    upload_id: String,
+    /// Upload url of each part, replaced by all parts once refreshed by one of them
+    upload_urls: Arc<tokio::sync::Mutex<Vec<String>>>,
+    sha1: Option<String>,
+    proof: Option<RapidUploadProof>,
+    rapid_uploaded: bool,
+    /// Name uploaded under until the upload is complete
+    staging_name: Option<String>,
+    /// Id of the overwritten file, removed once the upload is complete
+    replaced_file_id: Option<String>,
+}
+
+impl Default for UploadState {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            buffer: BytesMut::new(),
+            chunk_count: 0,
+            chunk: 1,
+            part_size: 0,
+            parts: PartUploads::default(),
+            upload_id: String::new(),
+            upload_urls: Arc::default(),
+            sha1: None,
+            proof: None,
+            rapid_uploaded: false,
+            staging_name: None,
+            replaced_file_id: None,
+        }
+    }
+}
+
+struct AliyunDavFile {
+    fs: AliyunDriveFileSystem,
+    file: AliyunFile,
+    parent_file_id: String,
+    parent_dir: PathBuf,
+    current_pos: u64,
+    upload_state: UploadState,
+    http_download: bool,
+    /// Spool the upload to disk before uploading it
+    spool_upload: bool,
+    /// Strip metadata of uploaded image
+    strip_metadata: Option<ImageKind>,
+    spool: Option<SpoolFile>,
+    /// Upstream retries spent reading this file so far
+    read_retries: u32,
+    /// Serve this version of the file instead of the current one
+    revision_id: Option<String>,
+    /// Opened for writing
+    write_mode: bool,
+    /// Serve the content from this local copy instead of the upstream
+    stale_copy: Option<PathBuf>,
+    /// Copy of the content downloaded so far, for the disk cache
+    cache_writer: Option<DiskCacheWriter>,
+    /// Bytes received for upload so far
+    written: u64,
+    active_read: Option<ActiveRead>,
+    active_upload: Option<ActiveUpload>,
+    prefetch: Prefetch,
+    /// Paces what is sent to the client of this download
+    download_limiter: Option<RateLimiter>,
+}
+
+/// Registers an upload as in progress for as long as it lives
+struct ActiveUpload {
+    uploads: Arc<Uploads>,
+    id: u64,
+    received: Arc<AtomicU64>,
+}
+
+impl ActiveUpload {
+    fn new(uploads: Arc<Uploads>, path: PathBuf, size: Option<u64>) -> Self {
+        let id = uploads.next_id.fetch_add(1, Ordering::Relaxed);
+        let received = Arc::new(AtomicU64::new(0));
+        uploads.active.insert(
+            id,
+            UploadProgress {
+                path,
+                size,
+                received: received.clone(),
+            },
+        );
+        Self {
+            uploads,
+            id,
+            received,
+        }
+    }
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        self.uploads.active.remove(&self.id);
+    }
+}
+
+impl Drop for AliyunDavFile {
+    fn drop(&mut self) {
+        // a staged upload abandoned before completing, e.g. by a client
+        // disconnecting, would leave its staged file behind
+        if self.upload_state.staging_name.take().is_none() {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let drive = self.fs.drive.clone();
+        let file_id = std::mem::take(&mut self.file.id);
+        handle.spawn(async move {
+            if let Err(err) = drive.remove_file(&file_id, false).await {
+                warn!(file_id = %file_id, error = %err, "delete abandoned staged upload failed");
+            }
+        });
+    }
+}
+
+/// Registers a path as being read for as long as it lives
+struct ActiveRead {
+    active_reads: Arc<DashMap<PathBuf, usize>>,
+    path: PathBuf,
+}
+
+impl ActiveRead {
+    fn new(active_reads: Arc<DashMap<PathBuf, usize>>, path: PathBuf) -> Self {
+        *active_reads.entry(path.clone()).or_default() += 1;
+        Self { active_reads, path }
+    }
+}
+
+impl Drop for ActiveRead {
+    fn drop(&mut self) {
+        self.active_reads.remove_if_mut(&self.path, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+/// Part uploads in progress, in the order of the parts, aborted when the
+/// upload is dropped
+#[derive(Debug, Default)]
+struct PartUploads {
+    uploads: VecDeque<(u64, JoinHandle<Result<()>>)>,
+}
+
+impl Drop for PartUploads {
+    fn drop(&mut self) {
+        for (_, task) in self.uploads.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Part of a multipart upload
+struct UploadPart<'a> {
+    file_id: &'a str,
+    upload_id: &'a str,
+    chunk_count: u64,
+    part: u64,
+    upload_urls: Arc<tokio::sync::Mutex<Vec<String>>>,
+}
+
+/// Upload one part of an upload session, retrying just this part, with a
+/// fresh upload url when it expired
+async fn upload_part(
+    drive: &dyn DriveBackend,
+    part: UploadPart<'_>,
+    permit: OwnedSemaphorePermit,
+    data: Bytes,
+) -> Result<()> {
+    let UploadPart {
+        file_id,
+        upload_id,
+        chunk_count,
+        part,
+        upload_urls,
+    } = part;
+    let index = part as usize - 1;
+    let mut upload_url = upload_urls.lock().await[index].clone();
+    let mut permit = Some(permit);
+    let mut attempt = 1;
+    loop {
+        if permit.is_none() {
+            permit = Some(drive.api_semaphore().acquire().await);
+        }
+        let err = match drive.upload(&upload_url, data.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
         };
-        debug!(path = %path.display(), prop = %prop_name, "fs: get_prop");
-        async move {
-            if prop.namespace.as_deref() == Some("http://owncloud.org/ns")
-                && prop.name == "checksums"
-            {
-                let file = self.get_file(path).await?.ok_or(FsError::NotFound)?;
-                if let Some(sha1) = file.content_hash {
-                    let xml = format!(
-                        r#"<?xml version="1.0"?>
-                        <oc:checksums xmlns:d="DAV:" xmlns:nc="http://nextcloud.org/ns" xmlns:oc="http://owncloud.org/ns">
-                            <oc:checksum>sha1:{}</oc:checksum>
-                        </oc:checksums>
-                    "#,
-                        sha1
-                    );
-                    return Ok(xml.into_bytes());
+        if attempt >= PART_UPLOAD_ATTEMPTS {
+            return Err(err);
+        }
+        warn!(
+            file_id = %file_id,
+            attempt = attempt,
+            error = %err,
+            "upload file chunk {} failed, retrying",
+            part
+        );
+        if err.to_string().contains("expired") {
+            // refreshing is an API call waiting for a permit of its own
+            drop(permit.take());
+            let mut urls = upload_urls.lock().await;
+            // unless another part refreshed them meanwhile
+            if urls[index] == upload_url {
+                match drive.get_upload_url(file_id, upload_id, chunk_count).await {
+                    Ok(part_info_list) if part_info_list.len() == urls.len() => {
+                        *urls = part_info_list.into_iter().map(|x| x.upload_url).collect();
+                    }
+                    Ok(_) => {
+                        warn!(file_id = %file_id, "refreshed upload urls don't match the parts");
+                    }
+                    Err(err) => {
+                        warn!(file_id = %file_id, error = %err, "refresh upload urls failed");
+                    }
                 }
             }
-            Err(FsError::NotImplemented)
+            upload_url = urls[index].clone();
+        } else {
+            tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
         }
-        .boxed()
+        attempt += 1;
     }
 }
 
-#[derive(Debug, Clone)]
-struct UploadState {
-    size: u64,
-    buffer: BytesMut,
-    chunk_count: u64,
-    chunk: u64,
-    upload_id: String,
-    upload_urls: Vec<String>,
-    sha1: Option<String>,
+/// Downloads of the chunks following the read position, in file order.
+/// Outstanding downloads are cancelled when dropped.
+#[derive(Default)]
+struct Prefetch {
+    chunks: VecDeque<(u64, usize, JoinHandle<Result<Bytes>>)>,
 }
 
-impl Default for UploadState {
-    fn default() -> Self {
-        Self {
-            size: 0,
-            buffer: BytesMut::new(),
-            chunk_count: 0,
-            chunk: 1,
-            upload_id: String::new(),
-            upload_urls: Vec::new(),
-            sha1: None,
+impl Prefetch {
+    /// Download of `len` bytes at `pos` if it is the next one queued,
+    /// otherwise the reader moved elsewhere and the queue is dropped
+    fn take(&mut self, pos: u64, len: usize) -> Option<JoinHandle<Result<Bytes>>> {
+        match self.chunks.front() {
+            Some((p, l, _)) if *p == pos && *l == len => self.chunks.pop_front().map(|c| c.2),
+            Some(_) => {
+                self.clear();
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Position following the last queued chunk
+    fn end(&self) -> Option<u64> {
+        self.chunks.back().map(|(pos, len, _)| pos + *len as u64)
+    }
+
+    fn clear(&mut self) {
+        for (_, _, task) in self.chunks.drain(..) {
+            task.abort();
         }
     }
 }
 
-struct AliyunDavFile {
-    fs: AliyunDriveFileSystem,
-    file: AliyunFile,
-    parent_file_id: String,
-    parent_dir: PathBuf,
-    current_pos: u64,
-    upload_state: UploadState,
-    http_download: bool,
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.clear();
+    }
 }
 
 impl Debug for AliyunDavFile {
@@ -656,16 +2419,509 @@ impl AliyunDavFile {
                 ..Default::default()
             },
             http_download: false,
+            spool_upload: false,
+            strip_metadata: None,
+            spool: None,
+            read_retries: 0,
+            revision_id: None,
+            write_mode: false,
+            stale_copy: None,
+            cache_writer: None,
+            written: 0,
+            active_read: None,
+            active_upload: None,
+            prefetch: Prefetch::default(),
+            download_limiter: None,
         }
     }
 
+    /// Get a valid download url, from the cache shared with the other readers
+    /// of this file if possible.
     async fn get_download_url(&self) -> Result<GetFileDownloadUrlResponse, FsError> {
-        self.fs.drive.get_download_url(&self.file.id).await.map_err(|err| {
+        let key = self.download_url_key();
+        if let Some(url) = self.fs.download_urls.get(&key) {
+            if !is_url_expired(&url) {
+                trace!(file_id = %self.file.id, "download url found in cache");
+                return Ok(GetFileDownloadUrlResponse {
+                    url,
+                    internal_url: None,
+                    streams_url: HashMap::new(),
+                });
+            }
+        }
+        let mut res = self.fetch_download_url().await?;
+        if self.fs.download_url_internal {
+            match res.internal_url.take().filter(|url| !url.is_empty()) {
+                Some(url) => res.url = url,
+                None => debug!(file_id = %self.file.id, "no internal download url, use public one"),
+            }
+        }
+        if !res.url.is_empty() {
+            self.fs.download_urls.insert(key, res.url.clone()).await;
+        }
+        Ok(res)
+    }
+
+    fn download_url_key(&self) -> String {
+        match self.revision_id.as_deref() {
+            Some(revision_id) => format!("{}@{}", self.file.id, revision_id),
+            None => self.file.id.clone(),
+        }
+    }
+
+    /// Download `count` bytes from the current position, joining an identical
+    /// download already in flight when reads are coalesced.
+    ///
+    /// Only one chunk of at most the read buffer size is shared per read, every
+    /// reader gets its own handle to the bytes once they are complete so a slow
+    /// reader never holds back the others.
+    async fn download_shared(
+        &mut self,
+        download_url: String,
+        count: usize,
+    ) -> Result<(Bytes, String), FsError> {
+        let Some(inflight) = self.fs.inflight_reads.clone() else {
+            return self.download_range(download_url, count).await;
+        };
+        let key = (self.download_url_key(), self.current_pos, count);
+        let read = inflight.entry(key.clone()).or_default().clone();
+        let mut leader = false;
+        let res = read
+            .get_or_init(|| {
+                leader = true;
+                self.download_range(download_url, count)
+            })
+            .await
+            .clone();
+        if !leader {
+            debug!(file_id = %self.file.id, pos = key.1, count = count, "read joined an in-flight download");
+        }
+        inflight.remove_if(&key, |_, v| Arc::ptr_eq(v, &read));
+        res
+    }
+
+    async fn fetch_download_url(&self) -> Result<GetFileDownloadUrlResponse, FsError> {
+        let res = match self.revision_id.as_deref() {
+            Some(revision_id) => {
+                self.fs
+                    .drive
+                    .get_revision_download_url(&self.file.id, revision_id)
+                    .await
+            }
+            None => self.fs.drive.get_download_url(&self.file.id).await,
+        };
+        res.map_err(|err| {
             error!(file_id = %self.file.id, file_name = %self.file.name, error = %err, "get download url failed");
             FsError::GeneralFailure
         })
     }
 
+    /// Download `count` bytes from the current position, resuming from the last
+    /// received byte when the connection is reset before all bytes arrived, or
+    /// with a fresh download url when the url expired during a long read.
+    ///
+    /// Returns the content along with the download url that was finally used.
+    async fn download_range(
+        &mut self,
+        mut download_url: String,
+        count: usize,
+    ) -> Result<(Bytes, String), FsError> {
+        let expected = count.min(self.file.size.saturating_sub(self.current_pos) as usize);
+        let mut buf = BytesMut::with_capacity(expected);
+        let mut retries = 0;
+        let mut url_refreshed = false;
+        loop {
+            let mut url =
+                reqwest::Url::parse(&download_url).map_err(|_| FsError::GeneralFailure)?;
+            if self.http_download {
+                url.set_scheme("http")
+                    .map_err(|_| FsError::GeneralFailure)?;
+            }
+            let start_pos = self.current_pos + buf.len() as u64;
+            let res = self
+                .fs
+                .drive
+                .download_range_into(url, start_pos, count - buf.len(), &mut buf)
+                .await;
+            let can_retry = retries < self.fs.retry_download_on_reset;
+            let err = match res {
+                Ok(()) if buf.len() >= expected || !can_retry => {
+                    return Ok((buf.freeze(), download_url));
+                }
+                Ok(()) => anyhow::anyhow!(
+                    "unexpected end of stream after {} of {} bytes",
+                    buf.len(),
+                    expected
+                ),
+                Err(err) if can_retry && is_connection_reset(&err) => err,
+                Err(err) if !url_refreshed && is_url_rejected(&err) => {
+                    self.spend_read_retry()?;
+                    warn!(
+                        file_id = %self.file.id,
+                        file_name = %self.file.name,
+                        received = buf.len(),
+                        "download url rejected, resuming with a fresh one"
+                    );
+                    url_refreshed = true;
+                    self.fs
+                        .download_urls
+                        .invalidate(&self.download_url_key())
+                        .await;
+                    download_url = self.get_download_url().await?.url;
+                    if download_url.is_empty() {
+                        return Err(FsError::NotFound);
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    error!(url = %download_url, error = %err, "download file failed");
+                    return Err(FsError::NotFound);
+                }
+            };
+            retries += 1;
+            self.spend_read_retry()?;
+            warn!(
+                file_id = %self.file.id,
+                file_name = %self.file.name,
+                received = buf.len(),
+                error = %err,
+                "download interrupted, resuming ({}/{})",
+                retries,
+                self.fs.retry_download_on_reset
+            );
+            if is_url_expired(&download_url) {
+                debug!(url = %download_url, "download url expired");
+                download_url = self.get_download_url().await?.url;
+            }
+        }
+    }
+
+    async fn read_upstream(&mut self, count: usize) -> Result<Bytes, FsError> {
+        let download_url = self.file.url.take();
+        let (mut download_url, streams_url) = if let Some(mut url) = download_url {
+            if is_url_expired(&url) {
+                debug!(url = %url, "download url expired");
+                url = self.get_download_url().await?.url;
+            }
+            (url, HashMap::new())
+        } else {
+            let res = self.get_download_url().await?;
+            (res.url, res.streams_url)
+        };
+
+        if !download_url.is_empty() {
+            let content = match self.read_prefetched(count).await {
+                Some(content) => content,
+                None => {
+                    let (content, url) = self.download_shared(download_url, count).await?;
+                    download_url = url;
+                    content
+                }
+            };
+            self.current_pos += content.len() as u64;
+            self.prefetch_ahead(&download_url, count);
+            self.file.url = Some(download_url);
+            Ok(content)
+        } else if streams_url.is_empty() {
+            Err(FsError::NotFound)
+        } else {
+            // Generate .livp file on the fly
+            let buf = Vec::new();
+            let mut zip = ZipWriter::new(Cursor::new(buf));
+            for (typ, url) in streams_url {
+                let content = self.fs.drive.download(&url, None).await.map_err(|err| {
+                    error!(url = %download_url, error = %err, "download file failed");
+                    FsError::NotFound
+                })?;
+                let name = self.file.name.replace(".livp", &format!(".{}", typ));
+                zip.start_file(
+                    name,
+                    FileOptions::default().compression_method(zip::CompressionMethod::Stored),
+                )
+                .map_err(|_| FsError::GeneralFailure)?;
+                zip.write(&content).map_err(|_| FsError::GeneralFailure)?;
+                self.current_pos += content.len() as u64;
+            }
+            let zip_buf = zip
+                .finish()
+                .map_err(|_| FsError::GeneralFailure)?
+                .into_inner();
+            Ok(Bytes::from(zip_buf))
+        }
+    }
+
+    /// Content at the current position downloaded ahead of time, if any
+    async fn read_prefetched(&mut self, count: usize) -> Option<Bytes> {
+        let len = count.min(self.file.size.saturating_sub(self.current_pos) as usize);
+        let task = self.prefetch.take(self.current_pos, len)?;
+        match task.await {
+            Ok(Ok(content)) if content.len() == len => {
+                trace!(file_id = %self.file.id, pos = self.current_pos, len = len, "read served from prefetch");
+                Some(content)
+            }
+            res => {
+                let error = match res {
+                    Ok(Err(err)) => err.to_string(),
+                    Err(err) => err.to_string(),
+                    Ok(Ok(_)) => "short read".to_string(),
+                };
+                debug!(file_id = %self.file.id, pos = self.current_pos, error = %error, "prefetch failed, downloading again");
+                self.prefetch.clear();
+                None
+            }
+        }
+    }
+
+    /// Queue downloads of the chunks following the current position, so that up
+    /// to `prefetch_chunks` read buffers are held or in flight for this reader
+    fn prefetch_ahead(&mut self, download_url: &str, count: usize) {
+        if self.fs.prefetch_chunks <= 1 || count == 0 || self.file.name.ends_with(".livp") {
+            return;
+        }
+        let Ok(mut url) = reqwest::Url::parse(download_url) else {
+            return;
+        };
+        if self.http_download && url.set_scheme("http").is_err() {
+            return;
+        }
+        let mut pos = self.prefetch.end().unwrap_or(self.current_pos);
+        while self.prefetch.chunks.len() + 1 < self.fs.prefetch_chunks && pos < self.file.size {
+            let len = count.min((self.file.size - pos) as usize);
+            let drive = self.fs.drive.clone();
+            let url = url.clone();
+            let task = tokio::spawn(async move {
+                let mut buf = BytesMut::with_capacity(len);
+                drive.download_range_into(url, pos, len, &mut buf).await?;
+                Ok(buf.freeze())
+            });
+            self.prefetch.chunks.push_back((pos, len, task));
+            pos += len as u64;
+        }
+    }
+
+    fn report_progress(&self) {
+        if let Some(upload) = self.active_upload.as_ref() {
+            upload.received.store(self.written, Ordering::Relaxed);
+        }
+    }
+
+    /// Complete local copy of this file in the disk cache
+    fn disk_cached_copy(&self) -> Option<PathBuf> {
+        if self.revision_id.is_some() {
+            return None;
+        }
+        self.fs.disk_cache.as_ref()?.lookup(&self.file)
+    }
+
+    async fn read_stale(&mut self, path: &Path, count: usize) -> Result<Bytes, FsError> {
+        let content = disk_cache::read_at(path, self.current_pos, count)
+            .await
+            .map_err(|err| {
+                error!(path = %path.display(), error = %err, "read disk cached copy failed");
+                FsError::GeneralFailure
+            })?;
+        self.current_pos += content.len() as u64;
+        metrics::record_bytes_downloaded(content.len());
+        Ok(content)
+    }
+
+    /// Whether chunks of this file can be kept in the disk cache,
+    /// .livp files are generated on the fly, versions share the id of the file
+    fn chunks_cacheable(&self) -> bool {
+        self.fs.disk_cache.is_some()
+            && self.revision_id.is_none()
+            && !self.file.name.ends_with(".livp")
+    }
+
+    /// Serve `count` bytes at the current position from the disk cache
+    async fn read_cached_chunk(&mut self, count: usize) -> Option<Bytes> {
+        if !self.chunks_cacheable() {
+            return None;
+        }
+        let len = count.min(self.file.size.saturating_sub(self.current_pos) as usize);
+        if len == 0 {
+            return None;
+        }
+        let disk_cache = self.fs.disk_cache.as_ref()?;
+        let content = disk_cache
+            .read_chunk(&self.file, self.current_pos, len)
+            .await?;
+        self.current_pos += content.len() as u64;
+        metrics::record_bytes_downloaded(content.len());
+        Some(content)
+    }
+
+    async fn cache_chunk(&self, start_pos: u64, content: &Bytes) {
+        if !self.chunks_cacheable() {
+            return;
+        }
+        if let Some(disk_cache) = self.fs.disk_cache.as_ref() {
+            disk_cache.write_chunk(&self.file, start_pos, content).await;
+        }
+    }
+
+    /// Store content read sequentially from the start of the file in the disk cache
+    async fn cache_content(&mut self, start_pos: u64, content: &Bytes) {
+        let Some(disk_cache) = self.fs.disk_cache.as_ref() else {
+            return;
+        };
+        // .livp files are generated on the fly, versions share the id of the file
+        if self.revision_id.is_some() || self.file.name.ends_with(".livp") {
+            return;
+        }
+        if start_pos == 0 && self.cache_writer.is_none() && disk_cache.lookup(&self.file).is_none()
+        {
+            self.cache_writer = disk_cache.writer(&self.file).await;
+        }
+        let Some(writer) = self.cache_writer.as_mut() else {
+            return;
+        };
+        if writer.len() != start_pos {
+            // not a sequential read of the whole file
+            self.cache_writer = None;
+            return;
+        }
+        if let Err(err) = writer.write(content).await {
+            warn!(file_id = %self.file.id, error = %err, "write disk cache failed");
+            self.cache_writer = None;
+        }
+    }
+
+    /// Account for one upstream retry against the per-request read retry budget
+    fn spend_read_retry(&mut self) -> Result<(), FsError> {
+        self.read_retries += 1;
+        if let Some(budget) = self.fs.read_retry_budget {
+            if self.read_retries > budget {
+                error!(
+                    file_id = %self.file.id,
+                    file_name = %self.file.name,
+                    budget = budget,
+                    "read retry budget exhausted"
+                );
+                return Err(FsError::GeneralFailure);
+            }
+        }
+        Ok(())
+    }
+
+    /// Content at the current position, from a local copy or from the upstream
+    async fn read_content(&mut self, count: usize) -> Result<Bytes, FsError> {
+        if self.file.id.is_empty() {
+            // upload in progress
+            return Err(FsError::NotFound);
+        }
+        if let Some(path) = self.stale_copy.clone() {
+            return self.read_stale(&path, count).await;
+        }
+        if let Some(content) = self.read_cached_chunk(count).await {
+            return Ok(content);
+        }
+        let start_pos = self.current_pos;
+        match self.read_upstream(count).await {
+            Ok(content) => {
+                self.cache_content(start_pos, &content).await;
+                if self.cache_writer.is_none() {
+                    self.cache_chunk(start_pos, &content).await;
+                }
+                metrics::record_bytes_downloaded(content.len());
+                Ok(content)
+            }
+            Err(err) => {
+                let Some(path) = self.disk_cached_copy() else {
+                    return Err(err);
+                };
+                warn!(
+                    file_id = %self.file.id,
+                    file_name = %self.file.name,
+                    "upstream read failed, serving the disk cached copy"
+                );
+                self.current_pos = start_pos;
+                self.stale_copy = Some(path.clone());
+                self.read_stale(&path, count).await
+            }
+        }
+    }
+
+    /// Upload what was written and not uploaded yet
+    async fn upload(&mut self) -> Result<(), FsError> {
+        let res = self.upload_pending().await;
+        if res.is_err() {
+            self.discard_staged().await;
+        }
+        res
+    }
+
+    async fn upload_pending(&mut self) -> Result<(), FsError> {
+        self.upload_spooled().await?;
+        if self.prepare_for_upload().await? {
+            self.maybe_upload_chunk(true).await?;
+            self.finish_parts().await?;
+            if !self.upload_state.upload_id.is_empty() {
+                self.fs
+                    .drive
+                    .complete_file_upload(&self.file.id, &self.upload_state.upload_id)
+                    .await
+                    .map_err(|err| {
+                        error!(
+                            file_id = %self.file.id,
+                            file_name = %self.file.name,
+                            error = %err,
+                            "complete file upload failed"
+                        );
+                        write_error(&err)
+                    })?;
+            }
+            self.publish_staged().await?;
+            self.fs
+                .remove_uploading_file(&self.parent_file_id, &self.file.name);
+            self.fs.dir_cache.invalidate(&self.parent_dir).await;
+            // an overwritten file keeps its id
+            self.fs.download_urls.invalidate(&self.file.id).await;
+        }
+        Ok(())
+    }
+
+    /// Rename a complete staged upload to the name of the file, then remove
+    /// the file it overwrites, which is left alone when the rename fails
+    async fn publish_staged(&mut self) -> Result<(), FsError> {
+        let Some(staging_name) = self.upload_state.staging_name.clone() else {
+            return Ok(());
+        };
+        // the overwritten file keeps the name until it is removed
+        if let Err(err) = self
+            .fs
+            .drive
+            .rename_file_allow_duplicate(&self.file.id, &self.file.name)
+            .await
+        {
+            error!(
+                file_id = %self.file.id,
+                file_name = %self.file.name,
+                staging_name = %staging_name,
+                error = %err,
+                "rename staged upload failed"
+            );
+            return Err(write_error(&err));
+        }
+        self.upload_state.staging_name = None;
+        if let Some(file_id) = self.upload_state.replaced_file_id.take() {
+            if let Err(err) = self.fs.drive.remove_file(&file_id, !self.fs.no_trash).await {
+                error!(file_id = %file_id, file_name = %self.file.name, error = %err, "delete replaced file failed");
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the staged file of an upload that failed
+    async fn discard_staged(&mut self) {
+        if self.upload_state.staging_name.take().is_none() {
+            return;
+        }
+        if let Err(err) = self.fs.drive.remove_file(&self.file.id, false).await {
+            warn!(file_id = %self.file.id, file_name = %self.file.name, error = %err, "delete staged upload failed");
+        }
+    }
+
     async fn prepare_for_upload(&mut self) -> Result<bool, FsError> {
         if self.upload_state.chunk_count == 0 {
             let size = self.upload_state.size;
@@ -683,8 +2939,10 @@ impl AliyunDavFile {
                     debug!(file_name = %self.file.name, size = size, "skip uploading same size file");
                     return Ok(false);
                 }
-                // existing file, delete before upload
-                if let Err(err) = self
+                // existing file, delete before upload, or once complete when staged
+                if self.fs.staged_upload {
+                    self.upload_state.replaced_file_id = Some(self.file.id.clone());
+                } else if let Err(err) = self
                     .fs
                     .drive
                     .remove_file(&self.file.id, !self.fs.no_trash)
@@ -693,21 +2951,40 @@ impl AliyunDavFile {
                     error!(file_name = %self.file.name, error = %err, "delete file before upload failed");
                 }
             }
+            let staging_name = self
+                .fs
+                .staged_upload
+                .then(|| format!("{}{:016x}", STAGING_PREFIX, rand::random::<u64>()));
+            let name = staging_name.as_deref().unwrap_or(&self.file.name);
             // TODO: create parent folders?
-            let upload_buffer_size = self.fs.upload_buffer_size as u64;
-            let chunk_count =
-                size / upload_buffer_size + if size % upload_buffer_size != 0 { 1 } else { 0 };
+            let part_size = self.fs.part_size(size);
+            let chunk_count = size.div_ceil(part_size);
             self.upload_state.chunk_count = chunk_count;
+            self.upload_state.part_size = part_size as usize;
             let res = self
                 .fs
                 .drive
-                .create_file_with_proof(&self.file.name, &self.parent_file_id, size, chunk_count)
+                .create_file_with_proof(
+                    name,
+                    &self.parent_file_id,
+                    size,
+                    chunk_count,
+                    self.upload_state.proof.as_ref(),
+                )
                 .await
                 .map_err(|err| {
                     error!(file_name = %self.file.name, error = %err, "create file with proof failed");
-                    FsError::GeneralFailure
+                    write_error(&err)
                 })?;
             self.file.id = res.file_id.clone();
+            self.upload_state.staging_name = staging_name;
+            if res.rapid_upload {
+                debug!(file_id = %self.file.id, file_name = %self.file.name, "rapid upload succeeded");
+                self.upload_state.rapid_uploaded = true;
+                // nothing left to upload
+                self.upload_state.chunk = chunk_count + 1;
+                return Ok(true);
+            }
             let Some(upload_id) = res.upload_id else {
                 error!("create file with proof failed: missing upload_id");
                 return Err(FsError::GeneralFailure);
@@ -722,23 +2999,165 @@ impl AliyunDavFile {
                 error!(file_id = %self.file.id, file_name = %self.file.name, "empty upload urls");
                 return Err(FsError::GeneralFailure);
             }
-            self.upload_state.upload_urls = upload_urls;
+            self.upload_state.upload_urls = Arc::new(tokio::sync::Mutex::new(upload_urls));
+        }
+        Ok(true)
+    }
+
+    async fn spool_bytes(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        if self.spool.is_none() {
+            let spool = SpoolFile::create(&self.fs.spool_dir).await.map_err(|err| {
+                error!(file_name = %self.file.name, error = %err, "create spool file failed");
+                FsError::GeneralFailure
+            })?;
+            self.spool = Some(spool);
+        }
+        // the free space may be eaten up by others while streaming
+        if self.written / SPOOL_SPACE_CHECK_INTERVAL
+            != (self.written - buf.len() as u64) / SPOOL_SPACE_CHECK_INTERVAL
+        {
+            self.fs.check_spool_space(0)?;
+        }
+        let spool = self.spool.as_mut().unwrap();
+        spool.write_all(buf).await.map_err(|err| {
+            error!(file_name = %self.file.name, error = %err, "write spool file failed");
+            FsError::GeneralFailure
+        })
+    }
+
+    /// Upload the spooled file, stripping image metadata first when requested
+    /// and trying rapid upload when enabled
+    async fn upload_spooled(&mut self) -> Result<(), FsError> {
+        let Some(mut spool) = self.spool.take() else {
+            return Ok(());
+        };
+        spool.finish().await.map_err(|err| {
+            error!(file_name = %self.file.name, error = %err, "finish spool file failed");
+            FsError::GeneralFailure
+        })?;
+        let stripped = match self.strip_metadata {
+            Some(kind) => self.strip_spooled(kind, &spool).await,
+            None => None,
+        };
+        let (upload_path, sha1) = match stripped.as_ref() {
+            Some((stripped, sha1)) => (stripped.path().to_path_buf(), sha1.clone()),
+            None => (spool.path().to_path_buf(), spool.sha1()),
+        };
+        self.upload_from_file(&upload_path, sha1).await
+    }
+
+    /// Returns the spool file of the stripped image along with its SHA1
+    async fn strip_spooled(
+        &self,
+        kind: ImageKind,
+        spool: &SpoolFile,
+    ) -> Option<(SpoolFile, String)> {
+        let stripped = SpoolFile::reserve(&self.fs.spool_dir);
+        let src = spool.path().to_path_buf();
+        let dst = stripped.path().to_path_buf();
+        let res = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+            let reader = std::io::BufReader::new(std::fs::File::open(src)?);
+            let mut writer = HashWriter::new(std::io::BufWriter::new(
+                spool::private_file_options().open(dst)?,
+            ));
+            strip_metadata(kind, reader, &mut writer)?;
+            Ok(writer.sha1())
+        })
+        .await;
+        match res {
+            Ok(Ok(sha1)) => {
+                debug!(file_name = %self.file.name, "image metadata stripped");
+                Some((stripped, sha1))
+            }
+            Ok(Err(err)) => {
+                warn!(file_name = %self.file.name, error = %err, "strip image metadata failed, uploading original file");
+                None
+            }
+            Err(err) => {
+                warn!(file_name = %self.file.name, error = %err, "strip image metadata failed, uploading original file");
+                None
+            }
+        }
+    }
+
+    async fn rapid_upload_proof(
+        &self,
+        file: &mut tokio::fs::File,
+        size: u64,
+        sha1: &str,
+    ) -> Option<RapidUploadProof> {
+        let offset = match self.fs.drive.proof_code_offset(size).await {
+            Ok(offset) => offset,
+            Err(err) => {
+                warn!(file_name = %self.file.name, error = %err, "compute proof code failed, skip rapid upload");
+                return None;
+            }
+        };
+        let mut buf = vec![0u8; (size - offset).min(8) as usize];
+        file.seek(SeekFrom::Start(offset)).await.ok()?;
+        file.read_exact(&mut buf).await.ok()?;
+        Some(RapidUploadProof {
+            content_hash: sha1.to_ascii_uppercase(),
+            proof_code: base64::engine::general_purpose::STANDARD.encode(buf),
+        })
+    }
+
+    async fn upload_from_file(&mut self, path: &Path, sha1: String) -> Result<(), FsError> {
+        let mut file = tokio::fs::File::open(path).await.map_err(|err| {
+            error!(path = %path.display(), error = %err, "open spool file failed");
+            FsError::GeneralFailure
+        })?;
+        let size = file
+            .metadata()
+            .await
+            .map_err(|_| FsError::GeneralFailure)?
+            .len();
+        self.upload_state.size = size;
+        self.file.size = size;
+        if self.fs.rapid_upload && size > 0 {
+            self.upload_state.proof = self.rapid_upload_proof(&mut file, size, &sha1).await;
+            file.seek(SeekFrom::Start(0))
+                .await
+                .map_err(|_| FsError::GeneralFailure)?;
+        }
+        self.upload_state.sha1 = Some(sha1);
+        if !self.prepare_for_upload().await? || self.upload_state.rapid_uploaded {
+            return Ok(());
+        }
+        let mut chunk = vec![0u8; self.fs.upload_buffer_size];
+        loop {
+            let n = file.read(&mut chunk).await.map_err(|err| {
+                error!(path = %path.display(), error = %err, "read spool file failed");
+                FsError::GeneralFailure
+            })?;
+            if n == 0 {
+                break;
+            }
+            self.upload_state.buffer.extend_from_slice(&chunk[..n]);
+            self.maybe_upload_chunk(false).await?;
         }
-        Ok(true)
+        Ok(())
     }
 
     async fn maybe_upload_chunk(&mut self, remaining: bool) -> Result<(), FsError> {
-        let chunk_size = if remaining {
-            // last chunk size maybe less than upload_buffer_size
-            self.upload_state.buffer.remaining()
-        } else {
-            self.fs.upload_buffer_size
-        };
-        let current_chunk = self.upload_state.chunk;
-        if chunk_size > 0
-            && self.upload_state.buffer.remaining() >= chunk_size
-            && current_chunk <= self.upload_state.chunk_count
-        {
+        // a single write may fill several parts
+        loop {
+            let chunk_size = if remaining {
+                // last chunk size maybe less than the part size
+                self.upload_state
+                    .buffer
+                    .remaining()
+                    .min(self.upload_state.part_size)
+            } else {
+                self.upload_state.part_size
+            };
+            let current_chunk = self.upload_state.chunk;
+            if chunk_size == 0
+                || self.upload_state.buffer.remaining() < chunk_size
+                || current_chunk > self.upload_state.chunk_count
+            {
+                break;
+            }
             let chunk_data = self.upload_state.buffer.split_to(chunk_size);
             debug!(
                 file_id = %self.file.id,
@@ -748,52 +3167,63 @@ impl AliyunDavFile {
                 current_chunk,
                 self.upload_state.chunk_count
             );
-            let mut upload_url = &self.upload_state.upload_urls[current_chunk as usize - 1];
-            let upload_data = chunk_data.freeze();
-            let mut res = self.fs.drive.upload(upload_url, upload_data.clone()).await;
-            if let Err(ref err) = res {
-                if err.to_string().contains("expired") {
-                    warn!(
-                        file_id = %self.file.id,
-                        file_name = %self.file.name,
-                        upload_url = %upload_url,
-                        "upload url expired"
-                    );
-                    if let Ok(part_info_list) = self
-                        .fs
-                        .drive
-                        .get_upload_url(
-                            &self.file.id,
-                            &self.upload_state.upload_id,
-                            self.upload_state.chunk_count,
-                        )
-                        .await
-                    {
-                        let upload_urls: Vec<_> =
-                            part_info_list.into_iter().map(|x| x.upload_url).collect();
-                        self.upload_state.upload_urls = upload_urls;
-                        upload_url = &self.upload_state.upload_urls[current_chunk as usize - 1];
-                        // retry upload
-                        res = self.fs.drive.upload(upload_url, upload_data).await;
-                    }
-                }
-                res.map_err(|err| {
-                    error!(
-                        file_id = %self.file.id,
-                        file_name = %self.file.name,
-                        upload_url = %upload_url,
-                        size = self.upload_state.size,
-                        error = %err,
-                        "upload file chunk {} failed",
-                        current_chunk
-                    );
-                    FsError::GeneralFailure
-                })?;
+            if self.upload_state.parts.uploads.len() >= self.fs.upload_concurrency {
+                self.finish_part().await?;
             }
+            // taken before spawning, so that the parts waiting for their turn
+            // across all uploads are bounded too, and their buffers with them
+            let permit = self.fs.drive.api_semaphore().acquire().await;
+            let drive = self.fs.drive.clone();
+            let file_id = self.file.id.clone();
+            let upload_id = self.upload_state.upload_id.clone();
+            let chunk_count = self.upload_state.chunk_count;
+            let upload_urls = self.upload_state.upload_urls.clone();
+            let data = chunk_data.freeze();
+            let task = tokio::spawn(async move {
+                let part = UploadPart {
+                    file_id: &file_id,
+                    upload_id: &upload_id,
+                    chunk_count,
+                    part: current_chunk,
+                    upload_urls,
+                };
+                upload_part(&*drive, part, permit, data).await
+            });
+            self.upload_state
+                .parts
+                .uploads
+                .push_back((current_chunk, task));
             self.upload_state.chunk += 1;
         }
         Ok(())
     }
+
+    /// Wait for the oldest part upload in progress
+    async fn finish_part(&mut self) -> Result<(), FsError> {
+        let Some((part, task)) = self.upload_state.parts.uploads.pop_front() else {
+            return Ok(());
+        };
+        let res = task.await.unwrap_or_else(|err| Err(anyhow::anyhow!(err)));
+        res.map_err(|err| {
+            error!(
+                file_id = %self.file.id,
+                file_name = %self.file.name,
+                size = self.upload_state.size,
+                error = %err,
+                "upload file chunk {} failed",
+                part
+            );
+            FsError::GeneralFailure
+        })
+    }
+
+    /// Wait for all part uploads in progress, before completing the upload
+    async fn finish_parts(&mut self) -> Result<(), FsError> {
+        while !self.upload_state.parts.uploads.is_empty() {
+            self.finish_part().await?;
+        }
+        Ok(())
+    }
 }
 
 impl DavFile for AliyunDavFile {
@@ -803,7 +3233,7 @@ impl DavFile for AliyunDavFile {
             // 阿里云盘接口没有 .livp 格式文件下载地址
             // 我们用 heic 和 mov 文件生成 zip 文件还原 .livp 文件
             // 故需要重新计算文件大小
-            if self.file.name.ends_with(".livp") {
+            if self.file.name.ends_with(".livp") && self.revision_id.is_none() {
                 if let Some(file) = self
                     .fs
                     .drive
@@ -811,19 +3241,18 @@ impl DavFile for AliyunDavFile {
                     .await
                     .map_err(|_| FsError::GeneralFailure)?
                 {
-                    Ok(Box::new(file) as Box<dyn DavMetaData>)
+                    Ok(self.fs.file_metadata(file))
                 } else {
                     Err(FsError::NotFound)
                 }
             } else {
-                let file = self.file.clone();
-                Ok(Box::new(file) as Box<dyn DavMetaData>)
+                Ok(self.fs.file_metadata(self.file.clone()))
             }
         }
         .boxed()
     }
 
-    fn redirect_url(&mut self) -> FsFuture<Option<String>> {
+    fn redirect_url(&mut self) -> FsFuture<'_, Option<String>> {
         debug!(file_id = %self.file.id, file_name = %self.file.name, "file: redirect_url");
         async move {
             if self.file.id.is_empty() {
@@ -855,6 +3284,15 @@ impl DavFile for AliyunDavFile {
     fn write_buf(&'_ mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
         debug!(file_id = %self.file.id, file_name = %self.file.name, "file: write_buf");
         async move {
+            // bodies without Content-Length are only checked as they arrive
+            self.written += buf.remaining() as u64;
+            self.fs.check_upload_size(self.written)?;
+            self.report_progress();
+            if self.spool_upload {
+                let mut buf = buf;
+                let bytes = buf.copy_to_bytes(buf.remaining());
+                return self.spool_bytes(&bytes).await;
+            }
             if self.prepare_for_upload().await? {
                 self.upload_state.buffer.put(buf);
                 self.maybe_upload_chunk(false).await?;
@@ -864,9 +3302,15 @@ impl DavFile for AliyunDavFile {
         .boxed()
     }
 
-    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<()> {
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
         debug!(file_id = %self.file.id, file_name = %self.file.name, size = buf.len(), "file: write_bytes");
         async move {
+            self.written += buf.len() as u64;
+            self.fs.check_upload_size(self.written)?;
+            self.report_progress();
+            if self.spool_upload {
+                return self.spool_bytes(&buf).await;
+            }
             if self.prepare_for_upload().await? {
                 self.upload_state.buffer.extend_from_slice(&buf);
                 self.maybe_upload_chunk(false).await?;
@@ -876,7 +3320,7 @@ impl DavFile for AliyunDavFile {
         .boxed()
     }
 
-    fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
         debug!(
             file_id = %self.file.id,
             file_name = %self.file.name,
@@ -886,72 +3330,18 @@ impl DavFile for AliyunDavFile {
             "file: read_bytes",
         );
         async move {
-            if self.file.id.is_empty() {
-                // upload in progress
-                return Err(FsError::NotFound);
-            }
-            let download_url = self.file.url.take();
-            let (download_url, streams_url) = if let Some(mut url) = download_url {
-                if is_url_expired(&url) {
-                    debug!(url = %url, "download url expired");
-                    url = self.get_download_url().await?.url;
-                }
-                (url, HashMap::new())
-            } else {
-                let res = self.get_download_url().await?;
-                (res.url, res.streams_url)
-            };
-
-            if !download_url.is_empty() {
-                let mut url =
-                    reqwest::Url::parse(&download_url).map_err(|_| FsError::GeneralFailure)?;
-                if self.http_download {
-                    url.set_scheme("http")
-                        .map_err(|_| FsError::GeneralFailure)?;
-                }
-                let content = self
-                    .fs
-                    .drive
-                    .download(url, Some((self.current_pos, count)))
-                    .await
-                    .map_err(|err| {
-                        error!(url = %download_url, error = %err, "download file failed");
-                        FsError::NotFound
-                    })?;
-                self.current_pos += content.len() as u64;
-                self.file.url = Some(download_url);
-                Ok(content)
-            } else if streams_url.is_empty() {
-                Err(FsError::NotFound)
-            } else {
-                // Generate .livp file on the fly
-                let buf = Vec::new();
-                let mut zip = ZipWriter::new(Cursor::new(buf));
-                for (typ, url) in streams_url {
-                    let content = self.fs.drive.download(&url, None).await.map_err(|err| {
-                        error!(url = %download_url, error = %err, "download file failed");
-                        FsError::NotFound
-                    })?;
-                    let name = self.file.name.replace(".livp", &format!(".{}", typ));
-                    zip.start_file(
-                        name,
-                        FileOptions::default().compression_method(zip::CompressionMethod::Stored),
-                    )
-                    .map_err(|_| FsError::GeneralFailure)?;
-                    zip.write(&content).map_err(|_| FsError::GeneralFailure)?;
-                    self.current_pos += content.len() as u64;
-                }
-                let zip_buf = zip
-                    .finish()
-                    .map_err(|_| FsError::GeneralFailure)?
-                    .into_inner();
-                Ok(Bytes::from(zip_buf))
+            let content = self.read_content(count).await?;
+            if let Some(limiter) = self.download_limiter.as_ref() {
+                // waiting here is harmless when the client stops reading,
+                // nothing is held while the next read is not asked for
+                limiter.acquire_many(content.len() as u64).await;
             }
+            Ok(content)
         }
         .boxed()
     }
 
-    fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
         debug!(
             file_id = %self.file.id,
             file_name = %self.file.name,
@@ -964,42 +3354,114 @@ impl DavFile for AliyunDavFile {
                 SeekFrom::End(pos) => (self.file.size as i64 + pos) as u64,
                 SeekFrom::Current(size) => self.current_pos + size as u64,
             };
+            if new_pos != self.current_pos {
+                self.prefetch.clear();
+            }
             self.current_pos = new_pos;
             Ok(new_pos)
         }
         .boxed()
     }
 
-    fn flush(&mut self) -> FsFuture<()> {
+    fn flush(&mut self) -> FsFuture<'_, ()> {
         debug!(file_id = %self.file.id, file_name = %self.file.name, "file: flush");
         async move {
-            if self.prepare_for_upload().await? {
-                self.maybe_upload_chunk(true).await?;
-                if !self.upload_state.upload_id.is_empty() {
-                    self.fs
-                        .drive
-                        .complete_file_upload(&self.file.id, &self.upload_state.upload_id)
-                        .await
-                        .map_err(|err| {
-                            error!(
-                                file_id = %self.file.id,
-                                file_name = %self.file.name,
-                                error = %err,
-                                "complete file upload failed"
-                            );
-                            FsError::GeneralFailure
-                        })?;
-                }
-                self.fs
-                    .remove_uploading_file(&self.parent_file_id, &self.file.name);
-                self.fs.dir_cache.invalidate(&self.parent_dir).await;
+            if !self.write_mode {
+                return self.upload().await;
             }
-            Ok(())
+            let fs = self.fs.clone();
+            let res = fs.record_write(self.upload()).await;
+            audit::note_file_id(&self.file.id);
+            let path = self.parent_dir.join(&self.file.name);
+            let entry = audit::Entry::new(Operation::Put, &path).bytes(self.upload_state.size);
+            fs.audit(entry, res).await;
+            res
         }
         .boxed()
     }
 }
 
+/// File with an ETag derived from its content hash, falling back to the size
+/// and modification time when the hash isn't known
+#[derive(Debug, Clone)]
+struct ContentHashEtag(AliyunFile);
+
+impl DavMetaData for ContentHashEtag {
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.0.modified()
+    }
+
+    fn is_dir(&self) -> bool {
+        DavMetaData::is_dir(&self.0)
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        self.0.created()
+    }
+
+    fn etag(&self) -> Option<String> {
+        match self.0.content_hash.as_ref() {
+            Some(hash) if !hash.is_empty() && !DavMetaData::is_dir(self) => {
+                Some(hash.to_ascii_lowercase())
+            }
+            _ => self.0.etag(),
+        }
+    }
+}
+
+impl DavDirEntry for ContentHashEtag {
+    fn name(&self) -> Vec<u8> {
+        self.0.name()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        async move { Ok(Box::new(self.clone()) as Box<dyn DavMetaData>) }.boxed()
+    }
+}
+
+/// Entry of a file version in a virtual `.versions` folder,
+/// named after the revision id and keeping the extension of the file.
+fn version_entry(file: &AliyunFile, revision: FileRevision) -> AliyunFile {
+    let name = match file.name.rsplit_once('.') {
+        Some((_, ext)) => format!("{}.{}", revision.revision_id, ext),
+        None => revision.revision_id,
+    };
+    AliyunFile {
+        name,
+        id: file.id.clone(),
+        r#type: FileType::File,
+        created_at: revision.created_at,
+        updated_at: revision.updated_at,
+        size: revision.size,
+        url: None,
+        content_hash: None,
+    }
+}
+
+fn version_revision_id(name: &str) -> &str {
+    name.split_once('.').map(|(id, _)| id).unwrap_or(name)
+}
+
+/// Map a failed write to an error, permission errors of limited-scope
+/// tokens become `Forbidden` instead of a general failure.
+fn write_error(err: &anyhow::Error) -> FsError {
+    let forbidden = err.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            .map(|status| status == reqwest::StatusCode::FORBIDDEN)
+            .unwrap_or(false)
+    });
+    if forbidden {
+        FsError::Forbidden
+    } else {
+        FsError::GeneralFailure
+    }
+}
+
 fn is_url_expired(url: &str) -> bool {
     if let Ok(oss_url) = ::url::Url::parse(url) {
         let expires = oss_url.query_pairs().find_map(|(k, v)| {
@@ -1021,3 +3483,849 @@ fn is_url_expired(url: &str) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use dav_server::{memls::MemLs, DavHandler};
+    use hyper::{Body, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::drive::mock::MockDrive;
+
+    fn new_fs(drive: &MockDrive) -> AliyunDriveFileSystem {
+        AliyunDriveFileSystem::new(drive.clone(), "/".to_string(), 1000, 600, 0, Vec::new())
+            .unwrap()
+    }
+
+    fn handler(fs: &AliyunDriveFileSystem) -> DavHandler {
+        DavHandler::builder()
+            .filesystem(Box::new(fs.clone()))
+            .locksystem(MemLs::new())
+            .build_handler()
+    }
+
+    async fn send(
+        handler: &DavHandler,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: impl Into<Body>,
+    ) -> (StatusCode, Response<dav_server::body::Body>, String) {
+        let mut req = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let res = handler.handle(req.body(body.into()).unwrap()).await;
+        let (parts, body) = res.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let text = String::from_utf8_lossy(&body).into_owned();
+        (
+            parts.status,
+            Response::from_parts(parts, dav_server::body::Body::empty()),
+            text,
+        )
+    }
+
+    fn file(id: &str, size: u64, content_hash: Option<&str>) -> AliyunFile {
+        AliyunFile {
+            name: format!("{}.txt", id),
+            id: id.to_string(),
+            r#type: FileType::File,
+            created_at: DateTime::new(SystemTime::UNIX_EPOCH),
+            updated_at: DateTime::new(SystemTime::now()),
+            size,
+            url: None,
+            content_hash: content_hash.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn identical_content_hashes_give_identical_etags() {
+        let hash = "2AAE6C35C94FCFB415DBE95F408B9CE91EE846ED";
+        let a = ContentHashEtag(file("a", 11, Some(hash)));
+        let mut b = ContentHashEtag(file("b", 11, Some(hash)));
+        b.0.updated_at = DateTime::new(SystemTime::now() + Duration::from_secs(3600));
+        assert_eq!(a.etag(), b.etag());
+        assert_eq!(a.etag().unwrap(), hash.to_ascii_lowercase());
+
+        // without a content hash the composite ETag is used
+        let c = ContentHashEtag(file("c", 11, None));
+        assert_eq!(c.etag(), c.0.etag());
+    }
+
+    #[tokio::test]
+    async fn propfind_lists_drive_entries() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "a.txt", "hello");
+        let handler = handler(&new_fs(&drive));
+
+        let (status, _, body) = send(&handler, "PROPFIND", "/docs/", &[("Depth", "1")], "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("/docs/a.txt"), "{}", body);
+        assert!(
+            body.contains("<D:getcontentlength>5</D:getcontentlength>"),
+            "{}",
+            body
+        );
+    }
+
+    fn part_content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn multipart_upload_with_concurrency() {
+        let drive = MockDrive::new();
+        let mut fs = new_fs(&drive);
+        fs.set_upload_buffer_size(1024)
+            .set_multipart_threshold(1024)
+            .set_upload_concurrency(4);
+        let handler = handler(&fs);
+        let content = part_content(10 * 1024 + 100);
+
+        let len = content.len().to_string();
+        let (status, _, _) = send(
+            &handler,
+            "PUT",
+            "/big.bin",
+            &[("Content-Length", &len)],
+            content.clone(),
+        )
+        .await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/big.bin").unwrap(), content);
+        assert_eq!(drive.calls("upload"), 11);
+        let concurrency = drive.max_concurrent_uploads();
+        assert!((2..=4).contains(&concurrency), "{}", concurrency);
+    }
+
+    #[tokio::test]
+    async fn threshold_above_buffer_size_still_uses_parts() {
+        let drive = MockDrive::new();
+        let mut fs = new_fs(&drive);
+        fs.set_upload_buffer_size(1024)
+            .set_multipart_threshold(1024 * 1024);
+        assert_eq!(fs.part_size(1000), 1000);
+        assert_eq!(fs.part_size(4096), 1024);
+        let handler = handler(&fs);
+        let content = part_content(4 * 1024);
+
+        let len = content.len().to_string();
+        let (status, _, _) = send(
+            &handler,
+            "PUT",
+            "/big.bin",
+            &[("Content-Length", &len)],
+            content.clone(),
+        )
+        .await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/big.bin").unwrap(), content);
+        assert_eq!(drive.calls("upload"), 4);
+    }
+
+    #[tokio::test]
+    async fn expired_upload_urls_are_refreshed_once() {
+        let drive = MockDrive::new();
+        drive.expire_new_upload_urls();
+        let mut fs = new_fs(&drive);
+        fs.set_upload_buffer_size(1024)
+            .set_multipart_threshold(1024)
+            .set_upload_concurrency(4);
+        let handler = handler(&fs);
+        let content = part_content(8 * 1024);
+
+        let len = content.len().to_string();
+        let (status, _, _) = send(
+            &handler,
+            "PUT",
+            "/big.bin",
+            &[("Content-Length", &len)],
+            content.clone(),
+        )
+        .await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/big.bin").unwrap(), content);
+        assert_eq!(drive.calls("get_upload_url"), 1);
+    }
+
+    #[tokio::test]
+    async fn protected_file_rejects_writes_next_to_writable_siblings() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "keep.txt", "important");
+        drive.add_file(&docs, "other.txt", "scratch");
+        let mut fs = new_fs(&drive);
+        fs.set_protected_paths(ProtectedPaths::new(&["/docs/keep.txt".to_string()]).unwrap());
+        let handler = handler(&fs);
+        let put = |path| {
+            let handler = handler.clone();
+            async move {
+                send(&handler, "PUT", path, &[("Content-Length", "3")], "new")
+                    .await
+                    .0
+            }
+        };
+
+        assert_eq!(put("/docs/keep.txt").await, StatusCode::FORBIDDEN);
+        let (status, _, _) = send(&handler, "DELETE", "/docs/keep.txt", &[], "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let destination = [("Destination", "/docs/moved.txt")];
+        let (status, _, _) = send(&handler, "MOVE", "/docs/keep.txt", &destination, "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(drive.content("/docs/keep.txt").unwrap(), "important");
+
+        assert!(put("/docs/other.txt").await.is_success());
+        assert_eq!(drive.content("/docs/other.txt").unwrap(), "new");
+        let (status, _, _) = send(&handler, "MOVE", "/docs/other.txt", &destination, "").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/docs/moved.txt").unwrap(), "new");
+        let (status, _, _) = send(&handler, "DELETE", "/docs/moved.txt", &[], "").await;
+        assert!(status.is_success(), "{}", status);
+        assert!(drive.content("/docs/moved.txt").is_none());
+    }
+
+    #[tokio::test]
+    async fn put_uploads_to_drive() {
+        let drive = MockDrive::new();
+        let fs = new_fs(&drive);
+        let handler = handler(&fs);
+
+        let (status, _, _) = send(
+            &handler,
+            "PUT",
+            "/new.txt",
+            &[("Content-Length", "11")],
+            "hello world",
+        )
+        .await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/new.txt").unwrap(), "hello world");
+        assert_eq!(drive.calls("complete_file_upload"), 1);
+    }
+
+    #[tokio::test]
+    async fn staged_upload_replaces_file_once_renamed() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "old");
+        let mut fs = new_fs(&drive);
+        fs.set_staged_upload(true).set_no_trash(true);
+        let handler = handler(&fs);
+
+        let (status, _, _) =
+            send(&handler, "PUT", "/a.txt", &[("Content-Length", "3")], "new").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/a.txt").unwrap(), "new");
+        assert_eq!(drive.names("root"), ["a.txt"]);
+        assert_eq!(drive.calls("rename_file_allow_duplicate"), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_staged_rename_keeps_the_original() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "old");
+        drive.fail("rename_file_allow_duplicate");
+        let mut fs = new_fs(&drive);
+        fs.set_staged_upload(true);
+        let handler = handler(&fs);
+
+        let (status, _, _) =
+            send(&handler, "PUT", "/a.txt", &[("Content-Length", "3")], "new").await;
+        assert!(!status.is_success(), "{}", status);
+        assert_eq!(drive.content("/a.txt").unwrap(), "old");
+        // the staged file is gone too
+        assert_eq!(drive.names("root"), ["a.txt"]);
+    }
+
+    #[tokio::test]
+    async fn sweep_removes_stale_staged_files() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        let stale = drive.add_file(&docs, ".partial-0123456789abcdef", "stale");
+        drive.set_updated_ago(&stale, Duration::from_secs(2 * 24 * 3600));
+        drive.add_file(&docs, ".partial-fedcba9876543210", "in progress");
+        let other = drive.add_file(&docs, ".partial-notes", "mine");
+        drive.set_updated_ago(&other, Duration::from_secs(2 * 24 * 3600));
+        let mut fs = new_fs(&drive);
+        fs.set_staged_upload(true);
+
+        fs.sweep_staged().await;
+        assert_eq!(
+            drive.names(&docs),
+            [".partial-fedcba9876543210", ".partial-notes"]
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_are_limited_to_visible_entries() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        let secret = drive.add_folder(&docs, ".secret");
+        let other = drive.add_folder("root", "other");
+        let visible = drive.add_file(&docs, "a.txt", "a");
+        let hidden = drive.add_file(&docs, ".b.txt", "b");
+        let in_hidden = drive.add_file(&secret, "c.txt", "c");
+        let outside = drive.add_file(&other, "d.txt", "d");
+        for file_id in [&visible, &hidden, &in_hidden, &outside, "removed"] {
+            drive.add_change("update", file_id);
+        }
+        let mut fs = AliyunDriveFileSystem::new(
+            drive.clone(),
+            "/docs".to_string(),
+            1000,
+            600,
+            0,
+            Vec::new(),
+        )
+        .unwrap();
+        fs.set_deny_hidden_files(true);
+
+        let delta = fs.list_changes(Some("0")).await.unwrap().unwrap();
+        let ids: Vec<_> = delta.items.iter().map(|i| i.file_id.as_str()).collect();
+        assert_eq!(ids, [visible.as_str(), "removed"]);
+    }
+
+    #[tokio::test]
+    async fn trashed_entries_are_only_listed() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "a.txt", "a");
+        let old = drive.add_file(&docs, "old.txt", "old");
+        drive.remove_file(&old, true).await.unwrap();
+        let mut fs = new_fs(&drive);
+        let (_, _, body) = send(&handler(&fs), "PROPFIND", "/docs/", &[("Depth", "1")], "").await;
+        assert!(!body.contains("old.txt"), "{}", body);
+
+        fs.set_listing_include_trashed(true);
+        let handler = handler(&fs);
+        let (status, _, body) = send(&handler, "PROPFIND", "/docs/", &[("Depth", "1")], "").await;
+        assert!(status.is_success(), "{}", status);
+        assert!(body.contains("old.txt%20%28trashed%29"), "{}", body);
+        assert!(body.contains("a.txt"), "{}", body);
+        let (status, _, _) = send(&handler, "GET", "/docs/old.txt%20(trashed)", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = send(&handler, "MKCOL", "/docs/old.txt%20(trashed)", &[], "").await;
+        assert!(status.is_success(), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn uploads_replace_hidden_placeholders() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, ".keep", "");
+        let mut fs = new_fs(&drive);
+        fs.set_placeholder_names(vec![".keep".to_string()]);
+        let handler = handler(&fs);
+
+        let (status, _, body) = send(&handler, "PROPFIND", "/docs/", &[("Depth", "1")], "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(!body.contains(".keep"), "{}", body);
+        let (status, _, _) = send(&handler, "GET", "/docs/.keep", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let headers = [("If-None-Match", "*"), ("Content-Length", "4")];
+        let (status, _, _) = send(&handler, "PUT", "/docs/.keep", &headers, "real").await;
+        assert!(status.is_success(), "{}", status);
+        let (_, _, body) = send(&handler, "PROPFIND", "/docs/", &[("Depth", "1")], "").await;
+        assert_eq!(body.matches("/docs/.keep<").count(), 1, "{}", body);
+        let (status, _, body) = send(&handler, "GET", "/docs/.keep", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "real"));
+    }
+
+    #[tokio::test]
+    async fn rejected_url_refresh_is_charged_to_the_retry_budget() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        drive.fail("download");
+        let mut fs = new_fs(&drive);
+        fs.set_read_retry_budget(Some(0));
+        let handler = handler(&fs);
+
+        // the body fails after the headers went out
+        let req = Request::get("/a.txt").body(Body::empty()).unwrap();
+        let res = handler.handle(req).await;
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+        assert_eq!(drive.calls("download"), 1);
+        assert_eq!(drive.calls("get_download_url"), 1);
+    }
+
+    #[tokio::test]
+    async fn tree_deletes_are_bounded_by_the_api_semaphore() {
+        let drive = MockDrive::new();
+        drive.fail("remove_tree");
+        let tree = drive.add_folder("root", "tree");
+        for dir in ["a", "b"] {
+            let dir = drive.add_folder(&tree, dir);
+            for i in 0..20 {
+                drive.add_file(&dir, &format!("{}.txt", i), "x");
+            }
+        }
+        let mut fs = new_fs(&drive);
+        fs.set_no_trash(true);
+
+        let failures = fs
+            .remove_tree(&DavPath::new("/tree").unwrap())
+            .await
+            .unwrap();
+        assert!(failures.is_empty());
+        assert!(drive.names("root").is_empty());
+        let permits = drive.api_semaphore().permits();
+        let concurrency = drive.max_concurrent_removes();
+        assert!((2..=permits).contains(&concurrency), "{}", concurrency);
+    }
+
+    #[tokio::test]
+    async fn names_over_the_byte_limit_are_refused() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "a");
+        let mut fs = new_fs(&drive);
+        fs.set_max_filename_bytes(Some(10));
+        let handler = handler(&fs);
+
+        // four characters, twelve bytes
+        let name = "/%E6%96%87%E4%BB%B6%E5%90%8D%E5%AD%97";
+        let (status, _, _) = send(&handler, "MKCOL", name, &[], "").await;
+        assert_eq!(status, StatusCode::URI_TOO_LONG);
+        let (status, _, _) = send(&handler, "PUT", name, &[], "x").await;
+        assert_eq!(status, StatusCode::URI_TOO_LONG);
+        let (status, _, _) = send(&handler, "MOVE", "/a.txt", &[("Destination", name)], "").await;
+        assert_eq!(status, StatusCode::URI_TOO_LONG);
+        assert_eq!(drive.names("root"), ["a.txt"]);
+        assert_eq!(drive.calls("create_file_with_proof"), 0);
+    }
+
+    #[tokio::test]
+    async fn move_between_folders_stays_on_the_server() {
+        let drive = MockDrive::new();
+        let from = drive.add_folder("root", "from");
+        drive.add_folder("root", "to");
+        drive.add_file(&from, "a.txt", "hello");
+        let fs = new_fs(&drive);
+        let handler = handler(&fs);
+
+        let destination = [("Destination", "/to/a.txt")];
+        let (status, _, _) = send(&handler, "MOVE", "/from/a.txt", &destination, "").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/to/a.txt").unwrap(), "hello");
+        assert!(drive.content("/from/a.txt").is_none());
+        assert_eq!(drive.calls("move_file"), 1);
+        assert_eq!(drive.calls("download"), 0);
+        assert_eq!(drive.calls("upload"), 0);
+    }
+
+    #[tokio::test]
+    async fn ttl_overrides_are_relative_to_the_root() {
+        let drive = MockDrive::new();
+        let media = drive.add_folder("root", "media");
+        for name in ["archive", "active"] {
+            let folder = drive.add_folder(&media, name);
+            drive.add_file(&folder, "a.txt", "hello");
+        }
+        let overrides = vec!["archive=600".parse().unwrap()];
+        let fs =
+            AliyunDriveFileSystem::new(drive.clone(), "/media".to_string(), 1000, 0, 0, overrides)
+                .unwrap();
+        let handler = handler(&fs);
+
+        for path in ["/archive/", "/active/"] {
+            for _ in 0..2 {
+                let (status, _, _) = send(&handler, "PROPFIND", path, &[("Depth", "1")], "").await;
+                assert_eq!(status, StatusCode::MULTI_STATUS);
+            }
+        }
+        // the listing of the archive is cached, the other one expires at once
+        assert_eq!(drive.calls("list_all"), 1 + 2);
+    }
+
+    #[tokio::test]
+    async fn exif_is_stripped_from_uploaded_jpegs() {
+        let drive = MockDrive::new();
+        let mut fs = new_fs(&drive);
+        fs.set_strip_exif(true);
+        let handler = handler(&fs);
+
+        let app0 = [0xFF, 0xE0, 0x00, 0x07, b'J', b'F', b'I', b'F', 0x00];
+        let mut app1 = vec![0xFF, 0xE1, 0x00, 0x0E];
+        app1.extend_from_slice(b"Exif\0\0GPS:42");
+        let scan = [0xFF, 0xDA, 0x01, 0x02, 0x03, 0xFF, 0xD9];
+        let jpeg = [&[0xFF, 0xD8][..], &app0, &app1, &scan].concat();
+        let length = jpeg.len().to_string();
+        let headers = [("Content-Length", length.as_str())];
+
+        let (status, _, _) = send(&handler, "PUT", "/photo.jpg", &headers, jpeg.clone()).await;
+        assert!(status.is_success(), "{}", status);
+        let stored = drive.content("/photo.jpg").unwrap();
+        assert_eq!(stored, [&[0xFF, 0xD8][..], &app0, &scan].concat());
+
+        // anything but images is stored as is
+        let (status, _, _) = send(&handler, "PUT", "/photo.bin", &headers, jpeg.clone()).await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/photo.bin").unwrap(), jpeg);
+    }
+
+    #[tokio::test]
+    async fn uploads_eating_into_the_quota_reserve_are_refused() {
+        let drive = MockDrive::new();
+        drive.set_total_space(1000);
+        drive.add_file("root", "a.bin", vec![0; 500]);
+        let mut fs = new_fs(&drive);
+        fs.set_quota_reserve(Some("300".parse().unwrap()));
+        let handler = handler(&fs);
+
+        let body = vec![1; 300];
+        let (status, _, _) = send(
+            &handler,
+            "PUT",
+            "/big.bin",
+            &[("Content-Length", "300")],
+            body,
+        )
+        .await;
+        assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+        assert!(drive.content("/big.bin").is_none());
+
+        let body = vec![1; 100];
+        let (status, _, _) = send(
+            &handler,
+            "PUT",
+            "/small.bin",
+            &[("Content-Length", "100")],
+            body,
+        )
+        .await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/small.bin").unwrap().len(), 100);
+    }
+
+    #[tokio::test]
+    async fn put_to_a_folder_conflicts() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "a.txt", "hello");
+        let handler = handler(&new_fs(&drive));
+
+        for path in ["/docs", "/docs/"] {
+            let (status, _, _) = send(&handler, "PUT", path, &[("Content-Length", "1")], "x").await;
+            assert_eq!(status, StatusCode::CONFLICT, "{}", path);
+        }
+        assert_eq!(drive.calls("create_file_with_proof"), 0);
+        assert_eq!(drive.calls("remove_file"), 0);
+        assert_eq!(drive.content("/docs/a.txt").unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn reset_downloads_resume_from_the_last_byte() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello world");
+        let mut fs = new_fs(&drive);
+        fs.set_retry_download_on_reset(1);
+        let resuming = handler(&fs);
+        let failing = handler(&new_fs(&drive));
+
+        drive.reset_next_download_after(4);
+        let (status, _, body) = send(&resuming, "GET", "/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello world"));
+        assert_eq!(drive.calls("download"), 2);
+
+        // without retries the body breaks off
+        drive.reset_next_download_after(4);
+        let req = Request::get("/a.txt").body(Body::empty()).unwrap();
+        let res = failing.handle(req).await;
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn spooled_uploads_are_hashed_for_rapid_upload() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello world");
+        let spool_dir = std::env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        let mut fs = new_fs(&drive);
+        fs.set_rapid_upload(true).set_spool_dir(spool_dir.clone());
+        let handler = handler(&fs);
+        let headers = [("Content-Length", "11")];
+
+        // same content as a.txt, nothing gets uploaded
+        let (status, _, _) = send(&handler, "PUT", "/b.txt", &headers, "hello world").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/b.txt").unwrap(), "hello world");
+        assert_eq!(drive.calls("upload"), 0);
+
+        let (status, _, _) = send(&handler, "PUT", "/c.txt", &headers, "other words").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/c.txt").unwrap(), "other words");
+        assert_eq!(drive.calls("upload"), 1);
+
+        // spool files are gone once uploaded
+        let left = std::fs::read_dir(&spool_dir).unwrap().count();
+        std::fs::remove_dir_all(&spool_dir).unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[tokio::test]
+    async fn hidden_files_are_neither_listed_nor_served() {
+        let drive = MockDrive::new();
+        drive.add_file("root", ".env", "SECRET=1");
+        let git = drive.add_folder("root", ".git");
+        drive.add_file(&git, "config", "x");
+        drive.add_file("root", "a.txt", "hello");
+        let mut fs = new_fs(&drive);
+        fs.set_deny_hidden_files(true);
+        let handler = handler(&fs);
+
+        let depth = [("Depth", "1")];
+        let (status, _, body) = send(&handler, "PROPFIND", "/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("a.txt"), "{}", body);
+        assert!(!body.contains(".env") && !body.contains(".git"), "{}", body);
+
+        let (status, _, _) = send(&handler, "GET", "/.env", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = send(&handler, "GET", "/.git/config", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = send(&handler, "GET", "/a.txt", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn versions_are_listed_and_read_from_versions_folders() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        let file_id = drive.add_file(&docs, "a.txt", "hello world");
+        drive.add_revision(&file_id, "rev1", "hello");
+        let mut fs = new_fs(&drive);
+        fs.set_enable_versions(true);
+        let handler = handler(&fs);
+        let depth = [("Depth", "1")];
+
+        let (status, _, body) = send(&handler, "PROPFIND", "/docs/.versions/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("/docs/.versions/a.txt/"), "{}", body);
+        let (status, _, body) =
+            send(&handler, "PROPFIND", "/docs/.versions/a.txt/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("/docs/.versions/a.txt/rev1.txt"), "{}", body);
+
+        let (status, _, body) =
+            send(&handler, "GET", "/docs/.versions/a.txt/rev1.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        let (status, _, _) = send(&handler, "GET", "/docs/.versions/a.txt/rev2.txt", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let headers = [("Content-Length", "1")];
+        let (status, _, _) = send(
+            &handler,
+            "PUT",
+            "/docs/.versions/a.txt/rev1.txt",
+            &headers,
+            "x",
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(drive.content("/docs/a.txt").unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn prewarm_caches_directories_down_to_the_depth() {
+        let drive = MockDrive::new();
+        let a = drive.add_folder("root", "a");
+        let b = drive.add_folder(&a, "b");
+        drive.add_folder(&b, "c");
+        drive.add_folder("root", "d");
+        let fs = new_fs(&drive);
+        let handler = handler(&fs);
+
+        fs.prewarm(vec!["/".to_string()], 1).await;
+        // the root, a and d
+        assert_eq!(drive.calls("list_all"), 3);
+        let depth = [("Depth", "1")];
+        let (status, _, body) = send(&handler, "PROPFIND", "/a/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("/a/b/"), "{}", body);
+        assert_eq!(drive.calls("list_all"), 3);
+
+        // b is one level too deep
+        send(&handler, "PROPFIND", "/a/b/", &depth, "").await;
+        assert_eq!(drive.calls("list_all"), 4);
+    }
+
+    #[tokio::test]
+    async fn concurrent_ranged_reads_share_and_refresh_the_download_url() {
+        let drive = MockDrive::new();
+        let content: String = (0..100)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        drive.add_file("root", "a.txt", content.clone());
+        let handler = handler(&new_fs(&drive));
+        let ranged_reads = || {
+            futures_util::future::join_all((0..4).map(|i| {
+                let handler = handler.clone();
+                async move {
+                    let range = format!("bytes={}-{}", i * 25, i * 25 + 24);
+                    let headers = [("Range", range.as_str())];
+                    let (status, _, body) = send(&handler, "GET", "/a.txt", &headers, "").await;
+                    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+                    body
+                }
+            }))
+        };
+
+        assert_eq!(ranged_reads().await.concat(), content);
+        assert_eq!(drive.calls("get_download_url"), 1);
+
+        // readers holding the expired url get a fresh one
+        drive.expire_download_urls();
+        assert_eq!(ranged_reads().await.concat(), content);
+        let refreshed = drive.calls("get_download_url") - 1;
+        assert!((1..=4).contains(&refreshed), "{}", refreshed);
+        assert_eq!(ranged_reads().await.concat(), content);
+        assert_eq!(drive.calls("get_download_url"), 1 + refreshed);
+    }
+
+    #[tokio::test]
+    async fn writes_refused_by_the_token_are_forbidden() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        drive.set_read_only();
+        let writable = handler(&new_fs(&drive));
+        let headers = [("Content-Length", "5")];
+
+        let (status, _, _) = send(&writable, "MKCOL", "/docs", &[], "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) = send(&writable, "PUT", "/b.txt", &headers, "hello").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // assuming a read-only token refuses writes before trying them
+        let mut fs = new_fs(&drive);
+        fs.set_read_only(true);
+        let read_only = handler(&fs);
+        let (status, _, _) = send(&read_only, "MKCOL", "/docs", &[], "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(drive.calls("create_folder"), 1);
+        let (status, _, body) = send(&read_only, "GET", "/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+    }
+
+    #[tokio::test]
+    async fn internal_download_urls_are_used_when_asked() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let public = handler(&new_fs(&drive));
+        let mut fs = new_fs(&drive);
+        fs.set_download_url_internal(true);
+        let internal = handler(&fs);
+
+        let (_, _, body) = send(&public, "GET", "/a.txt", &[], "").await;
+        assert_eq!(body, "hello");
+        assert_eq!(drive.calls("download_internal"), 0);
+        let public_downloads = drive.calls("download");
+        let (_, _, body) = send(&internal, "GET", "/a.txt", &[], "").await;
+        assert_eq!(body, "hello");
+        let internal_downloads = drive.calls("download") - public_downloads;
+        assert!(internal_downloads > 0);
+        assert_eq!(drive.calls("download_internal"), internal_downloads);
+    }
+
+    #[tokio::test]
+    async fn identical_concurrent_reads_share_one_download() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        drive.set_download_delay(Duration::from_millis(50));
+        let mut fs = new_fs(&drive);
+        fs.set_coalesce_reads(true);
+        let coalescing = handler(&fs);
+        let separate = handler(&new_fs(&drive));
+        let concurrent_reads = |handler: DavHandler| async move {
+            let reads = (0..4).map(|_| send(&handler, "GET", "/a.txt", &[], ""));
+            for (status, _, body) in futures_util::future::join_all(reads).await {
+                assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+            }
+        };
+
+        concurrent_reads(coalescing).await;
+        assert_eq!(drive.calls("download"), 1);
+        concurrent_reads(separate).await;
+        assert_eq!(drive.calls("download"), 1 + 4);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn spooling_is_refused_below_the_minimum_free_space() {
+        let drive = MockDrive::new();
+        let spool_dir = std::env::temp_dir();
+        let available = spool::available_space(&spool_dir).unwrap();
+        let mut fs = new_fs(&drive);
+        fs.set_rapid_upload(true)
+            .set_spool_dir(spool_dir)
+            .set_min_spool_free(Some(available + (1 << 30)));
+        let refusing = handler(&fs);
+        let headers = [("Content-Length", "5")];
+
+        let (status, _, _) = send(&refusing, "PUT", "/a.txt", &headers, "hello").await;
+        assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+        assert!(drive.content("/a.txt").is_none());
+
+        fs.set_min_spool_free(Some(0));
+        let accepting = handler(&fs);
+        let (status, _, _) = send(&accepting, "PUT", "/a.txt", &headers, "hello").await;
+        assert!(status.is_success(), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn overlay_files_are_listed_served_and_shadowed() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        let local = std::env::temp_dir().join(format!("overlay-test-{}", std::process::id()));
+        std::fs::write(&local, "overlay").unwrap();
+        let local = local.to_string_lossy().into_owned();
+        let overlay = Overlay::load(vec![
+            format!("/docs/README.txt={}", local).parse().unwrap(),
+            format!("/docs/a.txt={}", local).parse().unwrap(),
+        ])
+        .unwrap();
+        std::fs::remove_file(&local).unwrap();
+        let mut fs = new_fs(&drive);
+        fs.set_overlay(overlay);
+        let handler = handler(&fs);
+
+        let depth = [("Depth", "1")];
+        let (_, _, body) = send(&handler, "PROPFIND", "/docs/", &depth, "").await;
+        assert!(body.contains("/docs/README.txt"), "{}", body);
+        let (status, _, body) = send(&handler, "GET", "/docs/README.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "overlay"));
+        let headers = [("Content-Length", "1")];
+        let (status, _, _) = send(&handler, "PUT", "/docs/README.txt", &headers, "x").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // a real file takes precedence
+        drive.add_file(&docs, "a.txt", "real");
+        fs.dir_cache.invalidate_all().await;
+        let (_, _, body) = send(&handler, "GET", "/docs/a.txt", &[], "").await;
+        assert_eq!(body, "real");
+        let (_, _, body) = send(&handler, "PROPFIND", "/docs/", &depth, "").await;
+        assert_eq!(body.matches("/docs/a.txt").count(), 1, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn rejected_download_urls_are_replaced_once() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let handler = handler(&new_fs(&drive));
+
+        let (_, _, body) = send(&handler, "GET", "/a.txt", &[], "").await;
+        assert_eq!(body, "hello");
+        assert_eq!(drive.calls("get_download_url"), 1);
+
+        // the cached url is rejected by OSS, the read resumes with a fresh one
+        drive.expire_download_urls();
+        let (status, _, body) = send(&handler, "GET", "/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        assert_eq!(drive.calls("get_download_url"), 2);
+
+        // a fresh url rejected as well is not replaced again
+        drive.fail("download");
+        let req = Request::get("/a.txt").body(Body::empty()).unwrap();
+        let res = handler.handle(req).await;
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+        assert_eq!(drive.calls("get_download_url"), 3);
+    }
+}
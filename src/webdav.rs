@@ -1,51 +1,93 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::io;
-use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use dav_server::{body::Body, DavConfig, DavHandler};
+use bytes::Bytes;
+use clap::ValueEnum;
+use dav_server::{
+    body::Body,
+    davpath::{DavPath, ParseError},
+    fs::{DavFileSystem, FsError, ReadDirMeta},
+    ls::DavLockSystem,
+    memls::MemLs,
+    DavConfig, DavHandler,
+};
+use futures_util::stream::StreamExt;
 use headers::{authorization::Basic, Authorization, HeaderMapExt};
-use hyper::{service::Service, Request, Response};
-use tracing::{error, info};
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    server::conn::{AddrIncoming, AddrStream},
+    service::Service,
+    Method, Request, Response, StatusCode,
+};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::net::TcpSocket;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+use crate::audit;
+use crate::auth::{AuthCommand, AuthScheme, DigestAuth, REALM};
+use crate::cors::Cors;
+use crate::drive::{CircuitBreaker, FileType};
+use crate::health::{TokenHealth, UpstreamHealth};
+use crate::lock::TimeoutLs;
+use crate::metrics;
+use crate::net::{ip_in, IpNet};
+use crate::quirks::{
+    brief_multistatus, is_allprop, prefers_minimal, Quirk, QuirkRule, MINIMAL_PROPFIND,
+};
+use crate::reload::LiveSettings;
+use crate::static_files::StaticFiles;
+use crate::tls::TlsCerts;
+use crate::vfs::{mark_stale, track_stale, AliyunDriveFileSystem};
+
+/// Request header overriding the read buffer size, only honored from trusted proxies
+const READ_BUFFER_SIZE_HEADER: &str = "x-read-buffer-size";
+const MIN_READ_BUFFER_SIZE: usize = 4 * 1024;
+const MAX_READ_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+/// Control endpoints, kept at the root outside of `--dav-prefix`
+const CONTROL_PREFIX: &str = "/_control/";
+/// Thumbnail widths accepted by `?thumbnail=<width>`
+const THUMBNAIL_WIDTHS: std::ops::RangeInclusive<u32> = 16..=4096;
 
 #[cfg(feature = "rustls-tls")]
 use {
-    futures_util::stream::StreamExt,
+    crate::tls::tls_acceptor,
     hyper::server::accept,
-    hyper::server::conn::AddrIncoming,
-    std::fs::File,
     std::future::ready,
-    std::path::Path,
-    std::sync::Arc,
     tls_listener::{SpawningHandshakes, TlsListener},
-    tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig},
-    tokio_rustls::TlsAcceptor,
 };
 
 pub struct WebDavServer {
     pub host: String,
     pub port: u16,
-    pub auth_user: Option<String>,
-    pub auth_password: Option<String>,
-    pub tls_config: Option<(PathBuf, PathBuf)>,
-    pub handler: DavHandler,
+    pub tls_config: Option<Arc<TlsCerts>>,
+    pub listen_backlog: Option<u32>,
+    pub service: AliyunDriveWebDav,
 }
 
 impl WebDavServer {
-    pub async fn serve(self) -> Result<()> {
+    /// Serve until `shutdown` completes, then stop accepting connections and
+    /// return once the open ones are closed
+    pub async fn serve(self, shutdown: impl Future<Output = ()>) -> Result<()> {
         let addr = (self.host, self.port)
             .to_socket_addrs()
             .unwrap()
             .next()
             .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
         #[cfg(feature = "rustls-tls")]
-        if let Some((tls_cert, tls_key)) = self.tls_config {
+        if let Some(tls_certs) = self.tls_config {
             let incoming = TlsListener::new(
-                SpawningHandshakes(tls_acceptor(&tls_key, &tls_cert)?),
-                AddrIncoming::bind(&addr)?,
+                SpawningHandshakes(tls_acceptor(tls_certs)),
+                bind(addr, self.listen_backlog)?,
             )
             .filter(|conn| {
                 if let Err(err) = conn {
@@ -55,11 +97,11 @@ impl WebDavServer {
                     ready(true)
                 }
             });
-            let server = hyper::Server::builder(accept::from_stream(incoming)).serve(MakeSvc {
-                auth_user: self.auth_user,
-                auth_password: self.auth_password,
-                handler: self.handler,
-            });
+            let server = hyper::Server::builder(accept::from_stream(incoming))
+                .serve(MakeSvc {
+                    service: self.service,
+                })
+                .with_graceful_shutdown(shutdown);
             info!("listening on https://{}", addr);
             let _ = server.await.map_err(|e| error!("server error: {}", e));
             return Ok(());
@@ -69,22 +111,1196 @@ impl WebDavServer {
             anyhow::bail!("TLS is not supported in this build.");
         }
 
-        let server = hyper::Server::bind(&addr).serve(MakeSvc {
-            auth_user: self.auth_user,
-            auth_password: self.auth_password,
-            handler: self.handler,
+        let server = hyper::Server::builder(bind(addr, self.listen_backlog)?).serve(MakeSvc {
+            service: self.service,
         });
         info!("listening on http://{}", server.local_addr());
+        let server = server.with_graceful_shutdown(shutdown);
         let _ = server.await.map_err(|e| error!("server error: {}", e));
         Ok(())
     }
 }
 
+/// Bind the listening socket, with the given accept backlog or the OS default
+fn bind(addr: SocketAddr, backlog: Option<u32>) -> Result<AddrIncoming> {
+    let Some(backlog) = backlog else {
+        return Ok(AddrIncoming::bind(&addr)?);
+    };
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    let listener = socket.listen(backlog)?;
+    debug!(addr = %addr, backlog = backlog, "listen backlog set");
+    Ok(AddrIncoming::from_listener(listener)?)
+}
+
 #[derive(Clone)]
 pub struct AliyunDriveWebDav {
-    auth_user: Option<String>,
-    auth_password: Option<String>,
+    live: Arc<RwLock<LiveSettings>>,
+    digest: Option<Arc<DigestAuth>>,
+    auth_command: Option<Arc<AuthCommand>>,
     handler: DavHandler,
+    fs: AliyunDriveFileSystem,
+    auto_index: bool,
+    strip_prefix: Option<String>,
+    strip_suffix: Option<String>,
+    dav_prefix: Option<String>,
+    trusted_proxies: Vec<IpNet>,
+    public_paths: Vec<PathBuf>,
+    allow_anonymous_read: bool,
+    max_propfind_results: Option<usize>,
+    extra_headers: Vec<ExtraHeader>,
+    cors: Option<Cors>,
+    text_charset: Option<HeaderValue>,
+    content_language: Option<HeaderValue>,
+    maintenance: Option<(UpstreamHealth, Bytes)>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    metrics_path: Option<String>,
+    health_check: Option<(String, TokenHealth, Option<UpstreamHealth>)>,
+    static_files: StaticFiles,
+    principal_header: Option<HeaderName>,
+    lock_system: Option<Box<dyn DavLockSystem>>,
+    /// Lock systems of the users with their own root by root, the same path
+    /// is another file for each of them
+    user_lock_systems: Arc<Mutex<HashMap<String, Box<dyn DavLockSystem>>>>,
+    max_lock_timeout: Option<Duration>,
+    enable_thumbnails: bool,
+    symlink_policy: SymlinkPolicy,
+    access_log: bool,
+    advertise_locking: bool,
+    out_of_root_status: OutOfRootStatus,
+    propfind_mode: PropfindMode,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl AliyunDriveWebDav {
+    pub fn new(handler: DavHandler, fs: AliyunDriveFileSystem) -> Self {
+        Self {
+            live: Arc::default(),
+            digest: None,
+            auth_command: None,
+            handler,
+            fs,
+            auto_index: false,
+            strip_prefix: None,
+            strip_suffix: None,
+            dav_prefix: None,
+            trusted_proxies: Vec::new(),
+            public_paths: Vec::new(),
+            allow_anonymous_read: false,
+            max_propfind_results: None,
+            extra_headers: Vec::new(),
+            cors: None,
+            text_charset: None,
+            content_language: None,
+            maintenance: None,
+            circuit_breaker: None,
+            metrics_path: None,
+            health_check: None,
+            static_files: StaticFiles::default(),
+            principal_header: None,
+            lock_system: None,
+            user_lock_systems: Arc::default(),
+            max_lock_timeout: None,
+            enable_thumbnails: false,
+            symlink_policy: SymlinkPolicy::default(),
+            access_log: false,
+            advertise_locking: true,
+            out_of_root_status: OutOfRootStatus::default(),
+            propfind_mode: PropfindMode::default(),
+            remote_addr: None,
+        }
+    }
+
+    pub fn set_auth(
+        &mut self,
+        auth_user: Option<String>,
+        auth_password: Option<String>,
+    ) -> &mut Self {
+        let mut live = self.live.write().unwrap();
+        live.auth_user = auth_user;
+        live.auth_password = auth_password;
+        drop(live);
+        self
+    }
+
+    pub fn set_auth_scheme(&mut self, auth_scheme: AuthScheme) -> &mut Self {
+        self.digest = match auth_scheme {
+            AuthScheme::Basic => None,
+            AuthScheme::Digest => Some(Arc::new(DigestAuth::default())),
+        };
+        self
+    }
+
+    /// Verify Basic credentials of users without an account with an external command
+    pub fn set_auth_command(&mut self, command: Option<String>) -> &mut Self {
+        self.auth_command = command.map(|command| Arc::new(AuthCommand::new(command)));
+        self
+    }
+
+    /// Additional accounts, each confined to its own root directory
+    pub fn set_auth_users(&mut self, auth_users: Vec<AuthUser>) -> &mut Self {
+        let auth_users = auth_users
+            .into_iter()
+            .map(|u| (u.user, (u.password, u.root)))
+            .collect();
+        self.live.write().unwrap().auth_users = Arc::new(auth_users);
+        self
+    }
+
+    /// Settings shared by all connections that can be changed while running
+    pub fn live_settings(&self) -> Arc<RwLock<LiveSettings>> {
+        self.live.clone()
+    }
+
+    pub fn set_auto_index(&mut self, auto_index: bool) -> &mut Self {
+        self.auto_index = auto_index;
+        self
+    }
+
+    pub fn set_strip_prefix(&mut self, strip_prefix: Option<String>) -> &mut Self {
+        self.strip_prefix = strip_prefix;
+        self
+    }
+
+    /// Serve WebDAV only under this prefix, other paths that are not a control
+    /// endpoint get 404
+    pub fn set_dav_prefix(&mut self, dav_prefix: Option<String>) -> &mut Self {
+        if let Some(prefix) = dav_prefix.as_deref() {
+            self.strip_prefix = Some(prefix.to_string());
+        }
+        self.dav_prefix = dav_prefix;
+        self
+    }
+
+    pub fn set_strip_suffix(&mut self, strip_suffix: Option<String>) -> &mut Self {
+        self.strip_suffix = strip_suffix
+            .map(|suffix| suffix.trim_matches('/').to_string())
+            .filter(|suffix| !suffix.is_empty());
+        self
+    }
+
+    /// Path without the trailing `--strip-suffix` component, `None` if it is not there
+    fn strip_path_suffix<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let suffix = self.strip_suffix.as_deref()?;
+        let stripped = path
+            .trim_end_matches('/')
+            .strip_suffix(suffix)?
+            .strip_suffix('/')?;
+        Some(if stripped.is_empty() { "/" } else { stripped })
+    }
+
+    /// Remove `--strip-suffix` from the request path and the COPY/MOVE destination
+    fn strip_request_suffix(&self, req: &mut Request<hyper::Body>) {
+        if let Some(path) = self.strip_path_suffix(req.uri().path()) {
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            };
+            let mut parts = req.uri().clone().into_parts();
+            if let Ok(path_and_query) = path_and_query.parse() {
+                parts.path_and_query = Some(path_and_query);
+                if let Ok(uri) = hyper::Uri::from_parts(parts) {
+                    debug!(from = %req.uri(), to = %uri, "strip suffix");
+                    *req.uri_mut() = uri;
+                }
+            }
+        }
+        let dest = req
+            .headers()
+            .get("destination")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|dest| dest.parse::<hyper::Uri>().ok());
+        if let Some(dest) = dest {
+            if let Some(path) = self.strip_path_suffix(dest.path()) {
+                let mut parts = dest.clone().into_parts();
+                parts.path_and_query = path.parse().ok();
+                if let Some(value) = hyper::Uri::from_parts(parts)
+                    .ok()
+                    .and_then(|uri| HeaderValue::from_str(&uri.to_string()).ok())
+                {
+                    req.headers_mut().insert("destination", value);
+                }
+            }
+        }
+    }
+
+    pub fn set_trusted_proxies(&mut self, trusted_proxies: Vec<IpNet>) -> &mut Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn set_extra_headers(&mut self, extra_headers: Vec<ExtraHeader>) -> &mut Self {
+        self.extra_headers = extra_headers;
+        self
+    }
+
+    pub fn set_cors(&mut self, cors: Option<Cors>) -> &mut Self {
+        self.cors = cors;
+        self
+    }
+
+    /// Charset appended to `text/*` content types of downloads lacking one
+    pub fn set_text_charset(&mut self, charset: Option<HeaderValue>) -> &mut Self {
+        self.text_charset = charset;
+        self
+    }
+
+    pub fn set_content_language(&mut self, content_language: Option<HeaderValue>) -> &mut Self {
+        self.content_language = content_language;
+        self
+    }
+
+    /// Header carrying the user already authenticated by a trusted proxy
+    pub fn set_principal_header(&mut self, principal_header: Option<HeaderName>) -> &mut Self {
+        self.principal_header = principal_header;
+        self
+    }
+
+    pub fn set_quirks(&mut self, rules: Vec<QuirkRule>, builtin: bool) -> &mut Self {
+        self.live.write().unwrap().set_quirks(rules, builtin);
+        self
+    }
+
+    /// Log every request at info level, with its status, size and duration
+    pub fn set_access_log(&mut self, access_log: bool) -> &mut Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Whether OPTIONS advertises DAV class 2. Locks are only held in memory,
+    /// so with several instances behind a load balancer they don't protect anything.
+    pub fn set_advertise_locking(&mut self, advertise_locking: bool) -> &mut Self {
+        self.advertise_locking = advertise_locking;
+        self
+    }
+
+    pub fn set_symlink_policy(&mut self, symlink_policy: SymlinkPolicy) -> &mut Self {
+        self.symlink_policy = symlink_policy;
+        self
+    }
+
+    pub fn set_enable_thumbnails(&mut self, enable_thumbnails: bool) -> &mut Self {
+        self.enable_thumbnails = enable_thumbnails;
+        self
+    }
+
+    /// Serve the thumbnail generated by Aliyun for `GET /image.jpg?thumbnail=<width>`.
+    ///
+    /// Files other than images fall through to the regular download.
+    async fn thumbnail(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        if !self.enable_thumbnails || req.method() != Method::GET {
+            return None;
+        }
+        let width = url::form_urlencoded::parse(req.uri().query()?.as_bytes())
+            .find(|(k, _)| k == "thumbnail")?
+            .1;
+        let response = match width.parse::<u32>() {
+            Ok(width) if THUMBNAIL_WIDTHS.contains(&width) => {
+                let path = self.dav_path(req)?;
+                match self.fs.thumbnail(&path, width).await {
+                    Ok(Some(content)) => Response::builder()
+                        .status(StatusCode::OK)
+                        .header(header::CONTENT_TYPE, "image/jpeg")
+                        .body(Body::from(content)),
+                    Ok(None) => {
+                        debug!(path = %path, "no thumbnail, serve the file");
+                        return None;
+                    }
+                    Err(err) => Response::builder()
+                        .status(fs_error_status(err))
+                        .body(Body::empty()),
+                }
+            }
+            _ => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!(
+                    "thumbnail width must be between {} and {}",
+                    THUMBNAIL_WIDTHS.start(),
+                    THUMBNAIL_WIDTHS.end()
+                ))),
+        };
+        Some(response.unwrap())
+    }
+
+    /// Destination of a COPY or MOVE request
+    fn destination(&self, req: &Request<hyper::Body>) -> Option<DavPath> {
+        let dest = req.headers().get("destination")?.to_str().ok()?;
+        let path = match dest.parse::<hyper::Uri>() {
+            Ok(uri) => uri.path().to_string(),
+            Err(_) => dest.to_string(),
+        };
+        let mut path = DavPath::new(&path).ok()?;
+        if let Some(prefix) = self.strip_prefix.as_deref() {
+            path.set_prefix(prefix).ok()?;
+        }
+        Some(path)
+    }
+
+    /// Lock system of the DAV handler, consulted before deleting whole trees
+    pub fn set_lock_system(&mut self, lock_system: Box<dyn DavLockSystem>) -> &mut Self {
+        self.lock_system = Some(lock_system);
+        self
+    }
+
+    /// Longest timeout of the locks of users with their own root
+    pub fn set_max_lock_timeout(&mut self, max_lock_timeout: Duration) -> &mut Self {
+        self.max_lock_timeout = Some(max_lock_timeout);
+        self
+    }
+
+    /// Lock system of the users with this root directory
+    fn user_lock_system(&self, root: &str) -> Box<dyn DavLockSystem> {
+        self.user_lock_systems
+            .lock()
+            .unwrap()
+            .entry(root.to_string())
+            .or_insert_with(|| match self.max_lock_timeout {
+                Some(max_timeout) => TimeoutLs::new(MemLs::new(), max_timeout),
+                None => MemLs::new(),
+            })
+            .clone()
+    }
+
+    /// Delete a collection in one go rather than letting the DAV handler
+    /// delete its members one request at a time.
+    ///
+    /// Only plain DELETEs are handled here, conditional requests and trees
+    /// with locks in them are left to the DAV handler.
+    async fn delete_tree(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        if req.method() != Method::DELETE || !self.fs.deletes_trees() {
+            return None;
+        }
+        let headers = req.headers();
+        let conditional = ["if", "if-match", "if-none-match", "if-unmodified-since"]
+            .iter()
+            .any(|name| headers.contains_key(*name));
+        let depth = headers.get("depth").and_then(|v| v.to_str().ok());
+        if conditional
+            || depth
+                .map(|d| !d.eq_ignore_ascii_case("infinity"))
+                .unwrap_or(false)
+        {
+            return None;
+        }
+        let path = self.dav_path(req)?;
+        if !self.fs.metadata(&path).await.ok()?.is_dir() {
+            return None;
+        }
+        if let Some(ls) = self.lock_system.as_ref() {
+            if ls.check(&path, None, false, true, Vec::new()).is_err() {
+                return None;
+            }
+        }
+        let response = match self.fs.remove_tree(&path).await {
+            Ok(failures) if failures.is_empty() => {
+                if let Some(ls) = self.lock_system.as_ref() {
+                    ls.delete(&path).ok();
+                }
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+            }
+            Ok(failures) => {
+                warn!(path = %path, failed = failures.len(), "delete directory partially failed");
+                let mut body = String::from(
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">",
+                );
+                let base = req.uri().path().trim_end_matches('/');
+                for (rel_path, err) in failures {
+                    // encode the relative path, the request path already is
+                    let mut url = url::Url::parse("http://localhost/").unwrap();
+                    url.path_segments_mut()
+                        .unwrap()
+                        .pop_if_empty()
+                        .extend(rel_path.iter().map(|s| s.to_string_lossy()));
+                    let status = fs_error_status(err);
+                    body.push_str(&format!(
+                        "<D:response><D:href>{}{}</D:href><D:status>HTTP/1.1 {} {}</D:status></D:response>",
+                        base,
+                        url.path(),
+                        status.as_u16(),
+                        status.canonical_reason().unwrap_or_default()
+                    ));
+                }
+                body.push_str("</D:multistatus>\n");
+                Response::builder()
+                    .status(StatusCode::MULTI_STATUS)
+                    .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+                    .body(Body::from(body))
+            }
+            Err(err) => Response::builder()
+                .status(fs_error_status(err))
+                .body(Body::empty()),
+        };
+        Some(response.unwrap())
+    }
+
+    /// Principal set by a trusted proxy in the principal header.
+    ///
+    /// Returns an error if the header was sent by an untrusted source.
+    fn proxy_principal(&self, req: &Request<hyper::Body>) -> Result<Option<String>, ()> {
+        let Some(name) = self.principal_header.as_ref() else {
+            return Ok(None);
+        };
+        let Some(value) = req.headers().get(name) else {
+            return Ok(None);
+        };
+        if !self.is_trusted() {
+            warn!(remote_addr = ?self.remote_addr, header = %name, "reject principal header from untrusted source");
+            return Err(());
+        }
+        match value.to_str().map(str::trim) {
+            Ok(user) if !user.is_empty() => Ok(Some(user.to_string())),
+            _ => Err(()),
+        }
+    }
+
+    /// Serve `page` to browsers while the upstream is down
+    pub fn set_maintenance_page(&mut self, health: UpstreamHealth, page: Bytes) -> &mut Self {
+        self.maintenance = Some((health, page));
+        self
+    }
+
+    /// Fail fast with 503 while the circuit breaker is open
+    pub fn set_circuit_breaker(
+        &mut self,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+    ) -> &mut Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    /// Serve Prometheus metrics at this path, before authentication
+    pub fn set_metrics_path(&mut self, metrics_path: Option<String>) -> &mut Self {
+        self.metrics_path = metrics_path.filter(|path| !path.is_empty());
+        self
+    }
+
+    /// Answer health checks at this path, before authentication
+    pub fn set_health_check(
+        &mut self,
+        path: Option<String>,
+        token_health: TokenHealth,
+        upstream_health: Option<UpstreamHealth>,
+    ) -> &mut Self {
+        self.health_check = path
+            .filter(|path| !path.is_empty())
+            .map(|path| (path, token_health, upstream_health));
+        self
+    }
+
+    /// Favicon and `robots.txt` served at the root, before authentication
+    pub fn set_static_files(&mut self, static_files: StaticFiles) -> &mut Self {
+        self.static_files = static_files;
+        self
+    }
+
+    fn metrics_response(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        let metrics_path = self.metrics_path.as_deref()?;
+        if req.method() != Method::GET || req.uri().path() != metrics_path {
+            return None;
+        }
+        let circuit_open = self
+            .circuit_breaker
+            .as_ref()
+            .map(|breaker| breaker.rejects())
+            .unwrap_or(false);
+        let body = metrics::render(circuit_open, self.fs.is_write_degraded())?;
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(body))
+            .unwrap();
+        Some(response)
+    }
+
+    /// 200 while the access token is valid and the upstream is reachable, as
+    /// last seen by the token refresh, the circuit breaker and the upstream probe.
+    /// Degraded writes keep the 200 since reads are still served.
+    fn health_response(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        let (health_path, token_health, upstream_health) = self.health_check.as_ref()?;
+        if (req.method() != Method::GET && req.method() != Method::HEAD)
+            || req.uri().path() != health_path
+        {
+            return None;
+        }
+        let circuit_open = self
+            .circuit_breaker
+            .as_ref()
+            .map(|breaker| breaker.rejects())
+            .unwrap_or(false);
+        let upstream_down = upstream_health
+            .as_ref()
+            .map(|health| health.is_down())
+            .unwrap_or(false);
+        let (status, body) = if !token_health.is_valid() {
+            (StatusCode::SERVICE_UNAVAILABLE, "token refresh failed\n")
+        } else if circuit_open || upstream_down {
+            (StatusCode::SERVICE_UNAVAILABLE, "upstream unreachable\n")
+        } else if self.fs.is_write_degraded() {
+            (StatusCode::OK, "OK, read-only: writes keep failing\n")
+        } else {
+            (StatusCode::OK, "OK\n")
+        };
+        let body = if req.method() == Method::HEAD {
+            String::new()
+        } else {
+            body.to_string()
+        };
+        let response = Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::from(body))
+            .unwrap();
+        Some(response)
+    }
+
+    /// Status of requests for paths resolving outside of the served root
+    pub fn set_out_of_root_status(&mut self, out_of_root_status: OutOfRootStatus) -> &mut Self {
+        self.out_of_root_status = out_of_root_status;
+        self
+    }
+
+    /// Default verbosity of PROPFIND responses, `Brief` and `Prefer` headers
+    /// of a request take precedence
+    pub fn set_propfind_mode(&mut self, propfind_mode: PropfindMode) -> &mut Self {
+        self.propfind_mode = propfind_mode;
+        self
+    }
+
+    /// Whether the request path or its `Destination` climbs above the root with `..`,
+    /// checked on the normalized path before it reaches the file system
+    fn is_outside_root(&self, req: &Request<hyper::Body>) -> bool {
+        let escapes = |path: &str| matches!(DavPath::new(path), Err(ParseError::ForbiddenPath));
+        if escapes(req.uri().path()) {
+            return true;
+        }
+        req.headers()
+            .get("destination")
+            .and_then(|dest| dest.to_str().ok())
+            .map(|dest| match dest.parse::<hyper::Uri>() {
+                Ok(uri) => escapes(uri.path()),
+                Err(_) => escapes(dest),
+            })
+            .unwrap_or(false)
+    }
+
+    fn is_outside_dav_prefix(&self, req: &Request<hyper::Body>) -> bool {
+        let Some(prefix) = self.dav_prefix.as_deref() else {
+            return false;
+        };
+        let path = req.uri().path();
+        if path.starts_with(CONTROL_PREFIX) {
+            return false;
+        }
+        match path.strip_prefix(prefix) {
+            Some(rest) => !(rest.is_empty() || rest.starts_with('/')),
+            None => true,
+        }
+    }
+
+    fn circuit_open_response(&self, serve_stale: bool) -> Option<Response<Body>> {
+        let breaker = self.circuit_breaker.as_ref()?;
+        if serve_stale || !breaker.rejects() {
+            return None;
+        }
+        let retry_after = breaker.retry_after().as_secs().max(1);
+        debug!(retry_after = retry_after, "circuit breaker open, fail fast");
+        let response = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::RETRY_AFTER, retry_after.to_string())
+            .body(Body::from("Service Unavailable".to_string()))
+            .unwrap();
+        Some(response)
+    }
+
+    /// 503 response while the upstream is down, browsers get the maintenance page
+    fn maintenance_response(
+        &self,
+        req: &Request<hyper::Body>,
+        serve_stale: bool,
+    ) -> Option<Response<Body>> {
+        let (health, page) = self.maintenance.as_ref()?;
+        if !health.is_down() || serve_stale {
+            return None;
+        }
+        let is_browser = req.method() == Method::GET
+            && req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .map(|accept| accept.contains("text/html"))
+                .unwrap_or(false);
+        debug!(
+            browser = is_browser,
+            "upstream down, serve maintenance response"
+        );
+        let builder = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::RETRY_AFTER, "30");
+        let response = if is_browser {
+            builder
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(page.clone()))
+        } else {
+            builder.body(Body::from("Service Unavailable".to_string()))
+        };
+        Some(response.unwrap())
+    }
+
+    /// Add charset and language to the response of a successful download
+    fn set_content_headers(&self, response: &mut Response<Body>) {
+        if !response.status().is_success() {
+            return;
+        }
+        let headers = response.headers_mut();
+        if let Some(charset) = self.text_charset.as_ref() {
+            let content_type = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .filter(|v| v.starts_with("text/") && !v.contains("charset="))
+                .and_then(|v| {
+                    HeaderValue::from_str(&format!(
+                        "{}; charset={}",
+                        v,
+                        charset.to_str().unwrap_or_default()
+                    ))
+                    .ok()
+                });
+            if let Some(content_type) = content_type {
+                headers.insert(header::CONTENT_TYPE, content_type);
+            }
+        }
+        if let Some(language) = self.content_language.as_ref() {
+            headers
+                .entry(header::CONTENT_LANGUAGE)
+                .or_insert_with(|| language.clone());
+        }
+    }
+
+    /// Only serve requests for these hosts, any host is served when empty
+    pub fn set_allowed_hosts(&mut self, allowed_hosts: Vec<String>) -> &mut Self {
+        self.live.write().unwrap().set_allowed_hosts(allowed_hosts);
+        self
+    }
+
+    /// Whether the request is for an allowed host, from `X-Forwarded-Host` when
+    /// set by a trusted proxy and from `Host` otherwise
+    fn is_allowed_host(&self, req: &Request<hyper::Body>) -> bool {
+        let live = self.live.read().unwrap();
+        if live.allowed_hosts.is_empty() {
+            return true;
+        }
+        let forwarded_host = if self.is_trusted() {
+            req.headers()
+                .get("x-forwarded-host")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+        } else {
+            None
+        };
+        let host = forwarded_host
+            .or_else(|| {
+                req.headers()
+                    .get(header::HOST)
+                    .and_then(|v| v.to_str().ok())
+            })
+            .or_else(|| req.uri().authority().map(|a| a.as_str()));
+        let Some(host) = host.map(|h| h.trim().to_ascii_lowercase()) else {
+            debug!("request without host");
+            return false;
+        };
+        let hostname = match host.rsplit_once(':') {
+            // not a bare IPv6 address
+            Some((name, port)) if !port.contains(']') && !name.is_empty() => name,
+            _ => host.as_str(),
+        };
+        let allowed = live
+            .allowed_hosts
+            .iter()
+            .any(|allowed| *allowed == host || *allowed == hostname);
+        if !allowed {
+            warn!(host = %host, "request for a host not allowed");
+        }
+        allowed
+    }
+
+    /// Whether the request comes from a trusted proxy
+    fn is_trusted(&self) -> bool {
+        self.remote_addr
+            .map(|addr| ip_in(&self.trusted_proxies, addr.ip()))
+            .unwrap_or(false)
+    }
+
+    /// Address of the client, from `X-Forwarded-For` when sent by a trusted proxy
+    fn client_ip(&self, req: &Request<hyper::Body>) -> Option<IpAddr> {
+        let forwarded = if self.is_trusted() {
+            req.headers()
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .and_then(|ip| ip.trim().parse().ok())
+        } else {
+            None
+        };
+        forwarded.or_else(|| self.remote_addr.map(|addr| addr.ip()))
+    }
+
+    /// Read buffer size requested with the `X-Read-Buffer-Size` header, clamped to sane bounds
+    fn read_buf_size_override(&self, req: &Request<hyper::Body>) -> Option<usize> {
+        let size = req
+            .headers()
+            .get(READ_BUFFER_SIZE_HEADER)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<usize>()
+            .ok()?;
+        if !self.is_trusted() {
+            debug!(remote_addr = ?self.remote_addr, "ignore read buffer size header from untrusted source");
+            return None;
+        }
+        let size = size.clamp(MIN_READ_BUFFER_SIZE, MAX_READ_BUFFER_SIZE);
+        debug!(
+            read_buffer_size = size,
+            "read buffer size overridden by request header"
+        );
+        Some(size)
+    }
+
+    /// Subtrees readable without credentials, writes still require them
+    pub fn set_public_paths(&mut self, public_paths: Vec<String>) -> &mut Self {
+        self.public_paths = public_paths
+            .iter()
+            .map(|path| Path::new("/").join(path.trim_matches('/')))
+            .collect();
+        self
+    }
+
+    /// Let requests without credentials read everything, writes still require them
+    pub fn set_allow_anonymous_read(&mut self, allow_anonymous_read: bool) -> &mut Self {
+        self.allow_anonymous_read = allow_anonymous_read;
+        self
+    }
+
+    /// Whether the request only reads from a public path. The path is checked
+    /// after normalization, so `..` can not lead out of a public subtree.
+    fn is_public_read(&self, req: &Request<hyper::Body>) -> bool {
+        // credentials sent along are still checked, so users keep their own root
+        let anonymous =
+            self.allow_anonymous_read && !req.headers().contains_key(header::AUTHORIZATION);
+        if self.public_paths.is_empty() && !anonymous {
+            return false;
+        }
+        let is_read = matches!(
+            req.method().as_str(),
+            "GET" | "HEAD" | "OPTIONS" | "PROPFIND"
+        );
+        if !is_read || req.uri().path().starts_with(CONTROL_PREFIX) {
+            return false;
+        }
+        if anonymous {
+            return true;
+        }
+        let Some(path) = self.dav_path(req) else {
+            return false;
+        };
+        let path = path.as_pathbuf();
+        self.public_paths
+            .iter()
+            .any(|public| path.starts_with(public))
+    }
+
+    /// Byte length of the name of the file or folder created by the request and
+    /// the limit, if it exceeds `--max-filename-bytes`, checked before the body
+    /// is read
+    fn filename_too_long(&self, req: &Request<hyper::Body>) -> Option<(usize, usize)> {
+        let target = match req.method().as_str() {
+            "PUT" | "MKCOL" => self.dav_path(req)?,
+            "COPY" | "MOVE" => self.destination(req)?,
+            _ => return None,
+        };
+        self.fs.filename_too_long(&target)
+    }
+
+    /// Entries listed at most per collection in PROPFIND responses
+    pub fn set_max_propfind_results(&mut self, max_propfind_results: Option<usize>) -> &mut Self {
+        self.max_propfind_results = max_propfind_results;
+        self
+    }
+
+    /// `Warning` for a PROPFIND of a collection with more than `limit` entries
+    async fn propfind_truncation(
+        &self,
+        req: &Request<hyper::Body>,
+        limit: usize,
+    ) -> Option<HeaderValue> {
+        let path = self.dav_path(req)?;
+        let entries = self
+            .fs
+            .read_dir(&path, ReadDirMeta::None)
+            .await
+            .ok()?
+            .count()
+            .await;
+        if entries <= limit {
+            return None;
+        }
+        debug!(path = %path, entries = entries, limit = limit, "propfind results truncated");
+        HeaderValue::from_str(&format!(
+            "199 - \"Listing truncated to {} of {} entries\"",
+            limit, entries
+        ))
+        .ok()
+    }
+
+    fn dav_path(&self, req: &Request<hyper::Body>) -> Option<DavPath> {
+        let mut path = DavPath::new(req.uri().path()).ok()?;
+        if let Some(prefix) = self.strip_prefix.as_deref() {
+            path.set_prefix(prefix).ok()?;
+        }
+        Some(path)
+    }
+
+    /// Files changed since the `since` cursor, with the cursor to ask for the next ones
+    async fn changes(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        if req.method() != Method::GET || req.uri().path() != "/_control/changes" {
+            return None;
+        }
+        let since = req.uri().query().and_then(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .find(|(k, v)| k == "since" && !v.is_empty())
+                .map(|(_, v)| v.into_owned())
+        });
+        let delta = match self.fs.list_changes(since.as_deref()).await {
+            Ok(delta) => delta,
+            Err(err) => {
+                return Some(
+                    Response::builder()
+                        .status(fs_error_status(err))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }
+        };
+        let changes = match delta {
+            Some(delta) => Changes {
+                changes: delta
+                    .items
+                    .into_iter()
+                    .map(|item| {
+                        let file = item.file;
+                        ChangeEntry {
+                            op: item.op,
+                            file_id: item.file_id,
+                            name: file.as_ref().map(|f| f.name.clone()),
+                            parent_file_id: file.as_ref().and_then(|f| f.parent_file_id.clone()),
+                            is_dir: file.as_ref().map(|f| matches!(f.r#type, FileType::Folder)),
+                            size: file.as_ref().and_then(|f| f.size),
+                            mtime: file.as_ref().and_then(|f| {
+                                OffsetDateTime::from(*f.updated_at).format(&Rfc3339).ok()
+                            }),
+                        }
+                    })
+                    .collect(),
+                cursor: Some(delta.cursor),
+                has_more: delta.has_more,
+                needs_full_resync: false,
+            },
+            None => Changes {
+                changes: Vec::new(),
+                cursor: None,
+                has_more: false,
+                needs_full_resync: true,
+            },
+        };
+        let body = match serde_json::to_vec(&changes) {
+            Ok(body) => body,
+            Err(err) => {
+                error!(error = %err, "serialize changes failed");
+                return None;
+            }
+        };
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(Bytes::from(body)))
+            .unwrap();
+        Some(response)
+    }
+
+    /// Serve the auto-index of a collection as JSON when the client asks for it
+    /// with `Accept: application/json` or `?format=json`.
+    ///
+    /// Returns `None` to let the DAV handler serve the request as usual.
+    async fn auto_index_json(&self, req: &Request<hyper::Body>) -> Option<Response<Body>> {
+        if !self.auto_index || req.method() != Method::GET || !wants_json(req) {
+            return None;
+        }
+        let path = self.dav_path(req)?;
+        let meta = self.fs.metadata(&path).await.ok()?;
+        if !meta.is_dir() {
+            return None;
+        }
+        debug!(path = %path, "auto index: json");
+        let mut entries = self.fs.read_dir(&path, ReadDirMeta::Data).await.ok()?;
+        let mut index = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let Ok(meta) = entry.metadata().await else {
+                continue;
+            };
+            let name = String::from_utf8_lossy(&entry.name()).into_owned();
+            let is_dir = meta.is_dir();
+            let mime = if is_dir {
+                "httpd/unix-directory".to_string()
+            } else {
+                mime_guess::from_path(&name)
+                    .first_or_octet_stream()
+                    .to_string()
+            };
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| OffsetDateTime::from(t).format(&Rfc3339).ok());
+            index.push(IndexEntry {
+                name,
+                size: meta.len(),
+                mtime,
+                is_dir,
+                mime,
+            });
+        }
+        let body = match serde_json::to_vec(&index) {
+            Ok(body) => body,
+            Err(err) => {
+                error!(error = %err, "serialize auto index failed");
+                return None;
+            }
+        };
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(bytes::Bytes::from(body)))
+            .unwrap();
+        Some(response)
+    }
+}
+
+/// What to do with symbolic links uploaded by sync tools.
+///
+/// The target of a link lives on the client, so links can't be followed here,
+/// clients have to dereference them before uploading.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Keep the link as a small file holding its target
+    #[default]
+    Store,
+    /// Refuse symlink uploads with 403 Forbidden
+    Reject,
+}
+
+/// Verbosity of PROPFIND responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PropfindMode {
+    /// All properties for `allprop` requests, with not found ones reported
+    #[default]
+    Full,
+    /// Only core properties for `allprop` requests, without not found ones
+    Minimal,
+}
+
+/// Status of requests for paths outside of the served root
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutOfRootStatus {
+    /// 404 Not Found, doesn't reveal that anything exists outside of the root
+    #[default]
+    #[value(name = "404")]
+    NotFound,
+    /// 403 Forbidden
+    #[value(name = "403")]
+    Forbidden,
+}
+
+/// Whether a PUT uploads a symbolic link, sent as `Content-Type: inode/symlink`
+/// or translated by rclone into a `.rclonelink` file
+fn is_symlink_upload(req: &Request<hyper::Body>) -> bool {
+    let symlink_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().eq_ignore_ascii_case("inode/symlink"))
+        .unwrap_or(false);
+    symlink_type || req.uri().path().ends_with(".rclonelink")
+}
+
+/// WebDAV account confined to a directory, parsed from `user:password:/root`
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    user: String,
+    password: String,
+    root: String,
+}
+
+impl FromStr for AuthUser {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || "invalid user, expected `user:password:/root`".to_string();
+        let (user, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let (password, root) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        if user.is_empty() || password.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            user: user.to_string(),
+            password: password.to_string(),
+            root: root.to_string(),
+        })
+    }
+}
+
+/// Static header added to every response, parsed from `Name: Value`
+#[derive(Debug, Clone)]
+pub struct ExtraHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl FromStr for ExtraHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid header `{}`, expected `Name: Value`", s))?;
+        let name = HeaderName::from_str(name.trim())
+            .map_err(|_| format!("invalid header name `{}`", name.trim()))?;
+        // Framing and connection management are up to the server
+        const RESERVED: [HeaderName; 8] = [
+            header::CONNECTION,
+            header::CONTENT_LENGTH,
+            header::PROXY_AUTHENTICATE,
+            header::PROXY_AUTHORIZATION,
+            header::TE,
+            header::TRAILER,
+            header::TRANSFER_ENCODING,
+            header::UPGRADE,
+        ];
+        if RESERVED.contains(&name) || name.as_str() == "keep-alive" {
+            return Err(format!("header `{}` can not be overridden", name));
+        }
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("invalid value for header `{}`", name))?;
+        Ok(Self { name, value })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct IndexEntry {
+    name: String,
+    size: u64,
+    mtime: Option<String>,
+    is_dir: bool,
+    mime: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ChangeEntry {
+    op: String,
+    file_id: String,
+    name: Option<String>,
+    parent_file_id: Option<String>,
+    is_dir: Option<bool>,
+    size: Option<u64>,
+    mtime: Option<String>,
+}
+
+/// Response of `/_control/changes`, without a cursor when the client has to
+/// list everything again
+#[derive(Debug, Serialize)]
+struct Changes {
+    changes: Vec<ChangeEntry>,
+    cursor: Option<String>,
+    has_more: bool,
+    needs_full_resync: bool,
+}
+
+fn wants_json(req: &Request<hyper::Body>) -> bool {
+    let format_json = req
+        .uri()
+        .query()
+        .map(|q| {
+            url::form_urlencoded::parse(q.as_bytes()).any(|(k, v)| k == "format" && v == "json")
+        })
+        .unwrap_or(false);
+    format_json
+        || req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(|accept| accept.contains("application/json"))
+            .unwrap_or(false)
+}
+
+/// Ask for the core properties only when a PROPFIND asks for all of them
+async fn minimal_propfind(req: Request<hyper::Body>) -> Request<hyper::Body> {
+    let (mut parts, body) = req.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            error!(error = %err, "read PROPFIND request failed");
+            return Request::from_parts(parts, hyper::Body::empty());
+        }
+    };
+    if !is_allprop(&body) {
+        return Request::from_parts(parts, hyper::Body::from(body));
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    Request::from_parts(parts, hyper::Body::from(MINIMAL_PROPFIND))
+}
+
+/// Drop the properties that were not found from a PROPFIND response
+async fn brief_response(response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            error!(error = %err, "read PROPFIND response failed");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let body = match brief_multistatus(&body) {
+        Some(brief) => Bytes::from(brief),
+        None => body,
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Status code the DAV handler answers a file system error with
+fn fs_error_status(err: FsError) -> StatusCode {
+    match err {
+        FsError::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+        FsError::GeneralFailure => StatusCode::INTERNAL_SERVER_ERROR,
+        FsError::Exists => StatusCode::METHOD_NOT_ALLOWED,
+        FsError::NotFound => StatusCode::NOT_FOUND,
+        FsError::Forbidden => StatusCode::FORBIDDEN,
+        FsError::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+        FsError::LoopDetected => StatusCode::LOOP_DETECTED,
+        FsError::PathTooLong => StatusCode::URI_TOO_LONG,
+        FsError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        FsError::IsRemote => StatusCode::BAD_GATEWAY,
+    }
 }
 
 impl Service<Request<hyper::Body>> for AliyunDriveWebDav {
@@ -97,47 +1313,405 @@ impl Service<Request<hyper::Body>> for AliyunDriveWebDav {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: Request<hyper::Body>) -> Self::Future {
-        let should_auth = self.auth_user.is_some() && self.auth_password.is_some();
+    fn call(&mut self, mut req: Request<hyper::Body>) -> Self::Future {
+        if !self.is_allowed_host(&req) {
+            let response = Response::builder()
+                .status(StatusCode::MISDIRECTED_REQUEST)
+                .body(Body::from("Misdirected Request".to_string()))
+                .unwrap();
+            return Box::pin(async move { Ok(response) });
+        }
+        if let Some(response) = self
+            .metrics_response(&req)
+            .or_else(|| self.health_response(&req))
+            .or_else(|| self.static_files.response(&req))
+        {
+            return Box::pin(async move { Ok(response) });
+        }
+        if self.is_outside_dav_prefix(&req) {
+            debug!(path = %req.uri().path(), "request outside of the dav prefix");
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not Found".to_string()))
+                .unwrap();
+            return Box::pin(async move { Ok(response) });
+        }
+        if self.is_outside_root(&req) {
+            debug!(path = %req.uri().path(), "request outside of the root");
+            let response = match self.out_of_root_status {
+                OutOfRootStatus::NotFound => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("Not Found".to_string())),
+                OutOfRootStatus::Forbidden => Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Forbidden".to_string())),
+            }
+            .unwrap();
+            return Box::pin(async move { Ok(response) });
+        }
+        metrics::record_request(req.method().as_str());
+        // digest responses are computed over the uri as the client sent it
+        let request_uri = req.uri().clone();
+        self.strip_request_suffix(&mut req);
+        let live = self.live.read().unwrap();
+        let should_auth = (live.auth_user.is_some() && live.auth_password.is_some())
+            || !live.auth_users.is_empty()
+            || self.auth_command.is_some();
         let dav_server = self.handler.clone();
-        let auth_user = self.auth_user.clone();
-        let auth_pwd = self.auth_password.clone();
-        Box::pin(async move {
-            if should_auth {
-                let auth_user = auth_user.unwrap();
-                let auth_pwd = auth_pwd.unwrap();
-                let user = match req.headers().typed_get::<Authorization<Basic>>() {
-                    Some(Authorization(basic))
-                        if basic.username() == auth_user && basic.password() == auth_pwd =>
-                    {
-                        basic.username().to_string()
+        let auth_user = live.auth_user.clone();
+        let auth_pwd = live.auth_password.clone();
+        let auth_users = live.auth_users.clone();
+        let quirks = live.quirks.for_request(req.headers());
+        drop(live);
+        let mut this = self.clone();
+        let req_method = req.method().clone();
+        let req_path = req.uri().path().to_string();
+        // CORS preflights carry no credentials, answer them before authentication
+        let preflight = self.cors.as_ref().and_then(|cors| cors.preflight(&req));
+        let cors = self.cors.clone().map(|cors| (cors, req.headers().clone()));
+        if !quirks.is_empty() {
+            debug!(quirks = ?quirks, "client quirks");
+        }
+        if quirks.contains(&Quirk::NoRange) {
+            req.headers_mut().remove(header::RANGE);
+            req.headers_mut().remove(header::IF_RANGE);
+        }
+        let minimal = req.method().as_str() == "PROPFIND"
+            && prefers_minimal(req.headers())
+                .unwrap_or(self.propfind_mode == PropfindMode::Minimal);
+        let brief =
+            (quirks.contains(&Quirk::Brief) || minimal) && req.method().as_str() == "PROPFIND";
+        let ms_author_via = quirks.contains(&Quirk::MsAuthorVia) && req_method == Method::OPTIONS;
+        let is_download = req_method == Method::GET || req_method == Method::HEAD;
+        let upstream_down = is_download && self.fs.is_upstream_down();
+        let client_ip = self.client_ip(&req);
+        let fut = async move {
+            if let Some(response) = preflight {
+                return response;
+            }
+            let mut config = DavConfig::new();
+            let proxy_principal = match this.proxy_principal(&req) {
+                Ok(principal) => principal,
+                Err(_) => {
+                    return hyper::Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::from("Forbidden".to_string()))
+                        .unwrap();
+                }
+            };
+            if let Some(user) = proxy_principal {
+                debug!(user = %user, "principal set by trusted proxy");
+                Span::current().record("principal", user.as_str());
+                audit::set_principal(&user);
+                config = config.principal(user);
+            } else if should_auth && this.is_public_read(&req) {
+                debug!(path = %req.uri().path(), "public path, skip authentication");
+            } else if should_auth {
+                // password and root directory of a user
+                let account = |user: &str| {
+                    if auth_user.as_deref() == Some(user) {
+                        return auth_pwd.clone().map(|password| (password, None));
                     }
-                    Some(_) | None => {
-                        // return a 401 reply.
-                        let response = hyper::Response::builder()
-                            .status(401)
-                            .header("WWW-Authenticate", "Basic realm=\"aliyundrive-webdav\"")
-                            .body(Body::from("Authentication required".to_string()))
+                    auth_users
+                        .get(user)
+                        .map(|(password, root)| (password.clone(), Some(root.clone())))
+                };
+                let mut stale = false;
+                let authenticated = match this.digest.as_ref() {
+                    Some(digest) => match digest
+                        .verify(&req, &request_uri, |user| account(user).map(|(p, _)| p))
+                    {
+                        Ok(user) => account(&user).map(|(_, root)| (user, root)),
+                        Err(is_stale) => {
+                            stale = is_stale;
+                            None
+                        }
+                    },
+                    None => match req.headers().typed_get::<Authorization<Basic>>() {
+                        Some(Authorization(basic)) => match account(basic.username()) {
+                            Some((password, root)) => (password == basic.password())
+                                .then(|| (basic.username().to_string(), root)),
+                            None => match this.auth_command.as_ref() {
+                                Some(command)
+                                    if command.verify(basic.username(), basic.password()).await =>
+                                {
+                                    Some((basic.username().to_string(), None))
+                                }
+                                _ => None,
+                            },
+                        },
+                        None => None,
+                    },
+                };
+                let Some((user, root)) = authenticated else {
+                    let challenge = match this.digest.as_ref() {
+                        Some(digest) => digest.challenge(stale),
+                        None => {
+                            HeaderValue::from_str(&format!("Basic realm=\"{}\"", REALM)).unwrap()
+                        }
+                    };
+                    // return a 401 reply.
+                    let response = hyper::Response::builder()
+                        .status(401)
+                        .header(header::WWW_AUTHENTICATE, challenge)
+                        .body(Body::from("Authentication required".to_string()))
+                        .unwrap();
+                    return response;
+                };
+                if root.is_some() && req.uri().path().starts_with(CONTROL_PREFIX) {
+                    // changes are drive-wide, not confined to the root of the user
+                    return hyper::Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::from("Forbidden".to_string()))
+                        .unwrap();
+                }
+                if let Some(root) = root {
+                    debug!(user = %user, root = %root, "serve user root");
+                    this.fs = this.fs.with_root(&root);
+                    let lock_system = this.user_lock_system(&root);
+                    this.lock_system = Some(lock_system.clone());
+                    config = config
+                        .filesystem(Box::new(this.fs.clone()))
+                        .locksystem(lock_system);
+                }
+                Span::current().record("principal", user.as_str());
+                audit::set_principal(&user);
+                config = config.principal(user);
+            }
+            // files with a local copy are still served while the upstream is
+            // down, looked up once the root of the user is known
+            let serve_stale = match this.dav_path(&req) {
+                Some(path) if upstream_down => this.fs.has_disk_cached(&path).await,
+                _ => false,
+            };
+            if serve_stale {
+                mark_stale();
+            }
+            if let Some(response) = this.maintenance_response(&req, serve_stale) {
+                return response;
+            }
+            if let Some(response) = this.circuit_open_response(serve_stale) {
+                return response;
+            }
+            let conditional_write = !is_download
+                && (req.headers().contains_key(header::IF_MATCH)
+                    || req.headers().contains_key(header::IF_NONE_MATCH));
+            if conditional_write {
+                // compare the ETag of the file as it is now, not as cached,
+                // or an update by another client would be overwritten
+                if let Some(path) = this.dav_path(&req) {
+                    this.fs.invalidate_metadata(&path).await;
+                }
+            } else if req.headers().contains_key(header::RANGE)
+                && req.headers().contains_key(header::IF_RANGE)
+            {
+                // If-Range is checked against the ETag and Last-Modified of the file,
+                // make sure they are current so a changed file is sent in full
+                if let Some(path) = this.dav_path(&req) {
+                    this.fs.refresh_metadata(&path).await;
+                }
+            }
+            if quirks.contains(&Quirk::NoRedirect) {
+                config = config.redirect(false);
+            }
+            if let Some(size) = this.read_buf_size_override(&req) {
+                config = config.read_buf_size(size);
+            }
+            if let Some(response) = this.changes(&req).await {
+                return response;
+            }
+            if let Some(response) = this.auto_index_json(&req).await {
+                return response;
+            }
+            if let Some(response) = this.thumbnail(&req).await {
+                return response;
+            }
+            if matches!(req.method().as_str(), "COPY" | "MOVE") {
+                // RFC 4918 9.8.5, the source and the destination must not be the same,
+                // with `Overwrite: T` the source would be deleted before the copy
+                if let (Some(src), Some(dest)) = (this.dav_path(&req), this.destination(&req)) {
+                    if this.fs.is_same_resource(&src, &dest).await {
+                        debug!(src = %src, dest = %dest, "copy or move onto itself");
+                        return hyper::Response::builder()
+                            .status(StatusCode::FORBIDDEN)
+                            .body(Body::empty())
                             .unwrap();
-                        return Ok(response);
                     }
-                };
-                let config = DavConfig::new().principal(user);
-                Ok(dav_server.handle_with(config, req).await)
+                }
+            }
+            if req.method() == Method::PUT && this.fs.is_draining_uploads() {
+                return hyper::Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(header::CONNECTION, "close")
+                    .header(header::RETRY_AFTER, "30")
+                    .body(Body::from("Server is shutting down".to_string()))
+                    .unwrap();
+            }
+            if req.method() == Method::PUT
+                && this.symlink_policy == SymlinkPolicy::Reject
+                && is_symlink_upload(&req)
+            {
+                debug!(path = %req.uri().path(), "refuse symlink upload");
+                return hyper::Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Symbolic links are not accepted".to_string()))
+                    .unwrap();
+            }
+            if req.method().as_str() == "MOVE"
+                && this
+                    .dav_path(&req)
+                    .map(|path| this.fs.is_being_read(&path))
+                    .unwrap_or(false)
+            {
+                debug!(path = %req.uri().path(), "refuse to move a file being read");
+                return hyper::Response::builder()
+                    .status(StatusCode::LOCKED)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+            if let Some((len, limit)) = this.filename_too_long(&req) {
+                debug!(path = %req.uri().path(), len = len, "file name too long");
+                return hyper::Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(format!(
+                        "File name of {} bytes exceeds the limit of {} bytes",
+                        len, limit
+                    )))
+                    .unwrap();
+            }
+            if let Some(response) = this.delete_tree(&req).await {
+                return response;
+            }
+            // `100 Continue` is sent by hyper once the body is read, that is after
+            // the upload passed the checks when the file is opened
+            let expect = req.headers().get(header::EXPECT);
+            if expect
+                .map(|v| !v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+                .unwrap_or(false)
+            {
+                debug!(expect = ?expect, "unsupported expectation");
+                return hyper::Response::builder()
+                    .status(StatusCode::EXPECTATION_FAILED)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+            let mut truncated = None;
+            if let (Some(limit), "PROPFIND") = (this.max_propfind_results, req.method().as_str()) {
+                let depth_zero = req
+                    .headers()
+                    .get("depth")
+                    .map(|depth| depth.as_bytes() == b"0")
+                    .unwrap_or(false);
+                if !depth_zero {
+                    truncated = this.propfind_truncation(&req, limit).await;
+                    config = config.filesystem(Box::new(this.fs.with_dir_entry_limit(limit)));
+                }
+            }
+            if req.method().as_str() == "PROPFIND" {
+                if let Some(path) = this.dav_path(&req) {
+                    this.fs.preload_dir(&path).await;
+                }
+            }
+            let req = if minimal {
+                minimal_propfind(req).await
             } else {
-                Ok(dav_server.handle(req).await)
+                req
+            };
+            let mut response = dav_server.handle_with(config, req).await;
+            if let Some(warning) = truncated {
+                response.headers_mut().insert(header::WARNING, warning);
             }
-        })
+            response
+        };
+        let this = self.clone();
+        let span = info_span!(
+            "request",
+            method = %req_method,
+            path = %req_path,
+            principal = field::Empty,
+            status = field::Empty,
+            bytes = field::Empty,
+            duration_ms = field::Empty,
+        );
+        let started = Instant::now();
+        let access_log = self.access_log;
+        let fut = async move {
+            let (mut response, stale) = track_stale(audit::with_client(client_ip, fut)).await;
+            if is_download {
+                this.set_content_headers(&mut response);
+            }
+            if stale && response.status().is_success() {
+                response.headers_mut().insert(
+                    header::WARNING,
+                    HeaderValue::from_static("110 - \"Response is Stale\""),
+                );
+            }
+            if brief && response.status() == StatusCode::MULTI_STATUS {
+                response = brief_response(response).await;
+            }
+            if !this.advertise_locking && response.headers().contains_key("dav") {
+                response.headers_mut().insert(
+                    "dav",
+                    HeaderValue::from_static("1,3,sabredav-partialupdate"),
+                );
+            }
+            if ms_author_via {
+                response
+                    .headers_mut()
+                    .insert("ms-author-via", HeaderValue::from_static("DAV"));
+            }
+            if let Some((cors, req_headers)) = cors {
+                cors.apply(&req_headers, &mut response);
+            }
+            let headers = response.headers_mut();
+            for extra in &this.extra_headers {
+                headers.append(extra.name.clone(), extra.value.clone());
+            }
+            let span = Span::current();
+            span.record("status", response.status().as_u16());
+            if let Some(bytes) = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+            {
+                span.record("bytes", bytes);
+            }
+            span.record("duration_ms", started.elapsed().as_millis() as u64);
+            if access_log {
+                info!("request completed");
+            } else {
+                debug!("request completed");
+            }
+            Ok(response)
+        };
+        Box::pin(fut.instrument(span))
     }
 }
 
 pub struct MakeSvc {
-    pub auth_user: Option<String>,
-    pub auth_password: Option<String>,
-    pub handler: DavHandler,
+    pub service: AliyunDriveWebDav,
+}
+
+/// Connection types we can get the client address from
+pub trait RemoteAddr {
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl RemoteAddr for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
 }
 
-impl<T> Service<T> for MakeSvc {
+#[cfg(feature = "rustls-tls")]
+impl RemoteAddr for tokio_rustls::server::TlsStream<AddrStream> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.get_ref().0.remote_addr()
+    }
+}
+
+impl<T: RemoteAddr> Service<&T> for MakeSvc {
     type Response = AliyunDriveWebDav;
     type Error = hyper::Error;
     #[allow(clippy::type_complexity)]
@@ -147,54 +1721,1041 @@ impl<T> Service<T> for MakeSvc {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
-        let auth_user = self.auth_user.clone();
-        let auth_password = self.auth_password.clone();
-        let handler = self.handler.clone();
-        let fut = async move {
-            Ok(AliyunDriveWebDav {
-                auth_user,
-                auth_password,
-                handler,
-            })
-        };
+    fn call(&mut self, conn: &T) -> Self::Future {
+        let mut service = self.service.clone();
+        service.remote_addr = Some(conn.remote_addr());
+        let fut = async move { Ok(service) };
         Box::pin(fut)
     }
 }
 
-#[cfg(feature = "rustls-tls")]
-fn tls_acceptor(key: &Path, cert: &Path) -> anyhow::Result<TlsAcceptor> {
-    let mut key_reader = io::BufReader::new(File::open(key)?);
-    let mut cert_reader = io::BufReader::new(File::open(cert)?);
+#[cfg(test)]
+mod tests {
+    use dav_server::memls::MemLs;
+    use hyper::HeaderMap;
 
-    let key = PrivateKey(private_keys(&mut key_reader)?.remove(0));
-    let certs = rustls_pemfile::certs(&mut cert_reader)?
-        .into_iter()
-        .map(Certificate)
-        .collect();
+    use super::*;
+    use crate::disk_cache::DiskCache;
+    use crate::drive::mock::MockDrive;
 
-    let mut config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+    fn new_fs(drive: &MockDrive) -> AliyunDriveFileSystem {
+        AliyunDriveFileSystem::new(drive.clone(), "/".to_string(), 1000, 600, 0, Vec::new())
+            .unwrap()
+    }
 
-    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    fn new_service(drive: &MockDrive) -> AliyunDriveWebDav {
+        service_for(new_fs(drive))
+    }
 
-    Ok(Arc::new(config).into())
-}
+    fn service_for(fs: AliyunDriveFileSystem) -> AliyunDriveWebDav {
+        let handler = DavHandler::builder()
+            .filesystem(Box::new(fs.clone()))
+            .locksystem(MemLs::new())
+            .build_handler();
+        AliyunDriveWebDav::new(handler, fs)
+    }
 
-#[cfg(feature = "rustls-tls")]
-fn private_keys(rd: &mut dyn io::BufRead) -> Result<Vec<Vec<u8>>, io::Error> {
-    use rustls_pemfile::{read_one, Item};
-
-    let mut keys = Vec::<Vec<u8>>::new();
-    loop {
-        match read_one(rd)? {
-            None => return Ok(keys),
-            Some(Item::RSAKey(key)) => keys.push(key),
-            Some(Item::PKCS8Key(key)) => keys.push(key),
-            Some(Item::ECKey(key)) => keys.push(key),
-            _ => {}
+    async fn send(
+        service: &mut AliyunDriveWebDav,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: impl Into<hyper::Body>,
+    ) -> (StatusCode, String) {
+        let (status, _, body) = send_for_headers(service, method, path, headers, body).await;
+        (status, body)
+    }
+
+    async fn send_for_headers(
+        service: &mut AliyunDriveWebDav,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: impl Into<hyper::Body>,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut req = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let res = service.call(req.body(body.into()).unwrap()).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8_lossy(&body).into_owned(),
+        )
+    }
+
+    #[tokio::test]
+    async fn minimal_propfind_mode_trims_allprop() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+
+        let (status, full) = send(&mut service, "PROPFIND", "/", &[("Depth", "1")], "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(full.contains("a.txt"));
+        assert!(full.contains("supportedlock"));
+
+        service.set_propfind_mode(PropfindMode::Minimal);
+        let (status, minimal) = send(&mut service, "PROPFIND", "/", &[("Depth", "1")], "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(minimal.contains("a.txt"));
+        assert!(minimal.contains("getcontentlength"));
+        assert!(!minimal.contains("supportedlock"));
+
+        // Clients asking for everything explicitly still get it
+        let (_, verbose) = send(
+            &mut service,
+            "PROPFIND",
+            "/",
+            &[("Depth", "1"), ("Brief", "f")],
+            "",
+        )
+        .await;
+        assert!(verbose.contains("supportedlock"));
+    }
+
+    #[tokio::test]
+    async fn users_with_their_own_root_lock_independently() {
+        let drive = MockDrive::new();
+        for root in ["a", "b"] {
+            let folder = drive.add_folder("root", root);
+            drive.add_file(&folder, "x.txt", "x");
+        }
+        let mut service = new_service(&drive);
+        service.set_auth_users(vec![
+            "alice:secret:/a".parse().unwrap(),
+            "bob:secret:/b".parse().unwrap(),
+        ]);
+        let lock = r#"<?xml version="1.0" encoding="utf-8"?><D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"#;
+
+        for authorization in ["Basic YWxpY2U6c2VjcmV0", "Basic Ym9iOnNlY3JldA=="] {
+            let headers = [("Authorization", authorization), ("Timeout", "Second-60")];
+            let (status, _) = send(&mut service, "LOCK", "/x.txt", &headers, lock).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        // the lock of alice still holds for alice
+        let headers = [("Authorization", "Basic YWxpY2U6c2VjcmV0")];
+        let (status, _) = send(&mut service, "LOCK", "/x.txt", &headers, lock).await;
+        assert_eq!(status, StatusCode::LOCKED);
+    }
+
+    #[tokio::test]
+    async fn degraded_writes_show_in_health_and_metrics() {
+        let drive = MockDrive::new();
+        let mut fs = new_fs(&drive);
+        fs.set_auto_read_only(Some(1), Duration::from_secs(600));
+        let mut service = service_for(fs);
+        let token_health = TokenHealth::default();
+        token_health.set_valid(true);
+        service
+            .set_health_check(Some("/healthz".to_string()), token_health, None)
+            .set_metrics_path(Some("/metrics".to_string()));
+
+        let (status, body) = send(&mut service, "GET", "/healthz", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "OK\n"));
+
+        drive.fail("create_folder");
+        let (status, _) = send(&mut service, "MKCOL", "/new", &[], "").await;
+        assert!(status.is_server_error(), "{}", status);
+        let (status, body) = send(&mut service, "GET", "/healthz", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("read-only"), "{}", body);
+        #[cfg(feature = "metrics")]
+        {
+            let (_, body) = send(&mut service, "GET", "/metrics", &[], "").await;
+            assert!(body.contains("webdav_write_degraded 1"), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn if_range_keeps_the_listing_of_unchanged_files() {
+        let drive = MockDrive::new();
+        let file_id = drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+
+        let (_, listing) = send(&mut service, "PROPFIND", "/", &[("Depth", "1")], "").await;
+        let etag = listing
+            .split("<D:getetag>")
+            .nth(2)
+            .and_then(|rest| rest.split('<').next())
+            .map(|etag| format!("\"{}\"", etag.trim_matches('"')))
+            .unwrap();
+        let lookups = || drive.calls("list_all") + drive.calls("get_by_path");
+        let looked_up = lookups();
+        let headers = [("Range", "bytes=1-"), ("If-Range", etag.as_str())];
+        let (status, body) = send(&mut service, "GET", "/a.txt", &headers, "").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::PARTIAL_CONTENT, "ello")
+        );
+        assert_eq!(lookups(), looked_up);
+
+        drive.set_content(&file_id, "hello, world");
+        let (status, body) = send(&mut service, "GET", "/a.txt", &headers, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello, world"));
+    }
+
+    #[tokio::test]
+    async fn digest_is_checked_against_the_unstripped_uri() {
+        use md5::{Digest, Md5};
+
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service
+            .set_auth(Some("alice".to_string()), Some("secret".to_string()))
+            .set_auth_scheme(AuthScheme::Digest)
+            .set_strip_suffix(Some("dav".to_string()));
+
+        let req = Request::get("/a.txt/dav")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let res = service.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let challenge = res.headers()[header::WWW_AUTHENTICATE].to_str().unwrap();
+        let nonce = challenge
+            .split("nonce=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+
+        let md5 = |s: String| format!("{:x}", Md5::digest(s.as_bytes()));
+        let ha1 = md5(format!("alice:{}:secret", REALM));
+        let ha2 = md5("GET:/a.txt/dav".to_string());
+        let response = md5(format!("{}:{}:{}", ha1, nonce, ha2));
+        let authorization = format!(
+            r#"Digest username="alice", realm="{}", nonce="{}", uri="/a.txt/dav", response="{}""#,
+            REALM, nonce, response
+        );
+        let headers = [("Authorization", authorization.as_str())];
+        let (status, body) = send(&mut service, "GET", "/a.txt/dav", &headers, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+    }
+
+    #[tokio::test]
+    async fn long_names_are_refused_before_the_body_is_read() {
+        let drive = MockDrive::new();
+        let mut service = new_service(&drive);
+        service.fs.set_max_filename_bytes(Some(10));
+
+        let name = "/%E6%96%87%E4%BB%B6%E5%90%8D%E5%AD%97";
+        let (status, body) = send(&mut service, "PUT", name, &[], "x").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("12 bytes"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn head_refreshes_size_changed_upstream() {
+        let drive = MockDrive::new();
+        let file_id = drive.add_file("root", "a.txt", "hello");
+        let fs = AliyunDriveFileSystem::new(drive.clone(), "/".to_string(), 1000, 1, 0, Vec::new())
+            .unwrap();
+        let mut service = service_for(fs);
+        async fn head(service: &mut AliyunDriveWebDav) -> HeaderValue {
+            let req = Request::head("/a.txt").body(hyper::Body::empty()).unwrap();
+            let res = service.call(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            res.headers()[header::CONTENT_LENGTH].clone()
+        }
+
+        let (status, _) = send(&mut service, "PROPFIND", "/", &[("Depth", "1")], "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        drive.set_content(&file_id, "hello, world");
+        // served from the cache until it expires
+        let lookups =
+            || drive.calls("list_all") + drive.calls("get_by_path") + drive.calls("get_file");
+        let looked_up = lookups();
+        assert_eq!(head(&mut service).await, "5");
+        assert_eq!(lookups(), looked_up);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(head(&mut service).await, "12");
+    }
+
+    #[tokio::test]
+    async fn requests_for_other_hosts_are_misdirected() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service.set_allowed_hosts(vec!["Example.com".to_string()]);
+
+        for host in ["example.com", "EXAMPLE.com:8080"] {
+            let (status, body) = send(&mut service, "GET", "/a.txt", &[("Host", host)], "").await;
+            assert_eq!(
+                (status, body.as_str()),
+                (StatusCode::OK, "hello"),
+                "{}",
+                host
+            );
+        }
+        let (status, _) = send(&mut service, "GET", "/a.txt", &[("Host", "evil.com")], "").await;
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+    }
+
+    fn cors() -> Cors {
+        let methods = ["GET", "PUT", "PROPFIND"].map(String::from).to_vec();
+        let headers = ["Depth", "Authorization"].map(String::from).to_vec();
+        Cors::new(
+            vec!["https://app.example".to_string()],
+            methods,
+            headers,
+            600,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_are_answered_for_allowed_origins() {
+        let drive = MockDrive::new();
+        let mut service = new_service(&drive);
+        service.set_cors(Some(cors()));
+
+        let preflight = [
+            ("Origin", "https://app.example"),
+            ("Access-Control-Request-Method", "PROPFIND"),
+        ];
+        let (status, headers, _) =
+            send_for_headers(&mut service, "OPTIONS", "/", &preflight, "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, PUT, PROPFIND"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Depth, Authorization"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let preflight = [
+            ("Origin", "https://evil.example"),
+            ("Access-Control-Request-Method", "PROPFIND"),
+        ];
+        let (status, headers, _) =
+            send_for_headers(&mut service, "OPTIONS", "/", &preflight, "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+
+        // without Access-Control-Request-Method it is a WebDAV OPTIONS request
+        let origin = [("Origin", "https://app.example")];
+        let (status, headers, _) =
+            send_for_headers(&mut service, "OPTIONS", "/", &origin, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains_key("DAV"));
+    }
+
+    #[tokio::test]
+    async fn cors_headers_are_added_to_allowed_origins() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service.set_cors(Some(cors()));
+
+        let origin = [("Origin", "https://app.example")];
+        let (status, headers, body) =
+            send_for_headers(&mut service, "GET", "/a.txt", &origin, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert!(headers[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap()
+            .contains("ETag"));
+
+        let origin = [("Origin", "https://evil.example")];
+        let (status, headers, _) =
+            send_for_headers(&mut service, "GET", "/a.txt", &origin, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn public_paths_are_read_without_credentials() {
+        let drive = MockDrive::new();
+        for dir in ["public", "secret"] {
+            let folder = drive.add_folder("root", dir);
+            drive.add_file(&folder, "a.txt", dir);
+        }
+        let mut service = new_service(&drive);
+        service
+            .set_auth(Some("alice".to_string()), Some("secret".to_string()))
+            .set_public_paths(vec!["public/".to_string()]);
+
+        let (status, body) = send(&mut service, "GET", "/public/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "public"));
+        let (status, _) = send(&mut service, "GET", "/secret/a.txt", &[], "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&mut service, "GET", "/public/../secret/a.txt", &[], "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&mut service, "PUT", "/public/b.txt", &[], "x").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(drive.content("/public/b.txt").is_none());
+    }
+
+    #[tokio::test]
+    async fn principal_header_is_only_trusted_from_proxies() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service
+            .set_auth(Some("alice".to_string()), Some("secret".to_string()))
+            .set_trusted_proxies(vec!["10.0.0.0/8".parse().unwrap()])
+            .set_principal_header(Some(HeaderName::from_static("x-remote-user")));
+        let remote_user = [("X-Remote-User", "bob")];
+
+        service.remote_addr = Some(([10, 0, 0, 1], 40000).into());
+        let (status, body) = send(&mut service, "GET", "/a.txt", &remote_user, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        let (status, _) = send(&mut service, "GET", "/a.txt", &[], "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        service.remote_addr = Some(([192, 168, 1, 1], 40000).into());
+        let (status, _) = send(&mut service, "GET", "/a.txt", &remote_user, "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    /// Serve `service` over HTTP, returns its address
+    fn serve(service: AliyunDriveWebDav) -> SocketAddr {
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(MakeSvc { service });
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Status line and headers of the next response on the connection
+    async fn read_head(stream: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_answered_once_the_upload_is_accepted() {
+        use tokio::io::AsyncWriteExt;
+
+        let drive = MockDrive::new();
+        drive.set_total_space(1000);
+        let mut fs = new_fs(&drive);
+        fs.set_quota_reserve(Some("500".parse().unwrap()));
+        let mut service = service_for(fs);
+        service.set_auth(Some("alice".to_string()), Some("secret".to_string()));
+        let addr = serve(service);
+        let put = |length: usize, authorization: &str| {
+            format!(
+                "PUT /a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n{}Expect: 100-continue\r\n\r\n",
+                length, authorization
+            )
+        };
+        let alice = "Authorization: Basic YWxpY2U6c2VjcmV0\r\n";
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(put(5, alice).as_bytes()).await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 100 Continue"), "{}", head);
+        stream.write_all(b"hello").await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 201"), "{}", head);
+        assert_eq!(drive.content("/a.txt").unwrap(), "hello");
+
+        // rejected with the final status, without the body being sent
+        for (request, status) in [(put(5, ""), "401"), (put(600, alice), "507")] {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let head = read_head(&mut stream).await;
+            assert!(
+                head.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}",
+                head
+            );
+        }
+        assert_eq!(drive.calls("create_file_with_proof"), 1);
+    }
+
+    #[tokio::test]
+    async fn moving_a_locked_file_needs_the_lock_token() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        let lock = r#"<?xml version="1.0" encoding="utf-8"?><D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"#;
+        let (status, headers, _) =
+            send_for_headers(&mut service, "LOCK", "/a.txt", &[], lock).await;
+        assert_eq!(status, StatusCode::OK);
+        let token = headers["Lock-Token"].to_str().unwrap().to_string();
+
+        let destination = ("Destination", "/b.txt");
+        let (status, _) = send(&mut service, "MOVE", "/a.txt", &[destination], "").await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert!(drive.content("/a.txt").is_some());
+
+        let submitted = format!("({})", token);
+        let headers = [destination, ("If", submitted.as_str())];
+        let (status, _) = send(&mut service, "MOVE", "/a.txt", &headers, "").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/b.txt").unwrap(), "hello");
+        assert!(drive.content("/a.txt").is_none());
+    }
+
+    #[tokio::test]
+    async fn copy_and_move_onto_themselves_are_forbidden() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "a.txt", "hello");
+        let mut service = new_service(&drive);
+
+        for method in ["COPY", "MOVE"] {
+            for (src, dest) in [
+                ("/docs/a.txt", "/docs/a.txt"),
+                ("/docs/a.txt", "http://localhost/docs/%61.txt"),
+                ("/docs", "/docs/"),
+                ("/docs/", "http://localhost/docs"),
+            ] {
+                let headers = [("Destination", dest), ("Overwrite", "T")];
+                let (status, _) = send(&mut service, method, src, &headers, "").await;
+                assert_eq!(status, StatusCode::FORBIDDEN, "{} {} {}", method, src, dest);
+            }
+        }
+        assert_eq!(drive.names(&docs), ["a.txt"]);
+        assert_eq!(drive.content("/docs/a.txt").unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn webdav_is_only_served_under_the_dav_prefix() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let fs = new_fs(&drive);
+        let handler = DavHandler::builder()
+            .filesystem(Box::new(fs.clone()))
+            .locksystem(MemLs::new())
+            .strip_prefix("/dav")
+            .build_handler();
+        let mut service = AliyunDriveWebDav::new(handler, fs);
+        let token_health = TokenHealth::default();
+        token_health.set_valid(true);
+        service
+            .set_dav_prefix(Some("/dav".to_string()))
+            .set_health_check(Some("/healthz".to_string()), token_health, None);
+
+        let (status, body) = send(&mut service, "GET", "/dav/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        let (status, body) = send(&mut service, "PROPFIND", "/dav/", &[("Depth", "1")], "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("/dav/a.txt"), "{}", body);
+        let (status, _) = send(&mut service, "GET", "/healthz", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+
+        for path in ["/a.txt", "/", "/davx/a.txt"] {
+            let (status, _) = send(&mut service, "PROPFIND", path, &[("Depth", "1")], "").await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn symlink_uploads_are_rejected_by_the_policy() {
+        let drive = MockDrive::new();
+        let mut service = new_service(&drive);
+        service.set_symlink_policy(SymlinkPolicy::Reject);
+
+        let symlink = [("Content-Type", "inode/symlink"), ("Content-Length", "5")];
+        let (status, _) = send(&mut service, "PUT", "/link", &symlink, "a.txt").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let length = [("Content-Length", "5")];
+        let (status, _) = send(&mut service, "PUT", "/link.rclonelink", &length, "a.txt").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(drive.names("root").is_empty());
+
+        let (status, _) = send(&mut service, "PUT", "/a.txt", &length, "hello").await;
+        assert!(status.is_success(), "{}", status);
+        service.set_symlink_policy(SymlinkPolicy::Store);
+        let (status, _) = send(&mut service, "PUT", "/link", &symlink, "a.txt").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/link").unwrap(), "a.txt");
+    }
+
+    #[tokio::test]
+    async fn paths_outside_of_the_root_get_the_configured_status() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+
+        let (status, _) = send(&mut service, "GET", "/../a.txt", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let dest = [("Destination", "http://localhost/../b.txt")];
+        let (status, _) = send(&mut service, "COPY", "/a.txt", &dest, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        service.set_out_of_root_status(OutOfRootStatus::Forbidden);
+        let (status, _) = send(&mut service, "GET", "/../a.txt", &[], "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = send(&mut service, "COPY", "/a.txt", &dest, "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(drive.calls("copy"), 0);
+
+        let (status, body) = send(&mut service, "GET", "/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+    }
+
+    #[tokio::test]
+    async fn options_stops_advertising_locking_when_asked() {
+        let drive = MockDrive::new();
+        let mut service = new_service(&drive);
+        let (status, headers, _) = send_for_headers(&mut service, "OPTIONS", "/", &[], "").await;
+        assert!(status.is_success(), "{}", status);
+        let dav = headers["dav"].to_str().unwrap();
+        assert!(dav.split(',').any(|class| class == "2"), "{}", dav);
+
+        service.set_advertise_locking(false);
+        let (_, headers, _) = send_for_headers(&mut service, "OPTIONS", "/", &[], "").await;
+        assert_eq!(headers["dav"], "1,3,sabredav-partialupdate");
+    }
+
+    #[tokio::test]
+    async fn stale_listings_are_served_on_upstream_errors() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "a.txt", "hello");
+        let mut fs =
+            AliyunDriveFileSystem::new(drive.clone(), "/".to_string(), 1000, 1, 0, Vec::new())
+                .unwrap();
+        fs.set_serve_stale_on_error(Some(Duration::from_secs(60)));
+        let mut service = service_for(fs);
+        let depth = [("Depth", "1")];
+
+        let (status, headers, body) =
+            send_for_headers(&mut service, "PROPFIND", "/docs/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(!headers.contains_key(header::WARNING));
+        assert!(body.contains("a.txt"), "{}", body);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        drive.fail("list_all");
+        let (status, headers, body) =
+            send_for_headers(&mut service, "PROPFIND", "/docs/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(headers[header::WARNING]
+            .to_str()
+            .unwrap()
+            .starts_with("110 "));
+        assert!(body.contains("a.txt"), "{}", body);
+
+        // without a stale window the listing is missing
+        let fs = AliyunDriveFileSystem::new(drive.clone(), "/".to_string(), 1000, 1, 0, Vec::new())
+            .unwrap();
+        let mut service = service_for(fs);
+        let (_, headers, body) =
+            send_for_headers(&mut service, "PROPFIND", "/docs/", &depth, "").await;
+        assert!(!headers.contains_key(header::WARNING));
+        assert!(!body.contains("a.txt"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn auto_index_is_served_as_json_when_asked() {
+        let drive = MockDrive::new();
+        drive.add_folder("root", "docs");
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service.set_auto_index(true);
+
+        let accept = [("Accept", "application/json")];
+        let (status, headers, body) = send_for_headers(&mut service, "GET", "/", &accept, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        let index: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let mut entries: Vec<_> = index
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["name"].as_str().unwrap(), e["is_dir"].as_bool().unwrap()))
+            .collect();
+        entries.sort();
+        assert_eq!(entries, [("a.txt", false), ("docs", true)]);
+        let file = index
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|e| e["name"] == "a.txt")
+            .unwrap();
+        assert_eq!(file["size"], 5);
+        assert_eq!(file["mime"], "text/plain");
+
+        let (_, headers, _) = send_for_headers(&mut service, "GET", "/?format=json", &[], "").await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        // files are served as usual
+        let (_, body) = send(&mut service, "GET", "/a.txt", &accept, "").await;
+        assert_eq!(body, "hello");
+        let (_, headers, _) = send_for_headers(&mut service, "GET", "/", &[], "").await;
+        assert_ne!(
+            headers.get(header::CONTENT_TYPE).map(|v| v.as_bytes()),
+            Some(&b"application/json"[..])
+        );
+    }
+
+    #[test]
+    fn read_buffer_size_header_is_clamped_and_only_trusted_from_proxies() {
+        let drive = MockDrive::new();
+        let mut service = new_service(&drive);
+        service.set_trusted_proxies(vec!["10.0.0.0/8".parse().unwrap()]);
+        let request = |size: &str| {
+            Request::get("/a.txt")
+                .header(READ_BUFFER_SIZE_HEADER, size)
+                .body(hyper::Body::empty())
+                .unwrap()
         };
+
+        service.remote_addr = Some(([10, 0, 0, 1], 40000).into());
+        let size =
+            |service: &AliyunDriveWebDav, value| service.read_buf_size_override(&request(value));
+        assert_eq!(size(&service, "1048576"), Some(1048576));
+        assert_eq!(size(&service, "1"), Some(MIN_READ_BUFFER_SIZE));
+        assert_eq!(size(&service, "1000000000000"), Some(MAX_READ_BUFFER_SIZE));
+        assert_eq!(size(&service, "lots"), None);
+        let plain = Request::get("/a.txt").body(hyper::Body::empty()).unwrap();
+        assert_eq!(service.read_buf_size_override(&plain), None);
+
+        service.remote_addr = Some(([192, 168, 1, 1], 40000).into());
+        assert_eq!(size(&service, "1048576"), None);
+    }
+
+    #[tokio::test]
+    async fn extra_headers_are_added_to_responses() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service.set_extra_headers(vec![
+            "X-Frame-Options: DENY".parse().unwrap(),
+            "Link: <a>".parse().unwrap(),
+            "Link: <b>".parse().unwrap(),
+        ]);
+
+        let (_, headers, _) = send_for_headers(&mut service, "GET", "/a.txt", &[], "").await;
+        assert_eq!(headers["x-frame-options"], "DENY");
+        let links: Vec<_> = headers.get_all(header::LINK).iter().collect();
+        assert_eq!(links, ["<a>", "<b>"]);
+        let (status, headers, _) = send_for_headers(&mut service, "GET", "/b.txt", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers["x-frame-options"], "DENY");
+
+        assert!("no-colon".parse::<ExtraHeader>().is_err());
+        assert!("Content-Length: 1".parse::<ExtraHeader>().is_err());
+        assert!("Keep-Alive: 1".parse::<ExtraHeader>().is_err());
+    }
+
+    /// Connections made to a listener that accepts none of them, until one hangs
+    #[cfg(target_os = "linux")]
+    async fn connect_until_hanging(addr: SocketAddr) -> Option<usize> {
+        let mut pending = Vec::new();
+        for attempt in 0..8 {
+            let connect = tokio::net::TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(300), connect).await {
+                Ok(stream) => pending.push(stream.unwrap()),
+                Err(_) => return Some(attempt),
+            }
+        }
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn listen_backlog_limits_pending_connections() {
+        let incoming = bind(([127, 0, 0, 1], 0).into(), Some(1)).unwrap();
+        let hanging = connect_until_hanging(incoming.local_addr()).await;
+        assert!(matches!(hanging, Some(1..=3)), "{:?}", hanging);
+
+        let incoming = bind(([127, 0, 0, 1], 0).into(), None).unwrap();
+        assert_eq!(connect_until_hanging(incoming.local_addr()).await, None);
+    }
+
+    #[tokio::test]
+    async fn text_downloads_get_a_charset_and_language() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        drive.add_file("root", "a.bin", "hello");
+        let mut fs = new_fs(&drive);
+        fs.set_content_language(Some("de".to_string()));
+        let mut service = service_for(fs);
+        service
+            .set_text_charset(Some(HeaderValue::from_static("utf-8")))
+            .set_content_language(Some(HeaderValue::from_static("de")));
+
+        let (_, headers, _) = send_for_headers(&mut service, "GET", "/a.txt", &[], "").await;
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(headers[header::CONTENT_LANGUAGE], "de");
+        let (_, headers, _) = send_for_headers(&mut service, "GET", "/a.bin", &[], "").await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/octet-stream");
+        let (_, headers, _) = send_for_headers(&mut service, "GET", "/b.txt", &[], "").await;
+        assert!(!headers.contains_key(header::CONTENT_LANGUAGE));
+
+        let propfind = r#"<?xml version="1.0"?>
+            <D:propfind xmlns:D="DAV:"><D:prop><D:getcontentlanguage/></D:prop></D:propfind>"#;
+        let depth = [("Depth", "0")];
+        let (_, body) = send(&mut service, "PROPFIND", "/a.txt", &depth, propfind).await;
+        assert!(body.contains(">de</D:getcontentlanguage>"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn maintenance_page_is_served_while_the_upstream_is_down() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let health = UpstreamHealth::default();
+        let mut service = new_service(&drive);
+        service.set_maintenance_page(health.clone(), Bytes::from_static(b"<h1>Back soon</h1>"));
+        let browser = [("Accept", "text/html")];
+
+        let (status, body) = send(&mut service, "GET", "/a.txt", &browser, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+
+        drive.fail("get_quota");
+        health.spawn_probe(drive.clone(), Duration::from_millis(10));
+        while !health.is_down() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (status, headers, body) =
+            send_for_headers(&mut service, "GET", "/a.txt", &browser, "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers[header::RETRY_AFTER], "30");
+        assert_eq!(body, "<h1>Back soon</h1>");
+        // other clients only get the status
+        let (status, body) = send(&mut service, "PROPFIND", "/", &[], "").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable")
+        );
+    }
+
+    #[tokio::test]
+    async fn disk_cached_files_are_served_while_the_upstream_is_down() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        drive.add_file("root", "b.txt", "world");
+        let dir = std::env::temp_dir().join(format!("disk-cache-test-{}", std::process::id()));
+        let health = UpstreamHealth::default();
+        let mut fs = new_fs(&drive);
+        fs.set_disk_cache(Some(DiskCache::new(dir.clone(), 1 << 20)))
+            .set_upstream_health(Some(health.clone()));
+        let mut service = service_for(fs);
+        service.set_maintenance_page(health.clone(), Bytes::from_static(b"maintenance"));
+
+        // cached files are looked up in the cached listings
+        let depth = [("Depth", "1")];
+        send(&mut service, "PROPFIND", "/", &depth, "").await;
+        let (status, headers, body) =
+            send_for_headers(&mut service, "GET", "/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        assert!(!headers.contains_key(header::WARNING));
+
+        drive.fail("get_quota");
+        drive.fail("download");
+        health.spawn_probe(drive.clone(), Duration::from_millis(10));
+        while !health.is_down() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (status, headers, body) =
+            send_for_headers(&mut service, "GET", "/a.txt", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        assert!(headers[header::WARNING]
+            .to_str()
+            .unwrap()
+            .starts_with("110 "));
+        let (status, _) = send(&mut service, "GET", "/b.txt", &[], "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_in_progress_finish_while_draining() {
+        let drive = MockDrive::new();
+        let fs = new_fs(&drive);
+        let mut service = service_for(fs.clone());
+
+        let (mut body, request_body) = hyper::Body::channel();
+        let req = Request::put("/a.txt")
+            .header(header::CONTENT_LENGTH, "10")
+            .body(request_body)
+            .unwrap();
+        let upload = tokio::spawn(service.clone().call(req));
+        body.send_data("hello".into()).await.unwrap();
+        while fs.active_uploads().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        fs.drain_uploads();
+        let headers = [("Content-Length", "5")];
+        let (status, headers, _) =
+            send_for_headers(&mut service, "PUT", "/b.txt", &headers, "hello").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers[header::CONNECTION], "close");
+        // everything else is still served
+        let depth = [("Depth", "0")];
+        let (status, _) = send(&mut service, "PROPFIND", "/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+
+        body.send_data("world".into()).await.unwrap();
+        drop(body);
+        let res = upload.await.unwrap().unwrap();
+        assert!(res.status().is_success(), "{}", res.status());
+        assert_eq!(drive.content("/a.txt").unwrap(), "helloworld");
+        assert!(fs.active_uploads().is_empty());
+        assert!(drive.content("/b.txt").is_none());
+    }
+
+    #[tokio::test]
+    async fn thumbnails_are_served_for_images() {
+        let drive = MockDrive::new();
+        let photo = drive.add_file("root", "photo.jpg", "jpeg");
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+
+        // disabled by default
+        let (_, body) = send(&mut service, "GET", "/photo.jpg?thumbnail=256", &[], "").await;
+        assert_eq!(body, "jpeg");
+
+        service.set_enable_thumbnails(true);
+        let (status, headers, body) =
+            send_for_headers(&mut service, "GET", "/photo.jpg?thumbnail=256", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(body, format!("thumbnail {}/256", photo));
+        let (_, body) = send(&mut service, "GET", "/a.txt?thumbnail=256", &[], "").await;
+        assert_eq!(body, "hello");
+        let (status, _) = send(&mut service, "GET", "/photo.jpg?thumbnail=8", &[], "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(&mut service, "GET", "/missing.jpg?thumbnail=256", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn quirks_apply_to_matching_user_agents() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service.set_quirks(vec!["curl/=no-range".parse().unwrap()], true);
+
+        let curl = [("User-Agent", "curl/8.0"), ("Range", "bytes=0-1")];
+        let (status, body) = send(&mut service, "GET", "/a.txt", &curl, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        let other = [("User-Agent", "rclone/1.60"), ("Range", "bytes=0-1")];
+        let (status, body) = send(&mut service, "GET", "/a.txt", &other, "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::PARTIAL_CONTENT, "he"));
+
+        // properties that were not found are left out for Windows by default
+        let propfind = r#"<?xml version="1.0"?>
+            <D:propfind xmlns:D="DAV:"><D:prop><D:getetag/><D:missing/></D:prop></D:propfind>"#;
+        let windows = [
+            ("User-Agent", "Microsoft-WebDAV-MiniRedir/10.0"),
+            ("Depth", "0"),
+        ];
+        let (_, body) = send(&mut service, "PROPFIND", "/a.txt", &windows, propfind).await;
+        assert!(
+            body.contains("getetag") && !body.contains("missing"),
+            "{}",
+            body
+        );
+        // an empty rule disables the built-in one
+        service.set_quirks(vec!["Microsoft-WebDAV-MiniRedir=".parse().unwrap()], true);
+        let (_, body) = send(&mut service, "PROPFIND", "/a.txt", &windows, propfind).await;
+        assert!(body.contains("missing"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn suffix_is_stripped_from_paths_and_destinations() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "a.txt", "hello");
+        let mut service = new_service(&drive);
+        service.set_strip_suffix(Some("/webdav/".to_string()));
+
+        let (status, body) = send(&mut service, "GET", "/docs/a.txt/webdav", &[], "").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "hello"));
+        let depth = [("Depth", "1")];
+        let (status, body) = send(&mut service, "PROPFIND", "/webdav/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("/docs/"), "{}", body);
+
+        let dest = [("Destination", "http://localhost/docs/b.txt/webdav")];
+        let (status, _) = send(&mut service, "COPY", "/docs/a.txt/webdav", &dest, "").await;
+        assert!(status.is_success(), "{}", status);
+        assert_eq!(drive.content("/docs/b.txt").unwrap(), "hello");
+
+        // only a whole trailing component is stripped
+        let (status, _) = send(&mut service, "GET", "/docs/a.txtwebdav", &[], "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn large_propfinds_are_truncated_with_a_warning() {
+        let drive = MockDrive::new();
+        for i in 0..5 {
+            drive.add_file("root", &format!("{}.txt", i), "x");
+        }
+        let mut service = new_service(&drive);
+        service.set_max_propfind_results(Some(3));
+        let depth = [("Depth", "1")];
+
+        let (status, headers, body) =
+            send_for_headers(&mut service, "PROPFIND", "/", &depth, "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_eq!(
+            headers[header::WARNING],
+            "199 - \"Listing truncated to 3 of 5 entries\""
+        );
+        assert_eq!(body.matches(".txt</D:href>").count(), 3, "{}", body);
+
+        // a single entry is never truncated
+        let depth = [("Depth", "0")];
+        let (_, headers, _) = send_for_headers(&mut service, "PROPFIND", "/", &depth, "").await;
+        assert!(!headers.contains_key(header::WARNING));
+        service.set_max_propfind_results(Some(5));
+        let depth = [("Depth", "1")];
+        let (_, headers, body) = send_for_headers(&mut service, "PROPFIND", "/", &depth, "").await;
+        assert!(!headers.contains_key(header::WARNING));
+        assert_eq!(body.matches(".txt</D:href>").count(), 5, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn favicon_and_robots_are_served_before_authentication() {
+        let drive = MockDrive::new();
+        let mut service = new_service(&drive);
+        service.set_auth(Some("alice".to_string()), Some("secret".to_string()));
+        service.set_static_files(StaticFiles::load(None, None, true).unwrap());
+
+        let (status, headers, _) =
+            send_for_headers(&mut service, "GET", "/favicon.ico", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/x-icon");
+        let favicon_len = include_bytes!("favicon.ico").len().to_string();
+        assert_eq!(headers[header::CONTENT_LENGTH], favicon_len.as_str());
+        let (status, body) = send(&mut service, "GET", "/robots.txt", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "User-agent: *\nDisallow: /\n");
+        let (status, _) = send(&mut service, "PROPFIND", "/robots.txt", &[], "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // overrides replace the built-in files, which can be turned off
+        let robots = std::env::temp_dir().join(format!("robots-{}.txt", std::process::id()));
+        std::fs::write(&robots, "User-agent: *\nAllow: /\n").unwrap();
+        service.set_static_files(StaticFiles::load(None, Some(&robots), false).unwrap());
+        std::fs::remove_file(&robots).unwrap();
+        let (status, body) = send(&mut service, "GET", "/robots.txt", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "User-agent: *\nAllow: /\n");
+        let (status, _) = send(&mut service, "GET", "/favicon.ico", &[], "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(StaticFiles::load(Some(&robots), None, true).is_err());
     }
 }